@@ -0,0 +1,275 @@
+(function () {
+  "use strict";
+
+  var data = JSON.parse(document.getElementById("hayabusa_data").textContent);
+  var root = document.getElementById("hayabusa_interactive");
+  var LEVELS = ["emergency", "critical", "high", "medium", "low", "informational", "undefined"];
+  var PAGE_SIZE = 100;
+  var detections = data.Detections;
+  var state = { levels: {}, computer: "", rule: "", search: "", from: "", to: "", page: 0 };
+  LEVELS.forEach(function (l) { state.levels[l] = true; });
+
+  function esc(s) {
+    return String(s).replace(/[&<>"']/g, function (c) {
+      return { "&": "&amp;", "<": "&lt;", ">": "&gt;", "\"": "&quot;", "'": "&#39;" }[c];
+    });
+  }
+
+  function el(tag, attrs, html) {
+    var e = document.createElement(tag);
+    Object.keys(attrs || {}).forEach(function (k) { e.setAttribute(k, attrs[k]); });
+    if (html !== undefined) { e.innerHTML = html; }
+    return e;
+  }
+
+  function toEpoch(s) {
+    var t = Date.parse(s);
+    return isNaN(t) ? null : Math.floor(t / 1000);
+  }
+
+  function toInputTime(epoch) {
+    return new Date(epoch * 1000).toISOString().slice(0, 19);
+  }
+
+  function countBy(list, key) {
+    var counts = {};
+    list.forEach(function (d) { counts[d[key]] = (counts[d[key]] || 0) + 1; });
+    return Object.keys(counts).map(function (k) { return [k, counts[k]]; })
+      .sort(function (a, b) { return b[1] - a[1] || (a[0] < b[0] ? -1 : 1); });
+  }
+
+  function matches(d, computer) {
+    if (!state.levels[d.Level]) { return false; }
+    if (computer !== undefined && d.Computer !== computer) { return false; }
+    if (computer === undefined && state.computer && d.Computer !== state.computer) { return false; }
+    if (state.rule && d.Rule !== state.rule) { return false; }
+    var from = toEpoch(state.from + "Z");
+    var to = toEpoch(state.to + "Z");
+    if (from !== null && d.Time < from) { return false; }
+    if (to !== null && d.Time > to) { return false; }
+    if (state.search) {
+      var text = (d.Rule + " " + d.Computer + " " + JSON.stringify(d.Fields)).toLowerCase();
+      if (text.indexOf(state.search.toLowerCase()) < 0) { return false; }
+    }
+    return true;
+  }
+
+  // 区間ごとの件数を棒グラフで描画する。クリックした区間で時刻を絞り込む
+  function histogram(hist) {
+    var counts = hist.Counts;
+    if (!counts.length) { return el("p", {}, "No detections."); }
+    var max = Math.max.apply(null, counts);
+    var width = 820, height = 120, bar = width / counts.length;
+    var svg = "<svg class=\"hist\" viewBox=\"0 0 " + width + " " + (height + 20) + "\">";
+    counts.forEach(function (c, i) {
+      var h = max ? Math.max(c ? 1 : 0, c / max * height) : 0;
+      var start = hist.Start + i * hist.BinSeconds;
+      svg += "<rect data-start=\"" + start + "\" x=\"" + (i * bar) + "\" y=\"" + (height - h) +
+        "\" width=\"" + Math.max(bar - 1, 1) + "\" height=\"" + h + "\"><title>" +
+        esc(toInputTime(start).replace("T", " ")) + " UTC: " + c + "</title></rect>";
+    });
+    svg += "<text x=\"0\" y=\"" + (height + 15) + "\">" + esc(toInputTime(hist.Start).replace("T", " ")) + "</text>";
+    svg += "<text x=\"" + width + "\" y=\"" + (height + 15) + "\" text-anchor=\"end\">" +
+      esc(toInputTime(hist.Start + counts.length * hist.BinSeconds).replace("T", " ")) + "</text></svg>";
+    var div = el("div", {}, svg);
+    div.querySelectorAll("rect").forEach(function (r) {
+      r.addEventListener("click", function () {
+        var start = Number(r.getAttribute("data-start"));
+        state.from = toInputTime(start);
+        state.to = toInputTime(start + hist.BinSeconds - 1);
+        state.page = 0;
+        location.hash = "detections";
+        render();
+      });
+    });
+    return div;
+  }
+
+  function binTimes(times) {
+    if (!times.length) { return { Start: 0, BinSeconds: 1, Counts: [] }; }
+    var start = Math.min.apply(null, times), end = Math.max.apply(null, times);
+    var binSeconds = Math.max(Math.floor((end - start) / 100) + 1, 1);
+    var counts = new Array(Math.floor((end - start) / binSeconds) + 1).fill(0);
+    times.forEach(function (t) { counts[Math.floor((t - start) / binSeconds)] += 1; });
+    return { Start: start, BinSeconds: binSeconds, Counts: counts };
+  }
+
+  function computerLink(name) {
+    return "<a href=\"#computer=" + encodeURIComponent(name) + "\">" + esc(name) + "</a>";
+  }
+
+  function detectionTable(list) {
+    var wrap = el("div");
+    var pages = Math.max(Math.ceil(list.length / PAGE_SIZE), 1);
+    state.page = Math.min(state.page, pages - 1);
+    var rows = list.slice(state.page * PAGE_SIZE, (state.page + 1) * PAGE_SIZE);
+    var html = "<p>" + list.length + " detections" + (pages > 1 ? " (page " + (state.page + 1) + " of " + pages + ")" : "") + "</p>";
+    html += "<table class=\"detections\"><thead><tr><th>Timestamp</th><th>Computer</th><th>Level</th><th>Rule</th><th>EventID</th></tr></thead><tbody>";
+    rows.forEach(function (d, i) {
+      html += "<tr class=\"detection\" data-index=\"" + i + "\"><td>" + esc(d.Timestamp) + "</td><td>" + computerLink(d.Computer) +
+        "</td><td class=\"level-" + esc(d.Level) + "\">" + esc(d.Level) + "</td><td>" + esc(d.Rule) + "</td><td>" + esc(d.EventID) + "</td></tr>";
+    });
+    html += "</tbody></table>";
+    wrap.innerHTML = html;
+    wrap.querySelectorAll("tr.detection").forEach(function (tr) {
+      tr.addEventListener("click", function (e) {
+        if (e.target.tagName === "A") { return; }
+        var next = tr.nextSibling;
+        if (next && next.className === "fields") { next.remove(); return; }
+        var d = rows[Number(tr.getAttribute("data-index"))];
+        var fields = Object.keys(d.Fields).map(function (k) {
+          return "<b>" + esc(k) + ":</b> " + esc(d.Fields[k]);
+        }).join("<br>");
+        tr.insertAdjacentHTML("afterend", "<tr class=\"fields\"><td colspan=\"5\">" + fields + "</td></tr>");
+      });
+    });
+    if (pages > 1) {
+      var nav = el("p", { "class": "pager" });
+      [["Previous", -1], ["Next", 1]].forEach(function (p) {
+        var b = el("button", {}, p[0]);
+        b.disabled = state.page + p[1] < 0 || state.page + p[1] >= pages;
+        b.addEventListener("click", function () { state.page += p[1]; render(); });
+        nav.appendChild(b);
+      });
+      wrap.appendChild(nav);
+    }
+    return wrap;
+  }
+
+  function filters(computers) {
+    var form = el("div", { "class": "filters" });
+    var html = "<div>" + LEVELS.map(function (l) {
+      return "<label class=\"level-" + l + "\"><input type=\"checkbox\" name=\"level\" value=\"" + l + "\"" +
+        (state.levels[l] ? " checked" : "") + "> " + l + "</label>";
+    }).join(" ") + "</div>";
+    if (computers) {
+      html += "<label>Computer: <select name=\"computer\"><option value=\"\">All</option>" +
+        computers.map(function (c) {
+          return "<option" + (c[0] === state.computer ? " selected" : "") + " value=\"" + esc(c[0]) + "\">" + esc(c[0]) + "</option>";
+        }).join("") + "</select></label> ";
+    }
+    html += "<label>Rule: <select name=\"rule\"><option value=\"\">All</option>" +
+      countBy(detections, "Rule").map(function (r) {
+        return "<option" + (r[0] === state.rule ? " selected" : "") + " value=\"" + esc(r[0]) + "\">" + esc(r[0]) + "</option>";
+      }).join("") + "</select></label><br>";
+    html += "<label>From (UTC): <input type=\"datetime-local\" step=\"1\" name=\"from\" value=\"" + esc(state.from) + "\"></label> ";
+    html += "<label>To (UTC): <input type=\"datetime-local\" step=\"1\" name=\"to\" value=\"" + esc(state.to) + "\"></label><br>";
+    html += "<label>Search: <input type=\"search\" name=\"search\" value=\"" + esc(state.search) + "\"></label> ";
+    html += "<button name=\"reset\">Reset</button>";
+    form.innerHTML = html;
+    form.addEventListener("change", function (e) {
+      var t = e.target;
+      if (t.name === "level") { state.levels[t.value] = t.checked; } else if (t.name) { state[t.name] = t.value; }
+      state.page = 0;
+      render();
+    });
+    form.querySelector("button[name=reset]").addEventListener("click", function () {
+      LEVELS.forEach(function (l) { state.levels[l] = true; });
+      state.computer = state.rule = state.search = state.from = state.to = "";
+      state.page = 0;
+      render();
+    });
+    return form;
+  }
+
+  // 戦術ごとに検知したルールと件数を並べる
+  function tacticMatrix(list) {
+    var tactics = {};
+    list.forEach(function (d) {
+      d.Tactics.forEach(function (t) {
+        tactics[t] = tactics[t] || {};
+        tactics[t][d.Rule] = (tactics[t][d.Rule] || 0) + 1;
+      });
+    });
+    var names = Object.keys(tactics).sort();
+    if (!names.length) {
+      return el("p", {}, "No detections with MITRE ATT&amp;CK tactics. Make sure you run Hayabusa with a profile that includes %MitreTactics% in order to get this info.");
+    }
+    var html = "<div class=\"matrix\">";
+    names.forEach(function (t) {
+      html += "<div class=\"tactic\"><div class=\"tactic-name\">" + esc(t.replace(/^\d+\. /, "")) + "</div>";
+      Object.keys(tactics[t]).sort(function (a, b) { return tactics[t][b] - tactics[t][a]; }).forEach(function (r) {
+        html += "<div class=\"technique\" data-rule=\"" + esc(r) + "\">" + esc(r) + " (" + tactics[t][r] + ")</div>";
+      });
+      html += "</div>";
+    });
+    var div = el("div", {}, html + "</div>");
+    div.querySelectorAll(".technique").forEach(function (e) {
+      e.addEventListener("click", function () {
+        state.rule = e.getAttribute("data-rule");
+        state.page = 0;
+        location.hash = "detections";
+        render();
+      });
+    });
+    return div;
+  }
+
+  function levelSummary(list) {
+    var counts = countBy(list, "Level");
+    return el("p", {}, LEVELS.filter(function (l) {
+      return counts.some(function (c) { return c[0] === l; });
+    }).map(function (l) {
+      return "<span class=\"level-" + l + "\">" + l + ": " + counts.filter(function (c) { return c[0] === l; })[0][1] + "</span>";
+    }).join(" / "));
+  }
+
+  function renderComputer(name) {
+    var list = detections.filter(function (d) { return matches(d, name); });
+    var all = detections.filter(function (d) { return d.Computer === name; });
+    root.appendChild(el("p", {}, "<a href=\"#detections\">&larr; All detections</a>"));
+    root.appendChild(el("h3", {}, "Computer: " + esc(name)));
+    root.appendChild(levelSummary(all));
+    root.appendChild(el("h4", {}, "Detection Frequency Timeline"));
+    root.appendChild(histogram(binTimes(all.map(function (d) { return d.Time; }))));
+    root.appendChild(el("h4", {}, "Detected Rules"));
+    var rules = {};
+    all.forEach(function (d) {
+      var r = rules[d.Rule] = rules[d.Rule] || { level: d.Level, count: 0, first: d.Timestamp, last: d.Timestamp, firstTime: d.Time, lastTime: d.Time };
+      r.count += 1;
+      if (d.Time < r.firstTime) { r.firstTime = d.Time; r.first = d.Timestamp; }
+      if (d.Time > r.lastTime) { r.lastTime = d.Time; r.last = d.Timestamp; }
+    });
+    root.appendChild(el("table", {}, "<thead><tr><th>Rule</th><th>Level</th><th>Count</th><th>First</th><th>Last</th></tr></thead><tbody>" +
+      Object.keys(rules).sort(function (a, b) { return rules[b].count - rules[a].count; }).map(function (r) {
+        return "<tr><td>" + esc(r) + "</td><td class=\"level-" + esc(rules[r].level) + "\">" + esc(rules[r].level) + "</td><td>" +
+          rules[r].count + "</td><td>" + esc(rules[r].first) + "</td><td>" + esc(rules[r].last) + "</td></tr>";
+      }).join("") + "</tbody>"));
+    root.appendChild(el("h4", {}, "MITRE ATT&amp;CK Tactics"));
+    root.appendChild(tacticMatrix(all));
+    root.appendChild(el("h4", {}, "Detections"));
+    root.appendChild(filters());
+    root.appendChild(detectionTable(list));
+  }
+
+  function renderMain() {
+    var list = detections.filter(function (d) { return matches(d); });
+    var computers = countBy(detections, "Computer");
+    var note = data.TotalDetections > detections.length
+      ? " The " + detections.length + " detections with the highest levels out of " + data.TotalDetections + " are included in this report." : "";
+    root.appendChild(el("p", {}, "Click a bar in the timeline to filter by time, a computer name to open the computer page, or a rule in the MITRE ATT&amp;CK matrix to filter by rule." + esc(note)));
+    root.appendChild(el("h3", {}, "Detection Frequency Timeline"));
+    root.appendChild(histogram(data.Histogram));
+    root.appendChild(el("h3", {}, "Computers"));
+    root.appendChild(el("table", {}, "<thead><tr><th>Computer</th><th>Detections</th></tr></thead><tbody>" +
+      computers.map(function (c) { return "<tr><td>" + computerLink(c[0]) + "</td><td>" + c[1] + "</td></tr>"; }).join("") + "</tbody>"));
+    root.appendChild(el("h3", {}, "MITRE ATT&amp;CK Tactics"));
+    root.appendChild(tacticMatrix(detections));
+    root.appendChild(el("h3", { id: "detections" }, "Detections"));
+    root.appendChild(filters(computers));
+    root.appendChild(detectionTable(list));
+  }
+
+  function render() {
+    root.innerHTML = "";
+    var hash = location.hash.slice(1);
+    if (hash.indexOf("computer=") === 0) {
+      renderComputer(decodeURIComponent(hash.slice("computer=".length)));
+    } else {
+      renderMain();
+    }
+  }
+
+  window.addEventListener("hashchange", function () { state.page = 0; render(); });
+  render();
+})();
@@ -35,6 +35,7 @@ use crate::detections::utils::{
 use crate::level::{_get_output_color, LEVEL, create_output_color_map};
 use crate::options::htmlreport;
 use crate::options::profile::Profile;
+use crate::options::risk_scoring::{EntityType, RISK_RULE_ID, format_score};
 
 #[derive(Debug)]
 pub struct Colors {
@@ -63,6 +64,21 @@ pub struct AfterfactInfo {
     pub detected_computer_and_rule_names: HashSet<CompactString>,
     pub prev_message: HashMap<CompactString, Profile>,
     pub prev_details_convert_map: HashMap<CompactString, Vec<CompactString>>,
+    pub html_detections: Vec<serde_json::Value>,
+    pub incidents: Vec<Incident>,
+}
+
+/// --incidentsで共通する値と時間の近さでまとめた関連するアラート
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incident {
+    pub id: CompactString,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub detections: usize,
+    pub hosts: Vec<CompactString>,
+    pub users: Vec<CompactString>,
+    pub tactics: Vec<CompactString>,
+    pub max_level: LEVEL,
 }
 
 struct InitLevelMapResult(
@@ -120,6 +136,8 @@ impl Default for AfterfactInfo {
             detected_computer_and_rule_names: HashSet::new(),
             prev_message: HashMap::new(),
             prev_details_convert_map: HashMap::new(),
+            html_detections: vec![],
+            incidents: vec![],
         }
     }
 }
@@ -141,7 +159,7 @@ pub fn init_writer(stored_static: &StoredStatic) -> AfterfactWriter {
     let target: Box<dyn io::Write> = if let Some(path) = &stored_static.output_path {
         if matches!(
             stored_static.config.action.as_ref().unwrap(),
-            Action::PivotKeywordsList(_) | Action::LogonSummary(_)
+            Action::PivotKeywordsList(_) | Action::LogonSummary(_) | Action::LateralMovement(_)
         ) {
             Box::new(BufWriter::new(io::stdout()))
         } else {
@@ -251,6 +269,10 @@ fn output_afterfact_inner(
         HashSet::new()
     };
 
+    if let Some(window) = stored_static.incident_window {
+        afterfact_info.incidents = assign_incidents(detect_infos, &duplicate_idxes, window);
+    }
+
     emit_csv_inner(
         detect_infos,
         &duplicate_idxes,
@@ -416,7 +438,8 @@ fn emit_csv_inner(
                     match x.1 {
                         Profile::Details(_)
                         | Profile::AllFieldInfo(_)
-                        | Profile::ExtraFieldInfo(_) => {
+                        | Profile::ExtraFieldInfo(_)
+                        | Profile::OutputFields(_) => {
                             let ret = if remove_duplicate_data
                                 && x.1.to_value()
                                     == afterfact_info
@@ -479,7 +502,23 @@ fn calc_statistic_info(
         afterfact_info
             .timestamps
             .push(detect_info.detected_time.timestamp());
+        if output_option.html_interactive {
+            afterfact_info
+                .html_detections
+                .push(htmlreport::create_html_detection(
+                    detect_info,
+                    &output_option.time_format_options,
+                ));
+            // 上位の件数のみを埋め込む場合はメモリを抑えるために途中で絞り込む
+            if let Some(max) = output_option.html_max_detections {
+                if afterfact_info.html_detections.len() > max.saturating_mul(2) {
+                    htmlreport::truncate_html_detections(&mut afterfact_info.html_detections, max);
+                }
+            }
+        }
         match &detect_info.agg_result {
+            // リスクスコアのアラートは疑似的なレコードのため、検知したイベントとして数えない
+            None if detect_info.ruleid == RISK_RULE_ID => {}
             None => {
                 afterfact_info
                     .detected_record_idset
@@ -585,7 +624,7 @@ fn calc_statistic_info(
 pub fn output_additional_afterfact(
     stored_static: &StoredStatic,
     afterfact_writer: &mut AfterfactWriter,
-    afterfact_info: &AfterfactInfo,
+    afterfact_info: &mut AfterfactInfo,
 ) {
     if afterfact_writer.display_flag {
         println!();
@@ -917,6 +956,27 @@ pub fn output_additional_afterfact(
             html_output_stock.push("");
         }
 
+        if stored_static.incident_window.is_some() {
+            _print_incident_summary(
+                &afterfact_info.incidents,
+                &color_map,
+                &mut html_output_stock,
+                stored_static,
+            );
+            println!();
+            if stored_static.html_report_flag {
+                html_output_stock.push("");
+            }
+        }
+
+        if stored_static.risk_scoring.is_some() {
+            _print_risk_summary(&color_map, &mut html_output_stock, stored_static);
+            println!();
+            if stored_static.html_report_flag {
+                html_output_stock.push("");
+            }
+        }
+
         _print_detection_summary_tables(
             &afterfact_info.detect_counts_by_rule_and_level,
             &color_map,
@@ -936,6 +996,17 @@ pub fn output_additional_afterfact(
     if stored_static.html_report_flag {
         _output_html_computer_by_mitre_attck(&mut html_output_stock);
         htmlreport::add_md_data("Results Summary {#results_summary}", html_output_stock);
+        if output_option.html_interactive {
+            let mut detections = std::mem::take(&mut afterfact_info.html_detections);
+            if let Some(max) = output_option.html_max_detections {
+                htmlreport::truncate_html_detections(&mut detections, max);
+            }
+            htmlreport::set_interactive_data(
+                detections,
+                afterfact_info.timestamps.len(),
+                &afterfact_info.timestamps,
+            );
+        }
     }
 }
 
@@ -972,6 +1043,107 @@ pub fn sort_detect_info(detect_infos: &mut [DetectInfo]) {
     });
 }
 
+/// 時刻順に並べたアラートのうち、コンピュータ名、ユーザー名、送信元IPアドレス、プロセスGUID、ログオンIDのいずれかが共通し、
+/// 前のアラートからwindow秒以内のアラートを同じインシデントにまとめ、IncidentIDの列にインシデントのIDを設定する
+pub fn assign_incidents(
+    detect_infos: &mut [DetectInfo],
+    duplicate_idxes: &HashSet<usize>,
+    window: i64,
+) -> Vec<Incident> {
+    fn find(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    let mut parents: Vec<usize> = (0..detect_infos.len()).collect();
+    // エンティティごとに最後に検知したアラートの時刻と位置を保持する
+    {
+        let mut last_seen: HashMap<&CompactString, (DateTime<Utc>, usize)> = HashMap::new();
+        for (i, detect_info) in detect_infos.iter().enumerate() {
+            if duplicate_idxes.contains(&i) {
+                continue;
+            }
+            let Some(keys) = &detect_info.incident_keys else {
+                continue;
+            };
+            for entity in keys.entities.iter() {
+                if let Some((time, j)) = last_seen.get(entity) {
+                    if (detect_info.detected_time - *time).num_seconds() <= window {
+                        let (a, b) = (find(&mut parents, i), find(&mut parents, *j));
+                        // 先に検知したアラートをインシデントの代表にする
+                        parents[a.max(b)] = a.min(b);
+                    }
+                }
+                last_seen.insert(entity, (detect_info.detected_time, i));
+            }
+        }
+    }
+
+    let mut incidents: Vec<Incident> = vec![];
+    let mut root_to_incident: HashMap<usize, usize> = HashMap::new();
+    for (i, detect_info) in detect_infos.iter_mut().enumerate() {
+        if duplicate_idxes.contains(&i) {
+            continue;
+        }
+        let root = find(&mut parents, i);
+        let idx = *root_to_incident.entry(root).or_insert_with(|| {
+            incidents.push(Incident {
+                id: format!("INC-{:04}", incidents.len() + 1).into(),
+                first_seen: detect_info.detected_time,
+                last_seen: detect_info.detected_time,
+                detections: 0,
+                hosts: vec![],
+                users: vec![],
+                tactics: vec![],
+                max_level: LEVEL::UNDEFINED,
+            });
+            incidents.len() - 1
+        });
+        let incident = &mut incidents[idx];
+        incident.last_seen = incident.last_seen.max(detect_info.detected_time);
+        incident.detections += 1;
+        if detect_info.level.index() > incident.max_level.index() {
+            incident.max_level = detect_info.level.clone();
+        }
+        let hosts: Vec<CompactString> = match &detect_info.agg_result {
+            Some(agg) => agg
+                .agg_record_time_info
+                .iter()
+                .map(|a| CompactString::from(&a.computer))
+                .collect(),
+            None => vec![detect_info.computername.clone()],
+        };
+        for host in hosts {
+            if host != "-" && !incident.hosts.contains(&host) {
+                incident.hosts.push(host);
+            }
+        }
+        if let Some(keys) = &detect_info.incident_keys {
+            for user in keys.entities.iter().filter_map(|e| e.strip_prefix("User:")) {
+                if !incident.users.iter().any(|u| u == user) {
+                    incident.users.push(user.into());
+                }
+            }
+            // 戦術は検知した順に並べる
+            for tactic in keys.tactics.iter() {
+                if !incident.tactics.contains(tactic) {
+                    incident.tactics.push(tactic.clone());
+                }
+            }
+        }
+        let id = incident.id.clone();
+        for (_, profile) in detect_info.ext_field.iter_mut() {
+            if let Profile::IncidentID(_) = profile {
+                *profile = Profile::IncidentID(id.to_string().into());
+            }
+        }
+    }
+    incidents
+}
+
 pub fn get_duplicate_idxes(detect_infos: &mut [DetectInfo]) -> HashSet<usize> {
     // filtet duplicate event
     let mut filtered_detect_infos = HashSet::new();
@@ -1162,7 +1334,10 @@ fn _get_serialized_disp_output(
                         get_writable_color(level_color, no_color),
                     )]]
                 }
-                Profile::AllFieldInfo(_) | Profile::Details(_) | Profile::ExtraFieldInfo(_) => {
+                Profile::AllFieldInfo(_)
+                | Profile::Details(_)
+                | Profile::ExtraFieldInfo(_)
+                | Profile::OutputFields(_) => {
                     let mut output_str_char_pair = vec![];
                     for c in display_contents.split('¦') {
                         if let Some((field, val)) = c.split_once(':') {
@@ -1442,6 +1617,189 @@ fn _print_detection_summary_by_computer(
     buf_wtr.print(&wtr).ok();
 }
 
+/// --incidentsでまとめたインシデントをレベルと検知数の多い順に表形式で出力する関数
+fn _print_incident_summary(
+    incidents: &[Incident],
+    color_map: &HashMap<LEVEL, Colors>,
+    html_output_stock: &mut Nested<String>,
+    stored_static: &StoredStatic,
+) {
+    let time_format_options = &stored_static
+        .output_option
+        .as_ref()
+        .unwrap()
+        .time_format_options;
+    let sorted_incidents = incidents
+        .iter()
+        .sorted_by(|a, b| {
+            b.max_level
+                .index()
+                .cmp(&a.max_level.index())
+                .then_with(|| b.detections.cmp(&a.detections))
+                .then_with(|| a.first_seen.cmp(&b.first_seen))
+        })
+        .collect_vec();
+    let join_or_na = |v: &[CompactString]| {
+        if v.is_empty() {
+            "n/a".to_string()
+        } else {
+            v.iter().join(", ")
+        }
+    };
+
+    // html出力はすべてのインシデントを表示するようにする
+    if stored_static.html_report_flag {
+        html_output_stock.push("### Incidents: {#incidents}");
+        for incident in sorted_incidents.iter() {
+            html_output_stock.push(format!(
+                "- {} ({}): {} detections ({} ~ {}) Hosts: {} Users: {} Tactics: {}",
+                incident.id,
+                incident.max_level.to_full(),
+                incident.detections.to_formatted_string(&Locale::en),
+                format_time(&incident.first_seen, false, time_format_options),
+                format_time(&incident.last_seen, false, time_format_options),
+                join_or_na(&incident.hosts),
+                join_or_na(&incident.users),
+                join_or_na(&incident.tactics),
+            ));
+        }
+    }
+
+    let take_cnt = 10;
+    println!(
+        "Incidents: {}",
+        incidents.len().to_formatted_string(&Locale::en)
+    );
+    if incidents.is_empty() {
+        return;
+    }
+    let mut tb = Table::new();
+    tb.load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec![
+            "IncidentID",
+            "First Seen",
+            "Last Seen",
+            "Detections",
+            "Max Level",
+            "Hosts",
+            "Users",
+            "Tactics",
+        ]);
+    for incident in sorted_incidents.iter().take(take_cnt) {
+        tb.add_row(vec![
+            Cell::new(&incident.id),
+            Cell::new(format_time(
+                &incident.first_seen,
+                false,
+                time_format_options,
+            )),
+            Cell::new(format_time(&incident.last_seen, false, time_format_options)),
+            Cell::new(incident.detections.to_formatted_string(&Locale::en)),
+            Cell::new(incident.max_level.to_full()).fg(_get_table_color(
+                color_map,
+                &incident.max_level,
+            )
+            .unwrap_or(comfy_table::Color::Reset)),
+            Cell::new(join_or_na(&incident.hosts)),
+            Cell::new(join_or_na(&incident.users)),
+            Cell::new(join_or_na(&incident.tactics)),
+        ]);
+    }
+    println!("{tb}");
+    if incidents.len() > take_cnt {
+        println!(
+            "({} more incidents are not shown.)",
+            (incidents.len() - take_cnt).to_formatted_string(&Locale::en)
+        );
+    }
+}
+
+/// --risk-scoringで集計したコンピュータ名とユーザー名ごとのリスクスコアと、しきい値を超えたアラートを出力する関数
+fn _print_risk_summary(
+    color_map: &HashMap<LEVEL, Colors>,
+    html_output_stock: &mut Nested<String>,
+    stored_static: &StoredStatic,
+) {
+    let risk_scoring = stored_static.risk_scoring.as_ref().unwrap();
+    let buf_wtr = BufferWriter::stdout(ColorChoice::Always);
+    let mut wtr = buf_wtr.buffer();
+    wtr.set_color(ColorSpec::new().set_fg(None)).ok();
+
+    for (entity_type, anchor) in [
+        (EntityType::Computer, "computers"),
+        (EntityType::User, "users"),
+    ] {
+        let scores = risk_scoring.total_scores(entity_type);
+        // html出力はすべてのエンティティを表示するようにする
+        if stored_static.html_report_flag {
+            html_output_stock.push(format!(
+                "### {} risk scores: {{#{anchor}_risk_scores}}",
+                entity_type.to_str()
+            ));
+            for (entity, score) in scores.iter() {
+                html_output_stock.push(format!("- {entity} ({})", format_score(*score)));
+            }
+            html_output_stock.push("");
+        }
+        let result_str = if scores.is_empty() {
+            "n/a".to_string()
+        } else {
+            scores
+                .iter()
+                .take(5)
+                .map(|(entity, score)| format!("{entity} ({})", format_score(*score)))
+                .join(", ")
+        };
+        writeln!(wtr, "Top 5 {anchor} by risk score: {result_str}").ok();
+    }
+
+    let alerts = risk_scoring.alerts();
+    let title = format!(
+        "Risk threshold exceeded (threshold: {}, window: {}):",
+        format_score(risk_scoring.threshold),
+        risk_scoring.window
+    );
+    writeln!(wtr, "{title}").ok();
+    if stored_static.html_report_flag {
+        html_output_stock.push("### Risk threshold exceeded: {#risk_threshold_exceeded}");
+    }
+    if alerts.is_empty() {
+        writeln!(wtr, "n/a").ok();
+    }
+    let time_format_options = &stored_static
+        .output_option
+        .as_ref()
+        .unwrap()
+        .time_format_options;
+    for alert in alerts.iter() {
+        let msg = format!(
+            "{} {}: {} ({} ~ {}) Rules: {}",
+            alert.entity_type.to_str(),
+            alert.entity,
+            format_score(alert.score),
+            format_time(&alert.start, false, time_format_options),
+            format_time(&alert.end, false, time_format_options),
+            alert
+                .rules
+                .iter()
+                .map(|(title, cnt)| format!("{title} ({cnt})"))
+                .join(", ")
+        );
+        wtr.set_color(
+            ColorSpec::new().set_fg(_get_output_color(color_map, &risk_scoring.alert_level)),
+        )
+        .ok();
+        writeln!(wtr, "{msg}").ok();
+        if stored_static.html_report_flag {
+            html_output_stock.push(format!("- {msg}"));
+        }
+    }
+    wtr.set_color(ColorSpec::new().set_fg(None)).ok();
+    buf_wtr.print(&wtr).ok();
+}
+
 /// 各レベルごとで検出数が多かったルールを表形式で出力する関数
 fn _print_detection_summary_tables(
     detect_counts_by_rule_and_level: &HashMap<LEVEL, HashMap<CompactString, i128>>,
@@ -1583,7 +1941,10 @@ fn _get_json_vec(profile: &Profile, target_data: &String) -> Vec<String> {
         Profile::MitreTactics(_) | Profile::MitreTags(_) | Profile::OtherTags(_) => {
             target_data.split(": ").map(|x| x.to_string()).collect()
         }
-        Profile::Details(_) | Profile::AllFieldInfo(_) | Profile::ExtraFieldInfo(_) => {
+        Profile::Details(_)
+        | Profile::AllFieldInfo(_)
+        | Profile::ExtraFieldInfo(_)
+        | Profile::OutputFields(_) => {
             let ret: Vec<String> = target_data.split(" ¦ ").map(|x| x.to_string()).collect();
             if target_data == &ret[0] && !utils::contains_str(target_data, ": ") {
                 vec![]
@@ -1719,11 +2080,15 @@ pub fn output_json_str(
     if remove_duplicate_flag {
         for (field_name, profile) in detect_info.ext_field.iter() {
             match profile {
-                Profile::Details(_) | Profile::AllFieldInfo(_) | Profile::ExtraFieldInfo(_) => {
+                Profile::Details(_)
+                | Profile::AllFieldInfo(_)
+                | Profile::ExtraFieldInfo(_)
+                | Profile::OutputFields(_) => {
                     let details_key = match profile {
                         Profile::Details(_) => "Details",
                         Profile::AllFieldInfo(_) => "AllFieldInfo",
                         Profile::ExtraFieldInfo(_) => "ExtraFieldInfo",
+                        Profile::OutputFields(_) => "OutputFields",
                         _ => "",
                     };
 
@@ -1790,11 +2155,17 @@ pub fn output_json_str(
             ))
             && vec_data.is_empty()
         {
-            if matches!(profile, Profile::Details(_)) && val == "-" {
+            if matches!(profile, Profile::Details(_) | Profile::OutputFields(_)) && val == "-" {
                 target.push(format!("{}\"{}\": {{}}", " ".repeat(4), key));
                 continue;
             }
-            let tmp_val: Vec<&str> = val.split(": ").collect();
+            // フィールド式とルックアップの値は": "を含んでいても分割しない
+            let tmp_val: Vec<&str> =
+                if matches!(profile, Profile::Field(_) | Profile::Enrichment(_)) {
+                    vec![val.as_str()]
+                } else {
+                    val.split(": ").collect()
+                };
             let output_val =
                 _convert_valid_json_str(&tmp_val, matches!(profile, Profile::AllFieldInfo(_)));
             target.push(_create_json_output_format(
@@ -1822,12 +2193,16 @@ pub fn output_json_str(
                         4,
                     ));
                 }
-                Profile::Details(_) | Profile::AllFieldInfo(_) | Profile::ExtraFieldInfo(_) => {
+                Profile::Details(_)
+                | Profile::AllFieldInfo(_)
+                | Profile::ExtraFieldInfo(_)
+                | Profile::OutputFields(_) => {
                     let mut output_stock: Vec<String> = vec![];
                     let details_key = match profile {
                         Profile::Details(_) => "Details",
                         Profile::AllFieldInfo(_) => "AllFieldInfo",
                         Profile::ExtraFieldInfo(_) => "ExtraFieldInfo",
+                        Profile::OutputFields(_) => "OutputFields",
                         _ => "",
                     };
                     let details_target_stocks = detect_info
@@ -2144,10 +2519,11 @@ mod tests {
     use chrono::NaiveDateTime;
     use chrono::{Local, TimeZone, Utc};
     use compact_str::CompactString;
-    use hashbrown::HashMap;
+    use hashbrown::{HashMap, HashSet};
     use serde_json::Value;
 
     use crate::afterfact::AfterfactInfo;
+    use crate::afterfact::assign_incidents;
     use crate::afterfact::format_time;
     use crate::afterfact::init_writer;
     use crate::afterfact::output_afterfact_inner;
@@ -2162,6 +2538,7 @@ mod tests {
     use crate::detections::field_data_map::FieldDataMapKey;
     use crate::detections::message;
     use crate::detections::message::DetectInfo;
+    use crate::detections::message::IncidentKeys;
     use crate::detections::utils;
     use crate::level::LEVEL;
     use crate::options::profile::{Profile, load_profile};
@@ -2284,6 +2661,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2310,6 +2688,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2529,6 +2908,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2555,6 +2935,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2752,6 +3133,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2778,6 +3160,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -2985,6 +3368,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map,
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -3011,6 +3395,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map: HashMap::default(),
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -3294,6 +3679,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map,
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -3529,6 +3915,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map,
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -3707,6 +4094,7 @@ mod tests {
                     ext_field: output_profile.to_owned(),
                     agg_result: None,
                     details_convert_map,
+                    incident_keys: None,
                     rec_id: CompactString::default(),
                 },
                 &profile_converter,
@@ -3756,4 +4144,47 @@ mod tests {
         };
         assert!(remove_file("./test_emit_csv_jsonl.jsonl").is_ok());
     }
+
+    #[test]
+    fn test_assign_incidents() {
+        let detect_info = |min: u32, computer: &str, level: LEVEL, entities: &[&str]| DetectInfo {
+            detected_time: Utc.with_ymd_and_hms(2024, 1, 2, 0, min, 0).unwrap(),
+            level,
+            computername: computer.into(),
+            ext_field: vec![("IncidentID".into(), Profile::IncidentID("".into()))],
+            incident_keys: Some(IncidentKeys {
+                entities: entities.iter().map(|e| CompactString::from(*e)).collect(),
+                tactics: vec!["CredAccess".into()],
+            }),
+            ..Default::default()
+        };
+        // PC01とPC02は共通するユーザーで、PC03は共通する値がないため別のインシデントになる。
+        // 最後のPC01のアラートは前のアラートから30分を超えているため別のインシデントになる
+        let mut detect_infos = vec![
+            detect_info(0, "PC01", LEVEL::MEDIUM, &["Computer:pc01", "User:taro"]),
+            detect_info(10, "PC02", LEVEL::HIGH, &["Computer:pc02", "User:taro"]),
+            detect_info(15, "PC03", LEVEL::LOW, &["Computer:pc03"]),
+            detect_info(20, "PC02", LEVEL::LOW, &["Computer:pc02"]),
+            detect_info(55, "PC01", LEVEL::LOW, &["Computer:pc01"]),
+        ];
+        let incidents = assign_incidents(&mut detect_infos, &HashSet::new(), 1800);
+        let ids: Vec<String> = detect_infos
+            .iter()
+            .map(|d| d.ext_field[0].1.to_value())
+            .collect();
+        assert_eq!(
+            ids,
+            vec!["INC-0001", "INC-0001", "INC-0002", "INC-0001", "INC-0003"]
+        );
+        assert_eq!(incidents.len(), 3);
+        assert_eq!(incidents[0].detections, 3);
+        assert_eq!(incidents[0].hosts, vec!["PC01", "PC02"]);
+        assert_eq!(incidents[0].users, vec!["taro"]);
+        assert_eq!(incidents[0].tactics, vec!["CredAccess"]);
+        assert_eq!(incidents[0].max_level, LEVEL::HIGH);
+        assert_eq!(
+            incidents[0].last_seen,
+            Utc.with_ymd_and_hms(2024, 1, 2, 0, 20, 0).unwrap()
+        );
+    }
 }
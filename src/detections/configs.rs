@@ -1,13 +1,18 @@
 use super::message::create_output_filter_config;
 use super::utils::check_setting_path;
 use crate::detections::field_data_map::{FieldDataMap, create_field_data_map};
+use crate::detections::ioc::IocMatcher;
 use crate::detections::message::AlertMessage;
 use crate::detections::utils;
-use crate::level::LEVEL;
+use crate::level::{LEVEL, load_critical_systems};
+use crate::options::delta_scan::{DeltaScan, RuleManifest};
+use crate::options::enrichment::Enrichment;
 use crate::options::geoip_search::GeoIPSearch;
 use crate::options::htmlreport;
+use crate::options::level_override::LevelOverrides;
 use crate::options::pivot::PIVOT_KEYWORD;
 use crate::options::profile::{Profile, load_profile};
+use crate::options::risk_scoring::{RiskScoring, parse_window};
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
 use chrono::{DateTime, Days, Duration, Local, Months, Utc};
 use clap::{ArgAction, ArgGroup, Args, ColorChoice, Command, CommandFactory, Parser, Subcommand};
@@ -33,6 +38,8 @@ lazy_static! {
     pub static ref GEOIP_DB_PARSER: RwLock<Option<GeoIPSearch>> = RwLock::new(None);
     pub static ref GEOIP_DB_YAML: RwLock<Option<HashMap<CompactString, Yaml>>> = RwLock::new(None);
     pub static ref GEOIP_FILTER: RwLock<Option<Vec<Yaml>>> = RwLock::new(None);
+    pub static ref IOC_MATCHER: RwLock<Option<IocMatcher>> = RwLock::new(None);
+    pub static ref DELTA_SCAN: RwLock<Option<DeltaScan>> = RwLock::new(None);
     pub static ref CURRENT_EXE_PATH: PathBuf =
         current_exe().unwrap().parent().unwrap().to_path_buf();
     pub static ref IDS_REGEX: Regex =
@@ -115,6 +122,8 @@ pub struct StoredStatic {
     pub computer_metrics_flag: bool,
     pub log_metrics_flag: bool,
     pub extract_base64_flag: bool,
+    pub lateral_movement_flag: bool,
+    pub integrity_flag: bool,
     pub search_option: Option<SearchOption>,
     pub output_option: Option<OutputOption>,
     pub pivot_keyword_list_flag: bool,
@@ -130,6 +139,7 @@ pub struct StoredStatic {
     pub common_options: CommonOptions,
     pub multiline_flag: bool,
     pub tab_separator_flag: bool,
+    pub disable_abbreviation_flag: bool,
     pub include_computer: HashSet<CompactString>,
     pub exclude_computer: HashSet<CompactString>,
     pub include_eid: HashSet<CompactString>,
@@ -138,11 +148,17 @@ pub struct StoredStatic {
     pub field_data_map: Option<FieldDataMap>,
     pub no_pwsh_field_extraction: bool,
     pub enable_recover_records: bool,
+    pub remove_duplicate_records: bool,
     pub time_offset: Option<String>,
     pub is_low_memory: bool,
     pub enable_all_rules: bool,
     pub scan_all_evtx_files: bool,
     pub metrics_remove_duplication: bool,
+    pub critical_systems: HashSet<String>,
+    pub level_overrides: Option<LevelOverrides>,
+    pub enrichment: Option<Enrichment>,
+    pub risk_scoring: Option<RiskScoring>,
+    pub incident_window: Option<i64>,
 }
 
 impl StoredStatic {
@@ -162,6 +178,8 @@ impl StoredStatic {
             Some(Action::Search(opt)) => opt.quiet_errors,
             Some(Action::ComputerMetrics(opt)) => opt.quiet_errors,
             Some(Action::LogMetrics(opt)) => opt.detect_common_options.quiet_errors,
+            Some(Action::LateralMovement(opt)) => opt.detect_common_options.quiet_errors,
+            Some(Action::Integrity(opt)) => opt.detect_common_options.quiet_errors,
             _ => false,
         };
         let common_options = match &input_config.as_ref().unwrap().action {
@@ -180,6 +198,14 @@ impl StoredStatic {
             Some(Action::LogMetrics(opt)) => opt.common_options,
             Some(Action::ExpandList(opt)) => opt.common_options,
             Some(Action::ConfigCriticalSystems(opt)) => opt.common_options,
+            Some(Action::LateralMovement(opt)) => opt.common_options,
+            Some(Action::Integrity(opt)) => opt.common_options,
+            Some(Action::ValidateRules(opt)) => opt.common_options,
+            Some(Action::TestRules(opt)) => opt.common_options,
+            Some(Action::RulesDiff(opt)) => opt.common_options,
+            Some(Action::Serve(opt)) => opt.common_options,
+            Some(Action::Watch(opt)) => opt.common_options,
+            Some(Action::Compare(opt)) => opt.common_options,
             None => CommonOptions {
                 no_color: false,
                 quiet: false,
@@ -197,6 +223,11 @@ impl StoredStatic {
             Some(Action::Search(opt)) => &opt.config,
             Some(Action::ComputerMetrics(opt)) => &opt.config,
             Some(Action::LogMetrics(opt)) => &opt.detect_common_options.config,
+            Some(Action::LateralMovement(opt)) => &opt.detect_common_options.config,
+            Some(Action::Integrity(opt)) => &opt.detect_common_options.config,
+            Some(Action::ValidateRules(opt)) => &opt.config,
+            Some(Action::TestRules(opt)) => &opt.config,
+            Some(Action::Serve(opt)) => &opt.config,
             _ => &binding,
         };
         let verbose_flag = match &input_config.as_ref().unwrap().action {
@@ -209,6 +240,8 @@ impl StoredStatic {
             Some(Action::Search(opt)) => opt.verbose,
             Some(Action::ComputerMetrics(opt)) => opt.verbose,
             Some(Action::LogMetrics(opt)) => opt.detect_common_options.verbose,
+            Some(Action::LateralMovement(opt)) => opt.detect_common_options.verbose,
+            Some(Action::Integrity(opt)) => opt.detect_common_options.verbose,
             _ => false,
         };
         let json_input_flag = match &input_config.as_ref().unwrap().action {
@@ -220,6 +253,8 @@ impl StoredStatic {
             Some(Action::PivotKeywordsList(opt)) => opt.detect_common_options.json_input,
             Some(Action::ComputerMetrics(opt)) => opt.json_input,
             Some(Action::LogMetrics(opt)) => opt.detect_common_options.json_input,
+            Some(Action::LateralMovement(opt)) => opt.detect_common_options.json_input,
+            Some(Action::Integrity(opt)) => opt.detect_common_options.json_input,
             _ => false,
         };
         let is_valid_min_level = match &input_config.as_ref().unwrap().action {
@@ -293,6 +328,98 @@ impl StoredStatic {
             AlertMessage::alert(&err_msg).ok();
             process::exit(1);
         }
+        let ioc_path = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => opt.output_options.ioc.as_ref(),
+            Some(Action::JsonTimeline(opt)) => opt.output_options.ioc.as_ref(),
+            _ => None,
+        };
+        if let Some(ioc_path) = ioc_path {
+            match IocMatcher::load(ioc_path) {
+                Ok(matcher) => *IOC_MATCHER.write().unwrap() = Some(matcher),
+                Err(err_msg) => {
+                    AlertMessage::alert(&err_msg).ok();
+                    process::exit(1);
+                }
+            }
+        }
+        let level_overrides_path = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => opt.output_options.level_overrides.as_ref(),
+            Some(Action::JsonTimeline(opt)) => opt.output_options.level_overrides.as_ref(),
+            _ => None,
+        };
+        let level_overrides = match level_overrides_path.map(|p| LevelOverrides::load(p)) {
+            Some(Ok(overrides)) => Some(overrides),
+            Some(Err(err_msg)) => {
+                AlertMessage::alert(&err_msg).ok();
+                process::exit(1);
+            }
+            None => None,
+        };
+        let enrichment_path = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => opt.output_options.enrichment.as_ref(),
+            Some(Action::JsonTimeline(opt)) => opt.output_options.enrichment.as_ref(),
+            _ => None,
+        };
+        let enrichment = match enrichment_path.map(|p| Enrichment::load(p)) {
+            Some(Ok(enrichment)) => Some(enrichment),
+            Some(Err(err_msg)) => {
+                AlertMessage::alert(&err_msg).ok();
+                process::exit(1);
+            }
+            None => None,
+        };
+        let risk_scoring_path = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => opt.output_options.risk_scoring.as_ref(),
+            Some(Action::JsonTimeline(opt)) => opt.output_options.risk_scoring.as_ref(),
+            _ => None,
+        };
+        let risk_scoring = match risk_scoring_path.map(|p| RiskScoring::load(p)) {
+            Some(Ok(risk_scoring)) => Some(risk_scoring),
+            Some(Err(err_msg)) => {
+                AlertMessage::alert(&err_msg).ok();
+                process::exit(1);
+            }
+            None => None,
+        };
+        let incident_window = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) if opt.output_options.incidents => Some(
+                opt.output_options
+                    .incident_window
+                    .as_deref()
+                    .unwrap_or("30m"),
+            ),
+            Some(Action::JsonTimeline(opt)) if opt.output_options.incidents => Some(
+                opt.output_options
+                    .incident_window
+                    .as_deref()
+                    .unwrap_or("30m"),
+            ),
+            _ => None,
+        };
+        let incident_window = match incident_window {
+            Some(window) => match parse_window(window) {
+                Ok(sec) => Some(sec),
+                Err(err_msg) => {
+                    AlertMessage::alert(&err_msg).ok();
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let delta_from = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => opt.output_options.delta_from.as_ref(),
+            Some(Action::JsonTimeline(opt)) => opt.output_options.delta_from.as_ref(),
+            _ => None,
+        };
+        if let Some(manifest_path) = delta_from {
+            match RuleManifest::load(manifest_path) {
+                Ok(manifest) => *DELTA_SCAN.write().unwrap() = Some(DeltaScan::new(manifest)),
+                Err(err_msg) => {
+                    AlertMessage::alert(&err_msg).ok();
+                    process::exit(1);
+                }
+            }
+        }
         if let Some(geo_ip_db_path) = geo_ip_db_result.unwrap() {
             *GEOIP_DB_PARSER.write().unwrap() = Some(GeoIPSearch::new(
                 &geo_ip_db_path,
@@ -369,6 +496,11 @@ impl StoredStatic {
             Some(Action::Search(opt)) => opt.output.as_ref(),
             Some(Action::ComputerMetrics(opt)) => opt.output.as_ref(),
             Some(Action::LogMetrics(opt)) => opt.output.as_ref(),
+            Some(Action::LateralMovement(opt)) => opt.output.as_ref(),
+            Some(Action::Integrity(opt)) => opt.output.as_ref(),
+            Some(Action::ValidateRules(opt)) => opt.output.as_ref(),
+            Some(Action::TestRules(opt)) => opt.output.as_ref(),
+            Some(Action::RulesDiff(opt)) => opt.output.as_ref(),
             _ => None,
         };
         let disable_abbreviation = match &input_config.as_ref().unwrap().action {
@@ -488,6 +620,22 @@ impl StoredStatic {
                 .iter()
                 .map(CompactString::from)
                 .collect(),
+            Some(Action::LateralMovement(opt)) => opt
+                .detect_common_options
+                .include_computer
+                .as_ref()
+                .unwrap_or(&vec![])
+                .iter()
+                .map(CompactString::from)
+                .collect(),
+            Some(Action::Integrity(opt)) => opt
+                .detect_common_options
+                .include_computer
+                .as_ref()
+                .unwrap_or(&vec![])
+                .iter()
+                .map(CompactString::from)
+                .collect(),
             _ => HashSet::default(),
         };
         let exclude_computer: HashSet<CompactString> = match &input_config.as_ref().unwrap().action
@@ -550,6 +698,22 @@ impl StoredStatic {
                 .iter()
                 .map(CompactString::from)
                 .collect(),
+            Some(Action::LateralMovement(opt)) => opt
+                .detect_common_options
+                .exclude_computer
+                .as_ref()
+                .unwrap_or(&vec![])
+                .iter()
+                .map(CompactString::from)
+                .collect(),
+            Some(Action::Integrity(opt)) => opt
+                .detect_common_options
+                .exclude_computer
+                .as_ref()
+                .unwrap_or(&vec![])
+                .iter()
+                .map(CompactString::from)
+                .collect(),
             _ => HashSet::default(),
         };
         let include_eid: HashSet<CompactString> = match &input_config.as_ref().unwrap().action {
@@ -642,6 +806,26 @@ impl StoredStatic {
             Some(Action::PivotKeywordsList(opt)) => opt.input_args.recover_records,
             Some(Action::Search(opt)) => opt.input_args.recover_records,
             Some(Action::LogMetrics(opt)) => opt.input_args.recover_records,
+            Some(Action::LateralMovement(opt)) => opt.input_args.recover_records,
+            Some(Action::Integrity(opt)) => opt.input_args.recover_records,
+            _ => false,
+        };
+        let remove_duplicate_records = match &input_config.as_ref().unwrap().action {
+            Some(Action::CsvTimeline(opt)) => {
+                opt.output_options.input_args.remove_duplicate_records
+            }
+            Some(Action::JsonTimeline(opt)) => {
+                opt.output_options.input_args.remove_duplicate_records
+            }
+            Some(Action::EidMetrics(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::ExtractBase64(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::LogonSummary(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::PivotKeywordsList(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::Search(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::ComputerMetrics(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::LogMetrics(opt)) => opt.input_args.remove_duplicate_records,
+            Some(Action::LateralMovement(opt)) => opt.input_args.remove_duplicate_records,
+            // integrityで重複したレコードを取り除くと欠落として報告されるため、常に全てのレコードを確認する
             _ => false,
         };
         let time_offset = match &input_config.as_ref().unwrap().action {
@@ -654,6 +838,8 @@ impl StoredStatic {
             Some(Action::Search(opt)) => opt.input_args.time_offset.clone(),
             Some(Action::ComputerMetrics(opt)) => opt.input_args.time_offset.clone(),
             Some(Action::LogMetrics(opt)) => opt.input_args.time_offset.clone(),
+            Some(Action::LateralMovement(opt)) => opt.input_args.time_offset.clone(),
+            Some(Action::Integrity(opt)) => opt.input_args.time_offset.clone(),
             _ => None,
         };
         let include_status: HashSet<CompactString> = match &input_config.as_ref().unwrap().action {
@@ -693,8 +879,17 @@ impl StoredStatic {
             _ => false,
         };
         let scan_all_evtx_files = match &input_config.as_ref().unwrap().action {
-            Some(Action::CsvTimeline(opt)) => opt.output_options.scan_all_evtx_files,
-            Some(Action::JsonTimeline(opt)) => opt.output_options.scan_all_evtx_files,
+            // IOCとカバレッジレポートは全てのチャンネルが対象となるため、evtxファイルのチャンネルフィルターを無効にする
+            Some(Action::CsvTimeline(opt)) => {
+                opt.output_options.scan_all_evtx_files
+                    || opt.output_options.ioc.is_some()
+                    || opt.output_options.coverage_report.is_some()
+            }
+            Some(Action::JsonTimeline(opt)) => {
+                opt.output_options.scan_all_evtx_files
+                    || opt.output_options.ioc.is_some()
+                    || opt.output_options.coverage_report.is_some()
+            }
             _ => false,
         };
         let metrics_remove_duplication = match &input_config.as_ref().unwrap().action {
@@ -774,6 +969,8 @@ impl StoredStatic {
             computer_metrics_flag: action_id == 11,
             log_metrics_flag: action_id == 12,
             extract_base64_flag: action_id == 13,
+            lateral_movement_flag: action_id == 16,
+            integrity_flag: action_id == 17,
             search_option: extract_search_options(input_config.as_ref().unwrap()),
             output_option: extract_output_options(input_config.as_ref().unwrap()),
             pivot_keyword_list_flag: action_id == 4,
@@ -814,6 +1011,7 @@ impl StoredStatic {
             common_options,
             multiline_flag,
             tab_separator_flag,
+            disable_abbreviation_flag: disable_abbreviation,
             include_computer,
             exclude_computer,
             include_eid,
@@ -821,12 +1019,18 @@ impl StoredStatic {
             field_data_map,
             no_pwsh_field_extraction: no_pwsh_field_extraction_flag,
             enable_recover_records,
+            remove_duplicate_records,
             time_offset,
             include_status,
             is_low_memory,
             enable_all_rules,
             scan_all_evtx_files,
             metrics_remove_duplication,
+            critical_systems: load_critical_systems(),
+            level_overrides,
+            enrichment,
+            risk_scoring,
+            incident_window,
         };
         ret.profiles = load_profile(
             check_setting_path(
@@ -926,6 +1130,8 @@ fn check_thread_number(config: &Config) -> Option<usize> {
         Action::ExtractBase64(opt) => opt.detect_common_options.thread_number,
         Action::PivotKeywordsList(opt) => opt.detect_common_options.thread_number,
         Action::LogMetrics(opt) => opt.detect_common_options.thread_number,
+        Action::LateralMovement(opt) => opt.detect_common_options.thread_number,
+        Action::Integrity(opt) => opt.detect_common_options.thread_number,
         _ => None,
     }
 }
@@ -973,6 +1179,27 @@ pub enum Action {
     /// Output a summary of successful and failed logons
     LogonSummary(LogonSummaryOption),
 
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe lateral-movement <INPUT> [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 381,
+        disable_help_flag = true
+    )]
+    /// Create a lateral movement graph from network logons (DOT, GraphML, JSON)
+    LateralMovement(LateralMovementOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe integrity <INPUT> [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 370,
+        disable_help_flag = true,
+        mut_arg("remove_duplicate_records", |a| a.hide(true))
+    )]
+    /// Find event log tampering and gaps (record ID gaps, time reversals, silent periods, log clears)
+    Integrity(IntegrityOption),
+
     #[clap(
         author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
         help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe eid-metrics <INPUT> [OPTIONS]\n\n{all-args}",
@@ -1033,6 +1260,66 @@ pub enum Action {
     /// Update to the latest rules in the hayabusa-rules github repository
     UpdateRules(UpdateOption),
 
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe validate-rules [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 475,
+        disable_help_flag = true
+    )]
+    /// Validate all rules in a rules directory and report errors in JSON
+    ValidateRules(ValidateRulesOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe test-rules [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 476,
+        disable_help_flag = true
+    )]
+    /// Run the sample event tests written next to each rule (xxx.tests.yml)
+    TestRules(TestRulesOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe rules-diff <OLD> <NEW> [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 474,
+        disable_help_flag = true
+    )]
+    /// Compare two rule directories or git revisions by rule ID
+    RulesDiff(RulesDiffOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe serve [OPTIONS]\n\n{all-args}",
+        term_width = 400,
+        display_order = 477,
+        disable_help_flag = true
+    )]
+    /// Start a local REST API server to scan files on demand
+    Serve(ServeOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe watch <DIR> [OPTIONS] [-- <TIMELINE_OPTIONS>...]\n\n{all-args}",
+        term_width = 400,
+        display_order = 478,
+        disable_help_flag = true
+    )]
+    /// Watch a directory and create timelines of new evtx files and folders
+    Watch(WatchOption),
+
+    #[clap(
+        author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
+        help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  hayabusa.exe compare <OLD> <NEW> [OPTIONS] [-- <TIMELINE_OPTIONS>...]\n\n{all-args}",
+        term_width = 400,
+        display_order = 479,
+        disable_help_flag = true
+    )]
+    /// Compare the detections of two scans and report new and resolved detections
+    Compare(CompareOption),
+
     #[clap(
         author = "Yamato Security (https://github.com/Yamato-Security/hayabusa - @SecurityYamato)",
         help_template = "\nHayabusa v3.4.0 - Dev Build\n{author-with-newline}\n{usage-heading}\n  {usage}\n\n{all-args}",
@@ -1102,6 +1389,14 @@ impl Action {
                 Action::ExtractBase64(_) => 13,
                 Action::ExpandList(_) => 14,
                 Action::ConfigCriticalSystems(_) => 15,
+                Action::LateralMovement(_) => 16,
+                Action::Integrity(_) => 17,
+                Action::ValidateRules(_) => 18,
+                Action::TestRules(_) => 19,
+                Action::RulesDiff(_) => 20,
+                Action::Serve(_) => 21,
+                Action::Watch(_) => 22,
+                Action::Compare(_) => 23,
             }
         } else {
             100
@@ -1126,6 +1421,14 @@ impl Action {
                 Action::ExtractBase64(_) => "extract-base64",
                 Action::ExpandList(_) => "expand-list",
                 Action::ConfigCriticalSystems(_) => "config-critical-systems",
+                Action::LateralMovement(_) => "lateral-movement",
+                Action::Integrity(_) => "integrity",
+                Action::ValidateRules(_) => "validate-rules",
+                Action::TestRules(_) => "test-rules",
+                Action::RulesDiff(_) => "rules-diff",
+                Action::Serve(_) => "serve",
+                Action::Watch(_) => "watch",
+                Action::Compare(_) => "compare",
             }
         } else {
             ""
@@ -1385,6 +1688,18 @@ pub struct UpdateOption {
         display_order = 441
     )]
     pub rules: PathBuf,
+
+    /// Install rules from a local zip/tar archive or git bundle instead of downloading them
+    #[arg(help_heading = Some("General Options"), long = "from", value_name = "BUNDLE", display_order = 330)]
+    pub from: Option<PathBuf>,
+
+    /// Verify the bundle with a detached minisign signature (ex: rules.zip.minisig)
+    #[arg(help_heading = Some("General Options"), long = "signature", value_name = "FILE", requires_all = ["from", "public_key"], display_order = 460)]
+    pub signature: Option<PathBuf>,
+
+    /// Public key used to verify the signature (ex: minisign.pub)
+    #[arg(help_heading = Some("General Options"), long = "public-key", value_name = "FILE", requires = "signature", display_order = 420)]
+    pub public_key: Option<PathBuf>,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -1567,6 +1882,346 @@ pub struct LogonSummaryOption {
     pub start_timeline: Option<String>,
 }
 
+#[derive(Args, Clone, Debug, Default)]
+pub struct LateralMovementOption {
+    #[clap(flatten)]
+    pub input_args: InputOption,
+
+    /// Save the graph to DOT, GraphML and JSON files (ex: -o lateral-movement)
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILENAME-PREFIX", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    #[clap(flatten)]
+    pub detect_common_options: DetectCommonOption,
+
+    #[clap(flatten)]
+    pub time_format_options: TimeFormatOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short='C', long = "clobber", display_order = 290, requires = "output")]
+    pub clobber: bool,
+
+    /// End time of the event logs to load (ex: "2022-02-22 23:59:59 +09:00")
+    #[arg(help_heading = Some("Filtering"), long = "timeline-end", value_name = "DATE", display_order = 460)]
+    pub end_timeline: Option<String>,
+
+    /// Start time of the event logs to load (ex: "2020-02-22 00:00:00 +09:00")
+    #[arg(help_heading = Some("Filtering"), long = "timeline-start", value_name = "DATE", display_order = 460)]
+    pub start_timeline: Option<String>,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct IntegrityOption {
+    #[clap(flatten)]
+    pub input_args: InputOption,
+
+    /// Save the results to a CSV file
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    /// Report periods with no events longer than this number of hours (default: 24)
+    #[arg(help_heading = Some("Filtering"), long = "silent-hours", value_name = "HOURS", default_value = "24", hide_default_value = true, display_order = 450)]
+    pub silent_hours: u32,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    #[clap(flatten)]
+    pub detect_common_options: DetectCommonOption,
+
+    #[clap(flatten)]
+    pub time_format_options: TimeFormatOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short='C', long = "clobber", display_order = 290, requires = "output")]
+    pub clobber: bool,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ValidateRulesOption {
+    /// Specify rule directory (default: ./rules)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'r',
+        long,
+        default_value = "./rules",
+        hide_default_value = true,
+        value_name = "DIR/FILE",
+        display_order = 441
+    )]
+    pub rules: PathBuf,
+
+    /// Specify custom rule config directory (default: ./rules/config)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'c',
+        long = "rules-config",
+        default_value = "./rules/config",
+        hide_default_value = true,
+        value_name = "DIR",
+        display_order = 442
+    )]
+    pub config: PathBuf,
+
+    /// Save the validation report in JSON format (ex: validate-rules.json)
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short='C', long = "clobber", display_order = 290, requires = "output")]
+    pub clobber: bool,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct TestRulesOption {
+    /// Specify rule directory (default: ./rules)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'r',
+        long,
+        default_value = "./rules",
+        hide_default_value = true,
+        value_name = "DIR/FILE",
+        display_order = 441
+    )]
+    pub rules: PathBuf,
+
+    /// Specify custom rule config directory (default: ./rules/config)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'c',
+        long = "rules-config",
+        default_value = "./rules/config",
+        hide_default_value = true,
+        value_name = "DIR",
+        display_order = 442
+    )]
+    pub config: PathBuf,
+
+    /// Save the test results in JSON format (ex: test-rules.json)
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short='C', long = "clobber", display_order = 290, requires = "output")]
+    pub clobber: bool,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct RulesDiffOption {
+    /// Old rule directory or git revision (ex: ./rules-old, HEAD~10, v3.3.0)
+    #[arg(help_heading = Some("Input"), value_name = "OLD", display_order = 10)]
+    pub old: String,
+
+    /// New rule directory or git revision (ex: ./rules, HEAD, origin/main)
+    #[arg(help_heading = Some("Input"), value_name = "NEW", display_order = 20)]
+    pub new: String,
+
+    /// Git repository used when OLD or NEW is a git revision (default: ./rules)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'r',
+        long,
+        default_value = "./rules",
+        hide_default_value = true,
+        value_name = "DIR",
+        display_order = 441
+    )]
+    pub rules: PathBuf,
+
+    /// Save the differences in JSON format (ex: rules-diff.json)
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short='C', long = "clobber", display_order = 290, requires = "output")]
+    pub clobber: bool,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ServeOption {
+    /// Specify rule directory (default: ./rules)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'r',
+        long,
+        default_value = "./rules",
+        hide_default_value = true,
+        value_name = "DIR/FILE",
+        display_order = 441
+    )]
+    pub rules: PathBuf,
+
+    /// Specify custom rule config directory (default: ./rules/config)
+    #[arg(
+        help_heading = Some("General Options"),
+        short = 'c',
+        long = "rules-config",
+        default_value = "./rules/config",
+        hide_default_value = true,
+        value_name = "DIR",
+        display_order = 442
+    )]
+    pub config: PathBuf,
+
+    /// Address to listen on (default: 127.0.0.1)
+    #[arg(
+        help_heading = Some("General Options"),
+        long,
+        default_value = "127.0.0.1",
+        hide_default_value = true,
+        value_name = "HOST",
+        display_order = 320
+    )]
+    pub host: String,
+
+    /// Port to listen on (default: 8080)
+    #[arg(
+        help_heading = Some("General Options"),
+        long,
+        default_value = "8080",
+        hide_default_value = true,
+        value_name = "PORT",
+        display_order = 430
+    )]
+    pub port: u16,
+
+    /// Minimum level for rules to load (default: informational)
+    #[arg(
+        help_heading = Some("Filtering"),
+        short = 'm',
+        long = "min-level",
+        default_value = "informational",
+        hide_default_value = true,
+        value_name = "LEVEL",
+        display_order = 390
+    )]
+    pub min_level: String,
+
+    /// Enable rules set to noisy (./rules/config/noisy_rules.txt)
+    #[arg(help_heading = Some("Filtering"), short = 'n', long = "enable-noisy-rules", display_order = 311)]
+    pub enable_noisy_rules: bool,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct WatchOption {
+    /// Directory where new .evtx files and folders are dropped
+    #[arg(help_heading = Some("Input"), value_name = "DIR", display_order = 10)]
+    pub directory: PathBuf,
+
+    /// Directory to save the results of each drop
+    #[arg(help_heading = Some("Output"), short = 'o', long, required = true, value_name = "DIR", display_order = 410)]
+    pub output: PathBuf,
+
+    /// Specify output profile
+    #[arg(help_heading = Some("Output"), short = 'p', long = "profile", display_order = 420)]
+    pub profile: Option<String>,
+
+    /// Timeline command to run: csv-timeline or json-timeline (default: csv-timeline)
+    #[arg(
+        help_heading = Some("Output"),
+        short = 't',
+        long,
+        default_value = "csv-timeline",
+        hide_default_value = true,
+        value_parser = ["csv-timeline", "json-timeline"],
+        hide_possible_values = true,
+        value_name = "COMMAND",
+        display_order = 450
+    )]
+    pub timeline: String,
+
+    /// File to save the already scanned drops (default: <OUTPUT>/watch-state.json)
+    #[arg(help_heading = Some("General Options"), long, value_name = "FILE", display_order = 440)]
+    pub state: Option<PathBuf>,
+
+    /// Seconds a drop must stay unchanged before it is scanned (default: 30)
+    #[arg(
+        help_heading = Some("General Options"),
+        long = "settle-time",
+        default_value = "30",
+        hide_default_value = true,
+        value_name = "SECONDS",
+        display_order = 445
+    )]
+    pub settle_time: u64,
+
+    /// Seconds between checks for new drops (default: 10)
+    #[arg(
+        help_heading = Some("General Options"),
+        long,
+        default_value = "10",
+        hide_default_value = true,
+        value_name = "SECONDS",
+        display_order = 300
+    )]
+    pub interval: u64,
+
+    /// Exit after the drops currently in the directory are scanned
+    #[arg(help_heading = Some("General Options"), long, display_order = 410)]
+    pub once: bool,
+
+    /// Options passed to the timeline command (ex: -- -m medium -U)
+    #[arg(help_heading = Some("Output"), last = true, value_name = "TIMELINE_OPTIONS", display_order = 460)]
+    pub timeline_options: Vec<String>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct CompareOption {
+    /// Timeline (CSV/JSONL) of the previous scan, or an evtx file or directory to scan
+    #[arg(help_heading = Some("Input"), value_name = "OLD", display_order = 10)]
+    pub old: PathBuf,
+
+    /// Timeline (CSV/JSONL) of the new scan, or an evtx file or directory to scan
+    #[arg(help_heading = Some("Input"), value_name = "NEW", display_order = 20)]
+    pub new: PathBuf,
+
+    /// Fields to match detections on besides the rule ID and computer (ex: TgtUser,Details.SrcIP)
+    #[arg(help_heading = Some("General Options"), short = 'k', long = "key-fields", value_name = "FIELDS...", use_value_delimiter = true, value_delimiter = ',', display_order = 380)]
+    pub key_fields: Vec<String>,
+
+    /// Save the new, resolved and persistent detections in CSV format (ex: compare.csv)
+    #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
+    pub output: Option<PathBuf>,
+
+    /// Save the comparison to an HTML report (ex: compare.html)
+    #[arg(help_heading = Some("Output"), short = 'H', long = "HTML-report", value_name = "FILE", display_order = 80)]
+    pub html_report: Option<PathBuf>,
+
+    /// Output profile used when scanning evtx files (default: standard)
+    #[arg(help_heading = Some("Output"), short = 'p', long = "profile", display_order = 420)]
+    pub profile: Option<String>,
+
+    /// Options passed to json-timeline when scanning evtx files (ex: -- -m medium -U)
+    #[arg(help_heading = Some("Output"), last = true, value_name = "TIMELINE_OPTIONS", display_order = 460)]
+    pub timeline_options: Vec<String>,
+
+    #[clap(flatten)]
+    pub common_options: CommonOptions,
+
+    /// Overwrite files when saving
+    #[arg(help_heading = Some("General Options"), short = 'C', long = "clobber", display_order = 290)]
+    pub clobber: bool,
+}
+
 /// Options can be set when outputting
 #[derive(Args, Clone, Debug, Default)]
 #[clap(group(ArgGroup::new("level_rule_filtering").args(["min_level", "exact_level"]).multiple(false)))]
@@ -1689,10 +2344,22 @@ pub struct OutputOption {
     )]
     pub rules: PathBuf,
 
+    /// Add a rule directory that overrides rules with the same ID in the previous directories (can be used multiple times)
+    #[arg(help_heading = Some("General Options"), long = "add-rules", value_name = "DIR", requires = "no_wizard", display_order = 280)]
+    pub add_rules: Vec<PathBuf>,
+
     /// Save Results Summary details to an HTML report (ex: results.html)
     #[arg(help_heading = Some("Output"), short = 'H', long="HTML-report", conflicts_with = "no_summary", value_name = "FILE", display_order = 80, requires = "output")]
     pub html_report: Option<PathBuf>,
 
+    /// Add a searchable detection table, timeline chart, computer pages and MITRE ATT&CK matrix to the HTML report
+    #[arg(help_heading = Some("Output"), long = "HTML-interactive", requires = "html_report", display_order = 81)]
+    pub html_interactive: bool,
+
+    /// Maximum number of detections to add to the interactive HTML report by level (default: all)
+    #[arg(help_heading = Some("Output"), long = "HTML-max-detections", value_name = "NUMBER", requires = "html_interactive", display_order = 82)]
+    pub html_max_detections: Option<usize>,
+
     /// Do not display Results Summary for faster speed
     #[arg(help_heading = Some("Display Settings"), short = 'N', long = "no-summary", conflicts_with = "html_report", display_order = 401)]
     pub no_summary: bool,
@@ -1709,6 +2376,58 @@ pub struct OutputOption {
     #[arg(help_heading = Some("Output"), long = "no-pwsh-field-extraction", display_order = 410)]
     pub no_pwsh_field_extraction: bool,
 
+    /// Save PowerShell script blocks split across multiple 4104 events to a directory
+    #[arg(help_heading = Some("Output"), long = "export-scriptblocks", value_name = "DIR", display_order = 411)]
+    pub export_scriptblocks: Option<PathBuf>,
+
+    /// Save a report of rules that could not match and event types without rules (ex: coverage.csv or coverage.json)
+    #[arg(help_heading = Some("Output"), long = "coverage-report", value_name = "FILE", display_order = 412)]
+    pub coverage_report: Option<PathBuf>,
+
+    /// Save the IDs and hashes of the loaded rules for a later delta scan (ex: rules-manifest.json)
+    #[arg(help_heading = Some("Output"), long = "rules-manifest", value_name = "FILE", display_order = 413)]
+    pub rules_manifest: Option<PathBuf>,
+
+    /// Only run rules that are new or changed since a rule manifest and merge the results into the output file
+    #[arg(help_heading = Some("Filtering"), long = "delta-from", value_name = "MANIFEST", requires = "output", display_order = 305)]
+    pub delta_from: Option<PathBuf>,
+
+    /// Save scan progress to a checkpoint file after each event file (ex: scan.checkpoint)
+    #[arg(help_heading = Some("Output"), long = "checkpoint", value_name = "FILE", requires = "output", conflicts_with_all = ["reassemble_scriptblocks", "export_scriptblocks", "remove_duplicate_records"], display_order = 414)]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Resume an interrupted scan from the checkpoint file and skip completed event files
+    #[arg(help_heading = Some("Output"), long = "resume", requires = "checkpoint", display_order = 415)]
+    pub resume: bool,
+
+    /// Also scan reassembled PowerShell 4104 script blocks with the rules
+    #[arg(help_heading = Some("Filtering"), long = "reassemble-scriptblocks", display_order = 454)]
+    pub reassemble_scriptblocks: bool,
+
+    /// Match indicators of compromise (hashes, IPs, domains, filenames) from a .txt/.csv/STIX .json file or directory
+    #[arg(help_heading = Some("Filtering"), long = "ioc", value_name = "FILE|DIR", display_order = 355)]
+    pub ioc: Option<PathBuf>,
+
+    /// Raise, lower or suppress alert levels by computer, user, time or field value (ex: level-overrides.yml)
+    #[arg(help_heading = Some("Filtering"), long = "level-overrides", value_name = "FILE", display_order = 357)]
+    pub level_overrides: Option<PathBuf>,
+
+    /// Add fields from CSV/JSON lookup tables or an external command to detections (ex: enrichment.yml)
+    #[arg(help_heading = Some("Output"), long = "enrichment", value_name = "FILE", display_order = 416)]
+    pub enrichment: Option<PathBuf>,
+
+    /// Score detections per computer and user and alert when a risk threshold is exceeded (ex: risk-scoring.yml)
+    #[arg(help_heading = Some("Output"), long = "risk-scoring", value_name = "FILE", display_order = 417)]
+    pub risk_scoring: Option<PathBuf>,
+
+    /// Group related detections into incidents by shared computers, users, source IPs, process GUIDs and logon IDs
+    #[arg(help_heading = Some("Output"), long = "incidents", requires = "sort_events", display_order = 418)]
+    pub incidents: bool,
+
+    /// Maximum time between related detections in the same incident (default: 30m)
+    #[arg(help_heading = Some("Output"), long = "incident-window", value_name = "TIME", requires = "incidents", display_order = 419)]
+    pub incident_window: Option<String>,
+
     /// Duplicate field data will be replaced with "DUP"
     #[arg(
             help_heading = Some("Output"),
@@ -1774,6 +2493,10 @@ pub struct InputOption {
     #[arg(help_heading = Some("General Options"), short = 'x', long = "recover-records", conflicts_with = "json_input", display_order = 440)]
     pub recover_records: bool,
 
+    /// Skip records already loaded from another file (ex: VSS copies) by Computer, Channel, EventRecordID and timestamp
+    #[arg(help_heading = Some("Filtering"), long = "remove-duplicate-records", display_order = 459)]
+    pub remove_duplicate_records: bool,
+
     /// Scan recent events based on an offset (ex: 1y, 3M, 30d, 24h, 30m)
     #[arg(help_heading = Some("Filtering"), long = "time-offset", value_name = "OFFSET", conflicts_with = "start_timeline", display_order = 460)]
     pub time_offset: Option<String>,
@@ -1939,6 +2662,14 @@ pub struct ExtractBase64Option {
     #[arg(help_heading = Some("Output"), short = 'o', long, value_name = "FILE", display_order = 410)]
     pub output: Option<PathBuf>,
 
+    /// Maximum number of decoding layers (base64, hex, gzip/deflate/zlib, char codes) to apply (default: 5)
+    #[arg(help_heading = Some("Output"), long = "max-depth", value_name = "NUMBER", default_value = "5", hide_default_value = true, display_order = 405)]
+    pub max_depth: usize,
+
+    /// Save the final decoded payloads to a directory named by their SHA256 hash
+    #[arg(help_heading = Some("Output"), long = "save-payloads", value_name = "DIR", display_order = 430)]
+    pub save_payloads: Option<PathBuf>,
+
     #[clap(flatten)]
     pub common_options: CommonOptions,
 
@@ -2253,6 +2984,27 @@ impl TargetEventTime {
                 );
                 Self::set(parse_success_flag, start_time, end_time)
             }
+            Action::LateralMovement(option) => {
+                let start_time = if time_offset.is_some() {
+                    get_time(
+                        time_offset.as_ref(),
+                        "Invalid timeline offset. Please use one of the following formats: 1y, 3M, 30d, 24h, 30m",
+                        &mut parse_success_flag,
+                    )
+                } else {
+                    get_time(
+                        option.start_timeline.as_ref(),
+                        "start-timeline field: the timestamp format is not correct.",
+                        &mut parse_success_flag,
+                    )
+                };
+                let end_time = get_time(
+                    option.end_timeline.as_ref(),
+                    "end-timeline field: the timestamp format is not correct.",
+                    &mut parse_success_flag,
+                );
+                Self::set(parse_success_flag, start_time, end_time)
+            }
             Action::Search(option) => {
                 let start_time = if time_offset.is_some() {
                     get_time(
@@ -2277,7 +3029,8 @@ impl TargetEventTime {
             Action::LogMetrics(_)
             | Action::EidMetrics(_)
             | Action::ComputerMetrics(_)
-            | Action::ExtractBase64(_) => {
+            | Action::ExtractBase64(_)
+            | Action::Integrity(_) => {
                 let start_time = if time_offset.is_some() {
                     get_time(
                         time_offset.as_ref(),
@@ -2535,6 +3288,36 @@ fn extract_output_options(config: &Config) -> Option<OutputOption> {
             no_wizard: true,
             ..Default::default()
         }),
+        Action::LateralMovement(option) => Some(OutputOption {
+            input_args: option.input_args.clone(),
+            time_format_options: option.time_format_options.clone(),
+            common_options: option.common_options,
+            detect_common_options: option.detect_common_options.clone(),
+            clobber: option.clobber,
+            no_wizard: true,
+            ..Default::default()
+        }),
+        Action::Integrity(option) => Some(OutputOption {
+            input_args: option.input_args.clone(),
+            time_format_options: option.time_format_options.clone(),
+            common_options: option.common_options,
+            detect_common_options: option.detect_common_options.clone(),
+            clobber: option.clobber,
+            no_wizard: true,
+            ..Default::default()
+        }),
+        Action::TestRules(option) => Some(OutputOption {
+            rules: option.rules.clone(),
+            common_options: option.common_options,
+            detect_common_options: DetectCommonOption {
+                config: option.config.clone(),
+                ..Default::default()
+            },
+            time_format_options: TimeFormatOptions::default(),
+            clobber: option.clobber,
+            no_wizard: true,
+            ..Default::default()
+        }),
         Action::ComputerMetrics(option) => Some(OutputOption {
             input_args: option.input_args.clone(),
             common_options: option.common_options,
@@ -2605,6 +3388,7 @@ fn extract_output_options(config: &Config) -> Option<OutputOption> {
                 filepath: option.filepath.clone(),
                 live_analysis: false,
                 recover_records: false,
+                remove_duplicate_records: false,
                 time_offset: None,
             },
             time_format_options: TimeFormatOptions::default(),
@@ -1,6 +1,6 @@
 extern crate csv;
 
-use chrono::{Duration, TimeZone, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use compact_str::CompactString;
 use hashbrown::HashMap;
 use itertools::Itertools;
@@ -17,33 +17,50 @@ use tokio::{runtime::Runtime, spawn, task::JoinHandle};
 use yaml_rust2::Yaml;
 
 use crate::detections::configs::Action;
-use crate::detections::configs::STORED_EKEY_ALIAS;
 use crate::detections::field_data_map::FieldDataMapKey;
-use crate::detections::message::{AlertMessage, DetectInfo, ERROR_LOG_STACK, TAGS_CONFIG};
+use crate::detections::message::{
+    AlertMessage, DetectInfo, ERROR_LOG_STACK, IncidentKeys, TAGS_CONFIG,
+};
 use crate::detections::rule::correlation_parser::parse_correlation_rules;
 use crate::detections::rule::count::{AggRecordTimeInfo, get_sec_timeframe};
 use crate::detections::rule::{self, AggResult, CorrelationType, RuleNode};
 use crate::detections::utils::{
-    create_recordinfos, format_time, get_writable_color, write_color_buffer,
+    create_rec_info_with_alias, create_recordinfos, format_time, get_event_value,
+    get_writable_color, value_to_string, write_color_buffer,
 };
-use crate::detections::utils::{get_serde_number_to_string, make_ascii_titlecase};
+use crate::detections::utils::{get_serde_number_to_string, make_ascii_titlecase, remove_sp_char};
 use crate::filter;
 use crate::level::LEVEL;
+use crate::options::delta_scan::RuleManifest;
 use crate::options::htmlreport;
+use crate::options::level_override::USER_FIELDS;
 use crate::options::pivot::insert_pivot_keyword;
 use crate::options::profile::Profile::{
-    self, Channel, Computer, EventID, EvtxFile, Level, MitreTactics, MitreTags, OtherTags,
-    Provider, RecordID, RecoveredRecord, RenderedMessage, RuleAuthor, RuleCreationDate, RuleFile,
-    RuleID, RuleModifiedDate, RuleTitle, SrcASN, SrcCity, SrcCountry, Status, TgtASN, TgtCity,
-    TgtCountry, Timestamp,
+    self, Channel, Computer, EventID, EvtxFile, Level, MitreTactics, MitreTags, OriginalLevel,
+    OtherTags, OutputFields, Provider, RecordID, RecoveredRecord, RenderedMessage, RuleAuthor,
+    RuleCreationDate, RuleFile, RuleID, RuleModifiedDate, RuleTitle, SrcASN, SrcCity, SrcCountry,
+    Status, TgtASN, TgtCity, TgtCountry, Timestamp,
 };
+use crate::options::profile::parse_field_expression;
+use crate::options::risk_scoring::RISK_RULE_ID;
 use crate::yaml::ParseYaml;
 
 use super::configs::{
-    EventKeyAliasConfig, GEOIP_DB_PARSER, GEOIP_DB_YAML, GEOIP_FILTER, STORED_STATIC, StoredStatic,
+    DELTA_SCAN, EventKeyAliasConfig, GEOIP_DB_PARSER, GEOIP_DB_YAML, GEOIP_FILTER, IOC_MATCHER,
+    STORED_STATIC, StoredStatic,
 };
 use super::message::{self, COMPUTER_MITRE_ATTCK_MAP};
 
+/// --incidentsでアラートをまとめる際に除外するユーザー名、IPアドレス、ログオンID
+const INCIDENT_IGNORED_USERS: [&str; 4] = [
+    "system",
+    "local service",
+    "network service",
+    "anonymous logon",
+];
+const INCIDENT_IGNORED_IPS: [&str; 4] = ["127.0.0.1", "::1", "0.0.0.0", "::"];
+const INCIDENT_IGNORED_LOGON_IDS: [&str; 4] = ["0x0", "0x3e7", "0x3e4", "0x3e5"];
+
 // イベントファイルの1レコード分の情報を保持する構造体
 #[derive(Clone, Debug)]
 pub struct EvtxRecordInfo {
@@ -60,14 +77,34 @@ impl EvtxRecordInfo {
     }
 }
 
+// ルールを実行した結果。ルール、検知結果、ルールに一致したレコードのインデックス
+type RuleResult = (RuleNode, Vec<DetectInfo>, Vec<usize>);
+
 #[derive(Debug)]
 pub struct Detection {
     rules: Vec<RuleNode>,
+    matched_chunks: Option<Vec<Vec<(Value, bool)>>>, // チェックポイントに保存するためにルールに一致したレコード
 }
 
 impl Detection {
     pub fn new(rule_nodes: Vec<RuleNode>) -> Detection {
-        Detection { rules: rule_nodes }
+        Detection {
+            rules: rule_nodes,
+            matched_chunks: None,
+        }
+    }
+
+    /// いずれかのルールまたはIOCに一致したレコードを検知の単位ごとに保持するようにする
+    pub fn keep_matched_records(&mut self) {
+        self.matched_chunks = Some(vec![]);
+    }
+
+    /// 保持しているルールに一致したレコードを取り出す
+    pub fn take_matched_chunks(&mut self) -> Vec<Vec<(Value, bool)>> {
+        self.matched_chunks
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
     }
 
     pub fn start(self, rt: &Runtime, records: Vec<EvtxRecordInfo>) -> (Self, Vec<DetectInfo>) {
@@ -82,13 +119,21 @@ impl Detection {
         exclude_ids: &filter::RuleExclude,
         stored_static: &StoredStatic,
     ) -> Vec<RuleNode> {
-        // ルールファイルのパースを実行
+        // ルールファイルのパースを実行。--add-rulesで指定されたルールソースは後に指定されたものほど優先される
+        let mut rule_sources = vec![(rulespath.to_path_buf(), exclude_ids.to_owned())];
+        if let Some(output_option) = stored_static.output_option.as_ref() {
+            rule_sources.extend(
+                output_option
+                    .add_rules
+                    .iter()
+                    .map(|path| (path.to_owned(), filter::source_exclude_ids(path))),
+            );
+        }
         let mut rulefile_loader = ParseYaml::new(stored_static);
-        let result_readdir = rulefile_loader.read_dir(
-            rulespath,
+        let result_readdir = rulefile_loader.read_rule_sources(
+            &rule_sources,
             min_level,
             target_level,
-            exclude_ids,
             stored_static,
         );
         if result_readdir.is_err() {
@@ -104,8 +149,38 @@ impl Detection {
             }
             return vec![];
         }
+        // 差分スキャンのために読み込んだ全ルールのマニフェストを保存する
+        if let Some(manifest_path) = stored_static
+            .output_option
+            .as_ref()
+            .and_then(|o| o.rules_manifest.as_ref())
+        {
+            if let Err(err) = RuleManifest::new(&rulefile_loader.files).save(manifest_path) {
+                AlertMessage::alert(&format!("Failed to save the rule manifest. {err}")).ok();
+            }
+        }
+        // --delta-fromが指定された場合はマニフェストから追加・変更されたルールのみを実行する
+        if let Some(delta_scan) = DELTA_SCAN.write().unwrap().as_mut() {
+            delta_scan.filter_rules(
+                &mut rulefile_loader.files,
+                &rulefile_loader.defined_rule_ids,
+            );
+        }
         let mut parseerror_count = rulefile_loader.errorrule_count;
         let return_if_success = |mut rule: RuleNode| {
+            // 解析できないoutput_fieldsは出力されないため警告する
+            for err_msg in Detection::invalid_output_fields(&rule.yaml) {
+                let errmsg = format!("{err_msg} (FilePath : {})", rule.rulepath);
+                if stored_static.verbose_flag {
+                    AlertMessage::warn(&errmsg).ok();
+                }
+                if !stored_static.quiet_errors_flag {
+                    ERROR_LOG_STACK
+                        .lock()
+                        .unwrap()
+                        .push(format!("[WARN] {errmsg}"));
+                }
+            }
             let err_msgs_result = rule.init(stored_static);
             if err_msgs_result.is_ok() {
                 return Some(rule);
@@ -163,7 +238,7 @@ impl Detection {
         let records_arc = Arc::new(records);
         // // 各rule毎にスレッドを作成して、スレッドを起動する。
         let rules = self.rules;
-        let handles: Vec<JoinHandle<(RuleNode, Vec<DetectInfo>)>> = rules
+        let handles: Vec<JoinHandle<RuleResult>> = rules
             .into_iter()
             .map(|rule| {
                 let records_cloned = Arc::clone(&records_arc);
@@ -174,12 +249,33 @@ impl Detection {
         // 全スレッドの実行完了を待機
         let mut rules = vec![];
         let mut all_log_records = vec![];
+        let mut matched_indexes = std::collections::BTreeSet::new();
         for handle in handles {
-            let (ret_rule, log_records) = handle.await.unwrap();
+            let (ret_rule, log_records, indexes) = handle.await.unwrap();
             rules.push(ret_rule);
             for log_record in log_records {
                 all_log_records.push(log_record);
             }
+            matched_indexes.extend(indexes);
+        }
+        let (mut ioc_log_records, ioc_indexes) = Detection::detect_ioc(&records_arc);
+        all_log_records.append(&mut ioc_log_records);
+        matched_indexes.extend(ioc_indexes);
+        if let Some(chunks) = self.matched_chunks.as_mut() {
+            // 一致したレコードだけを再度検知すれば同じ検知結果と集計の状態が得られる
+            if !matched_indexes.is_empty() {
+                chunks.push(
+                    matched_indexes
+                        .into_iter()
+                        .map(|i| {
+                            (
+                                records_arc[i].record.clone(),
+                                records_arc[i].recovered_record,
+                            )
+                        })
+                        .collect(),
+                );
+            }
         }
 
         // この関数の先頭でrules.into_iter()を呼び出している。それにより所有権がmapのruleを経由し、execute_ruleの引数に渡しているruleに移っているので、self.rulesには所有権が無くなっている。
@@ -195,7 +291,7 @@ impl Detection {
         rt: &Runtime,
         stored_static: &StoredStatic,
     ) -> Vec<DetectInfo> {
-        rt.block_on(self.add_aggcondition_msg(stored_static))
+        rt.block_on(async { Detection::aggcondition_msges(&self.rules, stored_static) })
     }
 
     fn detect_within_timeframe(
@@ -241,10 +337,11 @@ impl Detection {
         result
     }
 
-    async fn add_aggcondition_msg(&self, stored_static: &StoredStatic) -> Vec<DetectInfo> {
+    /// 集計条件を持つルールと相関ルールの検知結果を返します。
+    pub fn aggcondition_msges(rules: &[RuleNode], stored_static: &StoredStatic) -> Vec<DetectInfo> {
         let mut ret = vec![];
         let mut detected_temporal_refs: HashMap<String, Vec<AggResult>> = HashMap::new();
-        for rule in &self.rules {
+        for rule in rules {
             if !rule.has_agg_condition() {
                 continue;
             }
@@ -258,12 +355,12 @@ impl Detection {
                     output = *generate;
                 }
                 if output {
-                    ret.push(Detection::create_agg_log_record(rule, value, stored_static));
+                    ret.extend(Detection::create_agg_log_record(rule, value, stored_static));
                 }
             }
         }
         // temporalルールは個々ルールの判定がすべて出揃ってから判定できるため、再度rulesをループしてtemporalルールの判定を行う
-        for rule in self.rules.iter() {
+        for rule in rules.iter() {
             let (ref_ids, temporal_ordered) = match &rule.correlation_type {
                 CorrelationType::Temporal(ref_ids) => (ref_ids, false),
                 CorrelationType::TemporalOrdered(ref_ids) => (ref_ids, true),
@@ -288,7 +385,7 @@ impl Detection {
                         temporal_ordered,
                     );
                     for res in results {
-                        ret.push(Detection::create_agg_log_record(rule, res, stored_static));
+                        ret.extend(Detection::create_agg_log_record(rule, res, stored_static));
                     }
                 }
             }
@@ -297,15 +394,23 @@ impl Detection {
     }
 
     // 複数のイベントレコードに対して、ルールを1個実行します。
-    fn execute_rule(
-        mut rule: RuleNode,
-        records: Arc<Vec<EvtxRecordInfo>>,
-    ) -> (RuleNode, Vec<DetectInfo>) {
-        let agg_condition = rule.has_agg_condition();
+    fn execute_rule(mut rule: RuleNode, records: Arc<Vec<EvtxRecordInfo>>) -> RuleResult {
         let binding = STORED_STATIC.read().unwrap();
         let stored_static = binding.as_ref().unwrap();
+        let (ret, matched_indexes) = Detection::select_records(&mut rule, &records, stored_static);
+        (rule, ret, matched_indexes)
+    }
+
+    /// ルールを複数のイベントレコードに対して実行し、検知結果とルールに一致したレコードのインデックスを返します。
+    pub fn select_records(
+        rule: &mut RuleNode,
+        records: &[EvtxRecordInfo],
+        stored_static: &StoredStatic,
+    ) -> (Vec<DetectInfo>, Vec<usize>) {
+        let agg_condition = rule.has_agg_condition();
         let mut ret = vec![];
-        for record_info in records.as_ref() {
+        let mut matched_indexes = vec![];
+        for (i, record_info) in records.iter().enumerate() {
             let result = rule.select(
                 record_info,
                 stored_static.verbose_flag,
@@ -316,6 +421,7 @@ impl Detection {
             if !result {
                 continue;
             }
+            matched_indexes.push(i);
 
             if stored_static.pivot_keyword_list_flag {
                 insert_pivot_keyword(&record_info.record, &stored_static.eventkey_alias);
@@ -324,15 +430,41 @@ impl Detection {
 
             // aggregation conditionが存在しない場合はそのまま出力対応を行う
             if !agg_condition {
-                ret.push(Detection::create_log_record(
-                    &rule,
+                ret.extend(Detection::create_log_record(
+                    rule,
                     record_info,
                     stored_static,
                 ));
             }
         }
 
-        (rule, ret)
+        (ret, matched_indexes)
+    }
+
+    /// --iocで読み込んだIOCと一致したレコードを疑似的なルールの検知結果として返す
+    fn detect_ioc(records: &[EvtxRecordInfo]) -> (Vec<DetectInfo>, Vec<usize>) {
+        let binding = IOC_MATCHER.read().unwrap();
+        let Some(matcher) = binding.as_ref() else {
+            return (vec![], vec![]);
+        };
+        let binding = STORED_STATIC.read().unwrap();
+        let stored_static = binding.as_ref().unwrap();
+        let mut ret = vec![];
+        let mut matched_indexes = vec![];
+        for (i, record_info) in records.iter().enumerate() {
+            let hits = matcher.find_matches(&record_info.record);
+            if !hits.is_empty() {
+                matched_indexes.push(i);
+            }
+            for hit in hits {
+                ret.extend(Detection::create_log_record(
+                    &hit.to_rule_node(),
+                    record_info,
+                    stored_static,
+                ));
+            }
+        }
+        (ret, matched_indexes)
     }
 
     /// create log record
@@ -340,7 +472,7 @@ impl Detection {
         rule: &RuleNode,
         record_info: &EvtxRecordInfo,
         stored_static: &StoredStatic,
-    ) -> DetectInfo {
+    ) -> Option<DetectInfo> {
         let tag_info: &Nested<String> = &Detection::get_tag_info(rule);
         let rec_id = if stored_static
             .profiles
@@ -379,12 +511,11 @@ impl Detection {
         let time = message::get_event_time(&record_info.record, stored_static.json_input_flag)
             .unwrap_or(default_time);
         let level_str = rule.yaml["level"].as_str().unwrap_or("-");
-        let mut level = &LEVEL::from(level_str);
+        let original_level = LEVEL::from(level_str);
 
         let mut profile_converter: HashMap<&str, Profile> = HashMap::new();
         let tags_config_values: Vec<&CompactString> = TAGS_CONFIG.values().collect();
-        let binding = STORED_EKEY_ALIAS.read().unwrap();
-        let eventkey_alias = binding.as_ref().unwrap();
+        let eventkey_alias = &stored_static.eventkey_alias;
         let is_json_timeline = matches!(stored_static.config.action, Some(Action::JsonTimeline(_)));
         let computer_name = CompactString::from(
             record_info.record["Event"]["System"]["Computer"]
@@ -392,6 +523,24 @@ impl Detection {
                 .unwrap_or_default()
                 .replace('\"', ""),
         );
+        // --level-overridesの条件に一致した場合はレベルを変更し、抑制する場合は出力しない
+        let overridden_level = Detection::override_level(
+            rule,
+            &original_level,
+            &computer_name,
+            &time,
+            Some(&record_info.record),
+            stored_static,
+        )?;
+        Detection::add_risk_score(
+            rule,
+            &overridden_level,
+            &computer_name,
+            &time,
+            Some(&record_info.record),
+            stored_static,
+        );
+        let mut level = &overridden_level;
         let mut computer_name_to_mitre_tactics = CompactString::default();
         for (key, profile) in stored_static.profiles.as_ref().unwrap().iter() {
             match profile {
@@ -437,7 +586,7 @@ impl Detection {
                     );
                 }
                 Level(_) => {
-                    level = level.convert(computer_name.as_str());
+                    level = level.convert(computer_name.as_str(), &stored_static.critical_systems);
                     let abbr_level = level.to_abbrev();
                     let prof_level = if stored_static.output_path.is_none() {
                         abbr_level
@@ -446,6 +595,12 @@ impl Detection {
                     };
                     profile_converter.insert(key.as_str(), Level(prof_level.to_string().into()));
                 }
+                OriginalLevel(_) => {
+                    profile_converter.insert(
+                        key.as_str(),
+                        OriginalLevel(original_level.to_abbrev().to_string().into()),
+                    );
+                }
                 EventID(_) => {
                     profile_converter.insert(key.as_str(), EventID(eid.to_string().into()));
                 }
@@ -640,6 +795,18 @@ impl Detection {
                     };
                     profile_converter.insert(key.as_str(), RenderedMessage(convert_value.into()));
                 }
+                OutputFields(_) => {
+                    profile_converter.insert(
+                        key.as_str(),
+                        OutputFields(
+                            Detection::get_output_fields(
+                                rule,
+                                stored_static.disable_abbreviation_flag,
+                            )
+                            .into(),
+                        ),
+                    );
+                }
                 TgtASN(_) | TgtCountry(_) | TgtCity(_) => {
                     if profile_converter.contains_key(key.as_str()) {
                         continue;
@@ -814,6 +981,12 @@ impl Detection {
                 .join(" ¦ "),
             },
         };
+        let incident_keys = Detection::get_incident_keys(
+            tag_info,
+            &computer_name,
+            Some(&record_info.record),
+            stored_static,
+        );
         let detect_info = DetectInfo {
             detected_time: time,
             rulepath: CompactString::from(&rule.rulepath),
@@ -828,9 +1001,10 @@ impl Detection {
             ext_field: stored_static.profiles.as_ref().unwrap().to_owned(),
             agg_result: None,
             details_convert_map: HashMap::default(),
+            incident_keys,
         };
 
-        message::create_message(
+        let detect_info = message::create_message(
             &record_info.record,
             CompactString::new(details_fmt_str),
             detect_info,
@@ -841,22 +1015,282 @@ impl Detection {
                 &field_data_map_key,
                 &stored_static.field_data_map,
             ),
-        )
+        );
+        Some(Detection::enrich(
+            detect_info,
+            Some(&record_info.record),
+            &field_data_map_key,
+            stored_static,
+        ))
+    }
+
+    /// --enrichmentのルックアップでEnrichmentのエイリアスを置き換える関数。レコードのない集計ルールのアラートや一致しない場合は"-"を出力する
+    fn enrich(
+        mut detect_info: DetectInfo,
+        record: Option<&Value>,
+        field_data_map_key: &FieldDataMapKey,
+        stored_static: &StoredStatic,
+    ) -> DetectInfo {
+        if !detect_info
+            .ext_field
+            .iter()
+            .any(|(_, p)| matches!(p, Profile::Enrichment(_)))
+        {
+            return detect_info;
+        }
+        // %Details.User%のようにDetailsの項目をキーにできるようにDetailsを項目ごとに分割する
+        let details: Vec<CompactString> = match detect_info.details_convert_map.get("#Details") {
+            Some(details) => details.clone(),
+            None => detect_info
+                .ext_field
+                .iter()
+                .filter(|(_, p)| matches!(p, Profile::Details(_)))
+                .flat_map(|(_, p)| {
+                    p.to_value()
+                        .split(" ¦ ")
+                        .map(CompactString::from)
+                        .collect_vec()
+                })
+                .collect(),
+        };
+        for (_, profile) in detect_info.ext_field.iter_mut() {
+            let Profile::Enrichment(target) = profile else {
+                continue;
+            };
+            let value = record
+                .zip(stored_static.enrichment.as_ref())
+                .and_then(|(record, enrichment)| {
+                    let (name, column) = target.split_once('.')?;
+                    let key = message::parse_field_expression_value(
+                        record,
+                        enrichment.match_field(name)?,
+                        &details,
+                        &stored_static.eventkey_alias,
+                        false,
+                        field_data_map_key,
+                        &stored_static.field_data_map,
+                    );
+                    if key == "n/a" {
+                        return None;
+                    }
+                    enrichment.get(name, &key, column)
+                })
+                .map(|v| remove_sp_char(v.into()).to_string())
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "-".to_string());
+            *profile = Profile::Enrichment(value.into());
+        }
+        detect_info
+    }
+
+    /// --level-overridesが指定されている場合は条件に一致した設定でレベルを変更する。
+    /// 抑制する場合と、変更後のレベルが--min-levelや--exact-levelの条件を満たさない場合はNoneを返す
+    fn override_level(
+        rule: &RuleNode,
+        level: &LEVEL,
+        computer: &str,
+        time: &DateTime<Utc>,
+        record: Option<&Value>,
+        stored_static: &StoredStatic,
+    ) -> Option<LEVEL> {
+        let Some(overrides) = stored_static.level_overrides.as_ref() else {
+            return Some(level.clone());
+        };
+        let overridden = overrides.apply(
+            level,
+            rule.yaml["id"].as_str().unwrap_or_default(),
+            computer,
+            time,
+            record,
+            &stored_static.eventkey_alias,
+        )?;
+        if overridden == *level {
+            return Some(overridden);
+        }
+        // ルールの読み込み時のレベルの条件を変更後のレベルでも確認する
+        let Some(output_option) = stored_static.output_option.as_ref() else {
+            return Some(overridden);
+        };
+        if overridden.index() < LEVEL::from(&output_option.min_level).index() {
+            return None;
+        }
+        if let Some(exact_level) = output_option.exact_level.as_ref() {
+            if overridden != LEVEL::from(exact_level) {
+                return None;
+            }
+        }
+        Some(overridden)
+    }
+
+    /// --risk-scoringが指定されている場合は検知のリスクスコアをコンピュータ名とユーザー名ごとに集計する
+    fn add_risk_score(
+        rule: &RuleNode,
+        level: &LEVEL,
+        computer: &str,
+        time: &DateTime<Utc>,
+        record: Option<&Value>,
+        stored_static: &StoredStatic,
+    ) {
+        let Some(risk_scoring) = stored_static.risk_scoring.as_ref() else {
+            return;
+        };
+        let rule_id = rule.yaml["id"].as_str().unwrap_or_default();
+        if rule_id == RISK_RULE_ID {
+            return;
+        }
+        let tags: Vec<&str> = rule.yaml["tags"]
+            .as_vec()
+            .map(|tags| tags.iter().filter_map(|t| t.as_str()).collect())
+            .unwrap_or_default();
+        // コンピュータアカウントや"-"のような値はユーザーとして扱わない
+        let user = record.and_then(|record| {
+            USER_FIELDS
+                .iter()
+                .filter_map(|f| {
+                    get_event_value(f, record, &stored_static.eventkey_alias)
+                        .and_then(value_to_string)
+                })
+                .map(|u| u.trim_matches('"').to_string())
+                .find(|u| !u.is_empty() && u != "-" && !u.ends_with('$'))
+        });
+        let score = risk_scoring.score(
+            level,
+            (rule_id, &tags),
+            computer,
+            user.as_deref(),
+            &stored_static.critical_systems,
+        );
+        risk_scoring.add(
+            *time,
+            rule.yaml["title"].as_str().unwrap_or("-"),
+            computer,
+            user.as_deref(),
+            score,
+        );
+    }
+
+    /// --risk-scoringでしきい値を超えたコンピュータ名とユーザー名を疑似的なルールの検知結果として返す
+    pub fn risk_alerts(stored_static: &StoredStatic) -> Vec<DetectInfo> {
+        let Some(risk_scoring) = stored_static.risk_scoring.as_ref() else {
+            return vec![];
+        };
+        risk_scoring
+            .alerts()
+            .iter()
+            .filter_map(|alert| {
+                let record_info = create_rec_info_with_alias(
+                    alert.to_record(),
+                    "-".to_string(),
+                    &Nested::<String>::new(),
+                    &false,
+                    &true,
+                    &stored_static.eventkey_alias,
+                );
+                Detection::create_log_record(
+                    &alert.to_rule_node(&risk_scoring.alert_level, &risk_scoring.window),
+                    &record_info,
+                    stored_static,
+                )
+            })
+            .collect()
+    }
+
+    /// --incidentsが指定されている場合は、関連するアラートをまとめるためのコンピュータ名、ユーザー名、送信元IPアドレス、プロセスGUID、ログオンIDとMITRE ATT&CKの戦術を取得する
+    fn get_incident_keys(
+        tag_info: &Nested<String>,
+        computer: &str,
+        record: Option<&Value>,
+        stored_static: &StoredStatic,
+    ) -> Option<IncidentKeys> {
+        stored_static.incident_window?;
+        let computers = computer.split(" ¦ ").filter(|c| !c.is_empty() && *c != "-");
+        let mut entities: Vec<CompactString> = computers
+            .clone()
+            .map(|c| format!("Computer:{}", c.to_lowercase()).into())
+            .collect();
+        if let Some(record) = record {
+            let field_values = |fields: &[&str]| {
+                fields
+                    .iter()
+                    .filter_map(|f| {
+                        get_event_value(f, record, &stored_static.eventkey_alias)
+                            .and_then(value_to_string)
+                    })
+                    .map(|v| v.trim_matches('"').to_lowercase())
+                    .filter(|v| !v.is_empty() && v != "-")
+                    .collect_vec()
+            };
+            // 多くのアラートで共通する値は関係のないアラートをまとめてしまうため除外する
+            for user in field_values(&USER_FIELDS) {
+                if !user.ends_with('$') && !INCIDENT_IGNORED_USERS.contains(&user.as_str()) {
+                    entities.push(format!("User:{user}").into());
+                }
+            }
+            for ip in field_values(&["IpAddress", "SourceAddress", "SourceIp"]) {
+                if !INCIDENT_IGNORED_IPS.contains(&ip.as_str()) {
+                    entities.push(format!("SrcIP:{ip}").into());
+                }
+            }
+            for guid in field_values(&["ProcessGuid", "ParentProcessGuid"]) {
+                if guid.trim_matches(['{', '}', '0', '-']).is_empty() {
+                    continue;
+                }
+                entities.push(format!("ProcessGuid:{guid}").into());
+            }
+            // ログオンIDはコンピュータごとに採番されるためコンピュータ名と組み合わせる
+            for logon_id in field_values(&["SubjectLogonId", "TargetLogonId", "LogonId"]) {
+                if INCIDENT_IGNORED_LOGON_IDS.contains(&logon_id.as_str()) {
+                    continue;
+                }
+                for c in computers.clone() {
+                    entities.push(format!("LogonID:{}:{logon_id}", c.to_lowercase()).into());
+                }
+            }
+        }
+        let tags_config_values: Vec<&CompactString> = TAGS_CONFIG.values().collect();
+        let tactics = tag_info
+            .iter()
+            .filter(|x| tags_config_values.contains(&&CompactString::from(*x)))
+            .filter_map(|x| x.split(',').next())
+            .map(CompactString::from)
+            .collect();
+        Some(IncidentKeys {
+            entities: entities.into_iter().unique().collect(),
+            tactics,
+        })
     }
 
     fn create_agg_log_record(
         rule: &RuleNode,
         agg_result: AggResult,
         stored_static: &StoredStatic,
-    ) -> DetectInfo {
+    ) -> Option<DetectInfo> {
         let tag_info: &Nested<String> = &Detection::get_tag_info(rule);
         let output = Detection::create_count_output(rule, &agg_result);
 
         let mut profile_converter: HashMap<&str, Profile> = HashMap::new();
         let level_str = rule.yaml["level"].as_str().unwrap_or("-");
-        let mut level = &LEVEL::from(level_str);
+        let original_level = LEVEL::from(level_str);
+        let eventkey_alias = &stored_static.eventkey_alias;
         let computers =
             Detection::join_agg_values(&agg_result.agg_record_time_info, |x| x.computer.clone());
+        let overridden_level = Detection::override_level(
+            rule,
+            &original_level,
+            &computers,
+            &agg_result.start_timedate,
+            None,
+            stored_static,
+        )?;
+        Detection::add_risk_score(
+            rule,
+            &overridden_level,
+            &computers,
+            &agg_result.start_timedate,
+            None,
+            stored_static,
+        );
+        let mut level = &overridden_level;
         let tags_config_values: Vec<&CompactString> = TAGS_CONFIG.values().collect();
         let is_json_timeline = matches!(stored_static.config.action, Some(Action::JsonTimeline(_)));
         for (key, profile) in stored_static.profiles.as_ref().unwrap().iter() {
@@ -900,7 +1334,7 @@ impl Detection {
                     );
                 }
                 Level(_) => {
-                    level = level.convert(computers.as_str());
+                    level = level.convert(computers.as_str(), &stored_static.critical_systems);
                     let abbr_level = level.to_abbrev();
                     let prof_level = if stored_static.output_path.is_none() {
                         abbr_level
@@ -909,6 +1343,12 @@ impl Detection {
                     };
                     profile_converter.insert(key.as_str(), Level(prof_level.to_string().into()));
                 }
+                OriginalLevel(_) => {
+                    profile_converter.insert(
+                        key.as_str(),
+                        OriginalLevel(original_level.to_abbrev().to_string().into()),
+                    );
+                }
                 EventID(_) => {
                     profile_converter.insert(
                         key.as_str(),
@@ -1060,6 +1500,9 @@ impl Detection {
                 RenderedMessage(_) => {
                     profile_converter.insert(key.as_str(), RenderedMessage("-".into()));
                 }
+                OutputFields(_) => {
+                    profile_converter.insert(key.as_str(), OutputFields("-".into()));
+                }
                 TgtASN(_) | TgtCountry(_) | TgtCity(_) => {
                     if profile_converter.contains_key(key.as_str()) {
                         continue;
@@ -1079,6 +1522,7 @@ impl Detection {
                 _ => {}
             }
         }
+        let incident_keys = Detection::get_incident_keys(tag_info, &computers, None, stored_static);
         let detect_info = DetectInfo {
             detected_time: agg_result.start_timedate,
             rulepath: CompactString::from(&rule.rulepath),
@@ -1093,20 +1537,24 @@ impl Detection {
             ext_field: stored_static.profiles.as_ref().unwrap().to_owned(),
             agg_result: Some(agg_result),
             details_convert_map: HashMap::default(),
+            incident_keys,
         };
-        let binding = STORED_EKEY_ALIAS.read().unwrap();
-        let eventkey_alias = binding.as_ref().unwrap();
-
         let field_data_map_key = FieldDataMapKey::default();
 
-        message::create_message(
+        let detect_info = message::create_message(
             &Value::default(),
             CompactString::from(detect_info.detail.as_str()),
             detect_info,
             &profile_converter,
             (true, is_json_timeline),
             (eventkey_alias, &field_data_map_key, &None),
-        )
+        );
+        Some(Detection::enrich(
+            detect_info,
+            None,
+            &field_data_map_key,
+            stored_static,
+        ))
     }
 
     fn join_agg_values<F>(
@@ -1142,6 +1590,63 @@ impl Detection {
         )
     }
 
+    /// ruleのoutput_fieldsを"出力名: %フィールド式%"を" ¦ "で連結した文字列に変換する関数。-bが指定された場合は出力名の代わりに元のフィールド名を使う
+    fn get_output_fields(rule: &RuleNode, disable_abbreviation: bool) -> String {
+        let Some(output_fields) = rule.yaml["output_fields"].as_hash() else {
+            return String::default();
+        };
+        output_fields
+            .iter()
+            .filter_map(|(name, expr)| {
+                let name = name.as_str()?;
+                let expr = expr.as_str()?.trim();
+                let expr = expr
+                    .strip_prefix('%')
+                    .and_then(|e| e.strip_suffix('%'))
+                    .unwrap_or(expr);
+                let (_, field) = parse_field_expression(expr)?;
+                let output_name = if disable_abbreviation {
+                    field.rsplit('.').next().unwrap_or(field)
+                } else {
+                    name
+                };
+                Some(format!("{output_name}: %{expr}%"))
+            })
+            .join(" ¦ ")
+    }
+
+    /// ルールのoutput_fieldsのうち、フィールド式として解析できない項目のエラーメッセージを返す
+    pub fn invalid_output_fields(rule_yaml: &Yaml) -> Vec<String> {
+        let output_fields = &rule_yaml["output_fields"];
+        if output_fields.is_badvalue() {
+            return vec![];
+        }
+        let Some(output_fields) = output_fields.as_hash() else {
+            return vec![
+                "output_fields must be a map of output names and field expressions.".to_string(),
+            ];
+        };
+        output_fields
+            .iter()
+            .filter_map(|(name, expr)| {
+                let name = name.as_str().unwrap_or("?");
+                let Some(expr) = expr.as_str() else {
+                    return Some(format!(
+                        "The output_fields value of {name} is not a string."
+                    ));
+                };
+                let trimmed = expr.trim();
+                let trimmed = trimmed
+                    .strip_prefix('%')
+                    .and_then(|e| e.strip_suffix('%'))
+                    .unwrap_or(trimmed);
+                parse_field_expression(trimmed).is_none().then(|| {
+                    format!("The output_fields expression of {name} cannot be parsed: {expr}")
+                })
+            })
+            .collect()
+    }
+
     ///aggregation conditionのcount部分の検知出力文の文字列を返す関数
     fn create_count_output(rule: &RuleNode, agg_result: &AggResult) -> CompactString {
         let mut ret: String = "".to_string();
@@ -1202,6 +1707,43 @@ impl Detection {
         sorted_ld_rc.sort_by(|a, b| a.0.cmp(b.0));
         let mut html_report_stock = Nested::<String>::new();
 
+        // 複数のルールソースが指定された場合はソースごとの読み込み数を表示する
+        if parse_yaml.rule_source_cnt.len() > 1 {
+            for (path, loaded_cnt, overridden_cnt) in parse_yaml.rule_source_cnt.iter() {
+                let key = format!("Rules from {path}: ");
+                let val = if overridden_cnt != &0 {
+                    format!(
+                        "{} ({} overridden by later sources)",
+                        loaded_cnt.to_formatted_string(&Locale::en),
+                        overridden_cnt.to_formatted_string(&Locale::en)
+                    )
+                } else {
+                    loaded_cnt.to_formatted_string(&Locale::en)
+                };
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    get_writable_color(
+                        Some(Color::Rgb(0, 255, 0)),
+                        stored_static.common_options.no_color,
+                    ),
+                    key.as_str(),
+                    false,
+                )
+                .ok();
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    val.as_str(),
+                    true,
+                )
+                .ok();
+                if stored_static.html_report_flag {
+                    html_report_stock.push(format!("- {key}{val}"));
+                }
+            }
+            println!();
+        }
+
         sorted_ld_rc.into_iter().for_each(|(key, value)| {
             if value != &0_u128 {
                 let disable_flag = if key.as_str() == "noisy"
@@ -1478,6 +2020,41 @@ impl Detection {
         if stored_static.html_report_flag {
             html_report_stock.push(format!("- {tmp_total_detect_output}"));
         }
+        // 差分スキャンの場合は実際に実行するルールの内訳を表示する
+        if let Some(delta_scan) = DELTA_SCAN.read().unwrap().as_ref() {
+            let key = "Delta scan rules: ";
+            let val = format!(
+                "{} new, {} changed, {} removed ({} unchanged rules skipped)",
+                delta_scan.new_cnt.to_formatted_string(&Locale::en),
+                delta_scan.changed_cnt.to_formatted_string(&Locale::en),
+                delta_scan
+                    .removed_rules
+                    .len()
+                    .to_formatted_string(&Locale::en),
+                delta_scan.skipped_cnt.to_formatted_string(&Locale::en)
+            );
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                get_writable_color(
+                    Some(Color::Rgb(0, 255, 0)),
+                    stored_static.common_options.no_color,
+                ),
+                key,
+                false,
+            )
+            .ok();
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                None,
+                val.as_str(),
+                true,
+            )
+            .ok();
+            println!();
+            if stored_static.html_report_flag {
+                html_report_stock.push(format!("- {key}{val}"));
+            }
+        }
         if !html_report_stock.is_empty() {
             htmlreport::add_md_data("General Overview {#general_overview}", html_report_stock);
         }
@@ -1509,6 +2086,7 @@ impl Detection {
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::path::Path;
 
     use chrono::TimeZone;
@@ -1523,6 +2101,7 @@ mod tests {
     use crate::detections::configs::CURRENT_EXE_PATH;
     use crate::detections::configs::Config;
     use crate::detections::configs::CsvOutputOption;
+    use crate::detections::configs::JSONOutputOption;
     use crate::detections::configs::OutputOption;
     use crate::detections::configs::STORED_EKEY_ALIAS;
     use crate::detections::configs::StoredStatic;
@@ -1533,6 +2112,7 @@ mod tests {
     use crate::detections::rule::create_rule;
     use crate::detections::utils;
     use crate::filter;
+    use crate::options::enrichment::Enrichment;
     use crate::options::profile::Profile;
 
     fn create_dummy_stored_static() -> StoredStatic {
@@ -1794,7 +2374,8 @@ mod tests {
                 let rule = &dummy_rule;
                 let record_info = &input_evtxrecord;
                 let stored_static = &stored_static;
-                let detect_info = Detection::create_log_record(rule, record_info, stored_static);
+                let detect_info =
+                    Detection::create_log_record(rule, record_info, stored_static).unwrap();
 
                 let expect_geo_ip_data: Vec<(CompactString, Profile)> = vec![
                     ("SrcASN".into(), Profile::SrcASN("Bredband2 AB".into())),
@@ -1877,7 +2458,8 @@ mod tests {
                 let rule = &dummy_rule;
                 let record_info = &input_evtxrecord;
                 let stored_static = &stored_static;
-                let detect_info = Detection::create_log_record(rule, record_info, stored_static);
+                let detect_info =
+                    Detection::create_log_record(rule, record_info, stored_static).unwrap();
                 let expect_geo_ip_data: Vec<(CompactString, Profile)> = vec![
                     ("SrcASN".into(), Profile::SrcASN("-".into())),
                     ("SrcCountry".into(), Profile::SrcCountry("-".into())),
@@ -1973,7 +2555,8 @@ mod tests {
                 let rule = &rule_node;
                 let record_info = &input_evtxrecord;
                 let stored_static: &StoredStatic = &stored_static.clone();
-                let detect_info = Detection::create_log_record(rule, record_info, stored_static);
+                let detect_info =
+                    Detection::create_log_record(rule, record_info, stored_static).unwrap();
 
                 let expect_extra_field_data: Vec<(CompactString, Profile)> = vec![(
                     "ExtraFieldInfo".into(),
@@ -1989,6 +2572,183 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_insert_message_output_fields() {
+        let test_filepath: &str = "test.evtx";
+        let dummy_action = Action::CsvTimeline(CsvOutputOption {
+            output_options: OutputOption {
+                min_level: "informational".to_string(),
+                no_summary: true,
+                no_wizard: true,
+                ..Default::default()
+            },
+            output: Some(Path::new("./test_emit_csv.csv").to_path_buf()),
+            ..Default::default()
+        });
+        let dummy_config = Some(Config {
+            action: Some(dummy_action),
+            debug: false,
+        });
+        let mut stored_static = StoredStatic::create_static_data(dummy_config);
+        stored_static.profiles.as_mut().unwrap().push((
+            "OutputFields".into(),
+            Profile::OutputFields(Default::default()),
+        ));
+        let eventkey_alias = load_eventkey_alias(
+            utils::check_setting_path(
+                &CURRENT_EXE_PATH.to_path_buf(),
+                "rules/config/eventkey_alias.txt",
+                true,
+            )
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        );
+        *STORED_EKEY_ALIAS.write().unwrap() = Some(eventkey_alias);
+
+        let val = r#"
+            {
+                "Event": {
+                    "EventData": {
+                        "TargetUserName": "Admin",
+                        "NewProcessName": "C:\\Windows\\System32\\CMD.EXE"
+                    },
+                    "System": {
+                        "TimeCreated_attributes": {
+                            "SystemTime": "1996-02-27T01:05:01Z"
+                        },
+                        "EventRecordID": "11111",
+                        "Channel": "Dummy",
+                        "EventID": "4688"
+                    }
+                }
+            }
+        "#;
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                Channel: 'Dummy'
+        details: 'User: %TargetUserName%'
+        output_fields:
+            User: '%TargetUserName%'
+            Proc: 'lower(basename(NewProcessName))'
+            Missing: '%Event.EventData.NoField%'
+        "#;
+        let event: Value = serde_json::from_str(val).unwrap();
+        let rule_yaml = YamlLoader::load_from_str(rule_str).unwrap().remove(0);
+        let mut rule_node = create_rule(test_filepath.to_string(), rule_yaml);
+        assert!(rule_node.init(&create_dummy_stored_static()).is_ok());
+        let keys = detections::rule::get_detection_keys(&rule_node);
+        let input_evtxrecord =
+            utils::create_rec_info(event, test_filepath.to_owned(), &keys, &false, &false);
+
+        let detect_info =
+            Detection::create_log_record(&rule_node, &input_evtxrecord, &stored_static).unwrap();
+        assert!(detect_info.ext_field.contains(&(
+            "OutputFields".into(),
+            Profile::OutputFields("User: Admin ¦ Proc: cmd.exe ¦ Missing: n/a".into())
+        )));
+
+        // -bが指定された場合は元のフィールド名で出力する
+        stored_static.disable_abbreviation_flag = true;
+        let detect_info =
+            Detection::create_log_record(&rule_node, &input_evtxrecord, &stored_static).unwrap();
+        assert!(detect_info.ext_field.contains(&(
+            "OutputFields".into(),
+            Profile::OutputFields(
+                "TargetUserName: Admin ¦ NewProcessName: cmd.exe ¦ NoField: n/a".into()
+            )
+        )));
+    }
+
+    #[test]
+    fn test_insert_message_enrichment() {
+        let test_filepath: &str = "test.evtx";
+        let dummy_action = Action::JsonTimeline(JSONOutputOption {
+            output_options: OutputOption {
+                min_level: "informational".to_string(),
+                no_summary: true,
+                no_wizard: true,
+                ..Default::default()
+            },
+            output: Some(Path::new("./test_emit_enrichment.json").to_path_buf()),
+            ..Default::default()
+        });
+        let dummy_config = Some(Config {
+            action: Some(dummy_action),
+            debug: false,
+        });
+        let mut stored_static = StoredStatic::create_static_data(dummy_config);
+        stored_static.profiles = Some(vec![
+            ("Details".into(), Profile::from("%Details%")),
+            ("Owner".into(), Profile::from("%Enrich.asset.owner%")),
+            ("Dept".into(), Profile::from("%Enrich.user.department%")),
+            ("Missing".into(), Profile::from("%Enrich.asset.location%")),
+        ]);
+        let dir = Path::new("./test_insert_message_enrichment");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("assets.csv"), "hostname,owner\nPC01,Alice\n").unwrap();
+        fs::write(dir.join("users.csv"), "user,department\nadmin,IT\n").unwrap();
+        let enrichment = Enrichment::parse(
+            "asset:\n    table: assets.csv\n    match: Event.System.Computer\nuser:\n    table: users.csv\n    match: Details.User",
+            dir,
+        );
+        fs::remove_dir_all(dir).ok();
+        stored_static.enrichment = Some(enrichment.unwrap());
+        let eventkey_alias = load_eventkey_alias(
+            utils::check_setting_path(
+                &CURRENT_EXE_PATH.to_path_buf(),
+                "rules/config/eventkey_alias.txt",
+                true,
+            )
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        );
+        *STORED_EKEY_ALIAS.write().unwrap() = Some(eventkey_alias);
+
+        let val = r#"
+            {
+                "Event": {
+                    "EventData": {
+                        "TargetUserName": "Admin"
+                    },
+                    "System": {
+                        "TimeCreated_attributes": {
+                            "SystemTime": "1996-02-27T01:05:01Z"
+                        },
+                        "Computer": "pc01",
+                        "EventRecordID": "11111",
+                        "Channel": "Dummy",
+                        "EventID": "4624"
+                    }
+                }
+            }
+        "#;
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                Channel: 'Dummy'
+        details: 'User: %TargetUserName%'
+        "#;
+        let event: Value = serde_json::from_str(val).unwrap();
+        let rule_yaml = YamlLoader::load_from_str(rule_str).unwrap().remove(0);
+        let mut rule_node = create_rule(test_filepath.to_string(), rule_yaml);
+        assert!(rule_node.init(&create_dummy_stored_static()).is_ok());
+        let keys = detections::rule::get_detection_keys(&rule_node);
+        let input_evtxrecord =
+            utils::create_rec_info(event, test_filepath.to_owned(), &keys, &false, &false);
+
+        let detect_info =
+            Detection::create_log_record(&rule_node, &input_evtxrecord, &stored_static).unwrap();
+        let ext_field = detect_info.ext_field;
+        assert!(ext_field.contains(&("Owner".into(), Profile::Enrichment("Alice".into()))));
+        assert!(ext_field.contains(&("Dept".into(), Profile::Enrichment("IT".into()))));
+        assert!(ext_field.contains(&("Missing".into(), Profile::Enrichment("-".into()))));
+    }
+
     #[test]
     fn test_insert_message_multiline_ruleauthor() {
         let test_filepath: &str = "test.evtx";
@@ -2067,7 +2827,8 @@ mod tests {
                 let rule = &rule_node;
                 let record_info = &input_evtxrecord;
                 let stored_static: &StoredStatic = &stored_static.clone();
-                let detect_info = Detection::create_log_record(rule, record_info, stored_static);
+                let detect_info =
+                    Detection::create_log_record(rule, record_info, stored_static).unwrap();
 
                 println!("{:?}", detect_info.ext_field);
                 assert!(detect_info.ext_field.iter().any(|x| x
@@ -0,0 +1,512 @@
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use hashbrown::{HashMap, HashSet};
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::LazyLock;
+use walkdir::WalkDir;
+use yaml_rust2::YamlLoader;
+
+use crate::detections::rule::RuleNode;
+use crate::detections::utils::value_to_string;
+
+static HASH_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:[0-9a-fA-F]{32}|[0-9a-fA-F]{40}|[0-9a-fA-F]{64})$").unwrap());
+static DOMAIN_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:[a-zA-Z0-9_](?:[a-zA-Z0-9_-]{0,61}[a-zA-Z0-9])?\.)+[a-zA-Z]{2,63}$").unwrap()
+});
+static STIX_PATTERN_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"([a-z0-9-]+):([A-Za-z0-9_.'\-]+)\s*=\s*'((?:[^'\\]|\\.)*)'").unwrap()
+});
+
+/// ファイル名として扱う拡張子。ドメインと区別するために利用する
+const FILE_EXTENSIONS: [&str; 28] = [
+    "exe", "dll", "sys", "scr", "cpl", "ocx", "ps1", "psm1", "psd1", "bat", "cmd", "vbs", "vbe",
+    "js", "jse", "wsf", "hta", "lnk", "msi", "jar", "iso", "img", "zip", "rar", "7z", "doc", "xls",
+    "tmp",
+];
+
+/// IOCと照合するEventDataのフィールド
+const TARGET_FIELDS: [(&str, IocType); 27] = [
+    ("Hashes", IocType::Hash),
+    ("Hash", IocType::Hash),
+    ("MD5", IocType::Hash),
+    ("SHA1", IocType::Hash),
+    ("SHA256", IocType::Hash),
+    ("IMPHASH", IocType::Hash),
+    ("DestinationIp", IocType::Ip),
+    ("SourceIp", IocType::Ip),
+    ("IpAddress", IocType::Ip),
+    ("SourceAddress", IocType::Ip),
+    ("DestAddress", IocType::Ip),
+    ("ClientAddress", IocType::Ip),
+    ("QueryName", IocType::Domain),
+    ("DestinationHostname", IocType::Domain),
+    ("SourceHostname", IocType::Domain),
+    ("TargetFilename", IocType::Filename),
+    ("Image", IocType::Filename),
+    ("ParentImage", IocType::Filename),
+    ("ImageLoaded", IocType::Filename),
+    ("OriginalFileName", IocType::Filename),
+    ("NewProcessName", IocType::Filename),
+    ("ParentProcessName", IocType::Filename),
+    ("ImagePath", IocType::Filename),
+    ("ServiceFileName", IocType::Filename),
+    ("CommandLine", IocType::Filename),
+    ("ParentCommandLine", IocType::Filename),
+    ("Path", IocType::Filename),
+];
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+pub enum IocType {
+    Hash,
+    Ip,
+    Domain,
+    Filename,
+}
+
+impl IocType {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            IocType::Hash => "Hash",
+            IocType::Ip => "IP",
+            IocType::Domain => "Domain",
+            IocType::Filename => "Filename",
+        }
+    }
+
+    /// IOCリストの種別の名前から変換する。MISPやSTIX、OTXで使われる名前に対応している
+    fn from_name(name: &str) -> Option<IocType> {
+        let name = name.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "hash" | "md5" | "sha1" | "sha256" | "imphash" | "filehash" => Some(IocType::Hash),
+            "ip" | "ipv4" | "ipv6" | "ip-src" | "ip-dst" | "ipv4-addr" | "ipv6-addr" => {
+                Some(IocType::Ip)
+            }
+            "domain" | "hostname" | "fqdn" | "domain-name" => Some(IocType::Domain),
+            "filename" | "file" | "file-name" | "filepath" | "file-path" => Some(IocType::Filename),
+            _ if name.starts_with("filehash-") => Some(IocType::Hash),
+            _ => None,
+        }
+    }
+
+    /// 種別が指定されていない値から種別を推測する
+    fn guess(value: &str) -> Option<IocType> {
+        if HASH_REGEX.is_match(value) {
+            Some(IocType::Hash)
+        } else if value.parse::<IpAddr>().is_ok() {
+            Some(IocType::Ip)
+        } else if value.contains(['\\', '/']) || is_file_name(value) {
+            Some(IocType::Filename)
+        } else if DOMAIN_REGEX.is_match(value) {
+            Some(IocType::Domain)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_file_name(value: &str) -> bool {
+    value
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| FILE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+#[derive(Debug, Clone)]
+pub struct Indicator {
+    pub value: String,
+    pub ioc_type: IocType,
+    pub source: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IocHit<'a> {
+    pub indicator: &'a Indicator,
+    pub field: String,
+}
+
+/// 読み込んだIOCの一覧。ハッシュ、IP、ドメインは完全一致でHashMapを引き、ファイル名はAho-Corasickで探す
+#[derive(Debug, Clone, Default)]
+pub struct IocMatcher {
+    indicators: Vec<Indicator>,
+    exact: HashMap<(IocType, String), usize>,
+    filenames: Option<AhoCorasick>,
+    filename_ids: Vec<usize>,
+}
+
+impl IocMatcher {
+    /// ファイルまたはディレクトリ内の.txt、.csv、.jsonファイルからIOCを読み込む
+    pub fn load(path: &Path) -> Result<IocMatcher, String> {
+        if !path.exists() {
+            return Err(format!("Could not find the IOC file. {}", path.display()));
+        }
+        let mut indicators = vec![];
+        for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+            let file = entry.path();
+            if !file.is_file() {
+                continue;
+            }
+            let ext = file
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if path.is_dir() && !matches!(ext.as_str(), "txt" | "csv" | "json") {
+                continue;
+            }
+            let contents = fs::read_to_string(file)
+                .map_err(|e| format!("Failed to read the IOC file. {} {e}", file.display()))?;
+            let source = file
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            match ext.as_str() {
+                "csv" => indicators.extend(parse_csv(&contents, &source)),
+                "json" => indicators.extend(parse_stix(&contents, &source)?),
+                _ => indicators.extend(parse_text(&contents, &source)),
+            }
+        }
+        Ok(IocMatcher::new(indicators))
+    }
+
+    pub fn new(indicators: Vec<Indicator>) -> IocMatcher {
+        let mut matcher = IocMatcher::default();
+        let mut seen = HashSet::new();
+        let mut patterns = vec![];
+        for indicator in indicators {
+            let value = normalize(&indicator.value, indicator.ioc_type);
+            if value.is_empty() || !seen.insert((indicator.ioc_type, value.clone())) {
+                continue;
+            }
+            let idx = matcher.indicators.len();
+            if indicator.ioc_type == IocType::Filename {
+                patterns.push(value);
+                matcher.filename_ids.push(idx);
+            } else {
+                matcher.exact.insert((indicator.ioc_type, value), idx);
+            }
+            matcher.indicators.push(indicator);
+        }
+        if !patterns.is_empty() {
+            matcher.filenames = AhoCorasickBuilder::new()
+                .ascii_case_insensitive(true)
+                .match_kind(MatchKind::LeftmostLongest)
+                .build(patterns)
+                .ok();
+        }
+        matcher
+    }
+
+    pub fn len(&self) -> usize {
+        self.indicators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indicators.is_empty()
+    }
+
+    /// 種別毎のIOCの件数を返す
+    pub fn count_by_type(&self) -> Vec<(IocType, usize)> {
+        [
+            IocType::Hash,
+            IocType::Ip,
+            IocType::Domain,
+            IocType::Filename,
+        ]
+        .into_iter()
+        .map(|t| {
+            (
+                t,
+                self.indicators.iter().filter(|i| i.ioc_type == t).count(),
+            )
+        })
+        .collect()
+    }
+
+    /// レコードのEventDataの対象フィールドとIOCを照合する
+    pub fn find_matches(&self, record: &Value) -> Vec<IocHit<'_>> {
+        let mut ret = vec![];
+        let Some(event_data) = record["Event"]["EventData"].as_object() else {
+            return ret;
+        };
+        for (field, ioc_type) in TARGET_FIELDS {
+            let Some(field_value) = event_data.get(field).and_then(value_to_string) else {
+                continue;
+            };
+            let mut hit = |idx: usize| {
+                if !ret
+                    .iter()
+                    .any(|h: &IocHit| std::ptr::eq(h.indicator, &self.indicators[idx]))
+                {
+                    ret.push(IocHit {
+                        indicator: &self.indicators[idx],
+                        field: field.to_string(),
+                    });
+                }
+            };
+            match ioc_type {
+                IocType::Hash => {
+                    // SysmonのHashesは"SHA1=...,MD5=..."の形式
+                    for hash in field_value.split(',') {
+                        let hash = hash.rsplit('=').next().unwrap_or_default();
+                        if let Some(&idx) = self
+                            .exact
+                            .get(&(IocType::Hash, normalize(hash, IocType::Hash)))
+                        {
+                            hit(idx);
+                        }
+                    }
+                }
+                IocType::Ip => {
+                    if let Some(&idx) = self
+                        .exact
+                        .get(&(IocType::Ip, normalize(&field_value, IocType::Ip)))
+                    {
+                        hit(idx);
+                    }
+                }
+                IocType::Domain => {
+                    // サブドメインも対象とするため、親ドメインを順に確認する
+                    let domain = normalize(&field_value, IocType::Domain);
+                    let mut target = domain.as_str();
+                    loop {
+                        if let Some(&idx) = self.exact.get(&(IocType::Domain, target.to_string())) {
+                            hit(idx);
+                            break;
+                        }
+                        match target.split_once('.') {
+                            Some((_, parent)) if parent.contains('.') => target = parent,
+                            _ => break,
+                        }
+                    }
+                }
+                IocType::Filename => {
+                    let Some(ac) = &self.filenames else {
+                        continue;
+                    };
+                    for m in ac.find_iter(&field_value) {
+                        let before = field_value[..m.start()].chars().next_back();
+                        let after = field_value[m.end()..].chars().next();
+                        let is_boundary = |c: Option<char>| {
+                            c.is_none_or(|c| matches!(c, '\\' | '/' | '"' | '\'' | ' ' | ','))
+                        };
+                        if is_boundary(before) && is_boundary(after) {
+                            hit(self.filename_ids[m.pattern().as_usize()]);
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl IocHit<'_> {
+    /// 検知結果として出力するための疑似的なルールを作成する
+    pub fn to_rule_node(&self) -> RuleNode {
+        let ioc_type = self.indicator.ioc_type.to_str();
+        // IOCの値に%が含まれるとフィールドのプレースホルダーとして解釈されるため置き換える
+        let details = format!(
+            "IOC: {} ¦ Type: {ioc_type} ¦ Source: {} ¦ Field: {}",
+            self.indicator.value, self.indicator.source, self.field
+        )
+        .replace('%', "％")
+            + &format!(" ¦ Value: %{}%", self.field);
+        let mut yaml = YamlLoader::load_from_str(&format!(
+            "title: 'IOC Match ({ioc_type})'\nid: ioc-{}\nlevel: high\nstatus: stable\nauthor: IOC\n",
+            ioc_type.to_ascii_lowercase()
+        ))
+        .unwrap()
+        .remove(0);
+        if let yaml_rust2::Yaml::Hash(hash) = &mut yaml {
+            hash.insert(
+                yaml_rust2::Yaml::from_str("details"),
+                yaml_rust2::Yaml::String(details),
+            );
+        }
+        RuleNode::new(self.indicator.source.clone(), yaml)
+    }
+}
+
+fn normalize(value: &str, ioc_type: IocType) -> String {
+    let value = value.trim().trim_matches(['"', '\'']);
+    match ioc_type {
+        IocType::Ip => value
+            .trim_start_matches("::ffff:")
+            .trim_matches(['[', ']'])
+            .to_string(),
+        IocType::Domain => value.trim_end_matches('.').to_ascii_lowercase(),
+        // 防御側で共有する際によく使われる"[.]"のような無害化を元に戻す
+        _ => value.to_ascii_lowercase(),
+    }
+    .replace("[.]", ".")
+}
+
+/// 1行に1つのIOCが書かれたテキストファイル。"#"で始まる行はコメントとして扱う
+fn parse_text(contents: &str, source: &str) -> Vec<Indicator> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let value = l.replace("[.]", ".");
+            IocType::guess(&value).map(|ioc_type| Indicator {
+                value,
+                ioc_type,
+                source: source.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// type,valueのような種別と値の列を持つCSVファイル。種別の列がない場合は値から推測する
+fn parse_csv(contents: &str, source: &str) -> Vec<Indicator> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_reader(contents.as_bytes());
+    let headers: Vec<String> = rdr
+        .headers()
+        .map(|h| h.iter().map(|c| c.trim().to_ascii_lowercase()).collect())
+        .unwrap_or_default();
+    let find_col = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+    let type_col = find_col(&["type", "ioc_type", "indicator_type", "category"]);
+    let value_col = find_col(&["value", "ioc", "indicator", "ioc_value"]);
+    let Some(value_col) = value_col else {
+        // ヘッダーがない場合は全ての値を1列ずつIOCとして扱う
+        return parse_text(&contents.replace(',', "\n"), source);
+    };
+    rdr.records()
+        .filter_map(|r| r.ok())
+        .filter_map(|r| {
+            let value = r.get(value_col)?.trim().replace("[.]", ".");
+            let ioc_type = type_col
+                .and_then(|c| r.get(c))
+                .and_then(IocType::from_name)
+                .or_else(|| IocType::guess(&value))?;
+            Some(Indicator {
+                value,
+                ioc_type,
+                source: source.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// STIX 2.xのバンドル。indicatorオブジェクトのpatternから値を取り出す
+fn parse_stix(contents: &str, source: &str) -> Result<Vec<Indicator>, String> {
+    let json: Value = serde_json::from_str(contents)
+        .map_err(|e| format!("Failed to parse the STIX file. {source} {e}"))?;
+    let objects = match &json["objects"] {
+        Value::Array(objects) => objects.clone(),
+        _ => vec![json],
+    };
+    let mut ret = vec![];
+    for obj in objects.iter().filter(|o| o["type"] == "indicator") {
+        let pattern = obj["pattern"].as_str().unwrap_or_default();
+        for cap in STIX_PATTERN_REGEX.captures_iter(pattern) {
+            let ioc_type = match (&cap[1], &cap[2]) {
+                ("file", prop) if prop.starts_with("hashes") => IocType::Hash,
+                ("file", "name") => IocType::Filename,
+                ("ipv4-addr" | "ipv6-addr", _) => IocType::Ip,
+                ("domain-name", _) => IocType::Domain,
+                _ => continue,
+            };
+            ret.push(Indicator {
+                value: cap[3].replace("\\'", "'").replace("\\\\", "\\"),
+                ioc_type,
+                source: source.to_string(),
+            });
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::ioc::{IocMatcher, IocType, parse_csv, parse_stix, parse_text};
+    use serde_json::json;
+
+    fn create_matcher() -> IocMatcher {
+        let mut indicators = parse_text(
+            "# comment\n44d88612fea8a8f36de82e1278abb02f\n10.0.0.5\nevil[.]com\nmimikatz.exe\n",
+            "intel.txt",
+        );
+        indicators.extend(parse_csv(
+            "type,value\nip-dst,192.168.1.99\ndomain,bad.org\nfilename,c:\\temp\\payload.dll\n",
+            "intel.csv",
+        ));
+        IocMatcher::new(indicators)
+    }
+
+    #[test]
+    fn test_parse_ioc_files() {
+        let matcher = create_matcher();
+        assert_eq!(matcher.len(), 7);
+        assert_eq!(
+            matcher.count_by_type(),
+            vec![
+                (IocType::Hash, 1),
+                (IocType::Ip, 2),
+                (IocType::Domain, 2),
+                (IocType::Filename, 2)
+            ]
+        );
+
+        let stix = r#"{"type": "bundle", "objects": [
+            {"type": "indicator", "pattern": "[file:hashes.'SHA-256' = 'AA00000000000000000000000000000000000000000000000000000000000000'] OR [ipv4-addr:value = '1.2.3.4']"},
+            {"type": "indicator", "pattern": "[url:value = 'http://example.com/']"},
+            {"type": "malware", "name": "test"}
+        ]}"#;
+        let indicators = parse_stix(stix, "stix.json").unwrap();
+        assert_eq!(indicators.len(), 2);
+        assert_eq!(indicators[0].ioc_type, IocType::Hash);
+        assert_eq!(indicators[1].ioc_type, IocType::Ip);
+        assert_eq!(indicators[1].value, "1.2.3.4");
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let matcher = create_matcher();
+        let record = json!({
+            "Event": {
+                "System": {"Channel": "Microsoft-Windows-Sysmon/Operational", "EventID": 1},
+                "EventData": {
+                    "Image": "C:\\Users\\test\\Mimikatz.exe",
+                    "Hashes": "SHA1=0000,MD5=44D88612FEA8A8F36DE82E1278ABB02F",
+                    "CommandLine": "notmimikatz.exe.bak"
+                }
+            }
+        });
+        let hits = matcher.find_matches(&record);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].field, "Hashes");
+        assert_eq!(hits[0].indicator.source, "intel.txt");
+        assert_eq!(hits[1].field, "Image");
+        assert_eq!(hits[1].indicator.value, "mimikatz.exe");
+
+        let record = json!({
+            "Event": {
+                "EventData": {
+                    "QueryName": "cdn.EVIL.com",
+                    "DestinationIp": "::ffff:192.168.1.99",
+                    "TargetFilename": "C:\\Temp\\payload.dll"
+                }
+            }
+        });
+        let hits = matcher.find_matches(&record);
+        let types: Vec<_> = hits.iter().map(|h| h.indicator.ioc_type).collect();
+        assert_eq!(types, vec![IocType::Ip, IocType::Domain, IocType::Filename]);
+        let rule = hits[1].to_rule_node();
+        assert_eq!(rule.yaml["title"].as_str(), Some("IOC Match (Domain)"));
+        assert_eq!(
+            rule.yaml["details"].as_str(),
+            Some(
+                "IOC: evil.com ¦ Type: Domain ¦ Source: intel.txt ¦ Field: QueryName ¦ Value: %QueryName%"
+            )
+        );
+    }
+}
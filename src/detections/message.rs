@@ -7,9 +7,10 @@ use crate::detections::rule::AggResult;
 use crate::detections::utils::{self, get_serde_number_to_string, write_color_buffer};
 use crate::level::LEVEL;
 use crate::options::profile::Profile::{
-    self, AllFieldInfo, Details, ExtraFieldInfo, Literal, SrcASN, SrcCity, SrcCountry, TgtASN,
-    TgtCity, TgtCountry,
+    self, AllFieldInfo, Details, Enrichment, ExtraFieldInfo, Field, IncidentID, Literal,
+    OutputFields, SrcASN, SrcCity, SrcCountry, TgtASN, TgtCity, TgtCountry,
 };
+use crate::options::profile::{apply_field_function, parse_field_expression};
 use chrono::{DateTime, Local, Utc};
 use compact_str::CompactString;
 use dashmap::DashMap;
@@ -45,6 +46,15 @@ pub struct DetectInfo {
     pub ext_field: Vec<(CompactString, Profile)>,
     pub agg_result: Option<AggResult>,
     pub details_convert_map: HashMap<CompactString, Vec<CompactString>>,
+    pub incident_keys: Option<IncidentKeys>,
+}
+
+/// --incidentsで関連するアラートをまとめるために検知時に取得する情報
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IncidentKeys {
+    /// "User:taro"のような種類付きの値。同じ値を持つアラートは同じインシデントになる
+    pub entities: Vec<CompactString>,
+    pub tactics: Vec<CompactString>,
 }
 
 pub struct AlertMessage {}
@@ -203,7 +213,63 @@ pub fn create_message(
                     replaced_profiles.push((key.to_owned(), AllFieldInfo(rec.into())));
                 }
             }
-            Literal(_) => replaced_profiles.push((key.to_owned(), profile.to_owned())),
+            // Enrichmentはcreate_message後にルックアップの値で置き換えるため、ルックアップ名と列名をそのまま残す
+            // IncidentIDは出力時にインシデントをまとめてから設定する
+            Literal(_) | Enrichment(_) | IncidentID(_) => {
+                replaced_profiles.push((key.to_owned(), profile.to_owned()))
+            }
+            Field(expr) => {
+                let value = if is_agg {
+                    CompactString::from("-")
+                } else {
+                    parse_field_expression_value(
+                        event_record,
+                        expr,
+                        &sp_removed_details_in_record,
+                        eventkey_alias,
+                        is_json_timeline,
+                        field_data_map_key,
+                        field_data_map,
+                    )
+                };
+                replaced_profiles.push((key.to_owned(), Field(value.to_string().into())));
+            }
+            OutputFields(_) => {
+                // ルールのoutput_fieldsは"出力名: %フィールド式%"を" ¦ "で連結した形でprofile_converterに格納されている
+                let template = profile_converter
+                    .get(key.as_str())
+                    .map(|p| p.to_value())
+                    .unwrap_or_default();
+                let output_fields: Vec<CompactString> = if is_agg {
+                    vec![]
+                } else {
+                    template
+                        .split(" ¦ ")
+                        .filter_map(|f| f.split_once(": "))
+                        .map(|(name, expr)| {
+                            let value = parse_field_expression_value(
+                                event_record,
+                                expr.trim_matches('%'),
+                                &sp_removed_details_in_record,
+                                eventkey_alias,
+                                is_json_timeline,
+                                field_data_map_key,
+                                field_data_map,
+                            );
+                            format!("{name}: {value}").into()
+                        })
+                        .collect()
+                };
+                if is_json_timeline && !output_fields.is_empty() {
+                    record_details_info_map.insert("#OutputFields".into(), output_fields.clone());
+                }
+                let value = if output_fields.is_empty() {
+                    "-".to_string()
+                } else {
+                    output_fields.join(" ¦ ")
+                };
+                replaced_profiles.push((key.to_owned(), OutputFields(value.into())));
+            }
             ExtraFieldInfo(_) => {
                 if is_agg {
                     if is_json_timeline {
@@ -285,6 +351,64 @@ pub fn create_message(
     detect_info
 }
 
+/// プロファイルのフィールド式を評価する。Details.XXXはDetailsの項目を、それ以外はeventkey_alias.txtのエイリアスかイベントのフィールドを参照する
+pub fn parse_field_expression_value(
+    event_record: &Value,
+    expr: &str,
+    details: &[CompactString],
+    eventkey_alias: &EventKeyAliasConfig,
+    json_timeline_flag: bool,
+    field_data_map_key: &FieldDataMapKey,
+    field_data_map: &Option<FieldDataMap>,
+) -> CompactString {
+    let Some((functions, field)) = parse_field_expression(expr) else {
+        return CompactString::from("n/a");
+    };
+    let value = if let Some(details_key) = field.strip_prefix("Details.") {
+        details.iter().find_map(|d| {
+            d.split_once(": ")
+                .filter(|(k, _)| *k == details_key)
+                .map(|(_, v)| v.to_string())
+        })
+    } else {
+        let event_key = match eventkey_alias.get_event_key(field) {
+            Some(event_key) => event_key.to_string(),
+            None if field.starts_with("Event.") => field.to_string(),
+            None => format!("Event.EventData.{field}"),
+        };
+        event_key
+            .split('.')
+            .try_fold(event_record, |v, k| v.get(k))
+            .and_then(|v| get_serde_number_to_string(v, false))
+            .map(|v| {
+                let name = event_key.rsplit('.').next().unwrap_or_default();
+                match field_data_map {
+                    Some(map) => convert_field_data(
+                        map,
+                        field_data_map_key,
+                        name.to_lowercase().as_str(),
+                        v.as_str(),
+                        event_record,
+                    )
+                    .unwrap_or(v),
+                    None => v,
+                }
+                .to_string()
+            })
+    };
+    let Some(value) = value else {
+        return CompactString::from("n/a");
+    };
+    let value = functions
+        .iter()
+        .fold(value, |v, f| apply_field_function(f, &v));
+    if json_timeline_flag {
+        remove_sp_char(value.into())
+    } else {
+        remove_sp_char(value.split_ascii_whitespace().join(" ").into())
+    }
+}
+
 /// メッセージ内の%で囲まれた箇所をエイリアスとしてレコード情報を参照して置き換える関数
 pub fn parse_message(
     event_record: &Value,
@@ -463,7 +587,7 @@ impl AlertMessage {
 mod tests {
     use crate::detections::configs::{CURRENT_EXE_PATH, StoredStatic, load_eventkey_alias};
     use crate::detections::field_data_map::FieldDataMapKey;
-    use crate::detections::message::{AlertMessage, parse_message};
+    use crate::detections::message::{AlertMessage, parse_field_expression_value, parse_message};
     use crate::detections::utils;
 
     use compact_str::CompactString;
@@ -783,6 +907,51 @@ mod tests {
             expected,
         );
     }
+    #[test]
+    fn test_parse_field_expression_value() {
+        let alias_path = "./test_field_expression_alias.txt";
+        std::fs::write(
+            alias_path,
+            "alias,event_key\nComputer,Event.System.Computer\nProc,Event.EventData.Image\n",
+        )
+        .unwrap();
+        let eventkey_alias = load_eventkey_alias(alias_path);
+        std::fs::remove_file(alias_path).ok();
+        let event_record: Value = serde_json::from_str(
+            r#"{"Event": {"System": {"Computer": "PC1", "EventID": 4688}, "EventData": {"Image": "C:\\Windows\\System32\\CMD.EXE", "TargetUserName": "Admin\tUser"}}}"#,
+        )
+        .unwrap();
+        let details = vec![
+            CompactString::from("User: admin"),
+            CompactString::from("Proc: cmd.exe"),
+        ];
+        let parse = |expr: &str, json_timeline_flag: bool| {
+            parse_field_expression_value(
+                &event_record,
+                expr,
+                &details,
+                &eventkey_alias,
+                json_timeline_flag,
+                &FieldDataMapKey::default(),
+                &None,
+            )
+        };
+        assert_eq!(parse("Computer", false), "PC1");
+        assert_eq!(parse("Event.System.EventID", false), "4688");
+        assert_eq!(parse("lower(basename(Proc))", false), "cmd.exe");
+        assert_eq!(
+            parse("upper(Image)", false),
+            "C:\\WINDOWS\\SYSTEM32\\CMD.EXE"
+        );
+        assert_eq!(parse("Details.User", false), "admin");
+        // CSV出力では空白文字をまとめ、JSON出力では他のフィールドと同様に制御文字を変換する
+        assert_eq!(parse("TargetUserName", false), "Admin User");
+        assert_eq!(parse("TargetUserName", true), "Admin🛂tUser");
+        assert_eq!(parse("Details.Nothing", false), "n/a");
+        assert_eq!(parse("Event.System", false), "n/a");
+        assert_eq!(parse("lower(NotExist)", false), "n/a");
+    }
+
     #[test]
     /// test of loading output filter config by mitre_tactics.txt
     fn test_load_mitre_tactics_log() {
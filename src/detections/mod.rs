@@ -2,6 +2,7 @@ pub mod configs;
 pub mod detection;
 pub mod field_data_map;
 pub mod field_extract;
+pub mod ioc;
 pub mod message;
 pub mod rule;
 pub mod utils;
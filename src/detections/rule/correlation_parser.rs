@@ -421,6 +421,52 @@ fn parse_temporal_rules(
     parsed_temporal_rules
 }
 
+/// 相関ルールを静的に検証し、見つかった問題を返す。validate-rulesコマンドで利用する
+pub fn validate_correlation_rule(rule: &RuleNode, other_rules: &[RuleNode]) -> Vec<String> {
+    let mut errors = vec![];
+    let rule_type = rule.yaml["correlation"]["type"].as_str();
+    if !matches!(
+        rule_type,
+        Some("event_count" | "value_count" | "temporal" | "temporal_ordered")
+    ) {
+        errors.push("The type of correlation rule only supports event_count/value_count/temporal/temporal_ordered.".to_string());
+    }
+    match get_related_rules_id(&rule.yaml) {
+        Ok(ids) if !ids.is_empty() => {
+            for id in ids {
+                if !other_rules
+                    .iter()
+                    .any(|other| !std::ptr::eq(other, rule) && is_referenced_rule(other, &id))
+                {
+                    errors.push(format!("The referenced rule was not found: {}", id));
+                }
+            }
+        }
+        _ => errors.push("Referenced rule not found.".to_string()),
+    }
+    match rule.yaml["correlation"]["timespan"].as_str() {
+        None => errors.push("key timespan not found.".to_string()),
+        Some(timespan) => {
+            if !parse_tframe(timespan.to_string()).is_ok_and(|t| t.timenum.is_ok()) {
+                errors.push(format!("Invalid timespan: {}", timespan));
+            }
+        }
+    }
+    if rule.yaml["correlation"]["group-by"].as_vec().is_none() {
+        errors.push("key group-by not found.".to_string());
+    }
+    if matches!(rule_type, Some("event_count" | "value_count")) {
+        match parse_condition(&rule.yaml["correlation"]) {
+            Ok((_, _, None)) if rule_type == Some("value_count") => {
+                errors.push("key field not found in condition.".to_string())
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    errors
+}
+
 pub fn parse_correlation_rules(
     rule_nodes: Vec<RuleNode>,
     stored_static: &StoredStatic,
@@ -1,5 +1,4 @@
 use crate::detections::configs::EventKeyAliasConfig;
-use crate::detections::configs::STORED_EKEY_ALIAS;
 use crate::detections::configs::StoredStatic;
 use crate::detections::detection::EvtxRecordInfo;
 use crate::detections::message;
@@ -23,13 +22,14 @@ pub fn count(
     verbose_flag: bool,
     quiet_errors_flag: bool,
     json_input_flag: bool,
+    eventkey_alias: &EventKeyAliasConfig,
 ) {
     let key: String = create_count_key(
         rule,
         &evtx_rec.record,
         verbose_flag,
         quiet_errors_flag,
-        STORED_EKEY_ALIAS.read().unwrap().as_ref().unwrap(),
+        eventkey_alias,
     );
     let binding = String::default();
     let field_name = match rule.get_agg_condition() {
@@ -47,10 +47,17 @@ pub fn count(
         false,
         verbose_flag,
         quiet_errors_flag,
-        STORED_EKEY_ALIAS.read().unwrap().as_ref().unwrap(),
+        eventkey_alias,
     )
     .unwrap_or_default();
-    countup(rule, key, field_value, evtx_rec, json_input_flag);
+    countup(
+        rule,
+        key,
+        field_value,
+        evtx_rec,
+        json_input_flag,
+        eventkey_alias,
+    );
 }
 
 ///count byの条件に合致する検知済みレコードの数を増やすための関数
@@ -60,30 +67,16 @@ pub fn countup(
     field_value: String,
     evtx_rec: &EvtxRecordInfo,
     json_input_flag: bool,
+    eventkey_alias: &EventKeyAliasConfig,
 ) {
     let record = &evtx_rec.record;
     let default_time = Utc.with_ymd_and_hms(1977, 1, 1, 0, 0, 0).unwrap();
     let time = message::get_event_time(record, json_input_flag).unwrap_or(default_time);
-    let event_id = utils::get_event_value(
-        "Event.System.EventID",
-        record,
-        STORED_EKEY_ALIAS.read().unwrap().as_ref().unwrap(),
-    )
-    .unwrap();
+    let event_id = utils::get_event_value("Event.System.EventID", record, eventkey_alias).unwrap();
     let event_id = event_id.to_string().trim_matches('\"').to_string();
-    let computer = utils::get_event_value(
-        "Event.System.Computer",
-        record,
-        STORED_EKEY_ALIAS.read().unwrap().as_ref().unwrap(),
-    )
-    .unwrap();
+    let computer = utils::get_event_value("Event.System.Computer", record, eventkey_alias).unwrap();
     let computer = computer.to_string().trim_matches('\"').to_string();
-    let channel = utils::get_event_value(
-        "Event.System.Channel",
-        record,
-        STORED_EKEY_ALIAS.read().unwrap().as_ref().unwrap(),
-    )
-    .unwrap();
+    let channel = utils::get_event_value("Event.System.Channel", record, eventkey_alias).unwrap();
     let channel = channel.to_string().trim_matches('\"').to_string();
     let evtx_file_path = evtx_rec.evtx_filepath.to_string();
     let value_map = rule.countdata.entry(key).or_default();
@@ -138,6 +138,7 @@ impl RuleNode {
                 verbose_flag,
                 quiet_errors_flag,
                 json_input_flag,
+                eventkey_alias,
             );
         }
         result
@@ -161,6 +162,10 @@ impl RuleNode {
     pub fn check_exist_countdata(&self) -> bool {
         !self.countdata.is_empty()
     }
+    /// 集計条件の判定のために保持しているレコードの情報を削除する
+    pub fn clear_countdata(&mut self) {
+        self.countdata.clear();
+    }
     /// ルール内のAggregationParseInfo(Aggregation Condition)を取得する関数
     pub fn get_agg_condition(&self) -> Option<&AggregationParseInfo> {
         if self.detection.aggregation_condition.as_ref().is_some() {
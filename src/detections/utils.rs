@@ -14,7 +14,7 @@ use std::vec;
 use std::{fs, io};
 
 use chrono::Local;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use compact_str::{CompactString, ToCompactString};
 use hashbrown::{HashMap, HashSet};
 use itertools::Itertools;
@@ -321,11 +321,31 @@ pub fn create_tokio_runtime(thread_number: Option<usize>) -> Runtime {
 
 // EvtxRecordInfoを作成します。
 pub fn create_rec_info(
+    data: Value,
+    path: String,
+    keys: &Nested<String>,
+    recovered_record: &bool,
+    no_pwsh_field_extraction: &bool,
+) -> EvtxRecordInfo {
+    let binding = STORED_EKEY_ALIAS.read().unwrap();
+    create_rec_info_with_alias(
+        data,
+        path,
+        keys,
+        recovered_record,
+        no_pwsh_field_extraction,
+        binding.as_ref().unwrap(),
+    )
+}
+
+// 指定されたエイリアスの設定でEvtxRecordInfoを作成します。
+pub fn create_rec_info_with_alias(
     mut data: Value,
     path: String,
     keys: &Nested<String>,
     recovered_record: &bool,
     no_pwsh_field_extraction: &bool,
+    eventkey_alias: &EventKeyAliasConfig,
 ) -> EvtxRecordInfo {
     // 高速化のための処理
 
@@ -336,8 +356,6 @@ pub fn create_rec_info(
     // それと、serde_jsonでは内部的に標準ライブラリのhashmapを使用しているが、hashbrownを使った方が早くなるらしい。標準ライブラリがhashbrownを採用したためserde_jsonについても高速化した。
     let mut key_2_values = HashMap::new();
 
-    let binding = STORED_EKEY_ALIAS.read().unwrap();
-    let eventkey_alias = binding.as_ref().unwrap();
     let mut event_id = None;
     let mut channel = None;
     for key in keys.iter() {
@@ -682,6 +700,29 @@ where
     }
 }
 
+/// format_timeで出力した日時の文字列を、同じ時刻フォーマットのオプションで日時に変換する
+pub fn parse_formatted_time(
+    time_str: &str,
+    time_args: &TimeFormatOptions,
+) -> Option<DateTime<FixedOffset>> {
+    let fmt = if time_args.rfc_2822 {
+        "%a, %e %b %Y %H:%M:%S %:z"
+    } else if time_args.rfc_3339 {
+        "%Y-%m-%d %H:%M:%S%.f%:z"
+    } else if time_args.us_time {
+        "%m-%d-%Y %I:%M:%S%.f %p %:z"
+    } else if time_args.us_military_time {
+        "%m-%d-%Y %H:%M:%S%.f %:z"
+    } else if time_args.european_time {
+        "%d-%m-%Y %H:%M:%S%.f %:z"
+    } else if time_args.iso_8601 {
+        return DateTime::parse_from_rfc3339(time_str).ok();
+    } else {
+        "%Y-%m-%d %H:%M:%S%.f %:z"
+    };
+    DateTime::parse_from_str(time_str, fmt).ok()
+}
+
 /// Check file path exist. If path is existed, output alert message.
 pub fn check_file_expect_not_exist(path: &Path, exist_alert_str: String) -> bool {
     let ret = path.exists();
@@ -707,6 +748,13 @@ pub fn contains_str(input: &str, check: &str) -> bool {
     memmem::find(input.as_bytes(), check.as_bytes()).is_some()
 }
 
+/// test-rulesコマンドで使うテストケースのファイル(xxx.tests.yml)かどうかを判定する。ルールとしては読み込まない
+pub fn is_rule_test_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".tests.yml"))
+}
+
 pub fn output_profile_name(output_option: &Option<OutputOption>, stdout: bool, no_color: bool) {
     // output profile name
     if let Some(profile_opt) = output_option {
@@ -839,7 +887,9 @@ mod tests {
     use crate::detections::field_data_map::FieldDataMapKey;
     use crate::{
         detections::{
-            configs::{Action, Config, CsvOutputOption, OutputOption, StoredStatic},
+            configs::{
+                Action, Config, CsvOutputOption, OutputOption, StoredStatic, TimeFormatOptions,
+            },
             utils::{self, check_setting_path, make_ascii_titlecase},
         },
         options::htmlreport::HTML_REPORTER,
@@ -1217,4 +1267,49 @@ mod tests {
         let ms = duration.num_milliseconds() - 1000 * s;
         assert_eq!(output_duration((s, ms)), "25:11:03.322".to_string());
     }
+
+    #[test]
+    fn test_parse_formatted_time() {
+        let time = NaiveDate::from_ymd_opt(2021, 12, 25)
+            .unwrap()
+            .and_hms_milli_opt(13, 23, 45, 678)
+            .unwrap()
+            .and_utc();
+        let options = [
+            TimeFormatOptions::default(),
+            TimeFormatOptions {
+                rfc_2822: true,
+                ..Default::default()
+            },
+            TimeFormatOptions {
+                rfc_3339: true,
+                ..Default::default()
+            },
+            TimeFormatOptions {
+                us_time: true,
+                ..Default::default()
+            },
+            TimeFormatOptions {
+                us_military_time: true,
+                ..Default::default()
+            },
+            TimeFormatOptions {
+                european_time: true,
+                ..Default::default()
+            },
+            TimeFormatOptions {
+                iso_8601: true,
+                ..Default::default()
+            },
+        ];
+        for option in options {
+            let time_str = utils::format_time(&time, false, &option);
+            assert_eq!(
+                utils::parse_formatted_time(&time_str, &option).map(|t| t.timestamp()),
+                Some(time.timestamp()),
+                "{time_str}"
+            );
+        }
+        assert!(utils::parse_formatted_time("-", &TimeFormatOptions::default()).is_none());
+    }
 }
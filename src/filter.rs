@@ -7,7 +7,7 @@ use regex::Regex;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use yaml_rust2::Yaml;
 
 #[derive(Debug)]
@@ -56,6 +56,21 @@ pub fn exclude_ids(stored_static: &StoredStatic) -> RuleExclude {
     exclude_ids
 }
 
+/// 追加のルールソースのconfigフォルダにあるnoisy_rules.txtとexclude_rules.txtを読み込む。ファイルが存在しない場合は何もしない
+pub fn source_exclude_ids(source_path: &Path) -> RuleExclude {
+    let mut exclude_ids = RuleExclude::default();
+    for filename in ["noisy_rules.txt", "exclude_rules.txt"] {
+        let path = source_path.join("config").join(filename);
+        if let Ok(f) = File::open(&path) {
+            exclude_ids.insert_lines(
+                BufReader::new(f).lines().map_while(Result::ok).collect(),
+                &path.display().to_string(),
+            );
+        }
+    }
+    exclude_ids
+}
+
 impl RuleExclude {
     fn insert_ids(&mut self, filename: &str, stored_static: &StoredStatic) {
         let re = Regex::new(r".*/").unwrap();
@@ -84,6 +99,10 @@ impl RuleExclude {
             let reader = BufReader::new(f.unwrap());
             reader.lines().map_while(Result::ok).collect()
         };
+        self.insert_lines(lines, filename);
+    }
+
+    fn insert_lines(&mut self, lines: Vec<String>, filename: &str) {
         for v in lines {
             let v = v.split('#').collect::<Vec<&str>>()[0].trim().to_string();
             if v.is_empty() || !configs::IDS_REGEX.is_match(&v) {
@@ -143,18 +162,16 @@ fn extract_channel_from_rules(
         intersection_channels: &mut Vec<String>,
     ) {
         match *value {
-            Yaml::String(ref s) => {
-                if key == "Channel" {
-                    if s.contains('*') {
-                        // SigmaルールでChannelにワイルドカードが使われた場合
-                        for ch in evtx_channels {
-                            if ch.contains(s.trim_matches('*')) {
-                                intersection_channels.push(ch.to_string());
-                            }
+            Yaml::String(ref s) if key == "Channel" => {
+                if s.contains('*') {
+                    // SigmaルールでChannelにワイルドカードが使われた場合
+                    for ch in evtx_channels {
+                        if ch.contains(s.trim_matches('*')) {
+                            intersection_channels.push(ch.to_string());
                         }
-                    } else if evtx_channels.contains(s) {
-                        intersection_channels.push(s.clone());
                     }
+                } else if evtx_channels.contains(s) {
+                    intersection_channels.push(s.clone());
                 }
             }
             Yaml::Hash(ref map) => {
@@ -4,19 +4,7 @@ use crate::detections::message::AlertMessage;
 use crate::detections::utils;
 use crate::detections::utils::parse_csv;
 use hashbrown::{HashMap, HashSet};
-use lazy_static::lazy_static;
 use rust_embed::Embed;
-lazy_static! {
-    static ref CRITICAL_SYSTEM: HashSet<String> = {
-        let current = CURRENT_EXE_PATH.to_path_buf();
-        let path = current.join("config/critical_systems.txt");
-        let content = fs::read_to_string(path).unwrap_or("".to_string());
-        content
-            .lines()
-            .map(|line| line.trim().to_string())
-            .collect()
-    };
-}
 use std::fs;
 use std::path::Path;
 use strum::EnumIter;
@@ -90,11 +78,11 @@ impl LEVEL {
         }
     }
 
-    pub fn convert(&self, computer: &str) -> &LEVEL {
-        // computerがCRITICAL_SYSTEMに含まれている場合は、レベルを上げる
+    pub fn convert(&self, computer: &str, critical_systems: &HashSet<String>) -> &LEVEL {
+        // computerがcritical_systemsに含まれている場合は、レベルを上げる
         let computers = computer.split(" ¦ ");
         for c in computers {
-            if CRITICAL_SYSTEM.contains(c) {
+            if critical_systems.contains(c) {
                 return match self {
                     LEVEL::INFORMATIONAL => &LEVEL::INFORMATIONAL,
                     LEVEL::LOW => &LEVEL::MEDIUM,
@@ -116,6 +104,16 @@ impl PartialEq<str> for LEVEL {
     }
 }
 
+/// config/critical_systems.txtに記載されたアラートのレベルを上げるコンピュータ名を読み込む
+pub fn load_critical_systems() -> HashSet<String> {
+    let path = CURRENT_EXE_PATH.join("config/critical_systems.txt");
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
 /// level_color.txtファイルを読み込み対応する文字色のマッピングを返却する関数
 pub fn create_output_color_map(no_color_flag: bool) -> HashMap<LEVEL, Colors> {
     let path = utils::check_setting_path(Path::new("."), "config/level_color.txt", false)
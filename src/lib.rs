@@ -5,6 +5,7 @@ pub mod filter;
 pub mod level;
 pub mod notify;
 pub mod options;
+pub mod scanner;
 pub mod timeline;
 pub mod yaml;
 pub mod yaml_expand;
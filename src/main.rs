@@ -17,8 +17,9 @@ use hashbrown::{HashMap, HashSet};
 use hayabusa::afterfact::{self, AfterfactInfo, AfterfactWriter};
 use hayabusa::debug::checkpoint_process_timer::CHECKPOINT;
 use hayabusa::detections::configs::{
-    Action, CURRENT_EXE_PATH, ConfigReader, EventKeyAliasConfig, ONE_CONFIG_MAP, STORED_EKEY_ALIAS,
-    STORED_STATIC, StoredStatic, TargetEventTime, TargetIds, load_pivot_keywords,
+    Action, CURRENT_EXE_PATH, ConfigReader, DELTA_SCAN, EventKeyAliasConfig, IOC_MATCHER,
+    ONE_CONFIG_MAP, STORED_EKEY_ALIAS, STORED_STATIC, StoredStatic, TargetEventTime, TargetIds,
+    load_pivot_keywords,
 };
 use hayabusa::detections::detection::{self, EvtxRecordInfo};
 use hayabusa::detections::message::{AlertMessage, DetectInfo, ERROR_LOG_STACK};
@@ -30,12 +31,23 @@ use hayabusa::detections::utils::{
 };
 use hayabusa::filter::create_channel_filter;
 use hayabusa::level::LEVEL;
+use hayabusa::options::compare::{Comparison, load_scan, output_comparison};
+use hayabusa::options::delta_scan::{delta_output_path, merge_timeline};
 use hayabusa::options::htmlreport::{self, HTML_REPORTER};
 use hayabusa::options::pivot::PIVOT_KEYWORD;
 use hayabusa::options::pivot::create_output;
 use hayabusa::options::profile::set_default_profile;
+use hayabusa::options::rules_diff::{RulesDiff, load_rules, output_rules_diff};
+use hayabusa::options::scan_checkpoint::{ScanCheckpoint, ScanState};
+use hayabusa::options::serve::serve;
+use hayabusa::options::test_rules::{output_rule_test_report, run_rule_tests};
+use hayabusa::options::validate_rules::{output_validation_report, validate_rules};
+use hayabusa::options::watch::watch;
 use hayabusa::options::{expand_list::expand_list, level_tuning::LevelTuning, update::Update};
+use hayabusa::scanner::Scanner;
 use hayabusa::timeline::computer_metrics::countup_event_by_computer;
+use hayabusa::timeline::coverage::RuleTarget;
+use hayabusa::yaml_expand::read_expand_files;
 use hayabusa::{detections::configs, timeline::timelines::Timeline};
 use hayabusa::{detections::utils::write_color_buffer, filter};
 use hayabusa::{options, yaml};
@@ -267,6 +279,19 @@ impl App {
             .ok();
             println!();
         }
+        if stored_static.lateral_movement_flag {
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                get_writable_color(
+                    Some(Color::Rgb(255, 175, 0)),
+                    stored_static.common_options.no_color,
+                ),
+                "Generating Lateral Movement Graph",
+                true,
+            )
+            .ok();
+            println!();
+        }
         if stored_static.search_flag {
             write_color_buffer(
                 &BufferWriter::stdout(ColorChoice::Always),
@@ -479,8 +504,27 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                         return;
                     }
                 }
+                // 差分スキャンの場合は結果を一時ファイルに出力し、スキャン後に既存のタイムラインにマージする
+                let delta_timeline = if DELTA_SCAN.read().unwrap().is_some() {
+                    let timeline = stored_static.output_path.clone().unwrap();
+                    if !timeline.exists() {
+                        AlertMessage::alert(&format!(
+                            "The timeline {} to merge the delta scan results into does not exist. Please specify the output file of the previous scan.",
+                            timeline.display()
+                        ))
+                        .ok();
+                        return;
+                    }
+                    stored_static.output_path = Some(delta_output_path(&timeline));
+                    Some(timeline)
+                } else {
+                    None
+                };
+                // --resumeの場合は中断前のスキャン結果を含めて出力し直すため、既存の出力ファイルを上書きする
                 if let Some(path) = &stored_static.output_path {
-                    if !stored_static.output_option.as_ref().unwrap().clobber
+                    if delta_timeline.is_none()
+                        && !stored_static.output_option.as_ref().unwrap().resume
+                        && !stored_static.output_option.as_ref().unwrap().clobber
                         && utils::check_file_expect_not_exist(
                             path.as_path(),
                             format!(
@@ -492,8 +536,24 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                         return;
                     }
                 }
+                if let Some(path) = &stored_static.output_option.as_ref().unwrap().checkpoint {
+                    if !stored_static.output_option.as_ref().unwrap().resume
+                        && !stored_static.output_option.as_ref().unwrap().clobber
+                        && utils::check_file_expect_not_exist(
+                            path.as_path(),
+                            format!(
+                                " The checkpoint {} already exists. Please add the --resume option to continue the interrupted scan or the -C, --clobber option to start over.\n",
+                                path.display()
+                            ),
+                        )
+                    {
+                        return;
+                    }
+                }
+                // --iocや--coverage-reportでもscan_all_evtx_filesが有効になるため、指定されたオプションで判定する
+                let output_option = stored_static.output_option.as_ref().unwrap();
                 if stored_static.json_input_flag
-                    && (stored_static.scan_all_evtx_files || stored_static.enable_all_rules)
+                    && (output_option.scan_all_evtx_files || output_option.enable_all_rules)
                 {
                     AlertMessage::alert("It is not necessary to specify -A (--enable-all-rules) or -a (--scan-all-evtx-files) with -J (--JSON-input) because the default channel filter only works with EVTX files.").ok();
                     println!();
@@ -501,6 +561,33 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 }
                 self.analysis_start(&target_extensions, &time_filter, stored_static);
 
+                if let Some(timeline) = delta_timeline {
+                    let delta_path = stored_static.output_path.replace(timeline.clone()).unwrap();
+                    if delta_path.exists() {
+                        let output_option = stored_static.output_option.as_ref().unwrap();
+                        let is_json = matches!(
+                            stored_static.config.action.as_ref().unwrap(),
+                            Action::JsonTimeline(_)
+                        );
+                        let sort_time = output_option
+                            .sort_events
+                            .then_some(&output_option.time_format_options);
+                        if let Err(err) = merge_timeline(
+                            &timeline,
+                            &delta_path,
+                            is_json,
+                            DELTA_SCAN.read().unwrap().as_ref().unwrap(),
+                            sort_time,
+                        ) {
+                            AlertMessage::alert(&format!(
+                                "Failed to merge the delta scan results into {}. The results are saved in {}. {err}",
+                                timeline.display(),
+                                delta_path.display()
+                            ))
+                            .ok();
+                        }
+                    }
+                }
                 output_profile_name(
                     &stored_static.output_option,
                     false,
@@ -511,6 +598,11 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     "Saved file",
                     &stored_static.html_report_flag,
                 );
+                output_saved_file(
+                    &stored_static.output_option.as_ref().unwrap().rules_manifest,
+                    "Saved rule manifest",
+                    &false,
+                );
             }
             Action::LogonSummary(_) => {
                 let mut target_output_path = Nested::<String>::new();
@@ -548,11 +640,49 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 }
                 println!();
             }
+            Action::LateralMovement(_) => {
+                let mut target_output_path = Nested::<String>::new();
+                if let Some(path) = &stored_static.output_path {
+                    for suffix in &[".dot", ".graphml", ".json"] {
+                        let output_file = format!("{}{suffix}", path.to_str().unwrap());
+                        if !stored_static.output_option.as_ref().unwrap().clobber
+                            && utils::check_file_expect_not_exist(
+                                Path::new(output_file.as_str()),
+                                format!(
+                                    " The files with a base name of {} already exist. Please specify a different base filename or add the -C, --clobber option to overwrite.\n",
+                                    path.as_os_str().to_str().unwrap()
+                                ),
+                            )
+                        {
+                            return;
+                        }
+                        target_output_path.push(output_file);
+                    }
+                }
+                self.analysis_start(&target_extensions, &time_filter, stored_static);
+                for target_path in target_output_path.iter() {
+                    output_saved_file(
+                        &Some(Path::new(target_path).to_path_buf()),
+                        "Lateral movement graph",
+                        &stored_static.html_report_flag,
+                    );
+                }
+                println!();
+            }
             Action::EidMetrics(_)
             | Action::ComputerMetrics(_)
             | Action::LogMetrics(_)
             | Action::Search(_)
-            | Action::ExtractBase64(_) => {
+            | Action::ExtractBase64(_)
+            | Action::Integrity(_) => {
+                if let Action::Integrity(opt) = stored_static.config.action.as_ref().unwrap() {
+                    // 重複したレコードを取り除くと2つ目以降のファイルで欠落として報告されるため、integrityでは指定できない
+                    if opt.input_args.remove_duplicate_records {
+                        AlertMessage::alert("--remove-duplicate-records cannot be used with the integrity command because removed records would be reported as gaps.").ok();
+                        println!();
+                        return;
+                    }
+                }
                 if let Some(path) = &stored_static.output_path {
                     if !stored_static.output_option.as_ref().unwrap().clobber
                         && utils::check_file_expect_not_exist(
@@ -706,19 +836,51 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 }
             }
             Action::UpdateRules(_) => {
-                let update_target = match &stored_static.config.action.as_ref().unwrap() {
-                    Action::UpdateRules(option) => Some(option.rules.to_owned()),
+                let update_option = match &stored_static.config.action.as_ref().unwrap() {
+                    Action::UpdateRules(option) => Some(option.to_owned()),
                     _ => None,
                 };
+                let update_target = update_option.as_ref().map(|o| o.rules.to_owned());
                 println!();
-                // エラーが出た場合はインターネット接続がそもそもできないなどの問題点もあるためエラー等の出力は行わない
-                let latest_version_data = Update::get_latest_hayabusa_version().unwrap_or_default();
+                let latest_version_data =
+                    if update_option.as_ref().is_some_and(|o| o.from.is_some()) {
+                        // --fromはインターネットに接続できない環境での利用を想定しているため、最新バージョンの確認は行わない
+                        None
+                    } else {
+                        // エラーが出た場合はインターネット接続がそもそもできないなどの問題点もあるためエラー等の出力は行わない
+                        Update::get_latest_hayabusa_version().unwrap_or_default()
+                    };
                 let now_version = &format!("v{}", env!("CARGO_PKG_VERSION"));
                 stored_static.include_status.insert("*".into());
                 let rule_encoded =
                     check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), "encoded_rules.yml", true)
                         .unwrap();
-                if rule_encoded.exists() {
+                if let Some(option) = update_option.as_ref().filter(|o| o.from.is_some()) {
+                    match Update::update_rules_from_bundle(
+                        option.from.as_ref().unwrap(),
+                        update_target.unwrap().to_str().unwrap(),
+                        (option.signature.as_ref(), option.public_key.as_ref()),
+                        stored_static,
+                    ) {
+                        Ok(output) => {
+                            if output != "You currently have the latest rules." {
+                                write_color_buffer(
+                                    &BufferWriter::stdout(ColorChoice::Always),
+                                    get_writable_color(
+                                        Some(Color::Rgb(255, 175, 0)),
+                                        stored_static.common_options.no_color,
+                                    ),
+                                    "Rules updated successfully.",
+                                    true,
+                                )
+                                .ok();
+                            }
+                        }
+                        Err(e) => {
+                            AlertMessage::alert(&format!("Failed to update rules. {e}")).ok();
+                        }
+                    }
+                } else if rule_encoded.exists() {
                     let url = "https://raw.githubusercontent.com/Yamato-Security/hayabusa-encoded-rules/main/encoded_rules.yml";
                     match get(url).call() {
                         Ok(mut res) => {
@@ -1026,6 +1188,240 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 let _ = self.output_open_close_message("closing_messages.txt", stored_static);
                 return;
             }
+            Action::ValidateRules(opt) => {
+                if !opt.rules.exists() {
+                    AlertMessage::alert(
+                        "The rules directory does not exist. Please check the path.",
+                    )
+                    .ok();
+                    return;
+                }
+                if let Some(path) = &opt.output {
+                    if !opt.clobber
+                        && utils::check_file_expect_not_exist(
+                            path.as_path(),
+                            format!(
+                                " The file {} already exists. Please specify a different filename or add the -C, --clobber option to overwrite.\n",
+                                path.as_os_str().to_str().unwrap()
+                            ),
+                        )
+                    {
+                        return;
+                    }
+                }
+                println!();
+                let expand_map =
+                    read_expand_files(CURRENT_EXE_PATH.join("config/expand")).unwrap_or_default();
+                let report = validate_rules(&opt.rules, &expand_map, stored_static);
+                if let Err(err) = output_validation_report(
+                    &report,
+                    opt.output.as_ref(),
+                    stored_static.common_options.no_color,
+                ) {
+                    AlertMessage::alert(&format!("Failed to write the validation report. {err}"))
+                        .ok();
+                }
+                output_saved_file(&opt.output, "Saved results", &false);
+                let _ = self.output_open_close_message("closing_messages.txt", stored_static);
+                // CIでのマージ判定に使えるように、エラーがある場合は終了コードを1にする
+                if report.error_cnt() > 0 {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            Action::TestRules(opt) => {
+                if !opt.rules.exists() {
+                    AlertMessage::alert(
+                        "The rules directory does not exist. Please check the path.",
+                    )
+                    .ok();
+                    return;
+                }
+                if let Some(path) = &opt.output {
+                    if !opt.clobber
+                        && utils::check_file_expect_not_exist(
+                            path.as_path(),
+                            format!(
+                                " The file {} already exists. Please specify a different filename or add the -C, --clobber option to overwrite.\n",
+                                path.as_os_str().to_str().unwrap()
+                            ),
+                        )
+                    {
+                        return;
+                    }
+                }
+                println!();
+                *STORED_EKEY_ALIAS.write().unwrap() = Some(stored_static.eventkey_alias.clone());
+                *STORED_STATIC.write().unwrap() = Some(stored_static.clone());
+                let expand_map =
+                    read_expand_files(CURRENT_EXE_PATH.join("config/expand")).unwrap_or_default();
+                let report = run_rule_tests(&opt.rules, &expand_map, stored_static, &self.rt);
+                if let Err(err) = output_rule_test_report(
+                    &report,
+                    opt.output.as_ref(),
+                    stored_static.common_options.no_color,
+                ) {
+                    AlertMessage::alert(&format!("Failed to write the test results. {err}")).ok();
+                }
+                output_saved_file(&opt.output, "Saved results", &false);
+                let _ = self.output_open_close_message("closing_messages.txt", stored_static);
+                if report.failed_cnt() > 0 {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            Action::RulesDiff(opt) => {
+                if let Some(path) = &opt.output {
+                    if !opt.clobber
+                        && utils::check_file_expect_not_exist(
+                            path.as_path(),
+                            format!(
+                                " The file {} already exists. Please specify a different filename or add the -C, --clobber option to overwrite.\n",
+                                path.as_os_str().to_str().unwrap()
+                            ),
+                        )
+                    {
+                        return;
+                    }
+                }
+                println!();
+                let rules = match (
+                    load_rules(&opt.old, &opt.rules),
+                    load_rules(&opt.new, &opt.rules),
+                ) {
+                    (Ok(old_rules), Ok(new_rules)) => (old_rules, new_rules),
+                    (Err(err), _) | (_, Err(err)) => {
+                        AlertMessage::alert(&format!("Failed to load rules. {err}")).ok();
+                        return;
+                    }
+                };
+                let diff = RulesDiff::new((&opt.old, rules.0), (&opt.new, rules.1));
+                if let Err(err) = output_rules_diff(
+                    &diff,
+                    opt.output.as_ref(),
+                    stored_static.common_options.no_color,
+                ) {
+                    AlertMessage::alert(&format!("Failed to write the rule differences. {err}"))
+                        .ok();
+                }
+                output_saved_file(&opt.output, "Saved results", &false);
+                let _ = self.output_open_close_message("closing_messages.txt", stored_static);
+                return;
+            }
+            Action::Serve(opt) => {
+                println!();
+                let scanner = match Scanner::builder(&opt.rules)
+                    .config_path(&opt.config)
+                    .min_level(&opt.min_level)
+                    .enable_noisy_rules(opt.enable_noisy_rules)
+                    .critical_systems(stored_static.critical_systems.iter().cloned())
+                    .build()
+                {
+                    Ok(scanner) => scanner,
+                    Err(err) => {
+                        AlertMessage::alert(&format!("Failed to load rules. {err}")).ok();
+                        return;
+                    }
+                };
+                println!(
+                    "Loaded rules: {}",
+                    scanner.rule_count().to_formatted_string(&Locale::en)
+                );
+                if let Err(err) = serve(opt, scanner) {
+                    AlertMessage::alert(&err).ok();
+                }
+                return;
+            }
+            Action::Watch(opt) => {
+                if let Some(profile) = &opt.profile {
+                    let profile_list = options::profile::get_profile_list("config/profiles.yaml");
+                    if !profile_list.iter().any(|p| &p[0] == profile) {
+                        AlertMessage::alert(&format!(
+                            "The profile {profile} does not exist. Please check the available profiles with the list-profiles command."
+                        ))
+                        .ok();
+                        return;
+                    }
+                }
+                let exe = match env::current_exe() {
+                    Ok(exe) => exe,
+                    Err(err) => {
+                        AlertMessage::alert(&format!("Failed to get the executable path. {err}"))
+                            .ok();
+                        return;
+                    }
+                };
+                println!();
+                let target_extensions = configs::get_target_extensions(None, false);
+                let collect = |dir: &Path| {
+                    Self::collect_evtxfiles(
+                        dir.to_str().unwrap_or_default(),
+                        &target_extensions,
+                        stored_static,
+                    )
+                };
+                if let Err(err) = watch(&exe, opt, target_extensions.clone(), collect) {
+                    AlertMessage::alert(&err).ok();
+                }
+                return;
+            }
+            Action::Compare(opt) => {
+                for path in [&opt.output, &opt.html_report].into_iter().flatten() {
+                    if !opt.clobber
+                        && utils::check_file_expect_not_exist(
+                            path.as_path(),
+                            format!(
+                                " The file {} already exists. Please specify a different filename or add the -C, --clobber option to overwrite.\n",
+                                path.as_os_str().to_str().unwrap()
+                            ),
+                        )
+                    {
+                        return;
+                    }
+                }
+                let exe = match env::current_exe() {
+                    Ok(exe) => exe,
+                    Err(err) => {
+                        AlertMessage::alert(&format!("Failed to get the executable path. {err}"))
+                            .ok();
+                        return;
+                    }
+                };
+                println!();
+                let records = match (
+                    load_scan(&exe, opt, &opt.old, "old"),
+                    load_scan(&exe, opt, &opt.new, "new"),
+                ) {
+                    (Ok(old_records), Ok(new_records)) => (old_records, new_records),
+                    (Err(err), _) | (_, Err(err)) => {
+                        AlertMessage::alert(&format!("Failed to load the scan results. {err}"))
+                            .ok();
+                        return;
+                    }
+                };
+                let comparison = match Comparison::new(
+                    (&opt.old.to_string_lossy(), &records.0),
+                    (&opt.new.to_string_lossy(), &records.1),
+                    &opt.key_fields,
+                ) {
+                    Ok(comparison) => comparison,
+                    Err(err) => {
+                        AlertMessage::alert(&err).ok();
+                        return;
+                    }
+                };
+                if let Err(err) = output_comparison(
+                    &comparison,
+                    opt.output.as_ref(),
+                    opt.html_report.as_ref(),
+                    stored_static.common_options.no_color,
+                ) {
+                    AlertMessage::alert(&format!("Failed to write the comparison. {err}")).ok();
+                }
+                output_saved_file(&opt.output, "Saved results", &false);
+                let _ = self.output_open_close_message("closing_messages.txt", stored_static);
+                return;
+            }
             Action::ConfigCriticalSystems(_) => {
                 self.analysis_start(&target_extensions, &time_filter, stored_static);
                 let _ = self.output_open_close_message("closing_messages.txt", stored_static);
@@ -1852,6 +2248,10 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             wait_message = "Loading detection rules. Please wait.";
         } else if stored_static.logon_summary_flag {
             wait_message = "Currently scanning for the logon summary. Please wait.";
+        } else if stored_static.lateral_movement_flag {
+            wait_message = "Currently scanning for lateral movement. Please wait.";
+        } else if stored_static.integrity_flag {
+            wait_message = "Currently checking event log integrity. Please wait.";
         } else if stored_static.search_flag {
             wait_message = "Currently searching. Please wait.";
         } else if stored_static.metrics_flag {
@@ -1900,12 +2300,51 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 .unwrap()
                 .set_checkpoint(Local::now());
             if rule_files.is_empty() {
-                AlertMessage::alert(
+                if DELTA_SCAN.read().unwrap().is_some() {
+                    write_color_buffer(
+                        &BufferWriter::stdout(ColorChoice::Always),
+                        get_writable_color(
+                            Some(Color::Rgb(255, 175, 0)),
+                            stored_static.common_options.no_color,
+                        ),
+                        "There are no new or changed rules since the rule manifest. The timeline was not changed.",
+                        true,
+                    )
+                    .ok();
+                    println!();
+                } else {
+                    AlertMessage::alert(
                         "No rules were loaded. Please download the latest rules with the update-rules command.\r\n",
                     )
                     .ok();
+                }
                 return;
             }
+            if let Some(ioc) = IOC_MATCHER.read().unwrap().as_ref() {
+                let counts = ioc
+                    .count_by_type()
+                    .iter()
+                    .map(|(t, c)| format!("{}: {}", t.to_str(), c))
+                    .join(", ");
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    get_writable_color(
+                        Some(Color::Rgb(0, 255, 0)),
+                        stored_static.common_options.no_color,
+                    ),
+                    "IOCs loaded: ",
+                    false,
+                )
+                .ok();
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!("{} ({counts})", ioc.len().to_formatted_string(&Locale::en)),
+                    true,
+                )
+                .ok();
+                println!();
+            }
             if !stored_static.json_input_flag
                 && !stored_static.scan_all_evtx_files
                 && !stored_static.enable_all_rules
@@ -2013,6 +2452,27 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             evtx_files.retain(|e| channel_filter.scanable_rule_exists(e));
         }
 
+        if stored_static.lateral_movement_flag && !stored_static.json_input_flag {
+            // lateral-movement用のChannelフィルターを作成
+            let yaml_str = r#"
+            detection:
+                selection:
+                    Channel:
+                        - Security
+                        - Microsoft-Windows-TerminalServices-RemoteConnectionManager/Operational
+                        - Microsoft-Windows-TerminalServices-LocalSessionManager/Operational
+            "#;
+            let yaml_data = YamlLoader::load_from_str(yaml_str);
+            let node = RuleNode::new(
+                "lateral-movement".to_string(),
+                yaml_data.ok().unwrap_or_default().first().unwrap().clone(),
+            );
+            let rule_files = vec![node];
+            let mut channel_filter =
+                create_channel_filter(&evtx_files, &rule_files, stored_static.quiet_errors_flag);
+            evtx_files.retain(|e| channel_filter.scanable_rule_exists(e));
+        }
+
         if matches!(
             stored_static.config.action.as_ref().unwrap(),
             Action::ConfigCriticalSystems(_)
@@ -2055,6 +2515,16 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
         .with_tab_width(55);
         pb.set_style(progress_style);
         self.rule_keys = self.get_all_keys(&rule_files);
+        let coverage_report = stored_static
+            .output_option
+            .as_ref()
+            .and_then(|o| o.coverage_report.as_ref());
+        // ルールはDetectionに渡すと参照できなくなるため、カバレッジの判定に必要な情報だけ先に取り出しておく
+        let coverage_rules: Vec<RuleTarget> = if coverage_report.is_some() {
+            rule_files.iter().map(RuleTarget::new).collect()
+        } else {
+            vec![]
+        };
         let mut detection = detection::Detection::new(rule_files);
         let mut tl = Timeline::new();
         *STORED_EKEY_ALIAS.write().unwrap() = Some(stored_static.eventkey_alias.clone());
@@ -2069,6 +2539,8 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     | Action::PivotKeywordsList(_)
                     | Action::ExtractBase64(_)
                     | Action::LogonSummary(_)
+                    | Action::LateralMovement(_)
+                    | Action::Integrity(_)
                     | Action::ComputerMetrics(_)
                     | Action::LogMetrics(_)
                     | Action::EidMetrics(_)
@@ -2076,7 +2548,79 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
         if is_show_progress {
             pb.enable_steady_tick(Duration::from_millis(300));
         }
+        // --checkpointが指定されている場合はファイルごとにスキャンの進捗を保存する
+        let mut scan_checkpoint = None;
+        if let Some(path) = stored_static
+            .output_option
+            .as_ref()
+            .and_then(|o| o.checkpoint.as_ref())
+        {
+            let resume = stored_static.output_option.as_ref().unwrap().resume;
+            let args: Vec<String> = env::args().skip(1).filter(|a| a != "--resume").collect();
+            match ScanCheckpoint::open(path, &args, &evtx_files, resume) {
+                Ok(checkpoint) => scan_checkpoint = Some(checkpoint),
+                Err(err) => {
+                    AlertMessage::alert(&err).ok();
+                    return;
+                }
+            }
+            detection.keep_matched_records();
+        }
+        if let Some(checkpoint) = scan_checkpoint.as_mut() {
+            if !checkpoint.completed_files.is_empty() {
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    get_writable_color(
+                        Some(Color::Rgb(0, 255, 0)),
+                        stored_static.common_options.no_color,
+                    ),
+                    "Resuming from checkpoint: ",
+                    false,
+                )
+                .ok();
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!(
+                        "{} of {} files already scanned",
+                        checkpoint.completed_files.len(),
+                        evtx_files.len()
+                    ),
+                    true,
+                )
+                .ok();
+            }
+            // 完了済みのファイルはルールに一致したレコードだけを再度検知して、中断前の検知結果と集計の状態を復元する
+            for completed in checkpoint.completed_files.iter_mut() {
+                let chunks = std::mem::take(&mut completed.chunks);
+                let (detection_tmp, mut detect_infos) = self.replay_checkpoint_file(
+                    (&completed.path, chunks, stored_static),
+                    detection,
+                    &mut afterfact_writer,
+                    &mut afterfact_info,
+                );
+                detection = detection_tmp;
+                all_detect_infos.append(&mut detect_infos);
+                if is_show_progress {
+                    pb.inc(1);
+                }
+            }
+            let state = &checkpoint.state;
+            afterfact_info.record_cnt = state.record_cnt;
+            afterfact_info.recover_record_cnt = state.recover_record_cnt;
+            tl.total_record_cnt = state.total_record_cnt;
+            tl.stats.start_time = state.start_time;
+            tl.stats.end_time = state.end_time;
+            tl.stats.stats_list = state.stats_list.clone();
+        }
         for evtx_file in evtx_files {
+            let file_path = evtx_file.display().to_string();
+            if scan_checkpoint
+                .as_ref()
+                .is_some_and(|c| c.is_completed(&file_path))
+            {
+                continue;
+            }
             if is_show_progress {
                 let size = get_file_size(
                     &evtx_file,
@@ -2101,7 +2645,7 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     self.analysis_json_file(
                         (evtx_file, time_filter, target_event_ids, stored_static),
                         detection,
-                        tl.to_owned(),
+                        std::mem::take(&mut tl),
                         &mut afterfact_writer,
                         &mut afterfact_info,
                     )
@@ -2109,7 +2653,7 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     self.analysis_file(
                         (evtx_file, time_filter, target_event_ids, stored_static),
                         detection,
-                        tl.to_owned(),
+                        std::mem::take(&mut tl),
                         &mut afterfact_writer,
                         &mut afterfact_info,
                     )
@@ -2119,6 +2663,21 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             afterfact_info.record_cnt += cnt_tmp as u128;
             afterfact_info.recover_record_cnt += recover_cnt_tmp as u128;
             all_detect_infos.append(&mut detect_infos);
+            if let Some(checkpoint) = scan_checkpoint.as_mut() {
+                let state = ScanState {
+                    record_cnt: afterfact_info.record_cnt,
+                    recover_record_cnt: afterfact_info.recover_record_cnt,
+                    total_record_cnt: tl.total_record_cnt,
+                    start_time: tl.stats.start_time,
+                    end_time: tl.stats.end_time,
+                    stats_list: tl.stats.stats_list.clone(),
+                };
+                if let Err(err) =
+                    checkpoint.save_file(&file_path, &detection.take_matched_chunks(), &state)
+                {
+                    AlertMessage::alert(&format!("Failed to save the checkpoint. {err}")).ok();
+                }
+            }
             if is_show_progress {
                 pb.inc(1);
             }
@@ -2127,6 +2686,16 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             stored_static.config.action.as_ref().unwrap(),
             Action::CsvTimeline(_) | Action::JsonTimeline(_)
         );
+        if let Some(dir) = stored_static
+            .output_option
+            .as_ref()
+            .and_then(|o| o.export_scriptblocks.as_ref())
+        {
+            // 最後まで揃わなかったスクリプトブロックも調査のために保存しておく
+            for script in tl.script_blocks.take_incomplete() {
+                tl.script_blocks.export(&script, dir, false);
+            }
+        }
         if !is_timeline_cmd {
             let msg = if stored_static.common_options.no_color {
                 style("Scanning finished.\n").color256(15).to_string()
@@ -2150,6 +2719,10 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             tl.tm_stats_dsp_msg(event_timeline_config, stored_static);
         } else if stored_static.logon_summary_flag {
             tl.tm_logon_stats_dsp_msg(stored_static);
+        } else if stored_static.lateral_movement_flag {
+            tl.lateral_movement_dsp_msg(stored_static);
+        } else if stored_static.integrity_flag {
+            tl.integrity_dsp_msg(stored_static);
         } else if stored_static.search_flag {
             tl.search_dsp_msg(stored_static);
         } else if stored_static.computer_metrics_flag {
@@ -2165,6 +2738,7 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
         }
         if is_timeline_cmd {
             let mut log_records = detection.add_aggcondition_msges(&self.rt, stored_static);
+            log_records.extend(detection::Detection::risk_alerts(stored_static));
             if stored_static.is_low_memory {
                 let empty_ids = HashSet::new();
                 afterfact::emit_csv(
@@ -2216,7 +2790,7 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 afterfact::output_additional_afterfact(
                     stored_static,
                     &mut afterfact_writer,
-                    &afterfact_info,
+                    &mut afterfact_info,
                 );
             } else {
                 afterfact::output_afterfact(
@@ -2236,6 +2810,98 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     }
                 }
             }
+            if let Some(dir) = stored_static
+                .output_option
+                .as_ref()
+                .and_then(|o| o.export_scriptblocks.as_ref())
+            {
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    get_writable_color(
+                        Some(Color::Rgb(0, 255, 0)),
+                        stored_static.common_options.no_color,
+                    ),
+                    "Saved script blocks:",
+                    false,
+                )
+                .ok();
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!(
+                        " {} ({} files)",
+                        dir.display(),
+                        tl.script_blocks.exported_cnt
+                    ),
+                    true,
+                )
+                .ok();
+            }
+            if let Some(path) = coverage_report {
+                tl.coverage.rules = coverage_rules;
+                tl.coverage.set_event_types(&tl.stats.stats_list);
+                println!();
+                tl.coverage
+                    .print_summary(stored_static.common_options.no_color);
+                if stored_static.html_report_flag {
+                    tl.coverage.add_html_report();
+                }
+                match tl.coverage.save(path) {
+                    Ok(saved) => {
+                        write_color_buffer(
+                            &BufferWriter::stdout(ColorChoice::Always),
+                            get_writable_color(
+                                Some(Color::Rgb(0, 255, 0)),
+                                stored_static.common_options.no_color,
+                            ),
+                            "Saved coverage report:",
+                            false,
+                        )
+                        .ok();
+                        write_color_buffer(
+                            &BufferWriter::stdout(ColorChoice::Always),
+                            None,
+                            &format!(
+                                " {}",
+                                saved.iter().map(|p| p.display().to_string()).join(", ")
+                            ),
+                            true,
+                        )
+                        .ok();
+                    }
+                    Err(err) => {
+                        AlertMessage::alert(&format!("Failed to save the coverage report. {err}"))
+                            .ok();
+                    }
+                }
+            }
+            // 出力まで完了したのでチェックポイントは不要になる
+            if let Some(checkpoint) = scan_checkpoint {
+                if let Err(err) = checkpoint.remove() {
+                    AlertMessage::alert(&format!("Failed to remove the checkpoint. {err}")).ok();
+                }
+            }
+        }
+        if stored_static.remove_duplicate_records {
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                get_writable_color(
+                    Some(Color::Rgb(0, 255, 0)),
+                    stored_static.common_options.no_color,
+                ),
+                "Duplicate records skipped: ",
+                false,
+            )
+            .ok();
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                None,
+                &tl.record_dedup
+                    .duplicate_cnt
+                    .to_formatted_string(&Locale::en),
+                true,
+            )
+            .ok();
         }
         CHECKPOINT
             .lock()
@@ -2249,6 +2915,42 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             .set_checkpoint(Local::now());
     }
 
+    // チェックポイントに保存された完了済みファイルのルールに一致したレコードを再度検知する
+    fn replay_checkpoint_file(
+        &self,
+        (path, chunks, stored_static): (&str, Vec<Vec<(Value, bool)>>, &StoredStatic),
+        mut detection: detection::Detection,
+        afterfact_writer: &mut AfterfactWriter,
+        afterfact_info: &mut AfterfactInfo,
+    ) -> (detection::Detection, Vec<DetectInfo>) {
+        let mut detect_infos = vec![];
+        for chunk in chunks {
+            let records = self.rt.block_on(App::create_rec_infos(
+                chunk,
+                &path,
+                self.rule_keys.to_owned(),
+                stored_static.no_pwsh_field_extraction,
+            ));
+            let (detection_tmp, mut log_records) = detection.start(&self.rt, records);
+            if stored_static.is_low_memory {
+                let empty_ids = HashSet::new();
+                afterfact::emit_csv(
+                    &log_records,
+                    &empty_ids,
+                    stored_static,
+                    afterfact_writer,
+                    afterfact_info,
+                );
+            } else {
+                detect_infos.append(&mut log_records);
+            }
+            detection = detection_tmp;
+        }
+        // 再検知したレコードはチェックポイントに保存済みのため破棄する
+        detection.take_matched_chunks();
+        (detection, detect_infos)
+    }
+
     // Windowsイベントログファイルを1ファイル分解析する。
     fn analysis_file(
         &self,
@@ -2329,7 +3031,8 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                     // computer-metricsコマンドでは検知は行わないためカウントのみ行い次のレコードを確認する
                     continue;
                 }
-                if !stored_static.search_flag {
+                // integrityコマンドではフィルタリングで除外したレコードが欠落として報告されないよう、全てのレコードを確認する
+                if !(stored_static.search_flag || stored_static.integrity_flag) {
                     // Computer名がinclude_computerで指定されたものに合致しないまたはexclude_computerで指定されたものに合致した場合はフィルタリングする。
                     if utils::is_filtered_by_computer_name(
                         utils::get_event_value(
@@ -2367,7 +3070,7 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
                 }
                 // EventID側の条件との条件の混同を防ぐため時間でのフィルタリングの条件分岐を分離した
                 let timestamp = record_result.as_ref().unwrap().timestamp;
-                if !time_filter.is_target(&Some(timestamp)) {
+                if !stored_static.integrity_flag && !time_filter.is_target(&Some(timestamp)) {
                     continue;
                 }
 
@@ -2378,14 +3081,21 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             if records_per_detect.is_empty() {
                 break;
             }
+            tl.remove_duplicate_records(&mut records_per_detect, &path.to_string(), stored_static);
+            if records_per_detect.is_empty() {
+                continue;
+            }
 
-            let records_per_detect = self.rt.block_on(App::create_rec_infos(
+            let mut records_per_detect = self.rt.block_on(App::create_rec_infos(
                 records_per_detect,
                 &path,
                 self.rule_keys.to_owned(),
                 stored_static.no_pwsh_field_extraction,
             ));
             tl.start(&records_per_detect, stored_static);
+            let mut script_block_records =
+                self.reassemble_script_blocks(&records_per_detect, &mut tl, stored_static);
+            records_per_detect.append(&mut script_block_records);
             if need_rule {
                 // detect event record by rule file
                 let (detection_tmp, mut log_records) =
@@ -2421,6 +3131,10 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
         (is_splunk_json, is_splunk_api_json): (bool, bool),
         data: &Value,
     ) -> bool {
+        // integrityコマンドではフィルタリングで除外したレコードが欠落として報告されないよう、全てのレコードを確認する
+        if stored_static.integrity_flag {
+            return false;
+        }
         // Computer名がinclude_computerで指定されたものに合致しないまたはexclude_computerで指定されたものに合致した場合はフィルタリングする。
         if utils::is_filtered_by_computer_name(
             utils::get_event_value("Event.System.Computer", data, &stored_static.eventkey_alias),
@@ -2705,8 +3419,12 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             if records_per_detect.is_empty() {
                 break;
             }
+            tl.remove_duplicate_records(&mut records_per_detect, &path.to_string(), stored_static);
+            if records_per_detect.is_empty() {
+                continue;
+            }
 
-            let records_per_detect = self.rt.block_on(App::create_rec_infos(
+            let mut records_per_detect = self.rt.block_on(App::create_rec_infos(
                 records_per_detect,
                 &path,
                 self.rule_keys.to_owned(),
@@ -2715,10 +3433,15 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
 
             // timeline機能の実行
             tl.start(&records_per_detect, stored_static);
+            let mut script_block_records =
+                self.reassemble_script_blocks(&records_per_detect, &mut tl, stored_static);
+            records_per_detect.append(&mut script_block_records);
 
             // 以下のコマンドの際にはルールにかけない
             if !(stored_static.metrics_flag
                 || stored_static.logon_summary_flag
+                || stored_static.lateral_movement_flag
+                || stored_static.integrity_flag
                 || stored_static.log_metrics_flag
                 || stored_static.search_flag)
             {
@@ -2744,6 +3467,43 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
         (detection, record_cnt, tl, recover_records_cnt, detect_infos)
     }
 
+    /// 分割された4104イベントのスクリプトブロックを復元する。--export-scriptblocksが指定されている場合はファイルに保存し、
+    /// --reassemble-scriptblocksが指定されている場合は復元したレコードを検知用に返す
+    fn reassemble_script_blocks(
+        &self,
+        records: &[EvtxRecordInfo],
+        tl: &mut Timeline,
+        stored_static: &StoredStatic,
+    ) -> Vec<EvtxRecordInfo> {
+        let Some(output_option) = stored_static.output_option.as_ref() else {
+            return vec![];
+        };
+        if output_option.export_scriptblocks.is_none() && !output_option.reassemble_scriptblocks {
+            return vec![];
+        }
+        let scripts = tl.script_blocks.process(records);
+        if let Some(dir) = &output_option.export_scriptblocks {
+            for script in &scripts {
+                tl.script_blocks.export(script, dir, true);
+            }
+        }
+        if !output_option.reassemble_scriptblocks {
+            return vec![];
+        }
+        scripts
+            .into_iter()
+            .map(|script| {
+                utils::create_rec_info(
+                    script.record,
+                    script.evtx_filepath,
+                    &self.rule_keys,
+                    &false,
+                    &stored_static.no_pwsh_field_extraction,
+                )
+            })
+            .collect()
+    }
+
     async fn create_rec_infos(
         records_per_detect: Vec<(Value, bool)>,
         path: &dyn Display,
@@ -2996,6 +3756,8 @@ Any hostnames added to the critical_systems.txt file will have all alerts above
             Action::CsvTimeline(_)
             | Action::JsonTimeline(_)
             | Action::LogonSummary(_)
+            | Action::LateralMovement(_)
+            | Action::Integrity(_)
             | Action::EidMetrics(_)
             | Action::PivotKeywordsList(_)
             | Action::SetDefaultProfile(_)
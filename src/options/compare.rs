@@ -0,0 +1,736 @@
+use crate::detections::configs::CompareOption;
+use crate::detections::utils::{get_writable_color, write_color_buffer};
+use crate::options::htmlreport::{HtmlReporter, create_html_file};
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use csv::{QuoteStyle, ReaderBuilder, WriterBuilder};
+use hashbrown::HashMap;
+use nested::Nested;
+use num_format::{Locale, ToFormattedString};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use termcolor::{BufferWriter, Color, ColorChoice};
+
+const HTML_SECTION: &str = "Scan Comparison {#scan_comparison}";
+
+/// タイムラインの1検知分のフィールド名と値
+pub type TimelineRecord = HashMap<String, String>;
+
+/// ルールID、コンピュータ名、キーフィールドの値が同じ検知をまとめたもの
+#[derive(Debug, Clone, Default)]
+pub struct CompareGroup {
+    pub rule_id: String,
+    pub rule_title: String,
+    pub level: String,
+    pub computer: String,
+    pub keys: Vec<String>,
+    pub old_cnt: usize,
+    pub new_cnt: usize,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+impl CompareGroup {
+    pub fn status(&self) -> &'static str {
+        if self.old_cnt == 0 {
+            "New"
+        } else if self.new_cnt == 0 {
+            "Resolved"
+        } else {
+            "Persistent"
+        }
+    }
+
+    fn add(&mut self, record: &TimelineRecord, is_new: bool) {
+        let field = |name: &str| record.get(name).map(|v| v.as_str()).unwrap_or_default();
+        // ルール名やレベルは新しいスキャンの値を優先する
+        if is_new || self.rule_title.is_empty() {
+            self.rule_title = field("RuleTitle").to_string();
+            self.level = field("Level").to_string();
+        }
+        if is_new {
+            self.new_cnt += 1;
+        } else {
+            self.old_cnt += 1;
+        }
+        let time = field("Timestamp");
+        if !time.is_empty() {
+            if self.first_seen.is_empty() || time < self.first_seen.as_str() {
+                self.first_seen = time.to_string();
+            }
+            if time > self.last_seen.as_str() {
+                self.last_seen = time.to_string();
+            }
+        }
+    }
+}
+
+/// コンピュータ名またはルールごとの検知数の変化
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DetectionDelta {
+    pub name: String,
+    pub level: String,
+    pub old_cnt: usize,
+    pub new_cnt: usize,
+    pub new_groups: usize,
+    pub resolved_groups: usize,
+    pub persistent_groups: usize,
+}
+
+impl DetectionDelta {
+    fn add(&mut self, group: &CompareGroup) {
+        self.level = group.level.clone();
+        self.old_cnt += group.old_cnt;
+        self.new_cnt += group.new_cnt;
+        match group.status() {
+            "New" => self.new_groups += 1,
+            "Resolved" => self.resolved_groups += 1,
+            _ => self.persistent_groups += 1,
+        }
+    }
+
+    pub fn change(&self) -> String {
+        match self.new_cnt.cmp(&self.old_cnt) {
+            std::cmp::Ordering::Greater => format!("+{}", self.new_cnt - self.old_cnt),
+            std::cmp::Ordering::Less => format!("-{}", self.old_cnt - self.new_cnt),
+            std::cmp::Ordering::Equal => "0".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    pub old_source: String,
+    pub new_source: String,
+    pub key_fields: Vec<String>,
+    pub groups: Vec<CompareGroup>,
+}
+
+/// キーフィールドの値を返す。タイムラインの列にない場合はDetailsのフィールドとして探す(ex: TgtUser, Details.TgtUser)
+fn key_field_value(record: &TimelineRecord, key: &str) -> Option<String> {
+    if let Some(value) = record.get(key) {
+        return Some(value.clone());
+    }
+    let name = key.strip_prefix("Details.").unwrap_or(key);
+    let details = record.get("Details")?;
+    // json-timelineではオブジェクト、csv-timelineでは"TgtUser: admin ¦ LogonType: 3"の形式になる
+    if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(details) {
+        return map.get(name).map(|v| match v {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        });
+    }
+    details
+        .split(" ¦ ")
+        .find_map(|field| field.strip_prefix(name)?.strip_prefix(": "))
+        .map(|v| v.to_string())
+}
+
+impl Comparison {
+    /// ルールID(ない場合はルール名)、コンピュータ名、キーフィールドの値で2つのスキャンの検知を照合する。
+    /// どちらのタイムラインにも存在しないキーフィールドが指定された場合はエラーを返す
+    pub fn new(
+        (old_source, old_records): (&str, &[TimelineRecord]),
+        (new_source, new_records): (&str, &[TimelineRecord]),
+        key_fields: &[String],
+    ) -> Result<Self, String> {
+        let mut groups: BTreeMap<(String, String, Vec<String>), CompareGroup> = BTreeMap::new();
+        let mut found_keys = vec![false; key_fields.len()];
+        for (records, is_new) in [(old_records, false), (new_records, true)] {
+            for record in records {
+                let field = |name: &str| record.get(name).cloned().unwrap_or_default();
+                let rule_id = match field("RuleID") {
+                    id if id.is_empty() => field("RuleTitle"),
+                    id => id,
+                };
+                let computer = field("Computer");
+                let keys: Vec<String> = key_fields
+                    .iter()
+                    .zip(found_keys.iter_mut())
+                    .map(|(k, found)| {
+                        let value = key_field_value(record, k);
+                        *found |= value.is_some();
+                        value.unwrap_or_default()
+                    })
+                    .collect();
+                groups
+                    .entry((rule_id.clone(), computer.clone(), keys.clone()))
+                    .or_insert_with(|| CompareGroup {
+                        rule_id,
+                        computer,
+                        keys,
+                        ..Default::default()
+                    })
+                    .add(record, is_new);
+            }
+        }
+        let missing_keys: Vec<&str> = key_fields
+            .iter()
+            .zip(found_keys)
+            .filter(|(_, found)| !found)
+            .map(|(k, _)| k.as_str())
+            .collect();
+        if !groups.is_empty() && !missing_keys.is_empty() {
+            return Err(format!(
+                "The key field(s) {} were not found in either timeline. Please specify a timeline column or a field in Details (ex: TgtUser or Details.TgtUser).",
+                missing_keys.join(", ")
+            ));
+        }
+        Ok(Comparison {
+            old_source: old_source.to_string(),
+            new_source: new_source.to_string(),
+            key_fields: key_fields.to_vec(),
+            groups: groups.into_values().collect(),
+        })
+    }
+
+    pub fn status_cnt(&self, status: &str) -> usize {
+        self.groups.iter().filter(|g| g.status() == status).count()
+    }
+
+    pub fn detection_cnt(&self) -> (usize, usize) {
+        self.groups
+            .iter()
+            .fold((0, 0), |(old, new), g| (old + g.old_cnt, new + g.new_cnt))
+    }
+
+    pub fn computer_deltas(&self) -> Vec<DetectionDelta> {
+        self.deltas(|g| g.computer.clone())
+    }
+
+    pub fn rule_deltas(&self) -> Vec<DetectionDelta> {
+        self.deltas(|g| g.rule_title.clone())
+    }
+
+    fn deltas(&self, name: impl Fn(&CompareGroup) -> String) -> Vec<DetectionDelta> {
+        let mut deltas: BTreeMap<String, DetectionDelta> = BTreeMap::new();
+        for group in &self.groups {
+            let name = name(group);
+            deltas
+                .entry(name.clone())
+                .or_insert_with(|| DetectionDelta {
+                    name,
+                    ..Default::default()
+                })
+                .add(group);
+        }
+        deltas.into_values().collect()
+    }
+
+    fn changed_groups(&self) -> impl Iterator<Item = &CompareGroup> {
+        self.groups
+            .iter()
+            .filter(|g| g.status() == "New")
+            .chain(self.groups.iter().filter(|g| g.status() == "Resolved"))
+    }
+}
+
+/// 拡張子がcsv、json、jsonlのファイルはタイムラインとして読み込み、それ以外はスキャン対象とする
+pub fn is_timeline_file(path: &Path) -> bool {
+    path.is_file()
+        && path.extension().is_some_and(|ext| {
+            ["csv", "json", "jsonl"].contains(&ext.to_string_lossy().to_lowercase().as_str())
+        })
+}
+
+/// CSVまたはJSON/JSONL形式のタイムラインを読み込む
+pub fn load_timeline(path: &Path) -> Result<Vec<TimelineRecord>, String> {
+    let mut records: Vec<TimelineRecord> = vec![];
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+    {
+        let mut rdr = ReaderBuilder::new()
+            .flexible(true)
+            .from_path(path)
+            .map_err(|e| format!("Failed to open {}. {e}", path.display()))?;
+        let header = rdr.headers().map_err(|e| e.to_string())?.clone();
+        for record in rdr.records() {
+            let record = record.map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+            records.push(
+                header
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            );
+        }
+    } else {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to open {}. {e}", path.display()))?;
+        // -Lを指定しない場合は複数行のJSONが連続するため、ストリームとして読み込む
+        for value in serde_json::Deserializer::from_str(&content).into_iter::<Value>() {
+            let value = value.map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+            let Value::Object(map) = value else {
+                continue;
+            };
+            records.push(
+                map.into_iter()
+                    .map(|(k, v)| match v {
+                        Value::String(s) => (k, s),
+                        Value::Null => (k, String::default()),
+                        v => (k, v.to_string()),
+                    })
+                    .collect(),
+            );
+        }
+    }
+    if records
+        .first()
+        .is_some_and(|r| !r.contains_key("RuleID") && !r.contains_key("RuleTitle"))
+    {
+        return Err(format!(
+            "{} does not have a RuleID or RuleTitle field. Please use a timeline created by csv-timeline or json-timeline.",
+            path.display()
+        ));
+    }
+    Ok(records)
+}
+
+/// 証拠ファイルのスキャンに使うjson-timelineの引数を作成する
+pub fn scan_args(option: &CompareOption, input: &Path, output: &Path) -> Vec<String> {
+    let input_option = if input.is_dir() { "-d" } else { "-f" };
+    let mut args = vec![
+        "json-timeline".to_string(),
+        input_option.to_string(),
+        input.to_string_lossy().to_string(),
+        "-o".to_string(),
+        output.to_string_lossy().to_string(),
+        "-L".to_string(),
+        "-p".to_string(),
+        option
+            .profile
+            .clone()
+            .unwrap_or_else(|| "standard".to_string()),
+    ];
+    args.extend(["-w", "-C", "-q", "-K", "-N"].map(String::from));
+    args.extend(option.timeline_options.iter().cloned());
+    args
+}
+
+/// タイムラインはそのまま読み込み、evtxファイルやディレクトリはjson-timelineでスキャンしてから読み込む
+pub fn load_scan(
+    exe: &Path,
+    option: &CompareOption,
+    input: &Path,
+    label: &str,
+) -> Result<Vec<TimelineRecord>, String> {
+    if is_timeline_file(input) {
+        return load_timeline(input);
+    }
+    if !input.exists() {
+        return Err(format!("{} does not exist.", input.display()));
+    }
+    let output = std::env::temp_dir().join(format!(
+        "hayabusa-compare-{}-{label}.jsonl",
+        std::process::id()
+    ));
+    println!("Scanning {}...", input.display());
+    let status = Command::new(exe)
+        .args(scan_args(option, input, &output))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| format!("Failed to run {}. {e}", exe.display()))?;
+    if !status.success() || !output.exists() {
+        fs::remove_file(&output).ok();
+        return Err(format!(
+            "Failed to scan {}. Exit code: {}",
+            input.display(),
+            status.code().unwrap_or(-1)
+        ));
+    }
+    let records = load_timeline(&output);
+    fs::remove_file(&output).ok();
+    records
+}
+
+fn group_keys(comparison: &Comparison, group: &CompareGroup) -> Vec<String> {
+    comparison
+        .key_fields
+        .iter()
+        .zip(group.keys.iter())
+        .map(|(k, v)| format!("{k}: {v}"))
+        .collect()
+}
+
+fn group_cnt(group: &CompareGroup) -> usize {
+    group.old_cnt.max(group.new_cnt)
+}
+
+fn new_table(header: Vec<&str>) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+        .set_header(header);
+    table
+}
+
+fn print_delta_table(title: &str, deltas: &[DetectionDelta], with_level: bool) {
+    let mut header = vec![title];
+    if with_level {
+        header.push("Level");
+    }
+    header.extend([
+        "Old Scan",
+        "New Scan",
+        "Change",
+        "New",
+        "Resolved",
+        "Persistent",
+    ]);
+    let mut table = new_table(header);
+    for delta in deltas {
+        let mut row = vec![Cell::new(&delta.name)];
+        if with_level {
+            row.push(Cell::new(&delta.level));
+        }
+        row.extend([
+            Cell::new(delta.old_cnt.to_formatted_string(&Locale::en)),
+            Cell::new(delta.new_cnt.to_formatted_string(&Locale::en)),
+            Cell::new(delta.change()),
+            Cell::new(delta.new_groups.to_formatted_string(&Locale::en)),
+            Cell::new(delta.resolved_groups.to_formatted_string(&Locale::en)),
+            Cell::new(delta.persistent_groups.to_formatted_string(&Locale::en)),
+        ]);
+        table.add_row(row);
+    }
+    println!("{table}");
+}
+
+pub fn output_comparison(
+    comparison: &Comparison,
+    out_path: Option<&PathBuf>,
+    html_path: Option<&PathBuf>,
+    no_color: bool,
+) -> Result<(), Box<dyn Error>> {
+    if comparison.changed_groups().next().is_some() {
+        let mut table = new_table(vec!["Change", "Rule", "Level", "Computer", "Keys", "Count"]);
+        for group in comparison.changed_groups() {
+            let color = if no_color {
+                comfy_table::Color::Reset
+            } else if group.status() == "New" {
+                comfy_table::Color::Red
+            } else {
+                comfy_table::Color::Green
+            };
+            table.add_row(vec![
+                Cell::new(group.status()).fg(color),
+                Cell::new(&group.rule_title),
+                Cell::new(&group.level),
+                Cell::new(&group.computer),
+                Cell::new(group_keys(comparison, group).join("\n")),
+                Cell::new(group_cnt(group).to_formatted_string(&Locale::en)),
+            ]);
+        }
+        println!("{table}");
+        println!();
+    }
+    if !comparison.groups.is_empty() {
+        print_delta_table("Computer", &comparison.computer_deltas(), false);
+        println!();
+        print_delta_table("Rule", &comparison.rule_deltas(), true);
+        println!();
+    }
+    let (old_cnt, new_cnt) = comparison.detection_cnt();
+    write_color_buffer(
+        &BufferWriter::stdout(ColorChoice::Always),
+        get_writable_color(Some(Color::Rgb(0, 255, 0)), no_color),
+        &format!(
+            "New: {} ¦ Resolved: {} ¦ Persistent: {} ¦ Detections: {} -> {}",
+            comparison
+                .status_cnt("New")
+                .to_formatted_string(&Locale::en),
+            comparison
+                .status_cnt("Resolved")
+                .to_formatted_string(&Locale::en),
+            comparison
+                .status_cnt("Persistent")
+                .to_formatted_string(&Locale::en),
+            old_cnt.to_formatted_string(&Locale::en),
+            new_cnt.to_formatted_string(&Locale::en),
+        ),
+        true,
+    )
+    .ok();
+    println!();
+    if let Some(out_path) = out_path {
+        output_comparison_csv(comparison, out_path)?;
+    }
+    if let Some(html_path) = html_path {
+        let mut reporter = HtmlReporter::new();
+        reporter.section_order = Nested::from_iter([HTML_SECTION]);
+        reporter.md_datas =
+            HashMap::from([(HTML_SECTION.to_string(), create_comparison_md(comparison))]);
+        create_html_file(
+            reporter.create_html(),
+            &html_path.to_string_lossy(),
+            no_color,
+        );
+        println!();
+    }
+    Ok(())
+}
+
+fn output_comparison_csv(comparison: &Comparison, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut wtr = WriterBuilder::new()
+        .quote_style(QuoteStyle::NonNumeric)
+        .from_path(path)?;
+    let mut header = vec!["Status", "RuleTitle", "RuleID", "Level", "Computer"];
+    header.extend(comparison.key_fields.iter().map(|k| k.as_str()));
+    header.extend(["OldCount", "NewCount", "FirstSeen", "LastSeen"]);
+    wtr.write_record(&header)?;
+    for group in comparison.changed_groups().chain(
+        comparison
+            .groups
+            .iter()
+            .filter(|g| g.status() == "Persistent"),
+    ) {
+        let mut record = vec![
+            group.status().to_string(),
+            group.rule_title.clone(),
+            group.rule_id.clone(),
+            group.level.clone(),
+            group.computer.clone(),
+        ];
+        record.extend(group.keys.iter().cloned());
+        record.extend([
+            group.old_cnt.to_string(),
+            group.new_cnt.to_string(),
+            group.first_seen.clone(),
+            group.last_seen.clone(),
+        ]);
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// HTMLレポート用のMarkdownを作成する。表の区切り文字と改行はエスケープする
+fn create_comparison_md(comparison: &Comparison) -> Nested<String> {
+    let md = |v: &str| v.replace('|', "\\|").replace(['\r', '\n'], " ");
+    let (old_cnt, new_cnt) = comparison.detection_cnt();
+    let mut data = Nested::<String>::new();
+    data.push(format!("- Old scan: {}", md(&comparison.old_source)));
+    data.push(format!("- New scan: {}", md(&comparison.new_source)));
+    let mut match_fields = vec!["RuleID".to_string(), "Computer".to_string()];
+    match_fields.extend(comparison.key_fields.iter().cloned());
+    data.push(format!("- Matched on: {}", md(&match_fields.join(", "))));
+    data.push(format!(
+        "- Detections: {} -> {}",
+        old_cnt.to_formatted_string(&Locale::en),
+        new_cnt.to_formatted_string(&Locale::en)
+    ));
+    for status in ["New", "Resolved", "Persistent"] {
+        data.push(format!(
+            "- {status}: {}",
+            comparison
+                .status_cnt(status)
+                .to_formatted_string(&Locale::en)
+        ));
+    }
+    for status in ["New", "Resolved"] {
+        data.push(format!("\n### {status} Detections\n"));
+        data.push(
+            "|Rule|Level|Computer|Keys|Count|First Seen|Last Seen|\n|---|---|---|---|---:|---|---|",
+        );
+        for group in comparison.groups.iter().filter(|g| g.status() == status) {
+            data.push(format!(
+                "|{}|{}|{}|{}|{}|{}|{}|",
+                md(&group.rule_title),
+                md(&group.level),
+                md(&group.computer),
+                md(&group_keys(comparison, group).join(", ")),
+                group_cnt(group).to_formatted_string(&Locale::en),
+                md(&group.first_seen),
+                md(&group.last_seen),
+            ));
+        }
+    }
+    for (title, deltas) in [
+        ("Computer", comparison.computer_deltas()),
+        ("Rule", comparison.rule_deltas()),
+    ] {
+        data.push(format!("\n### {title} Deltas\n"));
+        data.push(format!(
+            "|{title}|Old Scan|New Scan|Change|New|Resolved|Persistent|\n|---|---:|---:|---:|---:|---:|---:|"
+        ));
+        for delta in deltas {
+            data.push(format!(
+                "|{}|{}|{}|{}|{}|{}|{}|",
+                md(&delta.name),
+                delta.old_cnt.to_formatted_string(&Locale::en),
+                delta.new_cnt.to_formatted_string(&Locale::en),
+                delta.change(),
+                delta.new_groups,
+                delta.resolved_groups,
+                delta.persistent_groups,
+            ));
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::compare::{
+        Comparison, DetectionDelta, TimelineRecord, create_comparison_md, load_timeline,
+    };
+    use std::fs;
+    use std::path::Path;
+
+    fn record(rule: &str, computer: &str, user: &str, time: &str) -> TimelineRecord {
+        [
+            ("Timestamp", time),
+            ("RuleTitle", rule),
+            ("RuleID", &format!("id-{rule}")),
+            ("Level", "high"),
+            ("Computer", computer),
+            ("TgtUser", user),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let old = vec![
+            record("Rule A", "PC1", "admin", "2024-01-01 00:00:00"),
+            record("Rule A", "PC1", "admin", "2024-01-01 00:00:01"),
+            record("Rule A", "PC1", "guest", "2024-01-01 00:00:02"),
+            record("Rule B", "PC2", "admin", "2024-01-01 00:00:03"),
+        ];
+        let new = vec![
+            record("Rule A", "PC1", "admin", "2024-02-01 00:00:00"),
+            record("Rule C", "PC2", "admin", "2024-02-01 00:00:01"),
+        ];
+        let comparison = Comparison::new(
+            ("old.csv", &old),
+            ("new.jsonl", &new),
+            &["TgtUser".to_string()],
+        )
+        .unwrap();
+        assert_eq!(comparison.status_cnt("New"), 1);
+        assert_eq!(comparison.status_cnt("Resolved"), 2);
+        assert_eq!(comparison.status_cnt("Persistent"), 1);
+        assert_eq!(comparison.detection_cnt(), (4, 2));
+        let persistent = &comparison.groups[0];
+        assert_eq!(persistent.status(), "Persistent");
+        assert_eq!((persistent.old_cnt, persistent.new_cnt), (2, 1));
+        assert_eq!(persistent.first_seen, "2024-01-01 00:00:00");
+        assert_eq!(persistent.last_seen, "2024-02-01 00:00:00");
+        assert_eq!(
+            comparison.computer_deltas(),
+            vec![
+                DetectionDelta {
+                    name: "PC1".to_string(),
+                    level: "high".to_string(),
+                    old_cnt: 3,
+                    new_cnt: 1,
+                    new_groups: 0,
+                    resolved_groups: 1,
+                    persistent_groups: 1,
+                },
+                DetectionDelta {
+                    name: "PC2".to_string(),
+                    level: "high".to_string(),
+                    old_cnt: 1,
+                    new_cnt: 1,
+                    new_groups: 1,
+                    resolved_groups: 1,
+                    persistent_groups: 0,
+                },
+            ]
+        );
+        assert_eq!(comparison.computer_deltas()[0].change(), "-2");
+        assert_eq!(comparison.rule_deltas()[2].change(), "+1");
+
+        // キーフィールドを指定しない場合はルールとコンピュータ名のみで照合する
+        let comparison = Comparison::new(("old.csv", &old), ("new.jsonl", &new), &[]).unwrap();
+        assert_eq!(comparison.status_cnt("Resolved"), 1);
+        assert_eq!(comparison.status_cnt("Persistent"), 1);
+        let md = create_comparison_md(&comparison);
+        assert!(
+            md.iter()
+                .any(|l| l == "|Rule C|high|PC2||1|2024-02-01 00:00:01|2024-02-01 00:00:01|")
+        );
+    }
+
+    #[test]
+    fn test_load_timeline() {
+        let dir = Path::new("./test_compare_timeline");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("timeline.csv"),
+            "\"Timestamp\",\"RuleTitle\",\"Computer\",\"RuleID\"\n\"2024-01-01\",\"Rule A\",\"PC1\",\"id-a\"\n",
+        )
+        .unwrap();
+        // -Lを指定しないjson-timelineの出力は複数行のJSONが連続する
+        fs::write(
+            dir.join("timeline.json"),
+            "{\n    \"RuleTitle\": \"Rule A\",\n    \"Computer\": \"PC1\",\n    \"Details\": {\"User\": \"admin\"}\n}\n{\n    \"RuleTitle\": \"Rule B\",\n    \"Computer\": \"PC2\",\n    \"Details\": \"-\"\n}\n",
+        )
+        .unwrap();
+        fs::write(dir.join("invalid.jsonl"), "{\"Computer\": \"PC1\"}\n").unwrap();
+        let csv = load_timeline(&dir.join("timeline.csv"));
+        let json = load_timeline(&dir.join("timeline.json"));
+        let invalid = load_timeline(&dir.join("invalid.jsonl"));
+        fs::remove_dir_all(dir).ok();
+
+        let csv = csv.unwrap();
+        assert_eq!(csv.len(), 1);
+        assert_eq!(csv[0]["RuleID"], "id-a");
+        let json = json.unwrap();
+        assert_eq!(json.len(), 2);
+        assert_eq!(json[0]["Details"], "{\"User\":\"admin\"}");
+        assert_eq!(json[1]["Computer"], "PC2");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_compare_details_key_fields() {
+        let dir = Path::new("./test_compare_details");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("old.csv"),
+            "\"Timestamp\",\"Computer\",\"Channel\",\"EventID\",\"Level\",\"RecordID\",\"RuleTitle\",\"Details\",\"ExtraFieldInfo\",\"RuleID\"\n\
+             \"2024-01-01 00:00:00.000 +09:00\",\"PC1\",\"Sec\",4625,\"med\",1,\"Logon Failure\",\"Type: 3 - NETWORK ¦ TgtUser: admin ¦ SrcComp: WS1\",\"-\",\"id-a\"\n\
+             \"2024-01-01 00:00:01.000 +09:00\",\"PC1\",\"Sec\",4625,\"med\",2,\"Logon Failure\",\"Type: 3 - NETWORK ¦ TgtUser: guest ¦ SrcComp: WS1\",\"-\",\"id-a\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("new.jsonl"),
+            "{\"Timestamp\":\"2024-02-01 00:00:00.000 +09:00\",\"Computer\":\"PC1\",\"Channel\":\"Sec\",\"EventID\":4625,\"Level\":\"med\",\"RecordID\":3,\"RuleTitle\":\"Logon Failure\",\"Details\":{\"Type\":\"3 - NETWORK\",\"TgtUser\":\"admin\",\"SrcComp\":\"WS1\"},\"ExtraFieldInfo\":{},\"RuleID\":\"id-a\"}\n",
+        )
+        .unwrap();
+        let old = load_timeline(&dir.join("old.csv"));
+        let new = load_timeline(&dir.join("new.jsonl"));
+        fs::remove_dir_all(dir).ok();
+        let (old, new) = (old.unwrap(), new.unwrap());
+
+        for key in ["TgtUser", "Details.TgtUser"] {
+            let comparison =
+                Comparison::new(("old.csv", &old), ("new.jsonl", &new), &[key.to_string()])
+                    .unwrap();
+            assert_eq!(comparison.status_cnt("Persistent"), 1);
+            assert_eq!(comparison.status_cnt("Resolved"), 1);
+            assert_eq!(comparison.groups[0].keys, vec!["admin".to_string()]);
+            assert_eq!(comparison.groups[1].keys, vec!["guest".to_string()]);
+        }
+        let err = Comparison::new(
+            ("old.csv", &old),
+            ("new.jsonl", &new),
+            &["TgtUsr".to_string()],
+        )
+        .unwrap_err();
+        assert!(err.contains("TgtUsr"));
+    }
+}
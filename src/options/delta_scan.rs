@@ -0,0 +1,541 @@
+use crate::detections::configs::TimeFormatOptions;
+use crate::detections::utils::parse_formatted_time;
+use crate::options::rules_diff::rule_hash;
+use chrono::{DateTime, FixedOffset};
+use compact_str::CompactString;
+use csv::{QuoteStyle, ReaderBuilder, StringRecord, WriterBuilder};
+use hashbrown::HashSet;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use yaml_rust2::Yaml;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ManifestRule {
+    pub title: String,
+    pub hash: String,
+}
+
+/// スキャン時に読み込んだルールのIDとハッシュの一覧
+#[derive(Debug, Clone, Default)]
+pub struct RuleManifest {
+    pub rules: BTreeMap<String, ManifestRule>,
+}
+
+impl RuleManifest {
+    pub fn new(rule_docs: &[(String, Yaml)]) -> Self {
+        let rules = rule_docs
+            .iter()
+            .filter_map(|(_, yaml)| {
+                let id = yaml["id"].as_str()?;
+                Some((
+                    id.to_string(),
+                    ManifestRule {
+                        title: yaml["title"].as_str().unwrap_or_default().to_string(),
+                        hash: rule_hash(yaml),
+                    },
+                ))
+            })
+            .collect();
+        RuleManifest { rules }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read the rule manifest {}. {e}", path.display()))?;
+        let json: Value = serde_json::from_str(&content)
+            .map_err(|e| format!("Failed to parse the rule manifest {}. {e}", path.display()))?;
+        let Some(rules) = json["rules"].as_object() else {
+            return Err(format!(
+                "The rule manifest {} does not have a rules object.",
+                path.display()
+            ));
+        };
+        let rules = rules
+            .iter()
+            .map(|(id, rule)| {
+                (
+                    id.to_string(),
+                    ManifestRule {
+                        title: rule["title"].as_str().unwrap_or_default().to_string(),
+                        hash: rule["hash"].as_str().unwrap_or_default().to_string(),
+                    },
+                )
+            })
+            .collect();
+        Ok(RuleManifest { rules })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let rules: serde_json::Map<String, Value> = self
+            .rules
+            .iter()
+            .map(|(id, rule)| {
+                (
+                    id.to_string(),
+                    json!({"title": rule.title, "hash": rule.hash}),
+                )
+            })
+            .collect();
+        let manifest = json!({
+            "version": 1,
+            "rule_count": self.rules.len(),
+            "rules": rules,
+        });
+        fs::write(path, serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+}
+
+/// --delta-fromで指定されたマニフェストと、差分スキャンで実行したルールの情報
+#[derive(Debug, Clone, Default)]
+pub struct DeltaScan {
+    pub base: RuleManifest,
+    pub new_cnt: usize,
+    pub changed_cnt: usize,
+    pub skipped_cnt: usize,
+    /// 今回実行したルールのIDとタイトル。以前のタイムラインから同じルールの検知結果を置き換えるために使う
+    pub replayed_rules: Vec<(String, String)>,
+    /// マニフェストにあり今回のルールセットに存在しないルールのIDとタイトル。以前のタイムラインから検知結果を取り除くために使う
+    pub removed_rules: Vec<(String, String)>,
+}
+
+impl DeltaScan {
+    pub fn new(base: RuleManifest) -> Self {
+        DeltaScan {
+            base,
+            ..Default::default()
+        }
+    }
+
+    /// マニフェストと比較して追加・変更されたルールのみを残す。
+    /// 相関ルールは参照しているルールが変更された場合も実行し、実行する相関ルールが参照するルールも合わせて読み込む。
+    /// 削除されたルールは、レベルやステータスなどで除外される前の全てのルールのID(defined_rule_ids)と比較する
+    pub fn filter_rules(
+        &mut self,
+        rule_docs: &mut Vec<(String, Yaml)>,
+        defined_rule_ids: &HashSet<CompactString>,
+    ) {
+        let is_changed = |yaml: &Yaml| match yaml["id"].as_str() {
+            Some(id) => self
+                .base
+                .rules
+                .get(id)
+                .is_none_or(|rule| rule.hash != rule_hash(yaml)),
+            None => true,
+        };
+        let mut changed: Vec<bool> = rule_docs.iter().map(|(_, y)| is_changed(y)).collect();
+        self.removed_rules = self
+            .base
+            .rules
+            .iter()
+            .filter(|(id, _)| !defined_rule_ids.contains(id.as_str()))
+            .map(|(id, rule)| (id.clone(), rule.title.clone()))
+            .collect();
+        self.new_cnt = rule_docs
+            .iter()
+            .zip(changed.iter())
+            .filter(|((_, y), c)| {
+                **c && y["id"]
+                    .as_str()
+                    .is_none_or(|id| !self.base.rules.contains_key(id))
+            })
+            .count();
+        self.changed_cnt = changed.iter().filter(|c| **c).count() - self.new_cnt;
+
+        let references: Vec<Vec<usize>> = rule_docs
+            .iter()
+            .map(|(_, yaml)| referenced_rule_indexes(yaml, rule_docs))
+            .collect();
+        // 変更されたルールを参照している相関ルールを変更ありとする
+        loop {
+            let mut updated = false;
+            for (i, refs) in references.iter().enumerate() {
+                if !changed[i] && refs.iter().any(|r| changed[*r]) {
+                    changed[i] = true;
+                    updated = true;
+                }
+            }
+            if !updated {
+                break;
+            }
+        }
+        // 実行する相関ルールが参照しているルールは変更がなくても読み込む
+        let mut replay = changed;
+        loop {
+            let mut updated = false;
+            for (i, refs) in references.iter().enumerate() {
+                if replay[i] {
+                    for r in refs {
+                        if !replay[*r] {
+                            replay[*r] = true;
+                            updated = true;
+                        }
+                    }
+                }
+            }
+            if !updated {
+                break;
+            }
+        }
+
+        let total = rule_docs.len();
+        let mut replay_iter = replay.iter();
+        rule_docs.retain(|_| *replay_iter.next().unwrap());
+        self.skipped_cnt = total - rule_docs.len();
+        self.replayed_rules = rule_docs
+            .iter()
+            .filter_map(|(_, yaml)| {
+                let id = yaml["id"].as_str()?;
+                let title = match self.base.rules.get(id) {
+                    Some(rule) => rule.title.clone(),
+                    None => yaml["title"].as_str().unwrap_or_default().to_string(),
+                };
+                Some((id.to_string(), title))
+            })
+            .collect();
+    }
+
+    /// 以前のタイムラインから取り除く検知結果(再実行したルールと削除されたルール)かを判定する
+    fn is_replaced(&self, rule_id: Option<&str>, rule_title: Option<&str>) -> bool {
+        let mut rules = self.replayed_rules.iter().chain(self.removed_rules.iter());
+        match (rule_id, rule_title) {
+            (Some(id), _) => rules.any(|(i, _)| i == id),
+            (None, Some(title)) => rules.any(|(_, t)| t == title),
+            _ => false,
+        }
+    }
+}
+
+/// 相関ルールが参照しているルール(id/title/nameで指定)のインデックスを返す
+fn referenced_rule_indexes(yaml: &Yaml, rule_docs: &[(String, Yaml)]) -> Vec<usize> {
+    let Some(refs) = yaml["correlation"]["rules"].as_vec() else {
+        return vec![];
+    };
+    let refs: Vec<&str> = refs.iter().filter_map(|r| r.as_str()).collect();
+    rule_docs
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, other))| {
+            ["id", "title", "name"]
+                .iter()
+                .any(|key| other[*key].as_str().is_some_and(|v| refs.contains(&v)))
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// 差分スキャンの結果を書き出す一時ファイルのパス
+pub fn delta_output_path(timeline: &Path) -> PathBuf {
+    let mut path = timeline.as_os_str().to_owned();
+    path.push(".delta");
+    PathBuf::from(path)
+}
+
+/// 以前のタイムラインから差分スキャンで再実行したルールと削除されたルールの検知結果を取り除き、差分スキャンの結果を追加する。
+/// sort_timeが指定された場合はマージ後にTimestampで並べ替える
+pub fn merge_timeline(
+    timeline: &Path,
+    delta: &Path,
+    is_json: bool,
+    delta_scan: &DeltaScan,
+    sort_time: Option<&TimeFormatOptions>,
+) -> Result<(), Box<dyn Error>> {
+    let mut merged_path = timeline.as_os_str().to_owned();
+    merged_path.push(".merged");
+    let merged_path = PathBuf::from(merged_path);
+    let ret = if is_json {
+        merge_json_timeline(timeline, delta, &merged_path, delta_scan, sort_time)
+    } else {
+        merge_csv_timeline(timeline, delta, &merged_path, delta_scan, sort_time)
+    };
+    match ret {
+        Ok(_) => {
+            fs::rename(&merged_path, timeline)?;
+            fs::remove_file(delta)?;
+            Ok(())
+        }
+        Err(e) => {
+            fs::remove_file(&merged_path).ok();
+            Err(e)
+        }
+    }
+}
+
+fn merge_csv_timeline(
+    timeline: &Path,
+    delta: &Path,
+    merged: &Path,
+    delta_scan: &DeltaScan,
+    sort_time: Option<&TimeFormatOptions>,
+) -> Result<(), Box<dyn Error>> {
+    let mut old_rdr = ReaderBuilder::new().flexible(true).from_path(timeline)?;
+    let mut delta_rdr = ReaderBuilder::new().flexible(true).from_path(delta)?;
+    let header = old_rdr.headers()?.clone();
+    let delta_header = delta_rdr.headers()?.clone();
+    if !delta_header.is_empty() && header != delta_header {
+        return Err(
+            "The columns of the timeline are different. Please use the same output profile as the previous scan."
+                .into(),
+        );
+    }
+    let column = |name: &str| header.iter().position(|h| h == name);
+    let (id_col, title_col, time_col) =
+        (column("RuleID"), column("RuleTitle"), column("Timestamp"));
+    let is_replaced = |record: &StringRecord| {
+        delta_scan.is_replaced(
+            id_col.and_then(|i| record.get(i)),
+            title_col.and_then(|i| record.get(i)),
+        )
+    };
+
+    let mut wtr = WriterBuilder::new()
+        .quote_style(QuoteStyle::NonNumeric)
+        .from_path(merged)?;
+    wtr.write_record(&header)?;
+    let mut sorted_records: Vec<(Option<DateTime<FixedOffset>>, StringRecord)> = vec![];
+    let mut add_record = |record: StringRecord| -> Result<(), Box<dyn Error>> {
+        if let Some(time_args) = sort_time {
+            let time = time_col
+                .and_then(|i| record.get(i))
+                .and_then(|t| parse_formatted_time(t, time_args));
+            sorted_records.push((time, record));
+        } else {
+            wtr.write_record(&record)?;
+        }
+        Ok(())
+    };
+    for record in old_rdr.records() {
+        let record = record?;
+        if !is_replaced(&record) {
+            add_record(record)?;
+        }
+    }
+    for record in delta_rdr.records() {
+        add_record(record?)?;
+    }
+    sorted_records.sort_by_key(|r| r.0);
+    for (_, record) in sorted_records {
+        wtr.write_record(&record)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn merge_json_timeline(
+    timeline: &Path,
+    delta: &Path,
+    merged: &Path,
+    delta_scan: &DeltaScan,
+    sort_time: Option<&TimeFormatOptions>,
+) -> Result<(), Box<dyn Error>> {
+    // レコードは改行で区切られ、JSONLの場合のみ末尾に改行が付くため元のタイムラインに合わせる
+    let ends_with_newline = {
+        let mut file = File::open(timeline)?;
+        let mut last_byte = [0u8];
+        file.seek(SeekFrom::End(-1)).is_ok()
+            && file.read_exact(&mut last_byte).is_ok()
+            && last_byte[0] == b'\n'
+    };
+    let mut wtr = BufWriter::new(File::create(merged)?);
+    let mut is_first_record = true;
+    let mut write_record = |record: &str| -> io::Result<()> {
+        if !is_first_record {
+            wtr.write_all(b"\n")?;
+        }
+        is_first_record = false;
+        wtr.write_all(record.as_bytes())
+    };
+    let mut sorted_records: Vec<(Option<DateTime<FixedOffset>>, String)> = vec![];
+    for (path, is_delta) in [(timeline, false), (delta, true)] {
+        // JSONLは1行に1レコード、JSONは"{"で始まり"}"で終わる複数行で1レコードとなる
+        let mut record = String::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if record.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            if !record.is_empty() {
+                record.push('\n');
+            }
+            record.push_str(&line);
+            if !(line.starts_with('}') || line.starts_with('{') && line.ends_with('}')) {
+                continue;
+            }
+            let json: Value = serde_json::from_str(&record)?;
+            if is_delta
+                || !delta_scan.is_replaced(json["RuleID"].as_str(), json["RuleTitle"].as_str())
+            {
+                if let Some(time_args) = sort_time {
+                    let time = json["Timestamp"]
+                        .as_str()
+                        .and_then(|t| parse_formatted_time(t, time_args));
+                    sorted_records.push((time, record.clone()));
+                } else {
+                    write_record(&record)?;
+                }
+            }
+            record.clear();
+        }
+    }
+    sorted_records.sort_by_key(|r| r.0);
+    for (_, record) in sorted_records {
+        write_record(&record)?;
+    }
+    if ends_with_newline {
+        wtr.write_all(b"\n")?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust2::YamlLoader;
+
+    fn rule_docs(rules: &[&str]) -> Vec<(String, Yaml)> {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                (
+                    format!("{i}.yml"),
+                    YamlLoader::load_from_str(r).unwrap().remove(0),
+                )
+            })
+            .collect()
+    }
+
+    fn defined_ids(ids: &[&str]) -> HashSet<CompactString> {
+        ids.iter().map(|id| CompactString::from(*id)).collect()
+    }
+
+    #[test]
+    fn test_delta_scan_filter_rules() {
+        let base = RuleManifest::new(&rule_docs(&[
+            "id: a\ntitle: A\nlevel: low\ndate: 2024/01/01",
+            "id: b\ntitle: B\nlevel: low",
+            "id: c\ntitle: C\nlevel: low",
+            "id: d\ntitle: D\ncorrelation:\n  rules:\n    - c",
+            "id: e\ntitle: E\nlevel: low",
+        ]));
+        // a: 日付とキーの順番のみ変更、b: レベルを変更、c: 変更なし(dが参照)、d: 変更なし、e: 変更なし、f: 追加(eを参照)
+        let mut current = rule_docs(&[
+            "title: A\nid: a\nlevel: low\ndate: 2025/01/01",
+            "id: b\ntitle: B\nlevel: high",
+            "id: c\ntitle: C\nlevel: low",
+            "id: d\ntitle: D\ncorrelation:\n  rules:\n    - c",
+            "id: e\ntitle: E\nlevel: low",
+            "id: f\ntitle: F\ncorrelation:\n  rules:\n    - E",
+        ]);
+        let mut delta = DeltaScan::new(base);
+        delta.filter_rules(&mut current, &defined_ids(&["a", "b", "c", "d", "e", "f"]));
+        let ids: Vec<&str> = current
+            .iter()
+            .map(|(_, y)| y["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["b", "e", "f"]);
+        assert_eq!(delta.new_cnt, 1);
+        assert_eq!(delta.changed_cnt, 1);
+        assert_eq!(delta.skipped_cnt, 3);
+
+        // 参照先のルールが変更された場合は相関ルールも実行する
+        let base = RuleManifest::new(&current);
+        let mut current = rule_docs(&[
+            "id: e\ntitle: E\nlevel: medium",
+            "id: f\ntitle: F\ncorrelation:\n  rules:\n    - E",
+        ]);
+        let mut delta = DeltaScan::new(base.clone());
+        delta.filter_rules(&mut current.clone(), &defined_ids(&["e", "f"]));
+        // ルールセットに存在しないルールは削除されたルールとする
+        assert_eq!(
+            delta.removed_rules,
+            vec![("b".to_string(), "B".to_string())]
+        );
+        let mut delta = DeltaScan::new(base);
+        delta.filter_rules(&mut current, &defined_ids(&["b", "e", "f"]));
+        assert_eq!(current.len(), 2);
+        // --min-levelなどで読み込まれなかっただけのルールは削除されたルールとしない
+        assert!(delta.removed_rules.is_empty());
+    }
+
+    #[test]
+    fn test_rule_manifest_save_and_load() {
+        let path = Path::new("./test_rule_manifest.json");
+        let manifest = RuleManifest::new(&rule_docs(&["id: a\ntitle: A", "title: no id"]));
+        manifest.save(path).unwrap();
+        let loaded = RuleManifest::load(path).unwrap();
+        fs::remove_file(path).ok();
+        assert_eq!(loaded.rules.len(), 1);
+        assert_eq!(loaded.rules["a"], manifest.rules["a"]);
+        assert!(RuleManifest::load(Path::new("./not_exist_manifest.json")).is_err());
+    }
+
+    #[test]
+    fn test_merge_csv_timeline() {
+        let timeline = Path::new("./test_merge_timeline.csv");
+        let delta = delta_output_path(timeline);
+        fs::write(
+            timeline,
+            "\"Timestamp\",\"RuleTitle\",\"RuleID\"\n\"2024-01-01 00:00:02.000 +00:00\",\"A\",\"a\"\n\"2024-01-01 00:00:03.000 +00:00\",\"B\",\"b\"\n\"2024-01-01 00:00:05.000 +00:00\",\"D\",\"d\"\n",
+        )
+        .unwrap();
+        fs::write(
+            &delta,
+            "\"Timestamp\",\"RuleTitle\",\"RuleID\"\n\"2024-01-01 00:00:01.000 +00:00\",\"B2\",\"b\"\n\"2024-01-01 00:00:04.000 +00:00\",\"C\",\"c\"\n",
+        )
+        .unwrap();
+        let delta_scan = DeltaScan {
+            replayed_rules: vec![("b".into(), "B".into()), ("c".into(), "C".into())],
+            removed_rules: vec![("d".into(), "D".into())],
+            ..Default::default()
+        };
+        let ret = merge_timeline(
+            timeline,
+            &delta,
+            false,
+            &delta_scan,
+            Some(&TimeFormatOptions::default()),
+        );
+        let merged = fs::read_to_string(timeline).unwrap();
+        fs::remove_file(timeline).ok();
+        assert!(ret.is_ok());
+        assert!(!delta.exists());
+        assert_eq!(
+            merged,
+            "\"Timestamp\",\"RuleTitle\",\"RuleID\"\n\"2024-01-01 00:00:01.000 +00:00\",\"B2\",\"b\"\n\"2024-01-01 00:00:02.000 +00:00\",\"A\",\"a\"\n\"2024-01-01 00:00:04.000 +00:00\",\"C\",\"c\"\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_json_timeline() {
+        let timeline = Path::new("./test_merge_timeline.json");
+        let delta = delta_output_path(timeline);
+        fs::write(
+            timeline,
+            "{\n    \"Timestamp\": \"2024-01-01 00:00:02.000 +00:00\",\n    \"RuleTitle\": \"A\"\n}\n{\n    \"Timestamp\": \"2024-01-01 00:00:03.000 +00:00\",\n    \"RuleTitle\": \"B\"\n}",
+        )
+        .unwrap();
+        fs::write(
+            &delta,
+            "{ \"Timestamp\": \"2024-01-01 00:00:01.000 +00:00\", \"RuleTitle\": \"B\" }\n",
+        )
+        .unwrap();
+        let delta_scan = DeltaScan {
+            replayed_rules: vec![("b".into(), "B".into())],
+            ..Default::default()
+        };
+        let ret = merge_timeline(timeline, &delta, true, &delta_scan, None);
+        let merged = fs::read_to_string(timeline).unwrap();
+        fs::remove_file(timeline).ok();
+        assert!(ret.is_ok());
+        assert_eq!(
+            merged,
+            "{\n    \"Timestamp\": \"2024-01-01 00:00:02.000 +00:00\",\n    \"RuleTitle\": \"A\"\n}\n{ \"Timestamp\": \"2024-01-01 00:00:01.000 +00:00\", \"RuleTitle\": \"B\" }"
+        );
+    }
+}
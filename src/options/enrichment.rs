@@ -0,0 +1,382 @@
+use hashbrown::HashMap;
+use serde_json::{Value, json};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::detections::message::AlertMessage;
+use crate::detections::utils::value_to_string;
+
+/// ルックアップテーブルの1行分。列名と値の組み合わせ
+type LookupRow = HashMap<String, String>;
+
+/// --enrichmentで指定された、検知結果に資産情報やユーザー情報を追加するルックアップの設定
+#[derive(Debug, Clone, Default)]
+pub struct Enrichment {
+    lookups: Vec<Lookup>,
+}
+
+#[derive(Debug, Clone)]
+struct Lookup {
+    name: String,
+    match_field: String,
+    source: LookupSource,
+}
+
+#[derive(Debug, Clone)]
+enum LookupSource {
+    Table(HashMap<String, LookupRow>),
+    Command(Arc<Mutex<CommandLookup>>),
+}
+
+/// 標準入出力で1行ずつJSONをやり取りする外部コマンド。同じキーの結果はキャッシュする
+#[derive(Debug)]
+struct CommandLookup {
+    command: String,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    cache: HashMap<String, Option<LookupRow>>,
+    failed: bool,
+}
+
+impl Enrichment {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read the enrichment file {}. {e}", path.display()))?;
+        // テーブルとコマンドのパスは設定ファイルのディレクトリからの相対パスとして扱う
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        Enrichment::parse(&content, base_dir).map_err(|e| format!("{} {e}", path.display()))
+    }
+
+    pub fn parse(content: &str, base_dir: &Path) -> Result<Self, String> {
+        let docs = YamlLoader::load_from_str(content).map_err(|e| format!("YAML error. {e}"))?;
+        let Some(doc) = docs.first() else {
+            return Ok(Enrichment::default());
+        };
+        let Some(entries) = doc.as_hash() else {
+            return Err("The enrichment file must be a map of lookup names.".to_string());
+        };
+        let lookups = entries
+            .iter()
+            .map(|(name, entry)| {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| "The lookup names must be strings.".to_string())?;
+                parse_lookup(name, entry, base_dir).map_err(|e| format!("Lookup {name}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Enrichment { lookups })
+    }
+
+    /// ルックアップのキーとして使うフィールド式を返す
+    pub fn match_field(&self, name: &str) -> Option<&str> {
+        self.lookups
+            .iter()
+            .find(|l| l.name == name)
+            .map(|l| l.match_field.as_str())
+    }
+
+    /// ルックアップでキーに一致した行の指定された列の値を返す。キーは大文字小文字を区別しない
+    pub fn get(&self, name: &str, key: &str, column: &str) -> Option<String> {
+        let lookup = self.lookups.iter().find(|l| l.name == name)?;
+        let key = key.trim().to_lowercase();
+        if key.is_empty() {
+            return None;
+        }
+        match &lookup.source {
+            LookupSource::Table(rows) => rows.get(&key)?.get(column).cloned(),
+            LookupSource::Command(command) => command
+                .lock()
+                .unwrap()
+                .lookup(name, &key)?
+                .get(column)
+                .cloned(),
+        }
+    }
+}
+
+impl CommandLookup {
+    fn spawn(command: &[String], base_dir: &Path) -> Result<Self, String> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| "The command is empty.".to_string())?;
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(base_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start the command {program}. {e}"))?;
+        Ok(CommandLookup {
+            command: command.join(" "),
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            cache: HashMap::new(),
+            failed: false,
+        })
+    }
+
+    /// {"lookup": 名前, "key": キー}を1行で送信し、1行のJSONオブジェクトを受け取る。一致しない場合はnullか{}を返す想定
+    fn lookup(&mut self, name: &str, key: &str) -> Option<&LookupRow> {
+        if !self.cache.contains_key(key) {
+            if self.failed {
+                return None;
+            }
+            match self.request(name, key) {
+                Ok(row) => {
+                    self.cache.insert(key.to_string(), row);
+                }
+                Err(e) => {
+                    // コマンドが異常終了した場合は以降の問い合わせを行わない
+                    self.failed = true;
+                    AlertMessage::warn(&format!(
+                        "The enrichment command {} failed. {e}",
+                        self.command
+                    ))
+                    .ok();
+                    return None;
+                }
+            }
+        }
+        self.cache.get(key)?.as_ref()
+    }
+
+    fn request(&mut self, name: &str, key: &str) -> Result<Option<LookupRow>, String> {
+        let request = json!({"lookup": name, "key": key});
+        writeln!(self.stdin, "{request}")
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if self
+            .stdout
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Err("The command exited.".to_string());
+        }
+        let response: Value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+        Ok(response.as_object().map(|_| to_row(&response)))
+    }
+}
+
+fn parse_lookup(name: &str, entry: &Yaml, base_dir: &Path) -> Result<Lookup, String> {
+    if entry.as_hash().is_none() {
+        return Err("Each lookup must be a map.".to_string());
+    }
+    let match_field = entry["match"]
+        .as_str()
+        .map(|m| m.trim().trim_matches('%').to_string())
+        .ok_or_else(|| "The match field is required.".to_string())?;
+    let key_column = entry["key"].as_str();
+    let source = match (entry["table"].as_str(), &entry["command"]) {
+        (Some(table), Yaml::BadValue | Yaml::Null) => {
+            LookupSource::Table(load_table(&base_dir.join(table), key_column)?)
+        }
+        (None, Yaml::String(command)) => {
+            let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+            LookupSource::Command(Arc::new(Mutex::new(CommandLookup::spawn(
+                &command, base_dir,
+            )?)))
+        }
+        (None, Yaml::Array(command)) => {
+            let command: Vec<String> = command
+                .iter()
+                .filter_map(|c| c.as_str().map(String::from))
+                .collect();
+            LookupSource::Command(Arc::new(Mutex::new(CommandLookup::spawn(
+                &command, base_dir,
+            )?)))
+        }
+        (Some(_), _) => return Err("Specify either table or command.".to_string()),
+        (None, _) => return Err("The table or command field is required.".to_string()),
+    };
+    Ok(Lookup {
+        name: name.to_string(),
+        match_field,
+        source,
+    })
+}
+
+/// CSVまたはJSON(配列かJSONL)のルックアップテーブルを読み込む。keyが指定されていない場合は1列目をキーとする
+fn load_table(path: &Path, key_column: Option<&str>) -> Result<HashMap<String, LookupRow>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the lookup table {}. {e}", path.display()))?;
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json") || ext.eq_ignore_ascii_case("jsonl"));
+    let (columns, rows) = if is_json {
+        parse_json_table(&content).map_err(|e| format!("{} {e}", path.display()))?
+    } else {
+        parse_csv_table(&content).map_err(|e| format!("{} {e}", path.display()))?
+    };
+    let key_column = match key_column {
+        Some(key) if columns.iter().any(|c| c == key) => key.to_string(),
+        Some(key) => {
+            return Err(format!(
+                "The key column {key} does not exist in {}.",
+                path.display()
+            ));
+        }
+        None => columns
+            .first()
+            .cloned()
+            .ok_or_else(|| format!("{} has no columns.", path.display()))?,
+    };
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let key = row.get(&key_column)?.trim().to_lowercase();
+            (!key.is_empty()).then_some((key, row))
+        })
+        .collect())
+}
+
+fn parse_csv_table(content: &str) -> Result<(Vec<String>, Vec<LookupRow>), String> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let columns: Vec<String> = rdr
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|c| c.trim().to_string())
+        .collect();
+    let rows = rdr
+        .records()
+        .filter_map(|r| r.ok())
+        .map(|r| {
+            columns
+                .iter()
+                .zip(r.iter())
+                .map(|(c, v)| (c.clone(), v.trim().to_string()))
+                .collect()
+        })
+        .collect();
+    Ok((columns, rows))
+}
+
+fn parse_json_table(content: &str) -> Result<(Vec<String>, Vec<LookupRow>), String> {
+    let mut columns = vec![];
+    let mut rows = vec![];
+    for value in serde_json::Deserializer::from_str(content).into_iter::<Value>() {
+        let value = value.map_err(|e| e.to_string())?;
+        let objects = match value {
+            Value::Array(values) => values,
+            v => vec![v],
+        };
+        for object in objects.iter().filter(|o| o.is_object()) {
+            for column in object.as_object().unwrap().keys() {
+                if !columns.contains(column) {
+                    columns.push(column.clone());
+                }
+            }
+            rows.push(to_row(object));
+        }
+    }
+    Ok((columns, rows))
+}
+
+fn to_row(object: &Value) -> LookupRow {
+    object
+        .as_object()
+        .map(|o| {
+            o.iter()
+                .filter_map(|(k, v)| {
+                    let v = value_to_string(v)?;
+                    Some((k.clone(), v.trim_matches('"').to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enrichment_table() {
+        let dir = Path::new("./test_enrichment_table");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("assets.csv"),
+            "hostname,owner,criticality\nPC01,Alice,high\nDC01, Bob ,critical\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("users.json"),
+            r#"[{"sam": "admin", "department": "IT", "privileged": true}, {"sam": "guest", "department": "Sales"}]"#,
+        )
+        .unwrap();
+        let content = r#"
+asset:
+    table: assets.csv
+    match: Computer
+user:
+    table: users.json
+    key: sam
+    match: '%TargetUserName%'
+"#;
+        let enrichment = Enrichment::parse(content, dir).unwrap();
+        fs::remove_dir_all(dir).ok();
+        assert_eq!(enrichment.match_field("asset"), Some("Computer"));
+        assert_eq!(enrichment.match_field("user"), Some("TargetUserName"));
+        assert_eq!(enrichment.match_field("none"), None);
+        assert_eq!(
+            enrichment.get("asset", "dc01", "owner"),
+            Some("Bob".to_string())
+        );
+        assert_eq!(
+            enrichment.get("asset", "PC01", "criticality"),
+            Some("high".to_string())
+        );
+        assert_eq!(enrichment.get("asset", "PC02", "owner"), None);
+        assert_eq!(enrichment.get("asset", "PC01", "location"), None);
+        assert_eq!(
+            enrichment.get("user", "Admin", "privileged"),
+            Some("true".to_string())
+        );
+        assert_eq!(enrichment.get("user", "guest", "privileged"), None);
+    }
+
+    #[test]
+    fn test_parse_enrichment_error() {
+        let dir = Path::new("./test_enrichment_error");
+        fs::create_dir_all(dir).unwrap();
+        let err = Enrichment::parse("- table: a.csv", dir).unwrap_err();
+        assert_eq!(err, "The enrichment file must be a map of lookup names.");
+        let err = Enrichment::parse("asset:\n    table: a.csv", dir).unwrap_err();
+        assert_eq!(err, "Lookup asset: The match field is required.");
+        let err = Enrichment::parse("asset:\n    match: Computer", dir).unwrap_err();
+        assert_eq!(err, "Lookup asset: The table or command field is required.");
+        fs::write(dir.join("a.csv"), "hostname,owner\n").unwrap();
+        let err = Enrichment::parse(
+            "asset:\n    table: a.csv\n    key: host\n    match: Computer",
+            dir,
+        )
+        .unwrap_err();
+        fs::remove_dir_all(dir).ok();
+        assert!(err.contains("The key column host does not exist"));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_enrichment_command() {
+        // catは受け取ったリクエストをそのまま返すので、リクエストの内容が列として参照できる
+        let enrichment =
+            Enrichment::parse("dir:\n    command: cat\n    match: User", Path::new(".")).unwrap();
+        assert_eq!(
+            enrichment.get("dir", "Admin", "key"),
+            Some("admin".to_string())
+        );
+        assert_eq!(
+            enrichment.get("dir", "admin", "lookup"),
+            Some("dir".to_string())
+        );
+        assert_eq!(enrichment.get("dir", "admin", "none"), None);
+    }
+}
@@ -1,14 +1,18 @@
-use crate::detections::configs::{Action, Config};
-use crate::detections::utils::{get_writable_color, write_color_buffer};
+use crate::detections::configs::{Action, Config, TimeFormatOptions};
+use crate::detections::message::{DetectInfo, TAGS_CONFIG};
+use crate::detections::utils::{format_time, get_writable_color, write_color_buffer};
+use crate::options::profile::Profile;
 use base64::Engine;
 use base64::engine::general_purpose;
 use hashbrown::HashMap;
 use horrorshow::helper::doctype;
 use horrorshow::prelude::*;
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use nested::Nested;
 use pulldown_cmark::{Options, Parser, html};
 use rust_embed::Embed;
+use serde_json::{Map, Value, json};
 use std::fs::{File, create_dir};
 use std::io::{BufWriter, Write};
 use std::path::Path;
@@ -27,6 +31,7 @@ lazy_static! {
 pub struct HtmlReporter {
     pub section_order: Nested<String>,
     pub md_datas: HashMap<String, Nested<String>>,
+    pub interactive_data: Option<String>,
 }
 
 impl HtmlReporter {
@@ -35,6 +40,7 @@ impl HtmlReporter {
         HtmlReporter {
             section_order: init_section_order,
             md_datas: init_data,
+            interactive_data: None,
         }
     }
 
@@ -61,6 +67,14 @@ impl HtmlReporter {
 
         let mut ret = String::new();
         html::push_html(&mut ret, parser);
+        if let Some(data) = &self.interactive_data {
+            let script = HtmlReportsConfig::get("hayabusa_report.js")
+                .map(|f| String::from_utf8(f.data.to_vec()).unwrap_or_default())
+                .unwrap_or_default();
+            ret.push_str(&format!(
+                "<h2 id=\"interactive_report\">Interactive Report</h2>\n<div id=\"hayabusa_interactive\"></div>\n<script type=\"application/json\" id=\"hayabusa_data\">{data}</script>\n<script>{script}</script>\n"
+            ));
+        }
         ret
     }
 }
@@ -108,6 +122,104 @@ pub fn add_md_data(section_name: &str, data: Nested<String>) {
     HTML_REPORTER.write().unwrap().md_datas = md_with_section_data;
 }
 
+/// 初期状態では存在しないセクションを末尾に追加してデータを登録する
+pub fn add_md_section(section_name: &str, data: Nested<String>) {
+    {
+        let mut reporter = HTML_REPORTER.write().unwrap();
+        if !reporter.section_order.iter().any(|s| s == section_name) {
+            reporter.section_order.push(section_name);
+        }
+    }
+    add_md_data(section_name, data);
+}
+
+/// インタラクティブなHTMLレポートに埋め込む検知結果を作成する
+pub fn create_html_detection(
+    detect_info: &DetectInfo,
+    time_format_options: &TimeFormatOptions,
+) -> Value {
+    let tactic_names: HashMap<&str, &str> = TAGS_CONFIG
+        .values()
+        .filter_map(|v| v.split_once(','))
+        .collect();
+    let mut tactics = vec![];
+    let mut fields = Map::new();
+    for (key, profile) in &detect_info.ext_field {
+        if let Profile::MitreTactics(v) = profile {
+            tactics.extend(
+                v.split(" ¦ ")
+                    .filter_map(|t| tactic_names.get(t.trim()))
+                    .map(|t| t.to_string()),
+            );
+        }
+        let value = profile
+            .to_value()
+            .replace("🛂r", "\r")
+            .replace("🛂n", "\n")
+            .replace("🛂t", "\t");
+        fields.insert(key.to_string(), json!(value));
+    }
+    // 集計条件のルールは検知したレコードのコンピュータ名を使う
+    let computer = match &detect_info.agg_result {
+        Some(agg) if !agg.agg_record_time_info.is_empty() => agg
+            .agg_record_time_info
+            .iter()
+            .map(|a| a.computer.as_str())
+            .sorted_unstable()
+            .dedup()
+            .join(", "),
+        _ => detect_info.computername.to_string(),
+    };
+    json!({
+        "Time": detect_info.detected_time.timestamp(),
+        "Timestamp": format_time(&detect_info.detected_time, false, time_format_options).as_str(),
+        "Computer": computer,
+        "Rule": detect_info.ruletitle.as_str(),
+        "RuleID": detect_info.ruleid.as_str(),
+        "Level": detect_info.level.to_full(),
+        "LevelIndex": detect_info.level.index(),
+        "EventID": detect_info.eventid.as_str(),
+        "RecordID": detect_info.rec_id.as_str(),
+        "Tactics": tactics,
+        "Fields": fields,
+    })
+}
+
+/// 検知結果をレベルが高い順、時刻が古い順に並べてmax件に絞る
+pub fn truncate_html_detections(detections: &mut Vec<Value>, max: usize) {
+    detections.sort_by(|a, b| {
+        b["LevelIndex"]
+            .as_u64()
+            .cmp(&a["LevelIndex"].as_u64())
+            .then(a["Time"].as_i64().cmp(&b["Time"].as_i64()))
+    });
+    detections.truncate(max);
+}
+
+/// 検知時刻のヒストグラムをbins個の区間で集計する
+pub fn create_timeline_histogram(timestamps: &[i64], bins: usize) -> Value {
+    let (Some(start), Some(end)) = (timestamps.iter().min(), timestamps.iter().max()) else {
+        return json!({"Start": 0, "BinSeconds": 1, "Counts": []});
+    };
+    let bin_seconds = ((end - start) / bins as i64 + 1).max(1);
+    let mut counts = vec![0_usize; ((end - start) / bin_seconds + 1) as usize];
+    for t in timestamps {
+        counts[((t - start) / bin_seconds) as usize] += 1;
+    }
+    json!({"Start": start, "BinSeconds": bin_seconds, "Counts": counts})
+}
+
+/// インタラクティブなHTMLレポートのデータを登録する。script要素を閉じないように<をエスケープする
+pub fn set_interactive_data(detections: Vec<Value>, total: usize, timestamps: &[i64]) {
+    let data = json!({
+        "TotalDetections": total,
+        "Histogram": create_timeline_histogram(timestamps, 100),
+        "Detections": detections,
+    });
+    HTML_REPORTER.write().unwrap().interactive_data =
+        Some(data.to_string().replace('<', "\\u003c"));
+}
+
 /// create html file
 pub fn create_html_file(input_html: String, path_str: &str, no_color: bool) {
     let path = Path::new(path_str);
@@ -407,4 +519,41 @@ mod tests {
     fn test_not_exist_to_base64() {
         assert!(img_to_base64("not_exist.png").is_empty());
     }
+
+    #[test]
+    fn test_create_timeline_histogram() {
+        let hist = htmlreport::create_timeline_histogram(&[100, 150, 105, 199, 200], 2);
+        assert_eq!(
+            hist,
+            serde_json::json!({"Start": 100, "BinSeconds": 51, "Counts": [3, 2]})
+        );
+        let hist = htmlreport::create_timeline_histogram(&[], 100);
+        assert_eq!(hist["Counts"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_create_interactive_html() {
+        let mut detections = vec![
+            serde_json::json!({"Time": 3, "LevelIndex": 2, "Rule": "low"}),
+            serde_json::json!({"Time": 2, "LevelIndex": 4, "Rule": "high-2"}),
+            serde_json::json!({"Time": 1, "LevelIndex": 4, "Rule": "high-1"}),
+        ];
+        htmlreport::truncate_html_detections(&mut detections, 2);
+        assert_eq!(
+            detections
+                .iter()
+                .map(|d| d["Rule"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["high-1", "high-2"]
+        );
+
+        let html_reporter = HtmlReporter {
+            interactive_data: Some(r#"{"Detections":[{"Rule":"\u003c/script>"}]}"#.to_string()),
+            ..Default::default()
+        };
+        let html = html_reporter.create_html();
+        assert!(html.contains(r#"<div id="hayabusa_interactive"></div>"#));
+        assert!(html.contains(r#"<script type="application/json" id="hayabusa_data">{"Detections":[{"Rule":"\u003c/script>"}]}</script>"#));
+        assert_eq!(html.matches("</script>").count(), 2);
+    }
 }
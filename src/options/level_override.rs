@@ -0,0 +1,285 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use wildmatch::WildMatch;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::detections::configs::EventKeyAliasConfig;
+use crate::detections::utils::{get_event_value, value_to_string};
+use crate::level::LEVEL;
+
+/// userの条件で確認するフィールド
+pub(crate) const USER_FIELDS: [&str; 3] = ["SubjectUserName", "TargetUserName", "User"];
+
+/// 条件に一致したアラートのレベルの変更方法
+#[derive(Debug, Clone, PartialEq)]
+pub enum LevelAction {
+    Raise,
+    Lower,
+    Suppress,
+    Set(LEVEL),
+}
+
+/// レベルを変更する1件分の条件。指定された条件をすべて満たす場合に一致する
+#[derive(Debug, Clone)]
+pub struct LevelOverride {
+    rule_ids: Vec<WildMatch>,
+    computers: Vec<WildMatch>,
+    users: Vec<WildMatch>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    fields: Vec<(String, Vec<WildMatch>)>,
+    action: LevelAction,
+}
+
+/// --level-overridesで指定された条件付きのレベルの変更設定
+#[derive(Debug, Clone, Default)]
+pub struct LevelOverrides {
+    overrides: Vec<LevelOverride>,
+}
+
+impl LevelOverrides {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Failed to read the level overrides file {}. {e}",
+                path.display()
+            )
+        })?;
+        LevelOverrides::parse(&content).map_err(|e| format!("{} {e}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let docs = YamlLoader::load_from_str(content).map_err(|e| format!("YAML error. {e}"))?;
+        let Some(doc) = docs.first() else {
+            return Ok(LevelOverrides::default());
+        };
+        let Some(entries) = doc.as_vec() else {
+            return Err("The level overrides must be a list.".to_string());
+        };
+        let overrides = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| parse_override(entry).map_err(|e| format!("Entry {}: {e}", i + 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(LevelOverrides { overrides })
+    }
+
+    /// 上から順に条件を確認し、最初に一致した設定でレベルを変更する。抑制する場合はNoneを返す
+    pub fn apply(
+        &self,
+        level: &LEVEL,
+        rule_id: &str,
+        computer: &str,
+        time: &DateTime<Utc>,
+        record: Option<&Value>,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> Option<LEVEL> {
+        let Some(matched) = self
+            .overrides
+            .iter()
+            .find(|o| o.is_match(rule_id, computer, time, record, eventkey_alias))
+        else {
+            return Some(level.clone());
+        };
+        match &matched.action {
+            LevelAction::Raise => Some(shift_level(level, 1)),
+            LevelAction::Lower => Some(shift_level(level, -1)),
+            LevelAction::Suppress => None,
+            LevelAction::Set(new_level) => Some(new_level.clone()),
+        }
+    }
+}
+
+impl LevelOverride {
+    fn is_match(
+        &self,
+        rule_id: &str,
+        computer: &str,
+        time: &DateTime<Utc>,
+        record: Option<&Value>,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> bool {
+        if !self.rule_ids.is_empty() && !self.rule_ids.iter().any(|p| p.matches(rule_id)) {
+            return false;
+        }
+        // 相関ルールのアラートは複数のコンピュータ名が結合されている
+        if !self.computers.is_empty()
+            && !computer
+                .split(" ¦ ")
+                .any(|c| self.computers.iter().any(|p| p.matches(c)))
+        {
+            return false;
+        }
+        if self.start.is_some_and(|start| *time < start) || self.end.is_some_and(|end| *time > end)
+        {
+            return false;
+        }
+        if self.users.is_empty() && self.fields.is_empty() {
+            return true;
+        }
+        // user、fieldsの条件はイベントレコードのないアラートには一致しない
+        let Some(record) = record else {
+            return false;
+        };
+        let field_value = |key: &str| {
+            get_event_value(key, record, eventkey_alias)
+                .and_then(value_to_string)
+                .map(|v| v.trim_matches('"').to_string())
+        };
+        if !self.users.is_empty()
+            && !USER_FIELDS
+                .iter()
+                .filter_map(|f| field_value(f))
+                .any(|user| self.users.iter().any(|p| p.matches(&user)))
+        {
+            return false;
+        }
+        self.fields.iter().all(|(key, patterns)| {
+            field_value(key).is_some_and(|v| patterns.iter().any(|p| p.matches(&v)))
+        })
+    }
+}
+
+fn parse_override(entry: &Yaml) -> Result<LevelOverride, String> {
+    if entry.as_hash().is_none() {
+        return Err("Each level override must be a map.".to_string());
+    }
+    let action = match entry["level"].as_str().map(|s| s.to_lowercase()) {
+        Some(s) if s == "raise" => LevelAction::Raise,
+        Some(s) if s == "lower" => LevelAction::Lower,
+        Some(s) if s == "suppress" => LevelAction::Suppress,
+        Some(s) if LEVEL::from(&s) != LEVEL::UNDEFINED => LevelAction::Set(LEVEL::from(&s)),
+        Some(s) => return Err(format!("Invalid level: {s}")),
+        None => return Err("The level field is required.".to_string()),
+    };
+    let fields = match entry["fields"].as_hash() {
+        Some(fields) => fields
+            .iter()
+            .map(|(k, v)| {
+                let key = k
+                    .as_str()
+                    .ok_or_else(|| "The field names must be strings.".to_string())?;
+                Ok((key.to_string(), patterns(v, "fields")?))
+            })
+            .collect::<Result<Vec<_>, String>>()?,
+        None => vec![],
+    };
+    Ok(LevelOverride {
+        rule_ids: patterns(&entry["id"], "id")?,
+        computers: patterns(&entry["computer"], "computer")?,
+        users: patterns(&entry["user"], "user")?,
+        start: parse_time(&entry["start"])?,
+        end: parse_time(&entry["end"])?,
+        fields,
+        action,
+    })
+}
+
+/// 文字列または文字列のリストを大文字小文字を区別しないワイルドカードに変換する
+pub(crate) fn patterns(value: &Yaml, key: &str) -> Result<Vec<WildMatch>, String> {
+    let values = match value {
+        Yaml::BadValue | Yaml::Null => return Ok(vec![]),
+        Yaml::Array(values) => values.iter().collect(),
+        v => vec![v],
+    };
+    values
+        .into_iter()
+        .map(|v| match v {
+            Yaml::String(s) | Yaml::Real(s) => Ok(WildMatch::new_case_insensitive(s)),
+            Yaml::Integer(i) => Ok(WildMatch::new(&i.to_string())),
+            _ => Err(format!("Invalid value in {key}.")),
+        })
+        .collect()
+}
+
+fn parse_time(value: &Yaml) -> Result<Option<DateTime<Utc>>, String> {
+    let Some(time) = value.as_str() else {
+        return Ok(None);
+    };
+    DateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S %z")
+        .or_else(|_| DateTime::parse_from_str(time, "%Y/%m/%d %H:%M:%S %z"))
+        .map(|t| Some(t.with_timezone(&Utc)))
+        .map_err(|_| format!("Invalid time: {time} (ex: \"2024-03-01 09:00:00 +09:00\")"))
+}
+
+/// informationalからemergencyの範囲でレベルを上げ下げする
+fn shift_level(level: &LEVEL, step: i32) -> LEVEL {
+    match (level, step) {
+        (LEVEL::UNDEFINED, _) => LEVEL::UNDEFINED,
+        (LEVEL::INFORMATIONAL, 1) => LEVEL::LOW,
+        (LEVEL::LOW, 1) => LEVEL::MEDIUM,
+        (LEVEL::MEDIUM, 1) => LEVEL::HIGH,
+        (LEVEL::HIGH, 1) => LEVEL::CRITICAL,
+        (LEVEL::CRITICAL, 1) => LEVEL::EMERGENCY,
+        (LEVEL::EMERGENCY, -1) => LEVEL::CRITICAL,
+        (LEVEL::CRITICAL, -1) => LEVEL::HIGH,
+        (LEVEL::HIGH, -1) => LEVEL::MEDIUM,
+        (LEVEL::MEDIUM, -1) => LEVEL::LOW,
+        (LEVEL::LOW, -1) => LEVEL::INFORMATIONAL,
+        (level, _) => level.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn test_apply_level_overrides() {
+        let overrides = LevelOverrides::parse(
+            r#"
+- computer: "PENTEST-*"
+  start: "2024-03-01 00:00:00 +00:00"
+  end: "2024-03-05 00:00:00 +00:00"
+  level: suppress
+- id: rule-1
+  user: svc_backup
+  level: lower
+- fields:
+    Image: '*\nmap.exe'
+  level: critical
+- computer: dc01
+  level: raise
+"#,
+        )
+        .unwrap();
+        let alias = EventKeyAliasConfig::new();
+        let time = Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+        let later = Utc.with_ymd_and_hms(2024, 3, 6, 0, 0, 0).unwrap();
+        let record = json!({"Event": {"EventData": {"SubjectUserName": "SVC_BACKUP", "Image": "C:\\tools\\nmap.exe"}}});
+        let apply = |id: &str, computer: &str, time: &DateTime<Utc>, record: Option<&Value>| {
+            overrides.apply(&LEVEL::HIGH, id, computer, time, record, &alias)
+        };
+
+        assert_eq!(apply("rule-2", "PENTEST-01", &time, None), None);
+        assert_eq!(
+            apply("rule-2", "PENTEST-01", &later, None),
+            Some(LEVEL::HIGH)
+        );
+        assert_eq!(
+            apply("rule-1", "PC1", &time, Some(&record)),
+            Some(LEVEL::MEDIUM)
+        );
+        assert_eq!(
+            apply("rule-2", "PC1", &time, Some(&record)),
+            Some(LEVEL::CRITICAL)
+        );
+        assert_eq!(
+            apply("rule-2", "WS01 ¦ DC01", &time, None),
+            Some(LEVEL::CRITICAL)
+        );
+        assert_eq!(apply("rule-2", "PC1", &time, None), Some(LEVEL::HIGH));
+    }
+
+    #[test]
+    fn test_parse_level_overrides_error() {
+        assert!(LevelOverrides::parse("- computer: PC1").is_err());
+        assert!(LevelOverrides::parse("- level: severe").is_err());
+        assert!(LevelOverrides::parse("- level: low\n  start: yesterday").is_err());
+        assert!(LevelOverrides::parse("level: low").is_err());
+    }
+}
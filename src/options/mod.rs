@@ -1,7 +1,18 @@
+pub mod compare;
+pub mod delta_scan;
+pub mod enrichment;
 pub mod expand_list;
 pub mod geoip_search;
 pub mod htmlreport;
+pub mod level_override;
 pub mod level_tuning;
 pub mod pivot;
 pub mod profile;
+pub mod risk_scoring;
+pub mod rules_diff;
+pub mod scan_checkpoint;
+pub mod serve;
+pub mod test_rules;
 pub mod update;
+pub mod validate_rules;
+pub mod watch;
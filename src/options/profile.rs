@@ -1,11 +1,15 @@
-use crate::detections::configs::{Action, CURRENT_EXE_PATH, GEOIP_DB_PARSER, StoredStatic};
+use crate::detections::configs::{
+    Action, CURRENT_EXE_PATH, EventKeyAliasConfig, GEOIP_DB_PARSER, StoredStatic,
+};
 use crate::detections::message::AlertMessage;
 use crate::detections::utils::check_setting_path;
+use crate::options::enrichment::Enrichment as EnrichmentConfig;
 use crate::options::profile::Profile::{
-    AllFieldInfo, Channel, Computer, Details, EventID, EvtxFile, ExtraFieldInfo, Level, Literal,
-    MitreTactics, MitreTags, OtherTags, Provider, RecordID, RecoveredRecord, RenderedMessage,
-    RuleAuthor, RuleCreationDate, RuleFile, RuleID, RuleModifiedDate, RuleTitle, SrcASN, SrcCity,
-    SrcCountry, Status, TgtASN, TgtCity, TgtCountry, Timestamp,
+    AllFieldInfo, Channel, Computer, Details, Enrichment, EventID, EvtxFile, ExtraFieldInfo, Field,
+    IncidentID, Level, Literal, MitreTactics, MitreTags, OriginalLevel, OtherTags, OutputFields,
+    Provider, RecordID, RecoveredRecord, RenderedMessage, RuleAuthor, RuleCreationDate, RuleFile,
+    RuleID, RuleModifiedDate, RuleTitle, SrcASN, SrcCity, SrcCountry, Status, TgtASN, TgtCity,
+    TgtCountry, Timestamp,
 };
 use crate::yaml;
 use compact_str::CompactString;
@@ -29,6 +33,7 @@ pub enum Profile {
     Computer(Cow<'static, str>),
     Channel(Cow<'static, str>),
     Level(Cow<'static, str>),
+    OriginalLevel(Cow<'static, str>),
     EventID(Cow<'static, str>),
     RecordID(Cow<'static, str>),
     RuleTitle(Cow<'static, str>),
@@ -54,18 +59,27 @@ pub enum Profile {
     TgtCity(Cow<'static, str>),
     ExtraFieldInfo(Cow<'static, str>),
     RecoveredRecord(Cow<'static, str>),
-    Literal(Cow<'static, str>), // profiles.yamlの固定文字列を変換なしでそのまま出力する場合
+    OutputFields(Cow<'static, str>), // ルールのoutput_fieldsで指定されたフィールド
+    IncidentID(Cow<'static, str>),   // --incidentsでまとめたインシデントのID
+    Enrichment(Cow<'static, str>), // %Enrich.asset.owner%のような--enrichmentのルックアップの値。読み込み時はルックアップ名と列名を保持する
+    Literal(Cow<'static, str>),    // profiles.yamlの固定文字列を変換なしでそのまま出力する場合
+    Field(Cow<'static, str>), // %Event.EventData.TargetUserName%や%lower(Image)%のようなフィールド式。読み込み時は式を保持する
 }
 
+/// フィールド式で使える変換関数
+pub const FIELD_FUNCTIONS: [&str; 6] =
+    ["lower", "upper", "trim", "basename", "dirname", "extension"];
+
 impl Profile {
     pub fn to_value(&self) -> String {
         match &self {
-            Timestamp(v) | Computer(v) | Channel(v) | Level(v) | EventID(v) | RecordID(v)
-            | RuleTitle(v) | AllFieldInfo(v) | RuleFile(v) | EvtxFile(v) | MitreTactics(v)
-            | MitreTags(v) | OtherTags(v) | RuleAuthor(v) | RuleCreationDate(v)
-            | RuleModifiedDate(v) | Status(v) | RuleID(v) | Provider(v) | Details(v)
-            | RenderedMessage(v) | SrcASN(v) | SrcCountry(v) | SrcCity(v) | TgtASN(v)
-            | TgtCountry(v) | TgtCity(v) | RecoveredRecord(v) | ExtraFieldInfo(v) | Literal(v) => {
+            Timestamp(v) | Computer(v) | Channel(v) | Level(v) | OriginalLevel(v) | EventID(v)
+            | RecordID(v) | RuleTitle(v) | AllFieldInfo(v) | RuleFile(v) | EvtxFile(v)
+            | MitreTactics(v) | MitreTags(v) | OtherTags(v) | RuleAuthor(v)
+            | RuleCreationDate(v) | RuleModifiedDate(v) | Status(v) | RuleID(v) | Provider(v)
+            | Details(v) | RenderedMessage(v) | SrcASN(v) | SrcCountry(v) | SrcCity(v)
+            | TgtASN(v) | TgtCountry(v) | TgtCity(v) | RecoveredRecord(v) | ExtraFieldInfo(v)
+            | OutputFields(v) | IncidentID(v) | Enrichment(v) | Literal(v) | Field(v) => {
                 v.to_string()
             }
         }
@@ -77,6 +91,7 @@ impl Profile {
             Computer(_) => Computer(converted_string.to_owned().into()),
             Channel(_) => Channel(converted_string.to_owned().into()),
             Level(_) => Level(converted_string.to_owned().into()),
+            OriginalLevel(_) => OriginalLevel(converted_string.to_owned().into()),
             EventID(_) => EventID(converted_string.to_owned().into()),
             RecordID(_) => RecordID(converted_string.to_owned().into()),
             RuleTitle(_) => RuleTitle(converted_string.to_owned().into()),
@@ -100,8 +115,12 @@ impl Profile {
             TgtCity(_) => TgtCity(converted_string.to_owned().into()),
             ExtraFieldInfo(_) => ExtraFieldInfo(converted_string.to_owned().into()),
             RecoveredRecord(_) => RecoveredRecord(converted_string.to_owned().into()),
+            OutputFields(_) => OutputFields(converted_string.to_owned().into()),
+            IncidentID(_) => IncidentID(converted_string.to_owned().into()),
+            Enrichment(_) => Enrichment(converted_string.to_owned().into()),
             Details(_) => Details(converted_string.to_owned().into()),
             AllFieldInfo(_) => AllFieldInfo(converted_string.to_owned().into()),
+            Field(_) => Field(converted_string.to_owned().into()),
             p => p.to_owned(),
         }
     }
@@ -114,6 +133,7 @@ impl From<&str> for Profile {
             "%Computer%" => Computer(Default::default()),
             "%Channel%" => Channel(Default::default()),
             "%Level%" => Level(Default::default()),
+            "%OriginalLevel%" => OriginalLevel(Default::default()),
             "%EventID%" => EventID(Default::default()),
             "%RecordID%" => RecordID(Default::default()),
             "%RuleTitle%" => RuleTitle(Default::default()),
@@ -133,8 +153,122 @@ impl From<&str> for Profile {
             "%RenderedMessage%" => RenderedMessage(Default::default()),
             "%ExtraFieldInfo%" => ExtraFieldInfo(Default::default()),
             "%RecoveredRecord%" => RecoveredRecord(Default::default()),
-            s => Literal(s.to_string().into()), // profiles.yamlの固定文字列を変換なしでそのまま出力する場合
+            "%OutputFields%" => OutputFields(Default::default()),
+            "%IncidentID%" => IncidentID(Default::default()),
+            s if s
+                .strip_prefix("%Enrich.")
+                .and_then(|e| e.strip_suffix('%'))
+                .is_some_and(|e| e.contains('.')) =>
+            {
+                Enrichment(s["%Enrich.".len()..s.len() - 1].to_string().into())
+            }
+            s => match s
+                .strip_prefix('%')
+                .and_then(|e| e.strip_suffix('%'))
+                .filter(|e| e.contains(['.', '(']) && parse_field_expression(e).is_some())
+            {
+                Some(expr) => Field(expr.to_string().into()),
+                None => Literal(s.to_string().into()), // profiles.yamlの固定文字列を変換なしでそのまま出力する場合
+            },
+        }
+    }
+}
+
+/// %TargetUserName%のようなパスも変換関数も含まない名前は、eventkey_alias.txtに定義されている場合のみフィールド式にする。
+/// 固定文字列のまま出力されるフィールド式の警告メッセージを返す
+pub fn resolve_alias_fields(
+    profiles: &mut [(CompactString, Profile)],
+    eventkey_alias: &EventKeyAliasConfig,
+) -> Vec<String> {
+    let mut warnings = vec![];
+    for (key, profile) in profiles.iter_mut() {
+        let Literal(v) = profile else {
+            continue;
+        };
+        let Some(expr) = v.strip_prefix('%').and_then(|e| e.strip_suffix('%')) else {
+            continue;
+        };
+        if expr.contains('(') {
+            warnings.push(format!(
+                "Invalid field expression in the {key} column: {v}. Available functions: {}",
+                FIELD_FUNCTIONS.join(", ")
+            ));
+        } else if parse_field_expression(expr).is_some() {
+            if eventkey_alias.get_event_key(expr).is_some() {
+                *profile = Field(expr.to_string().into());
+            } else {
+                warnings.push(format!(
+                    "Unknown alias in the {key} column: {v}. It is not defined in eventkey_alias.txt so it will be outputted as is. Please use %Event.EventData.{expr}% to output an event field."
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+/// %Enrich.name.column%のルックアップ名が--enrichmentの設定に定義されているかを確認し、警告メッセージを返す
+pub fn check_enrichment_lookups(
+    profiles: &[(CompactString, Profile)],
+    enrichment: Option<&EnrichmentConfig>,
+) -> Vec<String> {
+    profiles
+        .iter()
+        .filter_map(|(key, profile)| {
+            let Enrichment(v) = profile else {
+                return None;
+            };
+            let name = v.split_once('.').map_or(v.as_ref(), |(name, _)| name);
+            match enrichment {
+                None => Some(format!(
+                    "The {key} column uses %Enrich.{v}% but --enrichment is not specified, so - will be outputted."
+                )),
+                Some(e) if e.match_field(name).is_none() => Some(format!(
+                    "The lookup {name} in the {key} column is not defined in the --enrichment file, so - will be outputted."
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// lower(basename(Image))のようなフィールド式を、内側から適用する変換関数の一覧とフィールド名に分解する
+pub fn parse_field_expression(expr: &str) -> Option<(Vec<&str>, &str)> {
+    let mut functions = vec![];
+    let mut target = expr.trim();
+    while let Some((function, arg)) = target.split_once('(') {
+        let function = function.trim();
+        if !FIELD_FUNCTIONS.contains(&function) {
+            return None;
         }
+        functions.push(function);
+        target = arg.strip_suffix(')')?.trim();
+    }
+    if target.is_empty() || target.contains([')', '%']) {
+        return None;
+    }
+    functions.reverse();
+    Some((functions, target))
+}
+
+/// フィールドの値に変換関数を適用する。パスはWindowsとUNIXのどちらの区切り文字にも対応する
+pub fn apply_field_function(function: &str, value: &str) -> String {
+    let basename = || value.rsplit(['\\', '/']).next().unwrap_or_default();
+    match function {
+        "lower" => value.to_lowercase(),
+        "upper" => value.to_uppercase(),
+        "trim" => value.trim().to_string(),
+        "basename" => basename().to_string(),
+        "dirname" => value
+            .rsplit_once(['\\', '/'])
+            .map(|(dir, _)| dir)
+            .unwrap_or_default()
+            .to_string(),
+        "extension" => basename()
+            .rsplit_once('.')
+            .map(|(_, ext)| ext)
+            .unwrap_or_default()
+            .to_string(),
+        _ => value.to_string(),
     }
 }
 
@@ -171,6 +305,27 @@ fn read_profile_data(profile_path: &str) -> Result<Vec<Yaml>, String> {
     }
 }
 
+/// バイナリに組み込まれたconfig/default_profile.yamlのプロファイルを返す関数
+pub fn embedded_default_profile() -> Vec<(CompactString, Profile)> {
+    let Some(file) = DefaultProfile::get("default_profile.yaml") else {
+        return vec![];
+    };
+    let profile =
+        YamlLoader::load_from_str(std::str::from_utf8(file.data.as_ref()).unwrap_or_default())
+            .unwrap_or_default();
+    profile
+        .first()
+        .and_then(|p| p.as_hash())
+        .map(|p| {
+            p.iter()
+                .filter_map(|(k, v)| {
+                    Some((CompactString::from(k.as_str()?), Profile::from(v.as_str()?)))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// プロファイル情報を読み込む関数
 pub fn load_profile(
     default_profile_path: &str,
@@ -248,6 +403,20 @@ pub fn load_profile(
                 ));
             });
     }
+    // 変換関数の指定が誤っているフィールド式や未定義のエイリアスは固定文字列として出力されるため警告する
+    for warning in resolve_alias_fields(
+        &mut ret,
+        &opt_stored_static.as_ref().unwrap().eventkey_alias,
+    ) {
+        AlertMessage::warn(&warning).ok();
+    }
+    // 未定義のルックアップを参照する%Enrich.name.column%は常に-が出力されるため警告する
+    for warning in check_enrichment_lookups(
+        &ret,
+        opt_stored_static.as_ref().unwrap().enrichment.as_ref(),
+    ) {
+        AlertMessage::warn(&warning).ok();
+    }
     // insert preserved keyword when get-ip option specified.
     if GEOIP_DB_PARSER.read().unwrap().is_some() {
         ret.push((CompactString::from("SrcASN"), SrcASN(Cow::default())));
@@ -270,6 +439,13 @@ pub fn load_profile(
                 RecoveredRecord(Cow::default()),
             ));
         }
+        // プロファイルで%IncidentID%を指定していない場合は最後の列に追加する
+        if opt.incidents && !ret.iter().any(|(_, p)| matches!(p, IncidentID(_))) {
+            ret.push((
+                CompactString::from("IncidentID"),
+                IncidentID(Cow::default()),
+            ));
+        }
     }
     Some(ret)
 }
@@ -396,12 +572,18 @@ pub fn get_profile_list(profile_path: &str) -> Nested<Vec<String>> {
 #[cfg(test)]
 mod tests {
 
+    use crate::detections::configs::load_eventkey_alias;
     use crate::detections::configs::{
         Action, Config, CsvOutputOption, GEOIP_DB_PARSER, OutputOption, StoredStatic,
     };
-    use crate::options::profile::{Profile, get_profile_list, load_profile};
+    use crate::options::enrichment::Enrichment;
+    use crate::options::profile::{
+        Profile, apply_field_function, check_enrichment_lookups, get_profile_list, load_profile,
+        parse_field_expression, resolve_alias_fields,
+    };
     use compact_str::CompactString;
     use nested::Nested;
+    use std::path::Path;
 
     fn create_dummy_stored_static(action: Action) -> StoredStatic {
         StoredStatic::create_static_data(Some(Config {
@@ -416,6 +598,110 @@ mod tests {
         assert_eq!("a", profile_enum.to_value())
     }
 
+    #[test]
+    fn test_profile_field_expression() {
+        assert_eq!(
+            Profile::from("%Event.EventData.TargetUserName%"),
+            Profile::Field("Event.EventData.TargetUserName".into())
+        );
+        assert_eq!(
+            Profile::from("%lower(basename(Image))%"),
+            Profile::Field("lower(basename(Image))".into())
+        );
+        assert_eq!(
+            parse_field_expression("lower( basename(Image) )"),
+            Some((vec!["basename", "lower"], "Image"))
+        );
+        assert_eq!(
+            parse_field_expression("Details.User"),
+            Some((vec![], "Details.User"))
+        );
+        assert_eq!(
+            Profile::from("%Enrich.asset.owner%"),
+            Profile::Enrichment("asset.owner".into())
+        );
+        // 存在しない変換関数や閉じ括弧のない式は固定文字列として扱う
+        assert_eq!(
+            Profile::from("%unknown(Image)%"),
+            Profile::Literal("%unknown(Image)%".into())
+        );
+        assert_eq!(
+            Profile::from("%lower(Image%"),
+            Profile::Literal("%lower(Image%".into())
+        );
+        assert_eq!(Profile::from("100%"), Profile::Literal("100%".into()));
+        // パスも変換関数も含まない名前はeventkey_alias.txtに定義されている場合のみフィールド式とする
+        assert_eq!(
+            Profile::from("%TargetUserName%"),
+            Profile::Literal("%TargetUserName%".into())
+        );
+        let alias_path = "./test_profile_eventkey_alias.txt";
+        std::fs::write(
+            alias_path,
+            "alias,event_key\nTargetUserName,Event.EventData.TargetUserName\n",
+        )
+        .unwrap();
+        let eventkey_alias = load_eventkey_alias(alias_path);
+        std::fs::remove_file(alias_path).ok();
+        let mut profiles: Vec<(CompactString, Profile)> = [
+            ("User", "%TargetUserName%"),
+            ("Typo", "%TargetUsrName%"),
+            ("Func", "%unknown(Image)%"),
+            ("Text", "hayabusa"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.into(), Profile::from(v)))
+        .collect();
+        let warnings = resolve_alias_fields(&mut profiles, &eventkey_alias);
+        assert_eq!(profiles[0].1, Profile::Field("TargetUserName".into()));
+        assert_eq!(profiles[1].1, Profile::Literal("%TargetUsrName%".into()));
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("Typo"));
+        assert!(warnings[1].contains("Func"));
+
+        let path = "C:\\Windows\\System32\\CMD.EXE";
+        assert_eq!(
+            apply_field_function("lower", path),
+            "c:\\windows\\system32\\cmd.exe"
+        );
+        assert_eq!(apply_field_function("basename", path), "CMD.EXE");
+        assert_eq!(
+            apply_field_function("dirname", path),
+            "C:\\Windows\\System32"
+        );
+        assert_eq!(apply_field_function("extension", path), "EXE");
+        assert_eq!(apply_field_function("basename", "/usr/bin/bash"), "bash");
+        assert_eq!(apply_field_function("extension", "C:\\a.b\\c"), "");
+        assert_eq!(apply_field_function("trim", " a "), "a");
+    }
+
+    #[test]
+    fn test_check_enrichment_lookups() {
+        let profiles: Vec<(CompactString, Profile)> = [
+            ("Owner", "%Enrich.asset.owner%"),
+            ("Dept", "%Enrich.user.dept%"),
+            ("Computer", "%Computer%"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.into(), Profile::from(v)))
+        .collect();
+        let warnings = check_enrichment_lookups(&profiles, None);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("--enrichment is not specified"));
+
+        let table_path = "./test_profile_enrichment.csv";
+        std::fs::write(table_path, "host,owner\nPC1,alice\n").unwrap();
+        let enrichment = Enrichment::parse(
+            "asset:\n    table: test_profile_enrichment.csv\n    match: Computer",
+            Path::new("."),
+        );
+        std::fs::remove_file(table_path).ok();
+        let enrichment = enrichment.unwrap();
+        let warnings = check_enrichment_lookups(&profiles, Some(&enrichment));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("The lookup user in the Dept column"));
+    }
+
     #[test]
     ///オプションの設定が入ると値の冪等性が担保できないためテストを逐次的に処理する
     fn test_load_profile() {
@@ -0,0 +1,555 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wildmatch::WildMatch;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::detections::rule::RuleNode;
+use crate::level::LEVEL;
+use crate::options::level_override::patterns;
+
+/// リスクスコアのしきい値を超えた場合に作成するアラートのルールID
+pub const RISK_RULE_ID: &str = "risk-threshold-exceeded";
+
+/// レベルごとの初期スコア
+const DEFAULT_LEVEL_SCORES: [(LEVEL, f64); 6] = [
+    (LEVEL::INFORMATIONAL, 1.0),
+    (LEVEL::LOW, 5.0),
+    (LEVEL::MEDIUM, 15.0),
+    (LEVEL::HIGH, 40.0),
+    (LEVEL::CRITICAL, 80.0),
+    (LEVEL::EMERGENCY, 100.0),
+];
+
+/// リスクスコアを集計する単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EntityType {
+    Computer,
+    User,
+}
+
+impl EntityType {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            EntityType::Computer => "Computer",
+            EntityType::User => "User",
+        }
+    }
+}
+
+/// 1件の検知のリスクスコア
+#[derive(Debug, Clone)]
+struct RiskEntry {
+    entity_type: EntityType,
+    entity: String,
+    time: DateTime<Utc>,
+    rule_title: String,
+    computer: String,
+    score: f64,
+}
+
+/// スコアを変更する条件。指定された条件をすべて満たす場合にmultiplierを掛けてaddを足す
+#[derive(Debug, Clone)]
+struct RiskModifier {
+    critical_system: bool,
+    rule_ids: Vec<WildMatch>,
+    computers: Vec<WildMatch>,
+    users: Vec<WildMatch>,
+    tags: Vec<WildMatch>,
+    multiplier: f64,
+    add: f64,
+}
+
+/// ウィンドウ内のスコアの合計がしきい値を超えたエンティティ
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskAlert {
+    pub entity_type: EntityType,
+    pub entity: String,
+    pub score: f64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// 検知したコンピュータ名
+    pub computers: Vec<String>,
+    /// スコアに寄与したルールのタイトルと検知数。検知数の多い順
+    pub rules: Vec<(String, usize)>,
+}
+
+/// --risk-scoringで指定されたリスクスコアの設定と、検知ごとのスコアの集計結果
+#[derive(Debug, Clone)]
+pub struct RiskScoring {
+    pub threshold: f64,
+    pub window: String,
+    pub alert_level: LEVEL,
+    window_sec: i64,
+    level_scores: Vec<(LEVEL, f64)>,
+    tag_scores: Vec<(WildMatch, f64)>,
+    modifiers: Vec<RiskModifier>,
+    entries: Arc<Mutex<Vec<RiskEntry>>>,
+}
+
+impl Default for RiskScoring {
+    fn default() -> Self {
+        RiskScoring {
+            threshold: 100.0,
+            window: "24h".to_string(),
+            alert_level: LEVEL::HIGH,
+            window_sec: 86400,
+            level_scores: DEFAULT_LEVEL_SCORES.to_vec(),
+            tag_scores: vec![],
+            modifiers: vec![],
+            entries: Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+impl RiskScoring {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Failed to read the risk scoring file {}. {e}",
+                path.display()
+            )
+        })?;
+        RiskScoring::parse(&content).map_err(|e| format!("{} {e}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let docs = YamlLoader::load_from_str(content).map_err(|e| format!("YAML error. {e}"))?;
+        let mut risk_scoring = RiskScoring::default();
+        let Some(doc) = docs.first() else {
+            return Ok(risk_scoring);
+        };
+        if doc.as_hash().is_none() {
+            return Err("The risk scoring file must be a map.".to_string());
+        }
+        if let Some(threshold) = to_f64(&doc["threshold"]) {
+            if threshold <= 0.0 {
+                return Err("The threshold must be greater than 0.".to_string());
+            }
+            risk_scoring.threshold = threshold;
+        }
+        if let Some(window) = doc["window"].as_str() {
+            risk_scoring.window_sec = parse_window(window)?;
+            risk_scoring.window = window.to_string();
+        }
+        if let Some(level) = doc["level"].as_str() {
+            risk_scoring.alert_level = LEVEL::from(level);
+            if risk_scoring.alert_level == LEVEL::UNDEFINED {
+                return Err(format!("Invalid level: {level}"));
+            }
+        }
+        if let Some(levels) = doc["levels"].as_hash() {
+            for (level, score) in levels {
+                let level_str = level.as_str().unwrap_or_default();
+                let level = LEVEL::from(level_str);
+                let score = to_f64(score)
+                    .ok_or_else(|| format!("The score of {level_str} must be a number."))?;
+                match risk_scoring
+                    .level_scores
+                    .iter_mut()
+                    .find(|(l, _)| *l == level)
+                {
+                    Some((_, s)) => *s = score,
+                    None => return Err(format!("Invalid level: {level_str}")),
+                }
+            }
+        }
+        if let Some(tags) = doc["tags"].as_hash() {
+            risk_scoring.tag_scores = tags
+                .iter()
+                .map(|(tag, score)| {
+                    let tag = tag
+                        .as_str()
+                        .ok_or_else(|| "The tags must be strings.".to_string())?;
+                    let score = to_f64(score)
+                        .ok_or_else(|| format!("The score of {tag} must be a number."))?;
+                    Ok((WildMatch::new_case_insensitive(tag), score))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+        }
+        if let Some(modifiers) = doc["modifiers"].as_vec() {
+            risk_scoring.modifiers = modifiers
+                .iter()
+                .enumerate()
+                .map(|(i, m)| parse_modifier(m).map_err(|e| format!("Modifier {}: {e}", i + 1)))
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+        Ok(risk_scoring)
+    }
+
+    /// レベルとタグのスコアの合計に、条件に一致したmodifierを上から順に適用したスコアを返す
+    pub fn score(
+        &self,
+        level: &LEVEL,
+        (rule_id, tags): (&str, &[&str]),
+        computer: &str,
+        user: Option<&str>,
+        critical_systems: &HashSet<String>,
+    ) -> f64 {
+        let level_score = self
+            .level_scores
+            .iter()
+            .find(|(l, _)| l == level)
+            .map(|(_, s)| *s)
+            .unwrap_or_default();
+        let tag_score: f64 = self
+            .tag_scores
+            .iter()
+            .filter(|(pattern, _)| tags.iter().any(|t| pattern.matches(t)))
+            .map(|(_, s)| s)
+            .sum();
+        let computers: Vec<&str> = computer.split(" ¦ ").collect();
+        self.modifiers
+            .iter()
+            .filter(|m| {
+                (!m.critical_system || computers.iter().any(|c| critical_systems.contains(*c)))
+                    && (m.rule_ids.is_empty() || m.rule_ids.iter().any(|p| p.matches(rule_id)))
+                    && (m.computers.is_empty()
+                        || computers
+                            .iter()
+                            .any(|c| m.computers.iter().any(|p| p.matches(c))))
+                    && (m.users.is_empty()
+                        || user.is_some_and(|u| m.users.iter().any(|p| p.matches(u))))
+                    && (m.tags.is_empty()
+                        || tags.iter().any(|t| m.tags.iter().any(|p| p.matches(t))))
+            })
+            .fold(level_score + tag_score, |score, m| {
+                score * m.multiplier + m.add
+            })
+    }
+
+    /// 検知のスコアをコンピュータ名ごとと、ユーザー名ごとに追加する
+    pub fn add(
+        &self,
+        time: DateTime<Utc>,
+        rule_title: &str,
+        computer: &str,
+        user: Option<&str>,
+        score: f64,
+    ) {
+        if score <= 0.0 {
+            return;
+        }
+        let entry = |entity_type, entity: &str| RiskEntry {
+            entity_type,
+            entity: entity.to_string(),
+            time,
+            rule_title: rule_title.to_string(),
+            computer: computer.to_string(),
+            score,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        // 相関ルールのアラートは複数のコンピュータ名が結合されている
+        for c in computer.split(" ¦ ").filter(|c| !c.is_empty() && *c != "-") {
+            entries.push(entry(EntityType::Computer, c));
+        }
+        if let Some(user) = user {
+            entries.push(entry(EntityType::User, user));
+        }
+    }
+
+    /// 集計したスコアを削除する
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// エンティティごとのスコアの合計を多い順に返す
+    pub fn total_scores(&self, entity_type: EntityType) -> Vec<(String, f64)> {
+        let entries = self.entries.lock().unwrap();
+        let mut totals: HashMap<String, f64> = HashMap::new();
+        for e in entries.iter().filter(|e| e.entity_type == entity_type) {
+            *totals.entry(e.entity.clone()).or_default() += e.score;
+        }
+        totals
+            .into_iter()
+            .sorted_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+            .collect()
+    }
+
+    /// エンティティごとに時刻順に並べ、window内のスコアの合計がしきい値以上になるたびにアラートを作成する
+    pub fn alerts(&self) -> Vec<RiskAlert> {
+        let entries = self.entries.lock().unwrap();
+        let mut entities: HashMap<(EntityType, String), Vec<&RiskEntry>> = HashMap::new();
+        for e in entries.iter() {
+            entities
+                .entry((e.entity_type, e.entity.to_lowercase()))
+                .or_default()
+                .push(e);
+        }
+        let window = TimeDelta::seconds(self.window_sec);
+        let mut alerts = vec![];
+        for (_, mut entity_entries) in entities {
+            entity_entries.sort_by_key(|e| e.time);
+            let mut start = 0;
+            let mut sum = 0.0;
+            for i in 0..entity_entries.len() {
+                sum += entity_entries[i].score;
+                while entity_entries[i].time - entity_entries[start].time > window {
+                    sum -= entity_entries[start].score;
+                    start += 1;
+                }
+                if sum >= self.threshold {
+                    alerts.push(create_alert(&entity_entries[start..=i], sum));
+                    // しきい値を超えた検知は次のアラートのスコアに含めない
+                    sum = 0.0;
+                    start = i + 1;
+                }
+            }
+        }
+        alerts.sort_by(|a, b| {
+            (a.end, a.entity_type, &a.entity).cmp(&(b.end, b.entity_type, &b.entity))
+        });
+        alerts
+    }
+}
+
+impl RiskAlert {
+    /// しきい値を超えたエンティティを疑似的なルールの検知結果として出力するためのルールを作成する
+    pub fn to_rule_node(&self, level: &LEVEL, window: &str) -> RuleNode {
+        let rules = self
+            .rules
+            .iter()
+            .map(|(title, cnt)| format!("{title} ({cnt})"))
+            .join(" / ");
+        // エンティティ名やルール名に%が含まれるとフィールドのプレースホルダーとして解釈されるため置き換える
+        let details = format!(
+            "Entity: {} ¦ Type: {} ¦ Score: {} ¦ Window: {window} ¦ Rules: {rules}",
+            self.entity,
+            self.entity_type.to_str(),
+            format_score(self.score)
+        )
+        .replace('%', "％");
+        let mut yaml = YamlLoader::load_from_str(&format!(
+            "title: 'Risk Threshold Exceeded'\nid: {RISK_RULE_ID}\nlevel: {}\nstatus: stable\nauthor: Risk Scoring\n",
+            level.to_full()
+        ))
+        .unwrap()
+        .remove(0);
+        if let Yaml::Hash(hash) = &mut yaml {
+            hash.insert(Yaml::from_str("details"), Yaml::String(details));
+        }
+        RuleNode::new("risk-scoring".to_string(), yaml)
+    }
+
+    /// 疑似的なルールの検知結果に出力するレコード。最後に寄与した検知の時刻とコンピュータ名を使用する
+    pub fn to_record(&self) -> Value {
+        let time = self.end.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string();
+        let computer = match self.entity_type {
+            EntityType::Computer => self.entity.clone(),
+            EntityType::User => self.computers.join(" ¦ "),
+        };
+        json!({
+            "Event": {
+                "System": {
+                    "Computer": computer,
+                    "Channel": "-",
+                    "EventID": "-",
+                    "@timestamp": time,
+                    "TimeCreated_attributes": {"SystemTime": time},
+                },
+            },
+        })
+    }
+}
+
+/// 小数点以下がない場合は整数として表示する
+pub fn format_score(score: f64) -> String {
+    if score.fract() == 0.0 {
+        format!("{score:.0}")
+    } else {
+        format!("{score:.1}")
+    }
+}
+
+fn create_alert(entries: &[&RiskEntry], score: f64) -> RiskAlert {
+    let mut rule_counts: HashMap<&str, usize> = HashMap::new();
+    for e in entries {
+        *rule_counts.entry(e.rule_title.as_str()).or_default() += 1;
+    }
+    RiskAlert {
+        entity_type: entries[0].entity_type,
+        entity: entries[0].entity.clone(),
+        score,
+        start: entries[0].time,
+        end: entries[entries.len() - 1].time,
+        computers: entries
+            .iter()
+            .flat_map(|e| e.computer.split(" ¦ "))
+            .filter(|c| *c != "-")
+            .unique()
+            .sorted()
+            .map(String::from)
+            .collect(),
+        rules: rule_counts
+            .into_iter()
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)))
+            .map(|(title, cnt)| (title.to_string(), cnt))
+            .collect(),
+    }
+}
+
+fn parse_modifier(entry: &Yaml) -> Result<RiskModifier, String> {
+    if entry.as_hash().is_none() {
+        return Err("Each modifier must be a map.".to_string());
+    }
+    let multiplier = match &entry["multiplier"] {
+        Yaml::BadValue | Yaml::Null => 1.0,
+        v => to_f64(v).ok_or_else(|| "The multiplier must be a number.".to_string())?,
+    };
+    let add = match &entry["add"] {
+        Yaml::BadValue | Yaml::Null => 0.0,
+        v => to_f64(v).ok_or_else(|| "The add value must be a number.".to_string())?,
+    };
+    Ok(RiskModifier {
+        critical_system: entry["critical_system"].as_bool().unwrap_or(false),
+        rule_ids: patterns(&entry["id"], "id")?,
+        computers: patterns(&entry["computer"], "computer")?,
+        users: patterns(&entry["user"], "user")?,
+        tags: patterns(&entry["tags"], "tags")?,
+        multiplier,
+        add,
+    })
+}
+
+fn to_f64(value: &Yaml) -> Option<f64> {
+    match value {
+        Yaml::Integer(i) => Some(*i as f64),
+        Yaml::Real(_) => value.as_f64(),
+        _ => None,
+    }
+}
+
+/// 30m、24h、7dのような時間を秒数に変換する
+pub fn parse_window(window: &str) -> Result<i64, String> {
+    let window = window.trim();
+    let invalid = || format!("Invalid window: {window} (ex: 30m, 24h, 7d)");
+    let (num, unit) = window.split_at(window.len().saturating_sub(1));
+    let num: i64 = num.parse().map_err(|_| invalid())?;
+    let sec = match unit {
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 3600,
+        "d" => num * 86400,
+        _ => return Err(invalid()),
+    };
+    if sec <= 0 {
+        return Err(invalid());
+    }
+    Ok(sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 0, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_risk_score() {
+        let content = r#"
+threshold: 50
+window: 10m
+levels:
+    medium: 20
+tags:
+    attack.credential_access: 10
+    attack.t1003*: 5
+modifiers:
+    - critical_system: true
+      multiplier: 2
+    - user: 'adm_*'
+      add: 5
+    - id: noisy-rule
+      multiplier: 0
+"#;
+        let risk_scoring = RiskScoring::parse(content).unwrap();
+        let critical_systems = HashSet::from(["DC01".to_string()]);
+        let tags = ["attack.credential_access", "attack.t1003.001"];
+        assert_eq!(
+            risk_scoring.score(&LEVEL::MEDIUM, ("id", &[]), "PC01", None, &critical_systems),
+            20.0
+        );
+        assert_eq!(
+            risk_scoring.score(&LEVEL::HIGH, ("id", &tags), "PC01", None, &critical_systems),
+            55.0
+        );
+        assert_eq!(
+            risk_scoring.score(
+                &LEVEL::MEDIUM,
+                ("id", &tags),
+                "DC01",
+                Some("adm_taro"),
+                &critical_systems
+            ),
+            75.0
+        );
+        assert_eq!(
+            risk_scoring.score(
+                &LEVEL::HIGH,
+                ("noisy-rule", &[]),
+                "PC01",
+                None,
+                &critical_systems
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_risk_alerts() {
+        let risk_scoring = RiskScoring::parse("threshold: 50\nwindow: 10m").unwrap();
+        // PC01は10分以内に50点に達するが、PC02は10分を超えて分散している
+        risk_scoring.add(time(0), "Rule A", "PC01", Some("taro"), 20.0);
+        risk_scoring.add(time(5), "Rule B", "PC01", None, 20.0);
+        risk_scoring.add(time(8), "Rule A", "pc01", None, 15.0);
+        risk_scoring.add(time(0), "Rule A", "PC02", Some("taro"), 20.0);
+        risk_scoring.add(time(11), "Rule A", "PC02", None, 20.0);
+        risk_scoring.add(time(22), "Rule A", "PC02", None, 20.0);
+        let alerts = risk_scoring.alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].entity_type, EntityType::Computer);
+        assert_eq!(alerts[0].entity, "PC01");
+        assert_eq!(alerts[0].score, 55.0);
+        assert_eq!((alerts[0].start, alerts[0].end), (time(0), time(8)));
+        assert_eq!(alerts[0].computers, vec!["PC01", "pc01"]);
+        assert_eq!(
+            alerts[0].rules,
+            vec![("Rule A".to_string(), 2), ("Rule B".to_string(), 1)]
+        );
+        assert_eq!(
+            risk_scoring.total_scores(EntityType::Computer)[0],
+            ("PC02".to_string(), 60.0)
+        );
+        assert_eq!(
+            risk_scoring.total_scores(EntityType::User),
+            vec![("taro".to_string(), 40.0)]
+        );
+    }
+
+    #[test]
+    fn test_parse_risk_scoring_error() {
+        assert_eq!(
+            RiskScoring::parse("- threshold: 10").unwrap_err(),
+            "The risk scoring file must be a map."
+        );
+        assert_eq!(
+            RiskScoring::parse("window: 1w").unwrap_err(),
+            "Invalid window: 1w (ex: 30m, 24h, 7d)"
+        );
+        assert_eq!(
+            RiskScoring::parse("levels:\n    severe: 10").unwrap_err(),
+            "Invalid level: severe"
+        );
+        assert_eq!(
+            RiskScoring::parse("modifiers:\n    - multiplier: x").unwrap_err(),
+            "Modifier 1: The multiplier must be a number."
+        );
+    }
+}
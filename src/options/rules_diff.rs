@@ -0,0 +1,509 @@
+use crate::detections::utils::{
+    contains_str, get_writable_color, is_rule_test_file, write_color_buffer,
+};
+use crate::yaml::ParseYaml;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use num_format::{Locale, ToFormattedString};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use walkdir::WalkDir;
+use yaml_rust2::{Yaml, YamlLoader};
+
+/// 検知ロジックに関わるキー。これらが変更された場合は検知ロジックの変更として扱う
+const LOGIC_KEYS: [&str; 3] = ["detection", "correlation", "logsource"];
+
+/// 比較時に無視するキー。日付のみの変更はルールの変更として扱わない。--delta-fromのルールのハッシュでも同じキーを無視する
+const IGNORE_KEYS: [&str; 2] = ["date", "modified"];
+
+#[derive(Debug, Clone)]
+pub struct DiffRule {
+    pub id: String,
+    pub title: String,
+    pub level: String,
+    pub status: String,
+    pub path: String,
+    pub yaml: Yaml,
+}
+
+impl DiffRule {
+    fn new(path: &str, yaml: Yaml) -> Option<Self> {
+        let id = yaml["id"].as_str()?.to_string();
+        Some(DiffRule {
+            id,
+            title: yaml["title"].as_str().unwrap_or_default().to_string(),
+            level: yaml["level"].as_str().unwrap_or_default().to_string(),
+            status: yaml["status"].as_str().unwrap_or_default().to_string(),
+            path: path.to_string(),
+            yaml,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "title": self.title,
+            "level": self.level,
+            "status": self.status,
+            "path": self.path,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModifiedRule {
+    pub id: String,
+    pub title: String,
+    pub level: String,
+    pub path: String,
+    pub title_change: Option<(String, String)>,
+    pub level_change: Option<(String, String)>,
+    pub status_change: Option<(String, String)>,
+    pub logic_changed: bool,
+    pub changed_fields: Vec<String>,
+}
+
+impl ModifiedRule {
+    fn to_json(&self) -> Value {
+        let change_json = |change: &Option<(String, String)>| {
+            change
+                .as_ref()
+                .map(|(old, new)| json!({"old": old, "new": new}))
+        };
+        json!({
+            "id": self.id,
+            "title": self.title,
+            "level": self.level,
+            "path": self.path,
+            "title_change": change_json(&self.title_change),
+            "level_change": change_json(&self.level_change),
+            "status_change": change_json(&self.status_change),
+            "logic_changed": self.logic_changed,
+            "changed_fields": self.changed_fields,
+        })
+    }
+
+    fn change_details(&self) -> Vec<String> {
+        let mut details = vec![];
+        for (name, change) in [
+            ("Title", &self.title_change),
+            ("Level", &self.level_change),
+            ("Status", &self.status_change),
+        ] {
+            if let Some((old, new)) = change {
+                details.push(format!("{name}: {old} -> {new}"));
+            }
+        }
+        if self.logic_changed {
+            details.push("Detection logic changed".to_string());
+        }
+        let other_fields: Vec<&str> = self
+            .changed_fields
+            .iter()
+            .map(|f| f.as_str())
+            .filter(|f| !["title", "level", "status"].contains(f) && !LOGIC_KEYS.contains(f))
+            .collect();
+        if !other_fields.is_empty() {
+            details.push(format!("Other fields: {}", other_fields.join(", ")));
+        }
+        details
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RulesDiff {
+    pub old_source: String,
+    pub new_source: String,
+    pub new_rules: Vec<DiffRule>,
+    pub removed_rules: Vec<DiffRule>,
+    pub modified_rules: Vec<ModifiedRule>,
+    pub unchanged_cnt: usize,
+}
+
+impl RulesDiff {
+    /// ルールIDをキーにして2つのルールセットを比較する
+    pub fn new(
+        (old_source, old_rules): (&str, BTreeMap<String, DiffRule>),
+        (new_source, mut new_rules): (&str, BTreeMap<String, DiffRule>),
+    ) -> Self {
+        let mut diff = RulesDiff {
+            old_source: old_source.to_string(),
+            new_source: new_source.to_string(),
+            ..Default::default()
+        };
+        for (id, old_rule) in old_rules {
+            match new_rules.remove(&id) {
+                Some(new_rule) => match compare_rule(&old_rule, &new_rule) {
+                    Some(modified) => diff.modified_rules.push(modified),
+                    None => diff.unchanged_cnt += 1,
+                },
+                None => diff.removed_rules.push(old_rule),
+            }
+        }
+        diff.new_rules = new_rules.into_values().collect();
+        diff
+    }
+
+    pub fn level_changed_cnt(&self) -> usize {
+        self.modified_rules
+            .iter()
+            .filter(|r| r.level_change.is_some())
+            .count()
+    }
+
+    pub fn status_changed_cnt(&self) -> usize {
+        self.modified_rules
+            .iter()
+            .filter(|r| r.status_change.is_some())
+            .count()
+    }
+
+    pub fn logic_changed_cnt(&self) -> usize {
+        self.modified_rules
+            .iter()
+            .filter(|r| r.logic_changed)
+            .count()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "old": self.old_source,
+            "new": self.new_source,
+            "summary": {
+                "new": self.new_rules.len(),
+                "removed": self.removed_rules.len(),
+                "modified": self.modified_rules.len(),
+                "level_changed": self.level_changed_cnt(),
+                "status_changed": self.status_changed_cnt(),
+                "logic_changed": self.logic_changed_cnt(),
+                "unchanged": self.unchanged_cnt,
+            },
+            "new_rules": self.new_rules.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+            "removed_rules": self.removed_rules.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+            "modified_rules": self.modified_rules.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// 比較対象がディレクトリとして存在する場合はディレクトリから、存在しない場合はgitのリビジョンとしてリポジトリから読み込む
+pub fn load_rules(
+    source: &str,
+    repo_path: &Path,
+) -> Result<BTreeMap<String, DiffRule>, Box<dyn Error>> {
+    let mut rules = BTreeMap::new();
+    if Path::new(source).is_dir() {
+        for entry in WalkDir::new(source)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let path = entry.path();
+            if !path.is_file() || !is_target_rule_path(&path.to_string_lossy()) {
+                continue;
+            }
+            if let Ok(content) = ParseYaml::read_file(&path.to_path_buf()) {
+                add_rule_docs(&mut rules, &path.to_string_lossy(), &content);
+            }
+        }
+        return Ok(rules);
+    }
+
+    let repo = Repository::open(repo_path).map_err(|e| {
+        format!(
+            "{source} is not a directory and {} could not be opened as a git repository. {}",
+            repo_path.display(),
+            e.message()
+        )
+    })?;
+    let tree = repo
+        .revparse_single(source)
+        .and_then(|obj| obj.peel_to_tree())
+        .map_err(|e| format!("Failed to find the git revision {source}. {}", e.message()))?;
+    let mut contents = vec![];
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let path = format!("{dir}{}", entry.name().unwrap_or_default());
+        if entry.kind() == Some(ObjectType::Blob) && is_target_rule_path(&path) {
+            if let Ok(blob) = entry.to_object(&repo).and_then(|o| o.peel_to_blob()) {
+                contents.push((path, String::from_utf8_lossy(blob.content()).to_string()));
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    for (path, content) in contents {
+        add_rule_docs(&mut rules, &format!("{source}:{path}"), &content);
+    }
+    Ok(rules)
+}
+
+/// ルール読み込み時と同様に、テスト用のファイルなどのルール以外のymlファイルは比較対象外とする
+fn is_target_rule_path(path: &str) -> bool {
+    Path::new(path).extension().unwrap_or_default() == "yml"
+        && !contains_str(path, "/.git/")
+        && !contains_str(path, "\\.git\\")
+        && !contains_str(path, "tools/sigmac/test_files")
+        && !contains_str(path, "tools\\sigmac\\test_files")
+        && !is_rule_test_file(Path::new(path))
+}
+
+fn add_rule_docs(rules: &mut BTreeMap<String, DiffRule>, path: &str, content: &str) {
+    let Ok(docs) = YamlLoader::load_from_str(content) else {
+        return;
+    };
+    for doc in docs {
+        if let Some(rule) = DiffRule::new(path, doc) {
+            rules.insert(rule.id.clone(), rule);
+        }
+    }
+}
+
+/// 2つのルールを比較し、差分がある場合は変更内容を返す
+fn compare_rule(old: &DiffRule, new: &DiffRule) -> Option<ModifiedRule> {
+    let empty = Default::default();
+    let old_hash = old.yaml.as_hash().unwrap_or(&empty);
+    let new_hash = new.yaml.as_hash().unwrap_or(&empty);
+    let mut changed_fields = vec![];
+    for key in old_hash.keys().chain(new_hash.keys()) {
+        let Some(key_str) = key.as_str() else {
+            continue;
+        };
+        if is_ignored_key(key_str) || changed_fields.iter().any(|f| f == key_str) {
+            continue;
+        }
+        if !yaml_eq(&old.yaml[key_str], &new.yaml[key_str]) {
+            changed_fields.push(key_str.to_string());
+        }
+    }
+    if changed_fields.is_empty() {
+        return None;
+    }
+    let change = |field: &str, old_value: &str, new_value: &str| {
+        changed_fields
+            .iter()
+            .any(|f| f == field)
+            .then(|| (old_value.to_string(), new_value.to_string()))
+    };
+    Some(ModifiedRule {
+        id: new.id.clone(),
+        title: new.title.clone(),
+        level: new.level.clone(),
+        path: new.path.clone(),
+        title_change: change("title", &old.title, &new.title),
+        level_change: change("level", &old.level, &new.level),
+        status_change: change("status", &old.status, &new.status),
+        logic_changed: changed_fields
+            .iter()
+            .any(|f| LOGIC_KEYS.contains(&f.as_str())),
+        changed_fields,
+    })
+}
+
+/// ルールの変更として扱わないキーかを判定する
+fn is_ignored_key(key: &str) -> bool {
+    IGNORE_KEYS.contains(&key)
+}
+
+/// キーの順番やdate/modifiedの違いに影響されないルールのSHA-256ハッシュを返す
+pub fn rule_hash(yaml: &Yaml) -> String {
+    let mut hasher = Sha256::new();
+    hash_yaml(yaml, true, &mut hasher);
+    hex::encode(hasher.finalize())
+}
+
+fn hash_yaml(yaml: &Yaml, is_root: bool, hasher: &mut Sha256) {
+    match yaml {
+        Yaml::Hash(hash) => {
+            let mut entries: Vec<(String, &Yaml)> = hash
+                .iter()
+                .filter(|(k, _)| !is_root || !k.as_str().is_some_and(is_ignored_key))
+                .map(|(k, v)| (format!("{k:?}"), v))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            hasher.update(b"{");
+            for (k, v) in entries {
+                hasher.update(k.as_bytes());
+                hasher.update(b":");
+                hash_yaml(v, false, hasher);
+                hasher.update(b",");
+            }
+            hasher.update(b"}");
+        }
+        Yaml::Array(items) => {
+            hasher.update(b"[");
+            for item in items {
+                hash_yaml(item, false, hasher);
+                hasher.update(b",");
+            }
+            hasher.update(b"]");
+        }
+        _ => hasher.update(format!("{yaml:?}").as_bytes()),
+    }
+}
+
+/// キーの順番の違いは無視してYAMLの値を比較する
+fn yaml_eq(a: &Yaml, b: &Yaml) -> bool {
+    match (a, b) {
+        (Yaml::Hash(a), Yaml::Hash(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(k, v)| b.get(k).is_some_and(|b_v| yaml_eq(v, b_v)))
+        }
+        (Yaml::Array(a), Yaml::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| yaml_eq(a, b))
+        }
+        _ => a == b,
+    }
+}
+
+pub fn output_rules_diff(
+    diff: &RulesDiff,
+    out_path: Option<&PathBuf>,
+    no_color: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !diff.new_rules.is_empty()
+        || !diff.removed_rules.is_empty()
+        || !diff.modified_rules.is_empty()
+    {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+            .set_header(["Change", "Rule", "Level", "Details"]);
+        let change_cell = |change: &str, color: comfy_table::Color| {
+            Cell::new(change).fg(if no_color {
+                comfy_table::Color::Reset
+            } else {
+                color
+            })
+        };
+        for rule in &diff.new_rules {
+            table.add_row(vec![
+                change_cell("New", comfy_table::Color::Green),
+                Cell::new(&rule.title),
+                Cell::new(&rule.level),
+                Cell::new(&rule.path),
+            ]);
+        }
+        for rule in &diff.modified_rules {
+            table.add_row(vec![
+                change_cell("Modified", comfy_table::Color::Yellow),
+                Cell::new(&rule.title),
+                Cell::new(&rule.level),
+                Cell::new(rule.change_details().join("\n")),
+            ]);
+        }
+        for rule in &diff.removed_rules {
+            table.add_row(vec![
+                change_cell("Removed", comfy_table::Color::Red),
+                Cell::new(&rule.title),
+                Cell::new(&rule.level),
+                Cell::new(&rule.path),
+            ]);
+        }
+        println!("{table}");
+    }
+    write_color_buffer(
+        &BufferWriter::stdout(ColorChoice::Always),
+        get_writable_color(Some(Color::Rgb(0, 255, 0)), no_color),
+        &format!(
+            "New: {} ¦ Removed: {} ¦ Modified: {} (Level: {} ¦ Status: {} ¦ Detection logic: {}) ¦ Unchanged: {}",
+            diff.new_rules.len().to_formatted_string(&Locale::en),
+            diff.removed_rules.len().to_formatted_string(&Locale::en),
+            diff.modified_rules.len().to_formatted_string(&Locale::en),
+            diff.level_changed_cnt().to_formatted_string(&Locale::en),
+            diff.status_changed_cnt().to_formatted_string(&Locale::en),
+            diff.logic_changed_cnt().to_formatted_string(&Locale::en),
+            diff.unchanged_cnt.to_formatted_string(&Locale::en),
+        ),
+        true,
+    )
+    .ok();
+    println!();
+    if let Some(out_path) = out_path {
+        fs::write(out_path, serde_json::to_string_pretty(&diff.to_json())?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::rules_diff::{RulesDiff, load_rules};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_rules_diff() {
+        let dir = Path::new("./test_rules_diff");
+        let rule = |id: &str, level: &str, status: &str, event_id: u32, tags: &str| {
+            format!(
+                "title: Rule {id}\nid: {id}\nauthor: test\nlevel: {level}\nstatus: {status}\ndate: 2024/01/01\ntags:\n    - {tags}\nlogsource:\n    product: windows\ndetection:\n    selection:\n        Channel: Security\n        EventID: {event_id}\n    condition: selection\n"
+            )
+        };
+        for d in ["old", "new"] {
+            fs::create_dir_all(dir.join(d)).unwrap();
+        }
+        fs::write(dir.join("old/1.yml"), rule("rule-1", "high", "test", 1, "a")).unwrap();
+        fs::write(dir.join("old/2.yml"), rule("rule-2", "high", "test", 2, "a")).unwrap();
+        fs::write(dir.join("old/3.yml"), rule("rule-3", "low", "test", 3, "a")).unwrap();
+        fs::write(dir.join("old/4.yml"), rule("rule-4", "low", "test", 4, "a")).unwrap();
+        // 1: レベルとステータスの変更、2: 検知ロジックの変更、3: 削除、4: 日付とキーの順番のみの変更、5: 追加
+        fs::write(
+            dir.join("new/1.yml"),
+            rule("rule-1", "critical", "stable", 1, "a"),
+        )
+        .unwrap();
+        fs::write(dir.join("new/2.yml"), rule("rule-2", "high", "test", 20, "b")).unwrap();
+        fs::write(
+            dir.join("new/4.yml"),
+            rule("rule-4", "low", "test", 4, "a")
+                .replace("date: 2024/01/01", "date: 2025/01/01")
+                .replace(
+                    "        Channel: Security\n        EventID: 4\n",
+                    "        EventID: 4\n        Channel: Security\n",
+                ),
+        )
+        .unwrap();
+        fs::write(dir.join("new/5.yml"), rule("rule-5", "medium", "test", 5, "a")).unwrap();
+        fs::write(dir.join("new/5.tests.yml"), "tests: []\n").unwrap();
+
+        let old_rules = load_rules(dir.join("old").to_str().unwrap(), Path::new("./rules"));
+        let new_rules = load_rules(dir.join("new").to_str().unwrap(), Path::new("./rules"));
+        fs::remove_dir_all(dir).ok();
+        let diff = RulesDiff::new(("old", old_rules.unwrap()), ("new", new_rules.unwrap()));
+
+        assert_eq!(diff.new_rules.len(), 1);
+        assert_eq!(diff.new_rules[0].id, "rule-5");
+        assert_eq!(diff.removed_rules.len(), 1);
+        assert_eq!(diff.removed_rules[0].id, "rule-3");
+        assert_eq!(diff.unchanged_cnt, 1);
+        assert_eq!(diff.modified_rules.len(), 2);
+        let modified_1 = &diff.modified_rules[0];
+        assert_eq!(
+            modified_1.level_change,
+            Some(("high".to_string(), "critical".to_string()))
+        );
+        assert_eq!(
+            modified_1.status_change,
+            Some(("test".to_string(), "stable".to_string()))
+        );
+        assert!(!modified_1.logic_changed);
+        let modified_2 = &diff.modified_rules[1];
+        assert!(modified_2.level_change.is_none());
+        assert!(modified_2.logic_changed);
+        assert_eq!(modified_2.changed_fields, vec!["tags", "detection"]);
+        assert_eq!(diff.to_json()["summary"]["logic_changed"], 1);
+    }
+
+    #[test]
+    fn test_load_rules_from_invalid_revision() {
+        let actual = load_rules("not-exist-revision", Path::new("./not_exist_repo"));
+        assert!(actual.is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use hashbrown::HashMap;
+use serde_json::{Value, json};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_VERSION: u64 = 1;
+
+/// 完了したファイルまでのスキャン全体の累計の状態
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanState {
+    pub record_cnt: u128,
+    pub recover_record_cnt: u128,
+    pub total_record_cnt: usize,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    /// EventMetrics::stats_listと同じ(EventID, Channel)ごとのレコード数
+    pub stats_list: HashMap<(CompactString, CompactString), usize>,
+}
+
+/// スキャンが完了したファイルと、そのファイルでルールに一致したレコード
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletedFile {
+    pub path: String,
+    pub chunks: Vec<Vec<(Value, bool)>>,
+}
+
+/// 中断したスキャンを再開するためのチェックポイントファイル。
+/// 1行目にスキャン条件、以降は一致したレコードのチャンクとファイル完了の行を追記していく
+pub struct ScanCheckpoint {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    pub completed_files: Vec<CompletedFile>,
+    pub state: ScanState,
+}
+
+impl ScanCheckpoint {
+    /// チェックポイントファイルを作成する。resumeの場合は既存のファイルから完了済みのファイルを読み込む
+    pub fn open(
+        path: &Path,
+        args: &[String],
+        files: &[PathBuf],
+        resume: bool,
+    ) -> Result<Self, String> {
+        let header = json!({
+            "version": CHECKPOINT_VERSION,
+            "args": args,
+            "files": files.iter().map(|f| json!({
+                "path": f.to_string_lossy(),
+                "size": fs::metadata(f).map(|m| m.len()).unwrap_or_default(),
+            })).collect::<Vec<_>>(),
+        });
+        if resume && path.exists() {
+            let (completed_files, state, valid_len) = read_checkpoint(path, &header)?;
+            // 途中までしか書き込まれていないファイルのチャンクは破棄して、そのファイルから再スキャンする
+            let file = OpenOptions::new()
+                .append(true)
+                .open(path)
+                .and_then(|f| f.set_len(valid_len).map(|_| f))
+                .map_err(|e| format!("Failed to open the checkpoint {}. {e}", path.display()))?;
+            return Ok(ScanCheckpoint {
+                path: path.to_path_buf(),
+                writer: BufWriter::new(file),
+                completed_files,
+                state,
+            });
+        }
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create the checkpoint {}. {e}", path.display()))?;
+        let mut checkpoint = ScanCheckpoint {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            completed_files: vec![],
+            state: ScanState::default(),
+        };
+        checkpoint
+            .write_line(&header)
+            .map_err(|e| format!("Failed to write the checkpoint {}. {e}", path.display()))?;
+        Ok(checkpoint)
+    }
+
+    pub fn is_completed(&self, path: &str) -> bool {
+        self.completed_files.iter().any(|f| f.path == path)
+    }
+
+    /// 1ファイル分のスキャン結果を追記する。ファイル完了の行まで書き込まれたファイルだけが再開時にスキップされる
+    pub fn save_file(
+        &mut self,
+        path: &str,
+        chunks: &[Vec<(Value, bool)>],
+        state: &ScanState,
+    ) -> std::io::Result<()> {
+        for chunk in chunks {
+            let records: Vec<Value> = chunk
+                .iter()
+                .map(|(record, recovered)| json!([record, recovered]))
+                .collect();
+            self.write_line(&json!({ "chunk": records }))?;
+        }
+        let mut event_types: Vec<Value> = state
+            .stats_list
+            .iter()
+            .map(|((eid, ch), cnt)| json!([eid.as_str(), ch.as_str(), cnt]))
+            .collect();
+        event_types.sort_by_key(|v| v.to_string());
+        self.write_line(&json!({
+            "file": path,
+            "record_cnt": state.record_cnt.to_string(),
+            "recover_record_cnt": state.recover_record_cnt.to_string(),
+            "total_record_cnt": state.total_record_cnt,
+            "start_time": state.start_time.map(|t| t.to_rfc3339()),
+            "end_time": state.end_time.map(|t| t.to_rfc3339()),
+            "event_types": event_types,
+        }))?;
+        self.writer.flush()
+    }
+
+    /// スキャンが最後まで完了したらチェックポイントファイルを削除する
+    pub fn remove(self) -> std::io::Result<()> {
+        drop(self.writer);
+        fs::remove_file(&self.path)
+    }
+
+    fn write_line(&mut self, value: &Value) -> std::io::Result<()> {
+        writeln!(self.writer, "{value}")?;
+        self.writer.flush()
+    }
+}
+
+/// チェックポイントファイルを読み込み、完了済みのファイル、最後の状態、有効なデータの長さを返す
+fn read_checkpoint(
+    path: &Path,
+    header: &Value,
+) -> Result<(Vec<CompletedFile>, ScanState, u64), String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to read the checkpoint {}. {e}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut offset = reader
+        .read_line(&mut line)
+        .map_err(|e| format!("Failed to read the checkpoint {}. {e}", path.display()))?
+        as u64;
+    let saved_header: Value = serde_json::from_str(&line).unwrap_or_default();
+    if saved_header["version"] != header["version"] {
+        return Err(format!(
+            "{} is not a checkpoint file of this version.",
+            path.display()
+        ));
+    }
+    if saved_header["args"] != header["args"] {
+        return Err(format!(
+            "The checkpoint {} was created with different options. Please run the same command as the interrupted scan.",
+            path.display()
+        ));
+    }
+    if saved_header["files"] != header["files"] {
+        return Err(format!(
+            "The event files have changed since the checkpoint {} was created.",
+            path.display()
+        ));
+    }
+    let mut completed_files = vec![];
+    let mut state = ScanState::default();
+    let mut valid_len = offset;
+    let mut chunks = vec![];
+    loop {
+        line.clear();
+        let Ok(len) = reader.read_line(&mut line) else {
+            break;
+        };
+        // 書き込み途中で中断された行は改行で終わらないため読み捨てる
+        if len == 0 || !line.ends_with('\n') {
+            break;
+        }
+        offset += len as u64;
+        let Ok(value) = serde_json::from_str::<Value>(&line) else {
+            break;
+        };
+        if let Some(records) = value["chunk"].as_array() {
+            chunks.push(
+                records
+                    .iter()
+                    .map(|r| (r[0].clone(), r[1].as_bool().unwrap_or_default()))
+                    .collect(),
+            );
+        } else if let Some(file) = value["file"].as_str() {
+            completed_files.push(CompletedFile {
+                path: file.to_string(),
+                chunks: std::mem::take(&mut chunks),
+            });
+            state = parse_state(&value);
+            valid_len = offset;
+        }
+    }
+    Ok((completed_files, state, valid_len))
+}
+
+fn parse_state(value: &Value) -> ScanState {
+    let parse_time = |v: &Value| {
+        v.as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc))
+    };
+    let parse_cnt = |v: &Value| {
+        v.as_str()
+            .and_then(|c| c.parse::<u128>().ok())
+            .unwrap_or_default()
+    };
+    let stats_list = value["event_types"]
+        .as_array()
+        .map(|types| {
+            types
+                .iter()
+                .map(|t| {
+                    (
+                        (
+                            CompactString::from(t[0].as_str().unwrap_or_default()),
+                            CompactString::from(t[1].as_str().unwrap_or_default()),
+                        ),
+                        t[2].as_u64().unwrap_or_default() as usize,
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ScanState {
+        record_cnt: parse_cnt(&value["record_cnt"]),
+        recover_record_cnt: parse_cnt(&value["recover_record_cnt"]),
+        total_record_cnt: value["total_record_cnt"].as_u64().unwrap_or_default() as usize,
+        start_time: parse_time(&value["start_time"]),
+        end_time: parse_time(&value["end_time"]),
+        stats_list,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_resume_checkpoint() {
+        let path = Path::new("./test_resume.checkpoint");
+        let args = vec!["csv-timeline".to_string(), "-d".to_string()];
+        let files = vec![
+            PathBuf::from("./test_files/evtx/a.evtx"),
+            PathBuf::from("./test_files/evtx/b.evtx"),
+        ];
+        let chunks = vec![vec![(json!({"Event": {"System": {"EventID": 1}}}), true)]];
+        let mut state = ScanState {
+            record_cnt: 10,
+            recover_record_cnt: 1,
+            total_record_cnt: 10,
+            start_time: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+            end_time: Some(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap()),
+            ..Default::default()
+        };
+        state
+            .stats_list
+            .insert(("4625".into(), "security".into()), 3);
+        let mut checkpoint = ScanCheckpoint::open(path, &args, &files, false).unwrap();
+        checkpoint.save_file("a.evtx", &chunks, &state).unwrap();
+        drop(checkpoint);
+        // 2ファイル目のスキャン中に中断された状態
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        write!(file, "{{\"chunk\":[]}}\n{{\"chunk\":[[{{\"Ev").unwrap();
+        drop(file);
+
+        let checkpoint = ScanCheckpoint::open(path, &args, &files, true).unwrap();
+        assert_eq!(
+            checkpoint.completed_files,
+            vec![CompletedFile {
+                path: "a.evtx".to_string(),
+                chunks,
+            }]
+        );
+        assert!(checkpoint.is_completed("a.evtx"));
+        assert!(!checkpoint.is_completed("b.evtx"));
+        assert_eq!(checkpoint.state, state);
+        drop(checkpoint);
+        let content = fs::read_to_string(path).unwrap();
+
+        let other_args = vec!["json-timeline".to_string()];
+        let ret = ScanCheckpoint::open(path, &other_args, &files, true);
+        fs::remove_file(path).ok();
+        assert_eq!(content.lines().count(), 3);
+        assert!(ret.is_err());
+    }
+}
@@ -0,0 +1,826 @@
+use crate::afterfact::{AfterfactInfo, output_json_str, sort_detect_info};
+use crate::detections::configs::{ServeOption, StoredStatic};
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::message::DetectInfo;
+use crate::detections::utils;
+use crate::scanner::Scanner;
+use crate::timeline::computer_metrics::{calc_elapsed_seconds, countup_event_by_computer};
+use crate::timeline::metrics::EventMetrics;
+use crate::timeline::timelines::{Timeline, replace_channel_abbr};
+use chrono::{DateTime, SecondsFormat, Utc};
+use evtx::{EvtxParser, ParserSettings};
+use hashbrown::HashMap;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+/// 1回の判定に渡すレコード数
+const MAX_DETECT_RECORDS: usize = 1000;
+/// ファイルパスを指定するリクエストボディの上限
+const MAX_JSON_BODY_SIZE: usize = 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed)
+    }
+}
+
+/// 1回のスキャンの状態と結果
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub files: Vec<PathBuf>,
+    pub uploaded: bool,
+    pub scanned_files: usize,
+    pub record_cnt: usize,
+    /// 検知結果(json-timeline -Lと同じ形式の1行ずつのJSON)
+    pub detections: Vec<String>,
+    pub errors: Vec<String>,
+    pub metrics: HashMap<&'static str, Value>,
+    pub created: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    pub fn new(files: Vec<PathBuf>, uploaded: bool) -> Self {
+        Job {
+            id: Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            files,
+            uploaded,
+            scanned_files: 0,
+            record_cnt: 0,
+            detections: vec![],
+            errors: vec![],
+            metrics: HashMap::new(),
+            created: Utc::now(),
+            started: None,
+            finished: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let time = |t: &DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Millis, true);
+        json!({
+            "id": self.id,
+            "status": self.status.as_str(),
+            "files": self.files.iter().map(|f| f.display().to_string()).collect::<Vec<_>>(),
+            "scanned_files": self.scanned_files,
+            "records": self.record_cnt,
+            "detections": self.detections.len(),
+            "errors": self.errors,
+            "created": time(&self.created),
+            "started": self.started.as_ref().map(time),
+            "finished": self.finished.as_ref().map(time),
+        })
+    }
+}
+
+/// HTTPのハンドラとスキャンを行うスレッドで共有する状態
+pub struct ServerState {
+    pub jobs: Mutex<Vec<Job>>,
+    pub rule_cnt: usize,
+    pub upload_dir: PathBuf,
+    queue: Mutex<Sender<String>>,
+}
+
+impl ServerState {
+    fn with_job<R>(&self, id: &str, f: impl FnOnce(&Job) -> R) -> Option<R> {
+        self.jobs.lock().unwrap().iter().find(|j| j.id == id).map(f)
+    }
+
+    fn update_job(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().iter_mut().find(|j| j.id == id) {
+            f(job);
+        }
+    }
+
+    /// スキャンを登録して待ち行列に追加する
+    pub fn submit(&self, job: Job) -> Value {
+        let ret = job.to_json();
+        let id = job.id.clone();
+        self.jobs.lock().unwrap().push(job);
+        self.queue.lock().unwrap().send(id).ok();
+        ret
+    }
+}
+
+/// ルールを読み込んだ後、サーバーを起動してCtrl+Cで終了するまでリクエストを処理する
+pub fn serve(option: &ServeOption, scanner: Scanner) -> Result<(), String> {
+    let upload_dir = std::env::temp_dir().join(format!("hayabusa-serve-{}", std::process::id()));
+    fs::create_dir_all(&upload_dir)
+        .map_err(|e| format!("Failed to create {}. {e}", upload_dir.display()))?;
+    let (state, receiver) = create_state(scanner.rule_count(), upload_dir.clone());
+    let worker_state = state.clone();
+    thread::spawn(move || run_worker(scanner, worker_state, receiver));
+
+    let addr = format!("{}:{}", option.host, option.port);
+    let rt = Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("hayabusa-serve")
+        .build()
+        .map_err(|e| e.to_string())?;
+    let ret = rt.block_on(async {
+        let listener = TcpListener::bind(&addr)
+            .await
+            .map_err(|e| format!("Failed to listen on {addr}. {e}"))?;
+        println!("Listening on http://{addr} (Press Ctrl+C to stop)");
+        tokio::select! {
+            _ = accept_loop(listener, state) => Ok(()),
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    });
+    fs::remove_dir_all(&upload_dir).ok();
+    ret
+}
+
+pub fn create_state(rule_cnt: usize, upload_dir: PathBuf) -> (Arc<ServerState>, Receiver<String>) {
+    let (sender, receiver) = channel();
+    let state = Arc::new(ServerState {
+        jobs: Mutex::new(vec![]),
+        rule_cnt,
+        upload_dir,
+        queue: Mutex::new(sender),
+    });
+    (state, receiver)
+}
+
+pub async fn accept_loop(listener: TcpListener, state: Arc<ServerState>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, state).await.ok();
+        });
+    }
+}
+
+/// 待ち行列のスキャンを1つずつ実行する。ルールの集計状態を共有しないように同時には実行しない
+pub fn run_worker(mut scanner: Scanner, state: Arc<ServerState>, receiver: Receiver<String>) {
+    let metrics_static = MetricsStatic::new(scanner.stored_static());
+    while let Ok(id) = receiver.recv() {
+        let Some((files, uploaded)) = state.with_job(&id, |j| (j.files.clone(), j.uploaded)) else {
+            continue;
+        };
+        state.update_job(&id, |j| {
+            j.status = JobStatus::Running;
+            j.started = Some(Utc::now());
+        });
+        let mut metrics = JobMetrics::default();
+        for file in &files {
+            let ret = scan_file(
+                file,
+                &mut scanner,
+                &metrics_static,
+                &mut metrics,
+                &state,
+                &id,
+            );
+            state.update_job(&id, |j| {
+                j.scanned_files += 1;
+                if let Err(e) = ret {
+                    j.errors.push(e);
+                }
+            });
+        }
+        let detections = to_jsonl(scanner.finish());
+        let metrics = metrics.to_json(&metrics_static.eid);
+        state.update_job(&id, |j| {
+            j.detections.extend(detections);
+            j.metrics = metrics;
+            // 全てのファイルの読み込みに失敗した場合は失敗として扱う
+            j.status = if !j.files.is_empty() && j.errors.len() == j.files.len() {
+                JobStatus::Failed
+            } else {
+                JobStatus::Completed
+            };
+            j.finished = Some(Utc::now());
+        });
+        // アップロードされたファイルはファイルごとのディレクトリに保存している
+        if uploaded {
+            files.iter().filter_map(|f| f.parent()).for_each(|d| {
+                fs::remove_dir_all(d).ok();
+            });
+        }
+    }
+}
+
+fn scan_file(
+    path: &Path,
+    scanner: &mut Scanner,
+    metrics_static: &MetricsStatic,
+    metrics: &mut JobMetrics,
+    state: &ServerState,
+    id: &str,
+) -> Result<(), String> {
+    let filepath = path.display().to_string();
+    let mut scan_records = |records: Vec<Value>| {
+        let records = scanner.create_record_infos(records, &filepath);
+        metrics.update(&records, metrics_static);
+        let detections = to_jsonl(scanner.scan_record_infos(&records));
+        state.update_job(id, |j| {
+            j.record_cnt += records.len();
+            j.detections.extend(detections);
+        });
+    };
+    if is_json_file(path) {
+        let records = utils::read_jsonl_to_value(&filepath)
+            .or_else(|_| utils::read_json_to_value(&filepath))
+            .map_err(|e| format!("Failed to read {filepath}. {e}"))?;
+        let mut records = records.map(normalize_json_record).peekable();
+        while records.peek().is_some() {
+            scan_records(records.by_ref().take(MAX_DETECT_RECORDS).collect());
+        }
+    } else {
+        let parser =
+            EvtxParser::from_path(path).map_err(|e| format!("Failed to read {filepath}. {e}"))?;
+        let settings = ParserSettings::default()
+            .separate_json_attributes(true)
+            .num_threads(0);
+        let mut parser = parser.with_configuration(settings);
+        let mut records = parser
+            .records_json_value()
+            .filter_map(|r| r.ok().map(|r| r.data))
+            .peekable();
+        while records.peek().is_some() {
+            scan_records(records.by_ref().take(MAX_DETECT_RECORDS).collect());
+        }
+    }
+    Ok(())
+}
+
+fn to_jsonl(mut detect_infos: Vec<DetectInfo>) -> Vec<String> {
+    sort_detect_info(&mut detect_infos);
+    detect_infos
+        .iter()
+        .map(|d| {
+            let (json, _) = output_json_str(d, &mut AfterfactInfo::default(), true, false, false);
+            format!("{{ {json} }}")
+        })
+        .collect()
+}
+
+fn is_json_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("json") || e.eq_ignore_ascii_case("jsonl"))
+}
+
+/// -J, --JSON-inputと同じようにJSON形式のログをルールで判定できる形式に変換する。
+/// evtxをJSONに変換したログ(Event.System)はそのまま使用する
+fn normalize_json_record(mut data: Value) -> Value {
+    if data["Event"]["EventData"]["Event"]["System"].is_object() {
+        return data["Event"]["EventData"].take();
+    }
+    let event_data = data["Event"]["EventData"].clone();
+    let mut system = match &event_data {
+        Value::Array(values) => values.first().cloned().unwrap_or_default(),
+        value => value.clone(),
+    };
+    if let Some(system) = system.as_object_mut() {
+        system.insert(
+            "EventRecordID".to_string(),
+            event_data["RecordNumber"].clone(),
+        );
+        system.insert(
+            "Provider_attributes".to_string(),
+            json!({"Name": event_data["SourceName"].clone()}),
+        );
+        // Computer名に対応する内容はHostnameに含まれる
+        system.insert("Computer".to_string(), event_data["Hostname"].clone());
+        // Scannerはevtxと同じTimeCreated_attributes.SystemTimeからイベントの時刻を取得する
+        if !event_data["@timestamp"].is_null() {
+            system.insert(
+                "TimeCreated_attributes".to_string(),
+                json!({"SystemTime": event_data["@timestamp"].clone()}),
+            );
+        }
+    }
+    data["Event"]["System"] = system;
+    data["Event"]["UserData"] = event_data;
+    data
+}
+
+/// eid-metricsとlogon-summaryの集計で参照するフラグを設定したStoredStatic
+struct MetricsStatic {
+    eid: StoredStatic,
+    logon: StoredStatic,
+}
+
+impl MetricsStatic {
+    fn new(stored_static: &StoredStatic) -> Self {
+        let mut eid = stored_static.clone();
+        eid.metrics_flag = true;
+        let mut logon = stored_static.clone();
+        logon.logon_summary_flag = true;
+        MetricsStatic { eid, logon }
+    }
+}
+
+#[derive(Default)]
+struct JobMetrics {
+    eid: EventMetrics,
+    logon: EventMetrics,
+    computer: Timeline,
+}
+
+impl JobMetrics {
+    fn update(&mut self, records: &[EvtxRecordInfo], metrics_static: &MetricsStatic) {
+        let eid = &metrics_static.eid;
+        self.eid
+            .evt_stats_start(records, eid, (&eid.include_computer, &eid.exclude_computer));
+        self.logon.logon_stats_start(records, &metrics_static.logon);
+        for record in records {
+            countup_event_by_computer(&record.record, &eid.eventkey_alias, &mut self.computer);
+        }
+    }
+
+    fn to_json(&self, stored_static: &StoredStatic) -> HashMap<&'static str, Value> {
+        HashMap::from([
+            ("eid-metrics", eid_metrics_json(&self.eid, stored_static)),
+            ("logon-summary", logon_summary_json(&self.logon)),
+            (
+                "computer-metrics",
+                computer_metrics_json(&self.computer.stats),
+            ),
+        ])
+    }
+}
+
+fn eid_metrics_json(stats: &EventMetrics, stored_static: &StoredStatic) -> Value {
+    let time = |t: &Option<DateTime<Utc>>| {
+        t.as_ref()
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+    };
+    let mut events: Vec<_> = stats.stats_list.iter().collect();
+    events.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let events: Vec<Value> = events
+        .into_iter()
+        .map(|((event_id, channel), cnt)| {
+            let rate = *cnt as f64 / stats.total as f64;
+            let title = stored_static
+                .event_timeline_config
+                .get_event_id(channel, event_id)
+                .map(|e| e.evttitle.as_str())
+                .unwrap_or("Unknown");
+            json!({
+                "Count": cnt,
+                "Percent": (rate * 1000.0).round() / 10.0,
+                "Channel": replace_channel_abbr(stored_static, channel).trim(),
+                "EventID": event_id.replace('"', ""),
+                "Event": title,
+            })
+        })
+        .collect();
+    json!({
+        "TotalEventRecords": stats.total,
+        "FirstTimestamp": time(&stats.start_time),
+        "LastTimestamp": time(&stats.end_time),
+        "Events": events,
+    })
+}
+
+fn logon_summary_json(stats: &EventMetrics) -> Value {
+    let logons = |idx: usize| {
+        let mut logons: Vec<_> = stats
+            .stats_login_list
+            .iter()
+            .filter(|(_, cnt)| cnt[idx] > 0)
+            .collect();
+        logons.sort_by(|a, b| b.1[idx].cmp(&a.1[idx]));
+        logons
+            .into_iter()
+            .map(|(e, cnt)| {
+                json!({
+                    "Count": cnt[idx],
+                    "Event": e.channel.as_str(),
+                    "TargetAccount": e.dst_user.as_str(),
+                    "TargetDomain": e.dst_domain.as_str(),
+                    "TargetComputer": e.hostname.as_str(),
+                    "LogonType": e.logontype.as_str(),
+                    "SourceAccount": e.src_user.as_str(),
+                    "SourceDomain": e.src_domain.as_str(),
+                    "SourceComputer": e.source_computer.as_str(),
+                    "SourceIPAddress": e.source_ip.as_str(),
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    json!({"Successful": logons(0), "Failed": logons(1)})
+}
+
+fn computer_metrics_json(stats: &EventMetrics) -> Value {
+    let mut computers: Vec<_> = stats.stats_computer.iter().collect();
+    computers.sort_by(|a, b| b.1.4.cmp(&a.1.4).then(a.0.cmp(b.0)));
+    computers
+        .into_iter()
+        .map(|(computer, (os, uptime, timezone, last_timestamp, cnt))| {
+            json!({
+                "Computer": computer.as_str(),
+                "OSInformation": os.as_str(),
+                "UpTime": calc_elapsed_seconds(uptime, last_timestamp),
+                "Timezone": timezone.as_str(),
+                "Events": cnt,
+            })
+        })
+        .collect()
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub content_length: usize,
+}
+
+/// リクエストラインとヘッダーを読み込む。ボディは読み込まずに残す
+pub async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<Request, String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err("Invalid request line.".to_string());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query
+            .split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .map(|(k, v)| (percent_decode(k), percent_decode(v)))
+            .collect(),
+        content_length: 0,
+    };
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(request);
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                request.content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Invalid Content-Length.".to_string())?;
+            }
+        }
+    }
+    Err("Too many headers.".to_string())
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                if let Some(b) = s
+                    .get(i + 1..i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    ret.push(b);
+                    i += 3;
+                    continue;
+                }
+                ret.push(b'%');
+            }
+            b'+' => ret.push(b' '),
+            b => ret.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).to_string()
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<ServerState>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let request = match read_request(&mut reader).await {
+        Ok(request) => request,
+        Err(e) => return respond_error(reader.get_mut(), 400, &e).await,
+    };
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["health"]) => {
+            let body = json!({"status": "ok", "rules": state.rule_cnt});
+            respond_json(reader.get_mut(), 200, &body).await
+        }
+        ("GET", ["scans"]) => {
+            let jobs: Vec<Value> = state
+                .jobs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|j| j.to_json())
+                .collect();
+            respond_json(reader.get_mut(), 200, &Value::Array(jobs)).await
+        }
+        ("POST", ["scans"]) => match create_job(&mut reader, &request, &state).await {
+            Ok(job) => respond_json(reader.get_mut(), 202, &state.submit(job)).await,
+            Err((status, e)) => respond_error(reader.get_mut(), status, &e).await,
+        },
+        ("GET", ["scans", id]) => match state.with_job(id, Job::to_json) {
+            Some(job) => respond_json(reader.get_mut(), 200, &job).await,
+            None => respond_error(reader.get_mut(), 404, "Scan not found.").await,
+        },
+        ("DELETE", ["scans", id]) => match state.with_job(id, |j| j.status.is_finished()) {
+            Some(true) => {
+                state.jobs.lock().unwrap().retain(|j| j.id != *id);
+                respond_json(reader.get_mut(), 200, &json!({"id": id})).await
+            }
+            Some(false) => respond_error(reader.get_mut(), 409, "Scan has not finished yet.").await,
+            None => respond_error(reader.get_mut(), 404, "Scan not found.").await,
+        },
+        ("GET", ["scans", id, "detections"]) => match state.with_job(id, |_| ()) {
+            Some(_) => stream_detections(reader.get_mut(), &state, id).await,
+            None => respond_error(reader.get_mut(), 404, "Scan not found.").await,
+        },
+        ("GET", ["scans", id, "metrics", name]) => match state.with_job(id, |j| {
+            (j.status.is_finished(), j.metrics.get(name).cloned())
+        }) {
+            Some((true, metrics)) => match metrics {
+                Some(metrics) => respond_json(reader.get_mut(), 200, &metrics).await,
+                None => {
+                    let e = "Unknown metrics. Please specify eid-metrics, logon-summary or computer-metrics.";
+                    respond_error(reader.get_mut(), 404, e).await
+                }
+            },
+            Some(_) => respond_error(reader.get_mut(), 409, "Scan has not finished yet.").await,
+            None => respond_error(reader.get_mut(), 404, "Scan not found.").await,
+        },
+        _ => respond_error(reader.get_mut(), 404, "Not found.").await,
+    }
+}
+
+/// ?filename=が指定されている場合はボディをファイルとして保存し、それ以外はボディのJSONのpathのファイルまたはディレクトリをスキャンする
+async fn create_job(
+    reader: &mut BufReader<TcpStream>,
+    request: &Request,
+    state: &ServerState,
+) -> Result<Job, (u16, String)> {
+    if let Some(filename) = request.query.get("filename") {
+        let filename = Path::new(filename)
+            .file_name()
+            .ok_or((400, "Invalid filename.".to_string()))?;
+        let dir = state.upload_dir.join(Uuid::new_v4().to_string());
+        let path = dir.join(filename);
+        let save = async {
+            tokio::fs::create_dir_all(&dir).await?;
+            let mut file = File::create(&path).await?;
+            let mut body = reader.take(request.content_length as u64);
+            tokio::io::copy(&mut body, &mut file).await?;
+            file.flush().await
+        };
+        save.await
+            .map_err(|e| (500, format!("Failed to save the file. {e}")))?;
+        return Ok(Job::new(vec![path], true));
+    }
+    if request.content_length > MAX_JSON_BODY_SIZE {
+        return Err((413, "Request body is too large.".to_string()));
+    }
+    let mut body = vec![0; request.content_length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| (400, e.to_string()))?;
+    let body: Value =
+        serde_json::from_slice(&body).map_err(|e| (400, format!("Invalid JSON body. {e}")))?;
+    let Some(path) = body["path"].as_str() else {
+        return Err((
+            400,
+            "Please specify the file or directory in \"path\".".to_string(),
+        ));
+    };
+    let path = PathBuf::from(path);
+    if !path.exists() {
+        return Err((400, format!("{} does not exist.", path.display())));
+    }
+    let files = if path.is_dir() {
+        WalkDir::new(&path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.into_path())
+            .filter(|p| {
+                is_json_file(p)
+                    || p.extension()
+                        .is_some_and(|e| e.eq_ignore_ascii_case("evtx"))
+            })
+            .collect()
+    } else {
+        vec![path]
+    };
+    Ok(Job::new(files, false))
+}
+
+/// 検知結果をJSONLで返す。スキャン中の場合は終了するまで検知結果を順次送信する
+async fn stream_detections(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    id: &str,
+) -> std::io::Result<()> {
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+    stream.write_all(header.as_bytes()).await?;
+    let mut sent = 0;
+    loop {
+        let (lines, finished) = {
+            let jobs = state.jobs.lock().unwrap();
+            let Some(job) = jobs.iter().find(|j| j.id == id) else {
+                break;
+            };
+            (job.detections[sent..].to_vec(), job.status.is_finished())
+        };
+        sent += lines.len();
+        if !lines.is_empty() {
+            let chunk = lines.join("\n") + "\n";
+            stream
+                .write_all(format!("{:X}\r\n{chunk}\r\n", chunk.len()).as_bytes())
+                .await?;
+        }
+        if finished {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    stream.write_all(b"0\r\n\r\n").await
+}
+
+async fn respond_json(stream: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    let response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn respond_error(stream: &mut TcpStream, status: u16, msg: &str) -> std::io::Result<()> {
+    respond_json(stream, status, &json!({"error": msg})).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use tokio::runtime::Runtime;
+
+    fn request(addr: &str, req: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    }
+
+    fn body(res: &str) -> Value {
+        serde_json::from_str(res.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn test_read_request() {
+        let rt = Runtime::new().unwrap();
+        let raw = b"POST /scans?filename=Security%20Log.evtx HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\n\r\nbody";
+        let req = rt
+            .block_on(read_request(&mut BufReader::new(&raw[..])))
+            .unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/scans");
+        assert_eq!(req.query["filename"], "Security Log.evtx");
+        assert_eq!(req.content_length, 12);
+        assert!(
+            rt.block_on(read_request(&mut BufReader::new(&b"\r\n"[..])))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_serve_scan() {
+        let dir = std::env::temp_dir().join(format!("hayabusa_serve_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join("config")).unwrap();
+        for file in [
+            "channel_abbreviations.txt",
+            "provider_abbreviations.txt",
+            "generic_abbreviations.txt",
+            "default_details.txt",
+            "channel_eid_info.txt",
+            "target_event_IDs.txt",
+        ] {
+            fs::write(dir.join("config").join(file), "").unwrap();
+        }
+        fs::write(
+            dir.join("config/eventkey_alias.txt"),
+            "alias,event_key\nEventID,Event.System.EventID\nChannel,Event.System.Channel\nTargetUserName,Event.EventData.TargetUserName\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("logon.yml"),
+            "title: Logon Failure\nid: 00000000-0000-0000-0000-000000000201\nlevel: low\nauthor: test\nstatus: test\ndate: 2024/01/01\nlogsource:\n    product: windows\ndetection:\n    selection:\n        EventID: 4625\n    condition: selection\n",
+        )
+        .unwrap();
+        let events: Vec<String> = (0..3)
+            .map(|i| {
+                json!({"Event": {"System": {"Channel": "Security", "EventID": 4625, "Computer": "PC1", "EventRecordID": i, "TimeCreated_attributes": {"SystemTime": format!("2024-01-01T00:00:0{i}.000Z")}}, "EventData": {"TargetUserName": "admin"}}}).to_string()
+            })
+            .collect();
+        fs::write(dir.join("events.jsonl"), events.join("\n")).unwrap();
+        let scanner = Scanner::builder(&dir).build().unwrap();
+
+        let (state, receiver) = create_state(scanner.rule_count(), dir.join("upload"));
+        let worker_state = state.clone();
+        thread::spawn(move || run_worker(scanner, worker_state, receiver));
+        let rt = Runtime::new().unwrap();
+        let listener = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        rt.spawn(accept_loop(listener, state));
+
+        let path = json!({"path": dir.join("events.jsonl")}).to_string();
+        let res = request(
+            &addr,
+            &format!(
+                "POST /scans HTTP/1.1\r\nContent-Length: {}\r\n\r\n{path}",
+                path.len()
+            ),
+        );
+        assert!(res.starts_with("HTTP/1.1 202"));
+        let id = body(&res)["id"].as_str().unwrap().to_string();
+        // 検知結果の取得はスキャンが終了するまで待つ
+        let detections = request(
+            &addr,
+            &format!("GET /scans/{id}/detections HTTP/1.1\r\n\r\n"),
+        );
+        let status = body(&request(
+            &addr,
+            &format!("GET /scans/{id} HTTP/1.1\r\n\r\n"),
+        ));
+        let metrics = body(&request(
+            &addr,
+            &format!("GET /scans/{id}/metrics/logon-summary HTTP/1.1\r\n\r\n"),
+        ));
+        let not_found = request(&addr, "GET /scans/unknown HTTP/1.1\r\n\r\n");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            detections
+                .matches("\"RuleTitle\": \"Logon Failure\"")
+                .count(),
+            3
+        );
+        assert_eq!(status["status"], "completed");
+        assert_eq!(status["records"], 3);
+        assert_eq!(metrics["Failed"][0]["Count"], 3);
+        assert_eq!(metrics["Failed"][0]["TargetAccount"], "admin");
+        assert!(not_found.starts_with("HTTP/1.1 404"));
+    }
+}
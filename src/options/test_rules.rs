@@ -0,0 +1,569 @@
+use crate::detections::configs::StoredStatic;
+use crate::detections::detection::Detection;
+use crate::detections::rule::correlation_parser::parse_correlation_rules;
+use crate::detections::rule::{RuleNode, get_detection_keys};
+use crate::detections::utils::{
+    contains_str, create_rec_info, get_writable_color, is_rule_test_file, write_color_buffer,
+};
+use crate::yaml::ParseYaml;
+use crate::yaml_expand::process_yaml;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use hashbrown::HashSet;
+use nested::Nested;
+use serde_json::{Map, Value, json};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use tokio::runtime::Runtime;
+use walkdir::WalkDir;
+use yaml_rust2::{Yaml, YamlLoader};
+
+const TEST_FILE_SUFFIX: &str = ".tests.yml";
+
+#[derive(Debug, Clone)]
+struct RuleDoc {
+    path: String,
+    yaml: Yaml,
+}
+
+impl RuleDoc {
+    fn is_correlation(&self) -> bool {
+        !self.yaml["correlation"].is_badvalue()
+    }
+
+    /// 相関ルールやテストケースからの参照(id/title/name)に一致するかを判定する
+    fn is_referenced_by(&self, id_or_title: &str) -> bool {
+        ["id", "title", "name"]
+            .iter()
+            .any(|key| self.yaml[*key].as_str() == Some(id_or_title))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleTestResult {
+    pub file: String,
+    pub id: String,
+    pub title: String,
+    pub name: String,
+    pub expected: String,
+    pub detections: Option<usize>,
+    pub passed: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuleTestReport {
+    pub rules_tested: usize,
+    pub rule_files_without_tests: usize,
+    pub results: Vec<RuleTestResult>,
+}
+
+impl RuleTestReport {
+    pub fn passed_cnt(&self) -> usize {
+        self.results.iter().filter(|r| r.passed).count()
+    }
+
+    pub fn failed_cnt(&self) -> usize {
+        self.results.len() - self.passed_cnt()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rules_tested": self.rules_tested,
+            "rule_files_without_tests": self.rule_files_without_tests,
+            "tests": self.results.len(),
+            "passed": self.passed_cnt(),
+            "failed": self.failed_cnt(),
+            "results": self.results.iter().map(|r| json!({
+                "result": if r.passed { "pass" } else { "fail" },
+                "file": r.file,
+                "id": r.id,
+                "title": r.title,
+                "name": r.name,
+                "expected": r.expected,
+                "detections": r.detections,
+                "message": r.message,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// テストケース1件分の期待値
+#[derive(Debug, Clone, PartialEq)]
+enum Expectation {
+    Match(Option<usize>),
+    NoMatch,
+}
+
+impl Expectation {
+    fn parse(case: &Yaml) -> Result<Self, String> {
+        let count = match &case["count"] {
+            Yaml::BadValue => None,
+            Yaml::Integer(i) if *i >= 0 => Some(*i as usize),
+            _ => return Err("count must be a positive integer.".to_string()),
+        };
+        match case["expect"].as_str() {
+            Some("match") => Ok(Expectation::Match(count)),
+            Some("no_match") if count.is_none() || count == Some(0) => Ok(Expectation::NoMatch),
+            Some("no_match") => Err("count cannot be used with expect: no_match.".to_string()),
+            _ => Err("expect must be match or no_match.".to_string()),
+        }
+    }
+
+    fn to_str(&self) -> String {
+        match self {
+            Expectation::Match(Some(cnt)) => format!("match ({cnt})"),
+            Expectation::Match(None) => "match".to_string(),
+            Expectation::NoMatch => "no_match".to_string(),
+        }
+    }
+
+    fn check(&self, detections: usize) -> Result<(), String> {
+        match self {
+            Expectation::Match(Some(cnt)) if detections != *cnt => {
+                Err(format!("Expected {cnt} detection(s) but got {detections}."))
+            }
+            Expectation::Match(None) if detections == 0 => {
+                Err("Expected a detection but got none.".to_string())
+            }
+            Expectation::NoMatch if detections > 0 => {
+                Err(format!("Expected no detections but got {detections}."))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// ルールディレクトリ内のxxx.tests.ymlを探し、同じ名前のルール(xxx.yml)に対してサンプルイベントを流して結果を判定する
+pub fn run_rule_tests(
+    rules_dir: &Path,
+    expand_map: &std::collections::HashMap<String, Vec<String>>,
+    stored_static: &StoredStatic,
+    rt: &Runtime,
+) -> RuleTestReport {
+    let mut report = RuleTestReport::default();
+    let mut rule_docs = vec![];
+    let mut test_files = vec![];
+    for entry in WalkDir::new(rules_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        let path_str = path.to_string_lossy();
+        if !path.is_file()
+            || path.extension().unwrap_or_default() != "yml"
+            || contains_str(&path_str, "/.git/")
+            || contains_str(&path_str, "\\.git\\")
+        {
+            continue;
+        }
+        if is_rule_test_file(path) {
+            test_files.push(path.to_path_buf());
+            continue;
+        }
+        let Ok(docs) = ParseYaml::read_file(&path.to_path_buf())
+            .and_then(|c| YamlLoader::load_from_str(&c).map_err(|e| e.to_string()))
+        else {
+            continue;
+        };
+        for doc in docs {
+            let (mut expand_found, mut expand_enabled) = (false, false);
+            rule_docs.push(RuleDoc {
+                path: path_str.to_string(),
+                yaml: process_yaml(&doc, expand_map, &mut expand_found, &mut expand_enabled),
+            });
+        }
+    }
+
+    let mut rule_files: HashSet<&str> = rule_docs.iter().map(|d| d.path.as_str()).collect();
+    for test_file in &test_files {
+        let test_file_str = test_file.to_string_lossy().to_string();
+        let rule_path = format!(
+            "{}.yml",
+            test_file_str
+                .strip_suffix(TEST_FILE_SUFFIX)
+                .unwrap_or(&test_file_str)
+        );
+        rule_files.remove(rule_path.as_str());
+        let docs: Vec<&RuleDoc> = rule_docs.iter().filter(|d| d.path == rule_path).collect();
+        let cases = match load_test_cases(test_file) {
+            Ok(cases) => cases,
+            Err(e) => {
+                report.results.push(failed_result(&test_file_str, "-", e));
+                continue;
+            }
+        };
+        if docs.is_empty() {
+            let msg = format!("The rule file for this test file was not found: {rule_path}");
+            report.results.push(failed_result(&test_file_str, "-", msg));
+            continue;
+        }
+        let mut tested_ids = HashSet::new();
+        for (i, case) in cases.iter().enumerate() {
+            let name = case["name"]
+                .as_str()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("#{}", i + 1));
+            // ruleの指定がない場合は、ファイル内の相関ルールを優先してテスト対象とする
+            let target = match case["rule"].as_str() {
+                Some(rule) => docs.iter().find(|d| d.is_referenced_by(rule)).copied(),
+                None => docs
+                    .iter()
+                    .find(|d| d.is_correlation())
+                    .or(docs.first())
+                    .copied(),
+            };
+            let Some(target) = target else {
+                let msg = format!(
+                    "The rule was not found in {rule_path}: {}",
+                    case["rule"].as_str().unwrap_or_default()
+                );
+                report
+                    .results
+                    .push(failed_result(&test_file_str, &name, msg));
+                continue;
+            };
+            tested_ids.insert(target.yaml["id"].as_str().unwrap_or(&target.path));
+            let mut result = RuleTestResult {
+                file: test_file_str.clone(),
+                id: target.yaml["id"].as_str().unwrap_or_default().to_string(),
+                title: target.yaml["title"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                name,
+                expected: "-".to_string(),
+                detections: None,
+                passed: false,
+                message: String::default(),
+            };
+            let outcome = Expectation::parse(case).and_then(|expect| {
+                result.expected = expect.to_str();
+                let events = load_events(&case["events"])?;
+                let cnt = run_test_case(
+                    target,
+                    &rule_docs,
+                    events,
+                    &test_file_str,
+                    stored_static,
+                    rt,
+                )?;
+                result.detections = Some(cnt);
+                expect.check(cnt)
+            });
+            match outcome {
+                Ok(_) => result.passed = true,
+                Err(e) => result.message = e,
+            }
+            report.results.push(result);
+        }
+        report.rules_tested += tested_ids.len();
+    }
+    report.rule_files_without_tests = rule_files.len();
+    report
+}
+
+fn failed_result(file: &str, name: &str, message: String) -> RuleTestResult {
+    RuleTestResult {
+        file: file.to_string(),
+        id: String::default(),
+        title: String::default(),
+        name: name.to_string(),
+        expected: "-".to_string(),
+        detections: None,
+        passed: false,
+        message,
+    }
+}
+
+fn load_test_cases(path: &PathBuf) -> Result<Vec<Yaml>, String> {
+    let contents = ParseYaml::read_file(path)?;
+    let docs =
+        YamlLoader::load_from_str(&contents).map_err(|e| format!("Failed to parse yml: {e}"))?;
+    let cases = docs
+        .first()
+        .and_then(|doc| doc["tests"].as_vec())
+        .ok_or("The key tests was not found or is not a list.")?;
+    Ok(cases.to_vec())
+}
+
+/// テストケースのイベント(YAMLまたはJSONで記載)をevtxをJSONに変換した時と同じ形のValueにする
+fn load_events(events: &Yaml) -> Result<Vec<Value>, String> {
+    let events = events
+        .as_vec()
+        .ok_or("The key events was not found or is not a list.")?;
+    events
+        .iter()
+        .map(|event| match yaml_to_json(event) {
+            Value::Object(obj) if obj.contains_key("Event") => Ok(Value::Object(obj)),
+            _ => Err("Each event must be an object with an Event key.".to_string()),
+        })
+        .collect()
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Hash(hash) => {
+            let mut obj = Map::new();
+            for (k, v) in hash {
+                let key = match k {
+                    Yaml::String(s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Real(r) => r.clone(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => continue,
+                };
+                obj.insert(key, yaml_to_json(v));
+            }
+            Value::Object(obj)
+        }
+        Yaml::Array(items) => Value::Array(items.iter().map(yaml_to_json).collect()),
+        Yaml::String(s) => Value::String(s.clone()),
+        Yaml::Integer(i) => json!(i),
+        Yaml::Real(r) => r
+            .parse::<f64>()
+            .map(|f| json!(f))
+            .unwrap_or_else(|_| Value::String(r.clone())),
+        Yaml::Boolean(b) => Value::Bool(*b),
+        _ => Value::Null,
+    }
+}
+
+/// 相関ルールの場合は参照先のルールも含めて、テスト対象のルールに必要なルールを集める
+fn collect_rule_docs<'a>(target: &'a RuleDoc, rule_docs: &'a [RuleDoc]) -> Vec<&'a RuleDoc> {
+    let mut ret = vec![target];
+    let mut i = 0;
+    while i < ret.len() {
+        let refs: Vec<&str> = ret[i].yaml["correlation"]["rules"]
+            .as_vec()
+            .map(|v| v.iter().filter_map(|r| r.as_str()).collect())
+            .unwrap_or_default();
+        for r in refs {
+            for doc in rule_docs.iter().filter(|d| d.is_referenced_by(r)) {
+                if !ret.iter().any(|added| std::ptr::eq(*added, doc)) {
+                    ret.push(doc);
+                }
+            }
+        }
+        i += 1;
+    }
+    ret
+}
+
+/// テスト対象のルールにイベントを順番に流し、テスト対象のルールの検知数を返す
+fn run_test_case(
+    target: &RuleDoc,
+    rule_docs: &[RuleDoc],
+    events: Vec<Value>,
+    test_file: &str,
+    stored_static: &StoredStatic,
+    rt: &Runtime,
+) -> Result<usize, String> {
+    let mut rule_nodes = vec![];
+    for doc in collect_rule_docs(target, rule_docs) {
+        let mut node = RuleNode::new(doc.path.clone(), doc.yaml.clone());
+        if !doc.is_correlation() {
+            node.init(stored_static).map_err(|e| e.join(" "))?;
+        }
+        rule_nodes.push(node);
+    }
+    let mut parse_error_cnt = 0;
+    let rule_nodes = parse_correlation_rules(rule_nodes, stored_static, &mut parse_error_cnt);
+    if parse_error_cnt > 0 {
+        return Err("Failed to parse the correlation rule. Please run validate-rules.".to_string());
+    }
+    let mut keys = HashSet::new();
+    for node in &rule_nodes {
+        keys.extend(get_detection_keys(node).iter().map(|k| k.to_string()));
+    }
+    let keys: Nested<String> = keys.into_iter().collect();
+    let records = events
+        .into_iter()
+        .map(|event| {
+            create_rec_info(
+                event,
+                test_file.to_string(),
+                &keys,
+                &false,
+                &stored_static.no_pwsh_field_extraction,
+            )
+        })
+        .collect();
+    let (detection, mut detect_infos) = Detection::new(rule_nodes).start(rt, records);
+    detect_infos.extend(detection.add_aggcondition_msges(rt, stored_static));
+    let target_id = target.yaml["id"].as_str().unwrap_or_default();
+    let target_title = target.yaml["title"].as_str().unwrap_or_default();
+    Ok(detect_infos
+        .iter()
+        .filter(|d| {
+            if target_id.is_empty() {
+                d.ruletitle == target_title
+            } else {
+                d.ruleid == target_id
+            }
+        })
+        .count())
+}
+
+pub fn output_rule_test_report(
+    report: &RuleTestReport,
+    out_path: Option<&PathBuf>,
+    no_color: bool,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(out_path) = out_path {
+        fs::write(out_path, serde_json::to_string_pretty(&report.to_json())?)?;
+    } else if !report.results.is_empty() {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+            .set_header([
+                "Result",
+                "Test File",
+                "Rule",
+                "Test",
+                "Expected",
+                "Detections",
+                "Message",
+            ]);
+        for r in &report.results {
+            let (result, color) = if r.passed {
+                ("PASS", comfy_table::Color::Green)
+            } else {
+                ("FAIL", comfy_table::Color::Red)
+            };
+            let color = if no_color {
+                comfy_table::Color::Reset
+            } else {
+                color
+            };
+            let detections = r
+                .detections
+                .map(|d| d.to_string())
+                .unwrap_or("-".to_string());
+            table.add_row(vec![
+                Cell::new(result).fg(color),
+                Cell::new(&r.file),
+                Cell::new(&r.title),
+                Cell::new(&r.name),
+                Cell::new(&r.expected),
+                Cell::new(detections),
+                Cell::new(&r.message),
+            ]);
+        }
+        println!("{table}");
+    }
+    let color = if report.failed_cnt() > 0 {
+        Color::Rgb(255, 0, 0)
+    } else {
+        Color::Rgb(0, 255, 0)
+    };
+    write_color_buffer(
+        &BufferWriter::stdout(ColorChoice::Always),
+        get_writable_color(Some(color), no_color),
+        &format!(
+            "Rules tested: {} ¦ Tests: {} ¦ Passed: {} ¦ Failed: {} ¦ Rule files without tests: {}",
+            report.rules_tested,
+            report.results.len(),
+            report.passed_cnt(),
+            report.failed_cnt(),
+            report.rule_files_without_tests
+        ),
+        true,
+    )
+    .ok();
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::{
+        Action, CommonOptions, Config, STORED_EKEY_ALIAS, STORED_STATIC, StoredStatic,
+        TestRulesOption,
+    };
+    use crate::options::test_rules::run_rule_tests;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use tokio::runtime::Runtime;
+
+    fn create_dummy_stored_static() -> StoredStatic {
+        StoredStatic::create_static_data(Some(Config {
+            action: Some(Action::TestRules(TestRulesOption {
+                rules: Path::new("./test_rule_tests").to_path_buf(),
+                common_options: CommonOptions::default(),
+                ..Default::default()
+            })),
+            debug: false,
+        }))
+    }
+
+    #[test]
+    fn test_run_rule_tests() {
+        let dir = Path::new("./test_rule_tests");
+        fs::create_dir_all(dir).unwrap();
+        let header = "author: test\nlevel: high\nstatus: test\ndate: 2024/01/01\n";
+        let rule = format!(
+            "title: Logon Failure\nid: 00000000-0000-0000-0000-000000000011\n{header}logsource:\n    product: windows\ndetection:\n    selection:\n        Event.System.EventID: 4625\n    condition: selection\n"
+        );
+        let correlation = format!(
+            "title: Many Logon Failures\nid: 00000000-0000-0000-0000-000000000012\n{header}correlation:\n    type: event_count\n    rules:\n        - 00000000-0000-0000-0000-000000000011\n    group-by:\n        - Event.System.Computer\n    timespan: 5m\n    condition:\n        gte: 2\n"
+        );
+        let event = |time: &str| {
+            format!(
+                "      - {{\"Event\": {{\"System\": {{\"EventID\": 4625, \"Computer\": \"PC1\", \"TimeCreated_attributes\": {{\"SystemTime\": \"{time}\"}}}}}}}}\n"
+            )
+        };
+        let rule_tests = format!(
+            "tests:\n  - name: positive\n    expect: match\n    count: 1\n    events:\n{}  - name: negative\n    expect: no_match\n    events:\n      - Event:\n          System:\n            EventID: 4624\n",
+            event("2024-01-01T00:00:00Z")
+        );
+        let correlation_tests = format!(
+            "tests:\n  - name: burst\n    expect: match\n    count: 1\n    events:\n{}{}  - name: spread\n    expect: no_match\n    events:\n{}{}  - name: wrong\n    expect: no_match\n    events:\n{}{}",
+            event("2024-01-01T00:00:00Z"),
+            event("2024-01-01T00:01:00Z"),
+            event("2024-01-01T00:00:00Z"),
+            event("2024-01-01T01:00:00Z"),
+            event("2024-01-01T00:00:00Z"),
+            event("2024-01-01T00:01:00Z"),
+        );
+        fs::write(dir.join("logon_failure.yml"), rule).unwrap();
+        fs::write(dir.join("logon_failure.tests.yml"), rule_tests).unwrap();
+        fs::write(dir.join("many_logon_failures.yml"), correlation).unwrap();
+        fs::write(dir.join("many_logon_failures.tests.yml"), correlation_tests).unwrap();
+        fs::write(dir.join("no_rule.tests.yml"), "tests: []\n").unwrap();
+
+        let stored_static = create_dummy_stored_static();
+        *STORED_EKEY_ALIAS.write().unwrap() = Some(stored_static.eventkey_alias.clone());
+        *STORED_STATIC.write().unwrap() = Some(stored_static.clone());
+        let report = run_rule_tests(
+            dir,
+            &HashMap::new(),
+            &stored_static,
+            &Runtime::new().unwrap(),
+        );
+        fs::remove_dir_all(dir).ok();
+
+        assert_eq!(report.rules_tested, 2);
+        assert_eq!(report.results.len(), 6);
+        let result = |name: &str| report.results.iter().find(|r| r.name == name).unwrap();
+        assert!(result("positive").passed);
+        assert!(result("negative").passed);
+        assert!(result("burst").passed);
+        assert!(result("spread").passed);
+        assert!(!result("wrong").passed);
+        assert_eq!(result("wrong").detections, Some(1));
+        assert!(
+            result("-")
+                .message
+                .contains("The rule file for this test file was not found")
+        );
+        assert_eq!(report.failed_cnt(), 2);
+    }
+}
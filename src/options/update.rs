@@ -3,15 +3,24 @@ use crate::detections::message::AlertMessage;
 use crate::detections::utils::{get_writable_color, write_color_buffer};
 use crate::filter;
 use crate::yaml::ParseYaml;
+use flate2::read::GzDecoder;
 use git2::{ErrorCode, Repository};
+use minisign_verify::{PublicKey, Signature};
 use serde_json::Value;
-use std::fs::{self, create_dir};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, create_dir};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 
 use termcolor::{BufferWriter, Color, ColorChoice};
 
+/// オフライン用ルールバンドルに同梱するSHA-256マニフェストのファイル名(sha256sumコマンドの出力形式)
+pub const BUNDLE_MANIFEST: &str = "SHA256SUMS";
+
 pub struct Update {}
 
 impl Update {
@@ -108,6 +117,214 @@ impl Update {
         result
     }
 
+    /// install rules from a local zip/tar archive or git bundle after verifying the SHA-256 manifest(for air-gapped environments)
+    pub fn update_rules_from_bundle(
+        bundle_path: &Path,
+        rule_path: &str,
+        (signature_path, public_key_path): (Option<&PathBuf>, Option<&PathBuf>),
+        stored_static: &StoredStatic,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        if !bundle_path.is_file() {
+            return Err(format!("{} does not exist.", bundle_path.display()).into());
+        }
+        if let (Some(signature_path), Some(public_key_path)) = (signature_path, public_key_path) {
+            Update::verify_bundle_signature(bundle_path, signature_path, public_key_path)?;
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                None,
+                "Verified the signature of the rule bundle.",
+                true,
+            )
+            .ok();
+        }
+
+        let rule_path = rule_path.trim_end_matches(['/', '\\']);
+        let extract_dir = PathBuf::from(format!("{rule_path}.bundle-tmp"));
+        fs::remove_dir_all(&extract_dir).ok();
+        fs::create_dir_all(&extract_dir)?;
+        let result = Update::extract_bundle(bundle_path, &extract_dir)
+            .and_then(|_| Update::find_bundle_root(&extract_dir))
+            .and_then(|bundle_root| {
+                let verified_cnt = Update::verify_manifest(&bundle_root)?;
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!(
+                        "Verified the SHA-256 hashes of {verified_cnt} files in the rule bundle."
+                    ),
+                    true,
+                )
+                .ok();
+                let prev_modified_rules = if Path::new(rule_path).exists() {
+                    Update::get_updated_rules(rule_path, stored_static)
+                } else {
+                    HashMap::default()
+                };
+                Update::replace_rules_dir(&bundle_root, Path::new(rule_path))?;
+                Ok(prev_modified_rules)
+            });
+        fs::remove_dir_all(&extract_dir).ok();
+        let prev_modified_rules = result?;
+
+        let updated_modified_rules = Update::get_updated_rules(rule_path, stored_static);
+        Ok(Update::print_diff_modified_rule_dates(
+            prev_modified_rules,
+            updated_modified_rules,
+            stored_static.common_options.no_color,
+        )?)
+    }
+
+    /// minisignの分離署名でバンドルファイルを検証する
+    fn verify_bundle_signature(
+        bundle_path: &Path,
+        signature_path: &Path,
+        public_key_path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let public_key = PublicKey::from_file(public_key_path)
+            .map_err(|e| format!("Failed to load the public key. {e}"))?;
+        let signature = Signature::from_file(signature_path)
+            .map_err(|e| format!("Failed to load the signature. {e}"))?;
+        public_key
+            .verify(&fs::read(bundle_path)?, &signature, false)
+            .map_err(|e| format!("Failed to verify the signature of the rule bundle. {e}"))?;
+        Ok(())
+    }
+
+    /// ファイルの先頭のバイト列からzip、gzip圧縮されたtar、tar、git bundleを判別して展開する
+    fn extract_bundle(bundle_path: &Path, dst: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut magic = [0u8; 512];
+        let magic_len = File::open(bundle_path)?.read(&mut magic)?;
+        let magic = &magic[..magic_len];
+        if magic.starts_with(b"PK\x03\x04") {
+            zip::ZipArchive::new(File::open(bundle_path)?)?.extract(dst)?;
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            tar::Archive::new(GzDecoder::new(File::open(bundle_path)?)).unpack(dst)?;
+        } else if magic.starts_with(b"# v2 git bundle") || magic.starts_with(b"# v3 git bundle") {
+            // libgit2はgit bundleに対応していないため、gitコマンドでcloneする
+            let clone_dst = dst.join("rules");
+            let output = Command::new("git")
+                .arg("clone")
+                .arg(bundle_path)
+                .arg(&clone_dst)
+                .output()
+                .map_err(|e| format!("git is required to install rules from a git bundle. {e}"))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "Failed to clone the git bundle. {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )
+                .into());
+            }
+            fs::remove_dir_all(clone_dst.join(".git"))?;
+        } else if magic.len() > 262 && &magic[257..262] == b"ustar" {
+            tar::Archive::new(File::open(bundle_path)?).unpack(dst)?;
+        } else {
+            return Err(format!(
+                "{} is not a zip/tar archive or git bundle.",
+                bundle_path.display()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// マニフェストが存在するディレクトリを返す。GitHubのアーカイブのように最上位のフォルダが1つだけの場合はその中を探す
+    fn find_bundle_root(extract_dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let mut dir = extract_dir.to_path_buf();
+        loop {
+            if dir.join(BUNDLE_MANIFEST).is_file() {
+                return Ok(dir);
+            }
+            let entries: Vec<PathBuf> = fs::read_dir(&dir)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .collect();
+            if entries.len() == 1 && entries[0].is_dir() {
+                dir = entries[0].clone();
+            } else {
+                return Err(format!(
+                    "{BUNDLE_MANIFEST} manifest was not found in the rule bundle."
+                )
+                .into());
+            }
+        }
+    }
+
+    /// マニフェストに記載された全ファイルのSHA-256ハッシュを検証する。記載されていないファイルがある場合もエラーとする
+    fn verify_manifest(bundle_root: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+        let manifest = fs::read_to_string(bundle_root.join(BUNDLE_MANIFEST))?;
+        let mut listed_files = HashSet::new();
+        let mut errors = vec![];
+        for line in manifest.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // sha256sumの出力形式 "<hash>  <path>" または "<hash> *<path>" を想定する
+            let Some((expected_hash, file)) = line.split_once(' ') else {
+                return Err(format!("Invalid line in {BUNDLE_MANIFEST}: {line}").into());
+            };
+            let file = file
+                .trim_start_matches([' ', '*'])
+                .trim_start_matches("./")
+                .replace('\\', "/");
+            if file.split('/').any(|c| c == "..") || Path::new(&file).is_absolute() {
+                return Err(format!("Invalid path in {BUNDLE_MANIFEST}: {file}").into());
+            }
+            match fs::read(bundle_root.join(&file)) {
+                Ok(data) => {
+                    if !hex::encode(Sha256::digest(&data)).eq_ignore_ascii_case(expected_hash) {
+                        errors.push(format!("Hash mismatch: {file}"));
+                    }
+                }
+                Err(_) => errors.push(format!("Missing file: {file}")),
+            }
+            listed_files.insert(file);
+        }
+        for entry in WalkDir::new(bundle_root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let file = entry
+                .path()
+                .strip_prefix(bundle_root)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            if file != BUNDLE_MANIFEST && !listed_files.contains(&file) {
+                errors.push(format!("File not in {BUNDLE_MANIFEST}: {file}"));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(format!(
+                "Integrity verification of the rule bundle failed.\n        {}",
+                errors.join("\n        ")
+            )
+            .into());
+        }
+        Ok(listed_files.len())
+    }
+
+    /// 検証済みのルールでrulesフォルダを置き換える。置き換えに失敗した場合は元のrulesフォルダを戻す
+    fn replace_rules_dir(
+        bundle_root: &Path,
+        rules_path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let backup_path = PathBuf::from(format!("{}.bak", rules_path.display()));
+        fs::remove_dir_all(&backup_path).ok();
+        let has_prev_rules = rules_path.exists();
+        if has_prev_rules {
+            fs::rename(rules_path, &backup_path)?;
+        }
+        if let Err(e) = fs::rename(bundle_root, rules_path) {
+            if has_prev_rules {
+                fs::rename(&backup_path, rules_path).ok();
+            }
+            return Err(format!("Failed to install the rules. {e}").into());
+        }
+        fs::remove_dir_all(&backup_path).ok();
+        Ok(())
+    }
+
     /// hard reset in main branch
     fn _repo_main_reset_hard(input_repo: &Repository) -> Result<(), git2::Error> {
         let branch = input_repo
@@ -286,9 +503,12 @@ impl Update {
 mod tests {
     use crate::{
         detections::configs::{Action, CommonOptions, Config, StoredStatic, UpdateOption},
-        options::update::Update,
+        options::update::{BUNDLE_MANIFEST, Update},
     };
-    use std::fs::read_to_string;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use sha2::{Digest, Sha256};
+    use std::fs::{self, File, read_to_string};
     use std::path::Path;
 
     #[test]
@@ -301,6 +521,7 @@ mod tests {
                     quiet: false,
                     help: None,
                 },
+                ..Default::default()
             })),
             debug: false,
         }));
@@ -324,6 +545,7 @@ mod tests {
                     quiet: false,
                     help: None,
                 },
+                ..Default::default()
             })),
             debug: false,
         }));
@@ -353,6 +575,7 @@ mod tests {
                     quiet: false,
                     help: None,
                 },
+                ..Default::default()
             })),
             debug: false,
         }));
@@ -376,4 +599,97 @@ mod tests {
         assert!(actual.is_ok());
         assert_eq!(actual.unwrap(), "Rule updated".to_string());
     }
+
+    fn create_dummy_stored_static() -> StoredStatic {
+        let mut stored_static = StoredStatic::create_static_data(Some(Config {
+            action: Some(Action::UpdateRules(UpdateOption {
+                rules: Path::new("./rules").to_path_buf(),
+                common_options: CommonOptions {
+                    no_color: true,
+                    quiet: true,
+                    help: None,
+                },
+                ..Default::default()
+            })),
+            debug: false,
+        }));
+        stored_static.include_status.insert("*".into());
+        stored_static
+    }
+
+    /// テスト用のルールバンドル(tar.gz)を作成する。tamperedがtrueの場合はマニフェスト作成後にルールを改ざんする
+    fn create_test_bundle(dir: &Path, tampered: bool) -> std::path::PathBuf {
+        let src = dir.join("src/hayabusa-rules");
+        fs::create_dir_all(src.join("hayabusa")).unwrap();
+        let rule = "title: Bundle Test\nid: 00000000-0000-0000-0000-000000000034\nauthor: test\nlevel: high\nstatus: test\ndate: 2024/01/01\nlogsource:\n    product: windows\ndetection:\n    selection:\n        EventID: 1\n    condition: selection\n";
+        fs::write(src.join("hayabusa/test.yml"), rule).unwrap();
+        fs::write(
+            src.join(BUNDLE_MANIFEST),
+            format!(
+                "{}  ./hayabusa/test.yml\n",
+                hex::encode(Sha256::digest(rule.as_bytes()))
+            ),
+        )
+        .unwrap();
+        if tampered {
+            fs::write(
+                src.join("hayabusa/test.yml"),
+                rule.replace("EventID: 1", "EventID: 2"),
+            )
+            .unwrap();
+        }
+        let bundle_path = dir.join("rules.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&bundle_path).unwrap(),
+            Compression::default(),
+        ));
+        builder.append_dir_all("hayabusa-rules", &src).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        bundle_path
+    }
+
+    #[test]
+    fn test_update_rules_from_bundle() {
+        let dir = Path::new("./test_update_bundle");
+        fs::remove_dir_all(dir).ok();
+        let bundle_path = create_test_bundle(dir, false);
+        let stored_static = create_dummy_stored_static();
+        let rules_path = dir.join("rules");
+        let actual = Update::update_rules_from_bundle(
+            &bundle_path,
+            rules_path.to_str().unwrap(),
+            (None, None),
+            &stored_static,
+        );
+        assert_eq!(actual.unwrap(), "Rule updated");
+        assert!(rules_path.join("hayabusa/test.yml").exists());
+        assert!(!dir.join("rules.bundle-tmp").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_update_rules_from_tampered_bundle() {
+        let dir = Path::new("./test_update_tampered_bundle");
+        fs::remove_dir_all(dir).ok();
+        let bundle_path = create_test_bundle(dir, true);
+        let stored_static = create_dummy_stored_static();
+        let rules_path = dir.join("rules");
+        fs::create_dir_all(&rules_path).unwrap();
+        let actual = Update::update_rules_from_bundle(
+            &bundle_path,
+            rules_path.to_str().unwrap(),
+            (None, None),
+            &stored_static,
+        );
+        assert!(
+            actual
+                .unwrap_err()
+                .to_string()
+                .contains("Hash mismatch: hayabusa/test.yml")
+        );
+        // 検証に失敗した場合は既存のrulesフォルダを変更しない
+        assert!(rules_path.exists());
+        assert!(!rules_path.join("hayabusa/test.yml").exists());
+        fs::remove_dir_all(dir).ok();
+    }
 }
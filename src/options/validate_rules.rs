@@ -0,0 +1,370 @@
+use crate::detections::configs::{EventKeyAliasConfig, StoredStatic};
+use crate::detections::detection::Detection;
+use crate::detections::rule::RuleNode;
+use crate::detections::rule::correlation_parser::validate_correlation_rule;
+use crate::detections::utils::{
+    contains_str, get_writable_color, is_rule_test_file, write_color_buffer,
+};
+use crate::yaml::{ParseYaml, check_hayabusa_rule_fmt};
+use crate::yaml_expand::process_yaml;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use hashbrown::HashMap;
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use walkdir::WalkDir;
+use yaml_rust2::{Yaml, YamlLoader};
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleIssue {
+    pub severity: Severity,
+    pub file: String,
+    pub id: String,
+    pub title: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub rules_checked: usize,
+    pub issues: Vec<RuleIssue>,
+}
+
+impl ValidationReport {
+    pub fn error_cnt(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    pub fn warning_cnt(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|i| i.severity == severity)
+            .count()
+    }
+
+    fn push(&mut self, severity: Severity, file: &str, yaml: &Yaml, message: String) {
+        self.issues.push(RuleIssue {
+            severity,
+            file: file.to_string(),
+            id: yaml["id"].as_str().unwrap_or_default().to_string(),
+            title: yaml["title"].as_str().unwrap_or_default().to_string(),
+            message,
+        });
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rules_checked": self.rules_checked,
+            "errors": self.error_cnt(),
+            "warnings": self.warning_cnt(),
+            "issues": self.issues.iter().map(|i| json!({
+                "severity": i.severity.to_str(),
+                "file": i.file,
+                "id": i.id,
+                "title": i.title,
+                "message": i.message,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// ルールディレクトリ内の全てのルールを、スキャン時と同じ初期化処理にかけて問題を洗い出す
+pub fn validate_rules(
+    rules_dir: &Path,
+    expand_map: &std::collections::HashMap<String, Vec<String>>,
+    stored_static: &StoredStatic,
+) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let mut rule_nodes = vec![];
+    for entry in WalkDir::new(rules_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        let path_str = path.to_string_lossy();
+        if !path.is_file()
+            || path.extension().unwrap_or_default() != "yml"
+            || contains_str(&path_str, "/.git/")
+            || contains_str(&path_str, "\\.git\\")
+            || contains_str(&path_str, "rules/tools/sigmac/test_files")
+            || contains_str(&path_str, "rules\\tools\\sigmac\\test_files")
+            || is_rule_test_file(path)
+        {
+            continue;
+        }
+        let docs = ParseYaml::read_file(&path.to_path_buf())
+            .and_then(|c| YamlLoader::load_from_str(&c).map_err(|e| e.to_string()));
+        let docs = match docs {
+            Ok(docs) => docs,
+            Err(e) => {
+                report.push(
+                    Severity::Error,
+                    &path_str,
+                    &Yaml::BadValue,
+                    format!("Failed to parse yml: {e}"),
+                );
+                continue;
+            }
+        };
+        for doc in docs {
+            report.rules_checked += 1;
+            let (mut expand_found, mut expand_enabled) = (false, false);
+            let doc = process_yaml(&doc, expand_map, &mut expand_found, &mut expand_enabled);
+            if expand_found && !expand_enabled {
+                report.push(
+                    Severity::Warning,
+                    &path_str,
+                    &doc,
+                    "The expand placeholder is not defined in the config/expand directory, so this rule will not be loaded.".to_string(),
+                );
+            }
+            if let Err(errmsg) = check_hayabusa_rule_fmt(&doc) {
+                for msg in errmsg.split(" ¦ ") {
+                    report.push(Severity::Error, &path_str, &doc, msg.to_string());
+                }
+            }
+            for msg in Detection::invalid_output_fields(&doc) {
+                report.push(Severity::Error, &path_str, &doc, msg);
+            }
+            let mut node = RuleNode::new(path_str.to_string(), doc);
+            if node.yaml["correlation"].is_badvalue() {
+                if let Err(errmsgs) = node.init(stored_static) {
+                    for msg in errmsgs {
+                        report.push(Severity::Error, &path_str, &node.yaml, msg);
+                    }
+                }
+                for field in unknown_fields(&node.yaml["detection"], &stored_static.eventkey_alias)
+                {
+                    report.push(
+                        Severity::Warning,
+                        &path_str,
+                        &node.yaml,
+                        format!(
+                            "The field {field} is not defined in eventkey_alias.txt and will be looked up as Event.EventData.{field}."
+                        ),
+                    );
+                }
+            }
+            rule_nodes.push(node);
+        }
+    }
+
+    // 相関ルールの参照先は全てのルールの中から探す
+    for node in rule_nodes
+        .iter()
+        .filter(|n| !n.yaml["correlation"].is_badvalue())
+    {
+        for msg in validate_correlation_rule(node, &rule_nodes) {
+            report.push(Severity::Error, &node.rulepath, &node.yaml, msg);
+        }
+    }
+
+    let mut id_to_files: HashMap<&str, Vec<&str>> = HashMap::new();
+    for node in &rule_nodes {
+        if let Some(id) = node.yaml["id"].as_str() {
+            id_to_files.entry(id).or_default().push(&node.rulepath);
+        }
+    }
+    for node in &rule_nodes {
+        let Some(files) = node.yaml["id"].as_str().and_then(|id| id_to_files.get(id)) else {
+            continue;
+        };
+        if files.len() > 1 {
+            let others: Vec<&str> = files
+                .iter()
+                .filter(|f| **f != node.rulepath)
+                .copied()
+                .collect();
+            report.push(
+                Severity::Error,
+                &node.rulepath,
+                &node.yaml,
+                format!("Duplicate rule ID. (Also used in: {})", others.join(", ")),
+            );
+        }
+    }
+    report
+        .issues
+        .sort_by(|a, b| (&a.file, a.severity, &a.message).cmp(&(&b.file, b.severity, &b.message)));
+    report
+}
+
+/// detection内で使われているフィールドのうち、eventkey_alias.txtで定義されていないものを返す
+fn unknown_fields(detection: &Yaml, eventkey_alias: &EventKeyAliasConfig) -> Vec<String> {
+    let mut fields = BTreeSet::new();
+    let Some(detection) = detection.as_hash() else {
+        return vec![];
+    };
+    for (name, selection) in detection {
+        if matches!(name.as_str(), Some("condition" | "timeframe")) {
+            continue;
+        }
+        let hashes: Vec<&Yaml> = match selection {
+            Yaml::Array(items) => items.iter().filter(|i| i.is_hash()).collect(),
+            Yaml::Hash(_) => vec![selection],
+            _ => vec![],
+        };
+        for hash in hashes.into_iter().filter_map(|h| h.as_hash()) {
+            for key in hash.keys().filter_map(|k| k.as_str()) {
+                let field = key.split('|').next().unwrap_or_default();
+                if !field.is_empty()
+                    && !field.contains('.')
+                    && eventkey_alias.get_event_key(field).is_none()
+                {
+                    fields.insert(field.to_string());
+                }
+            }
+        }
+    }
+    fields.into_iter().collect()
+}
+
+pub fn output_validation_report(
+    report: &ValidationReport,
+    out_path: Option<&PathBuf>,
+    no_color: bool,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(out_path) = out_path {
+        fs::write(out_path, serde_json::to_string_pretty(&report.to_json())?)?;
+    } else if !report.issues.is_empty() {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+            .set_header(["Severity", "File", "Rule ID", "Message"]);
+        for issue in &report.issues {
+            table.add_row([
+                issue.severity.to_str(),
+                &issue.file,
+                &issue.id,
+                &issue.message,
+            ]);
+        }
+        println!("{table}");
+    }
+    let color = if report.error_cnt() > 0 {
+        Color::Rgb(255, 0, 0)
+    } else if report.warning_cnt() > 0 {
+        Color::Rgb(255, 175, 0)
+    } else {
+        Color::Rgb(0, 255, 0)
+    };
+    write_color_buffer(
+        &BufferWriter::stdout(ColorChoice::Always),
+        get_writable_color(Some(color), no_color),
+        &format!(
+            "Rules checked: {} ¦ Errors: {} ¦ Warnings: {}",
+            report.rules_checked,
+            report.error_cnt(),
+            report.warning_cnt()
+        ),
+        true,
+    )
+    .ok();
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::configs::{
+        Action, CommonOptions, Config, StoredStatic, ValidateRulesOption,
+    };
+    use crate::options::validate_rules::{Severity, validate_rules};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    fn create_dummy_stored_static() -> StoredStatic {
+        StoredStatic::create_static_data(Some(Config {
+            action: Some(Action::ValidateRules(ValidateRulesOption {
+                rules: Path::new("./test_validate_rules").to_path_buf(),
+                common_options: CommonOptions::default(),
+                ..Default::default()
+            })),
+            debug: false,
+        }))
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        let dir = Path::new("./test_validate_rules");
+        fs::create_dir_all(dir).unwrap();
+        let header = "author: test\nlevel: high\nstatus: test\ndate: 2024/01/01\nlogsource:\n    product: windows\n";
+        let valid = format!(
+            "title: Valid\nid: 00000000-0000-0000-0000-000000000001\n{header}detection:\n    selection:\n        Channel: Security\n    condition: selection\n"
+        );
+        let undefined_selection = format!(
+            "title: Undefined Selection\nid: 00000000-0000-0000-0000-000000000001\n{header}detection:\n    selection:\n        Channel: Security\n    condition: selection and filter\n"
+        );
+        let bad_regex = format!(
+            "title: Bad Regex\nid: 00000000-0000-0000-0000-000000000002\n{header}detection:\n    selection:\n        Channel|re: '(abc'\n    condition: selection\n"
+        );
+        let correlation = "title: Correlation\nid: 00000000-0000-0000-0000-000000000003\nauthor: test\nlevel: high\nstatus: test\ndate: 2024/01/01\ncorrelation:\n    type: event_count\n    rules:\n        - 00000000-0000-0000-0000-000000000009\n    group-by:\n        - Computer\n    timespan: 5m\n    condition:\n        gte: 10\n";
+        fs::write(dir.join("valid.yml"), valid).unwrap();
+        fs::write(dir.join("undefined_selection.yml"), undefined_selection).unwrap();
+        fs::write(dir.join("bad_regex.yml"), bad_regex).unwrap();
+        fs::write(dir.join("correlation.yml"), correlation).unwrap();
+        fs::write(dir.join("broken.yml"), "title: [").unwrap();
+        let bad_output_fields = format!(
+            "title: Bad Output Fields\nid: 00000000-0000-0000-0000-000000000004\n{header}detection:\n    selection:\n        Channel: Security\n    condition: selection\noutput_fields:\n    Cmd: '%unknown(CommandLine)%'\n"
+        );
+        fs::write(dir.join("bad_output_fields.yml"), bad_output_fields).unwrap();
+
+        let report = validate_rules(dir, &HashMap::new(), &create_dummy_stored_static());
+        fs::remove_dir_all(dir).ok();
+
+        assert_eq!(report.rules_checked, 5);
+        let errors: Vec<_> = report
+            .issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .collect();
+        let has_error = |file: &str, msg: &str| {
+            errors
+                .iter()
+                .any(|i| i.file.ends_with(file) && i.message.contains(msg))
+        };
+        assert!(has_error("broken.yml", "Failed to parse yml"));
+        assert!(has_error("bad_regex.yml", ""));
+        assert!(has_error("undefined_selection.yml", ""));
+        assert!(has_error("undefined_selection.yml", "Duplicate rule ID"));
+        assert!(has_error("valid.yml", "Duplicate rule ID"));
+        assert!(has_error(
+            "correlation.yml",
+            "The referenced rule was not found: 00000000-0000-0000-0000-000000000009"
+        ));
+        assert!(!has_error("valid.yml", "Missing"));
+        assert!(has_error(
+            "bad_output_fields.yml",
+            "The output_fields expression of Cmd cannot be parsed"
+        ));
+        assert_eq!(report.to_json()["errors"], report.error_cnt());
+    }
+}
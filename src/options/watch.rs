@@ -0,0 +1,473 @@
+use crate::detections::configs::WatchOption;
+use crate::detections::message::AlertMessage;
+use chrono::Local;
+use hashbrown::{HashMap, HashSet};
+use serde_json::{Map, Value, json};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STATE_VERSION: u64 = 1;
+
+/// 監視するディレクトリに置かれたファイルまたはフォルダ(ドロップ)
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchDrop {
+    pub name: String,
+    pub path: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+/// ドロップ内のファイルとサイズ、更新日時。書き込み中かどうかの判定に使う
+#[derive(Debug, Clone, PartialEq)]
+struct DropSnapshot {
+    files: Vec<(PathBuf, u64, Option<std::time::SystemTime>)>,
+}
+
+impl DropSnapshot {
+    fn new(files: &[PathBuf]) -> Self {
+        let mut files: Vec<_> = files
+            .iter()
+            .map(|f| {
+                let metadata = fs::metadata(f).ok();
+                (
+                    f.to_path_buf(),
+                    metadata.as_ref().map(|m| m.len()).unwrap_or_default(),
+                    metadata.and_then(|m| m.modified().ok()),
+                )
+            })
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        DropSnapshot { files }
+    }
+}
+
+/// 監視するディレクトリのドロップを調べて、書き込みが完了したものを返す
+pub struct DropWatcher {
+    dir: PathBuf,
+    settle_time: Duration,
+    target_extensions: HashSet<String>,
+    excludes: Vec<PathBuf>,
+    pending: HashMap<String, (DropSnapshot, Instant)>,
+}
+
+impl DropWatcher {
+    pub fn new(
+        dir: &Path,
+        settle_time: Duration,
+        target_extensions: HashSet<String>,
+        excludes: &[PathBuf],
+    ) -> Self {
+        DropWatcher {
+            dir: dir.to_path_buf(),
+            settle_time,
+            target_extensions,
+            excludes: excludes
+                .iter()
+                .filter_map(|p| p.canonicalize().ok())
+                .collect(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// ファイルの一覧とサイズがsettle_timeの間変わらなかったドロップを書き込み完了とみなして返す
+    pub fn poll<F>(&mut self, state: &WatchState, collect: F) -> Result<Vec<WatchDrop>, String>
+    where
+        F: Fn(&Path) -> Vec<PathBuf>,
+    {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| format!("Failed to read the directory {}. {e}", self.dir.display()))?;
+        let mut names = vec![];
+        let mut ready = vec![];
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            // コピー中の一時ファイルや出力先のディレクトリは対象外とする
+            if name.starts_with('.')
+                || state.contains(&name)
+                || path
+                    .canonicalize()
+                    .is_ok_and(|p| self.excludes.contains(&p))
+            {
+                continue;
+            }
+            let files = if path.is_dir() {
+                collect(&path)
+            } else if self.target_extensions.contains(
+                path.extension()
+                    .unwrap_or_else(|| OsStr::new(""))
+                    .to_str()
+                    .unwrap_or_default(),
+            ) {
+                vec![path.clone()]
+            } else {
+                vec![]
+            };
+            if files.is_empty() {
+                continue;
+            }
+            let snapshot = DropSnapshot::new(&files);
+            let stable_since = match self.pending.get(&name) {
+                Some((prev, since)) if *prev == snapshot => *since,
+                _ => Instant::now(),
+            };
+            if stable_since.elapsed() >= self.settle_time {
+                self.pending.remove(&name);
+                ready.push(WatchDrop { name, path, files });
+            } else {
+                self.pending.insert(name.clone(), (snapshot, stable_since));
+                names.push(name);
+            }
+        }
+        // 削除されたドロップは監視対象から外す
+        self.pending.retain(|name, _| names.contains(name));
+        ready.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(ready)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// スキャン済みのドロップを記録する状態ファイル。再起動時に同じドロップを再スキャンしないために使う
+pub struct WatchState {
+    path: PathBuf,
+    drops: Map<String, Value>,
+}
+
+impl WatchState {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(WatchState {
+                path: path.to_path_buf(),
+                drops: Map::new(),
+            });
+        }
+        let state: Value = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Failed to read the state file {}. {e}", path.display()))?;
+        if state["version"] != STATE_VERSION {
+            return Err(format!(
+                "{} is not a state file of this version.",
+                path.display()
+            ));
+        }
+        Ok(WatchState {
+            path: path.to_path_buf(),
+            drops: state["drops"].as_object().cloned().unwrap_or_default(),
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.drops.contains_key(name)
+    }
+
+    /// スキャンしたドロップを追加して保存する。書き込み途中で中断されても壊れないように一時ファイルから置き換える
+    pub fn record(&mut self, name: &str, entry: Value) -> Result<(), String> {
+        self.drops.insert(name.to_string(), entry);
+        let state = json!({"version": STATE_VERSION, "drops": self.drops});
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&state).unwrap())
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|e| format!("Failed to save the state file {}. {e}", self.path.display()))
+    }
+}
+
+/// ドロップごとの出力先ディレクトリに保存するファイル
+pub struct DropOutputs {
+    pub dir: PathBuf,
+    pub timeline: PathBuf,
+    pub html_report: PathBuf,
+    pub log: PathBuf,
+    pub manifest: PathBuf,
+}
+
+impl DropOutputs {
+    pub fn new(option: &WatchOption, drop: &WatchDrop) -> Self {
+        let dir = option.output.join(&drop.name);
+        let timeline = if option.timeline == "json-timeline" {
+            "timeline.jsonl"
+        } else {
+            "timeline.csv"
+        };
+        DropOutputs {
+            timeline: dir.join(timeline),
+            html_report: dir.join("report.html"),
+            log: dir.join("hayabusa.log"),
+            manifest: dir.join("manifest.json"),
+            dir,
+        }
+    }
+}
+
+/// ドロップをスキャンするタイムラインコマンドの引数を作成する
+pub fn timeline_args(option: &WatchOption, drop: &WatchDrop, outputs: &DropOutputs) -> Vec<String> {
+    let input = if drop.path.is_dir() { "-d" } else { "-f" };
+    let mut args = vec![
+        option.timeline.clone(),
+        input.to_string(),
+        drop.path.to_string_lossy().to_string(),
+        "-o".to_string(),
+        outputs.timeline.to_string_lossy().to_string(),
+        "-H".to_string(),
+        outputs.html_report.to_string_lossy().to_string(),
+    ];
+    if option.timeline == "json-timeline" {
+        args.push("-L".to_string());
+    }
+    if let Some(profile) = &option.profile {
+        args.extend(["-p".to_string(), profile.clone()]);
+    }
+    args.extend(["-w", "-C", "-q", "-K"].map(String::from));
+    args.extend(option.timeline_options.iter().cloned());
+    args
+}
+
+/// タイムラインの検知数を数える。CSVはヘッダーを除いたレコード数、JSONLは行数とする
+fn count_detections(timeline: &Path) -> Option<usize> {
+    if timeline.extension() == Some(OsStr::new("csv")) {
+        let mut reader = csv::Reader::from_path(timeline).ok()?;
+        Some(reader.records().filter(|r| r.is_ok()).count())
+    } else {
+        let content = fs::read_to_string(timeline).ok()?;
+        Some(content.lines().filter(|l| !l.trim().is_empty()).count())
+    }
+}
+
+/// ドロップをスキャンしてマニフェストを保存する。シグナルで中断された場合は再スキャンできるようにErrを返す
+pub fn process_drop(exe: &Path, option: &WatchOption, drop: &WatchDrop) -> Result<Value, String> {
+    let outputs = DropOutputs::new(option, drop);
+    fs::create_dir_all(&outputs.dir).map_err(|e| {
+        format!(
+            "Failed to create the output directory {}. {e}",
+            outputs.dir.display()
+        )
+    })?;
+    let args = timeline_args(option, drop, &outputs);
+    let log = File::create(&outputs.log)
+        .map_err(|e| format!("Failed to create {}. {e}", outputs.log.display()))?;
+    let stderr = log.try_clone().map_err(|e| e.to_string())?;
+    let start_time = Local::now();
+    let status = Command::new(exe)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(log)
+        .stderr(stderr)
+        .status()
+        .map_err(|e| format!("Failed to run {}. {e}", exe.display()))?;
+    let Some(exit_code) = status.code() else {
+        return Err(format!("The scan of {} was interrupted.", drop.name));
+    };
+    let mut output_files = Map::new();
+    for (key, path) in [
+        ("Timeline", &outputs.timeline),
+        ("HTMLReport", &outputs.html_report),
+        ("Log", &outputs.log),
+    ] {
+        if path.exists() {
+            output_files.insert(key.to_string(), json!(path.to_string_lossy()));
+        }
+    }
+    let manifest = json!({
+        "Drop": drop.name,
+        "Path": drop.path.to_string_lossy(),
+        "Files": drop.files.iter().map(|f| json!({
+            "Path": f.to_string_lossy(),
+            "Size": fs::metadata(f).map(|m| m.len()).unwrap_or_default(),
+        })).collect::<Vec<_>>(),
+        "Command": option.timeline,
+        "Arguments": args,
+        "Profile": option.profile,
+        "StartTime": start_time.to_rfc3339(),
+        "EndTime": Local::now().to_rfc3339(),
+        "ExitCode": exit_code,
+        "Status": if exit_code == 0 { "completed" } else { "failed" },
+        "Detections": count_detections(&outputs.timeline),
+        "Outputs": output_files,
+    });
+    fs::write(
+        &outputs.manifest,
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )
+    .map_err(|e| format!("Failed to save {}. {e}", outputs.manifest.display()))?;
+    Ok(manifest)
+}
+
+/// ディレクトリを監視して、書き込みが完了したドロップごとにタイムラインコマンドを実行する
+pub fn watch<F>(
+    exe: &Path,
+    option: &WatchOption,
+    target_extensions: HashSet<String>,
+    collect: F,
+) -> Result<(), String>
+where
+    F: Fn(&Path) -> Vec<PathBuf>,
+{
+    if !option.directory.is_dir() {
+        return Err(format!(
+            "The directory {} does not exist. Please specify a valid directory.",
+            option.directory.display()
+        ));
+    }
+    fs::create_dir_all(&option.output).map_err(|e| {
+        format!(
+            "Failed to create the output directory {}. {e}",
+            option.output.display()
+        )
+    })?;
+    let state_path = option
+        .state
+        .clone()
+        .unwrap_or_else(|| option.output.join("watch-state.json"));
+    let mut state = WatchState::load(&state_path)?;
+    let mut watcher = DropWatcher::new(
+        &option.directory,
+        Duration::from_secs(option.settle_time),
+        target_extensions,
+        &[option.output.clone(), state_path],
+    );
+    println!("Watching: {}", option.directory.display());
+    loop {
+        for drop in watcher.poll(&state, &collect)? {
+            println!(
+                "Scanning {} ({} file{})",
+                drop.name,
+                drop.files.len(),
+                if drop.files.len() == 1 { "" } else { "s" }
+            );
+            match process_drop(exe, option, &drop) {
+                Ok(manifest) => {
+                    println!(
+                        "Saved results: {} ({})",
+                        option.output.join(&drop.name).display(),
+                        manifest["Status"].as_str().unwrap_or_default()
+                    );
+                    state.record(
+                        &drop.name,
+                        json!({
+                            "Status": manifest["Status"],
+                            "EndTime": manifest["EndTime"],
+                            "Files": drop.files.len(),
+                        }),
+                    )?;
+                }
+                Err(err) => {
+                    AlertMessage::alert(&err).ok();
+                }
+            }
+        }
+        if option.once && !watcher.has_pending() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(option.interval));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_watch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("hayabusa_watch_{name}_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("drops/host1/logs")).unwrap();
+        fs::write(dir.join("drops/host1/logs/Security.evtx"), "evtx").unwrap();
+        fs::write(dir.join("drops/host1/memo.txt"), "memo").unwrap();
+        fs::write(dir.join("drops/System.evtx"), "evtx").unwrap();
+        fs::write(dir.join("drops/.partial.evtx"), "evtx").unwrap();
+        fs::create_dir_all(dir.join("drops/empty")).unwrap();
+        dir
+    }
+
+    fn collect(dir: &Path) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(dir)
+            .into_iter()
+            .flatten()
+            .map(|e| e.into_path())
+            .filter(|p| p.extension() == Some(OsStr::new("evtx")))
+            .collect()
+    }
+
+    #[test]
+    fn test_poll_drops() {
+        let dir = create_watch_dir("poll");
+        let drops = dir.join("drops");
+        let exts = HashSet::from(["evtx".to_string()]);
+        let mut state = WatchState::load(&dir.join("state.json")).unwrap();
+        let mut watcher = DropWatcher::new(&drops, Duration::ZERO, exts.clone(), &[]);
+        let ready = watcher.poll(&state, collect).unwrap();
+        assert_eq!(
+            ready.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(),
+            vec!["System.evtx", "host1"]
+        );
+        assert_eq!(ready[1].files, vec![drops.join("host1/logs/Security.evtx")]);
+
+        // 状態ファイルに記録したドロップは再起動後もスキャンしない
+        state
+            .record("host1", json!({"Status": "completed"}))
+            .unwrap();
+        let state = WatchState::load(&dir.join("state.json")).unwrap();
+        assert!(state.contains("host1"));
+        fs::write(drops.join("empty/Application.evtx"), "evtx").unwrap();
+        let ready = watcher.poll(&state, collect).unwrap();
+        assert_eq!(
+            ready.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(),
+            vec!["System.evtx", "empty"]
+        );
+
+        // 書き込み中のドロップはsettle_timeの間変化がなくなるまで待つ
+        let mut watcher = DropWatcher::new(&drops, Duration::from_secs(3600), exts, &[]);
+        assert!(watcher.poll(&state, collect).unwrap().is_empty());
+        assert!(watcher.has_pending());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_timeline_args() {
+        let option = WatchOption {
+            output: PathBuf::from("results"),
+            profile: Some("super-verbose".to_string()),
+            timeline: "json-timeline".to_string(),
+            timeline_options: vec!["-m".to_string(), "medium".to_string()],
+            ..Default::default()
+        };
+        let drop = WatchDrop {
+            name: "Security.evtx".to_string(),
+            path: PathBuf::from("drops/Security.evtx"),
+            files: vec![PathBuf::from("drops/Security.evtx")],
+        };
+        let outputs = DropOutputs::new(&option, &drop);
+        let timeline = Path::new("results")
+            .join("Security.evtx")
+            .join("timeline.jsonl");
+        let html = Path::new("results")
+            .join("Security.evtx")
+            .join("report.html");
+        assert_eq!(
+            timeline_args(&option, &drop, &outputs),
+            vec![
+                "json-timeline",
+                "-f",
+                "drops/Security.evtx",
+                "-o",
+                timeline.to_str().unwrap(),
+                "-H",
+                html.to_str().unwrap(),
+                "-L",
+                "-p",
+                "super-verbose",
+                "-w",
+                "-C",
+                "-q",
+                "-K",
+                "-m",
+                "medium",
+            ]
+        );
+    }
+}
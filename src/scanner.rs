@@ -0,0 +1,384 @@
+use compact_str::CompactString;
+use hashbrown::HashSet;
+use itertools::Itertools;
+use nested::Nested;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::detections::configs::{
+    Action, Config, DetectCommonOption, EventKeyAliasConfig, JSONOutputOption, OutputOption,
+    StoredStatic,
+};
+use crate::detections::detection::{Detection, EvtxRecordInfo};
+use crate::detections::message::DetectInfo;
+use crate::detections::rule::correlation_parser::parse_correlation_rules;
+use crate::detections::rule::{self, RuleNode, get_detection_keys};
+use crate::detections::utils::create_rec_info_with_alias;
+use crate::filter;
+use crate::level::LEVEL;
+use crate::options::enrichment::Enrichment;
+use crate::options::level_override::LevelOverrides;
+use crate::options::profile::{Profile, embedded_default_profile, resolve_alias_fields};
+use crate::options::risk_scoring::RiskScoring;
+use crate::yaml::ParseYaml;
+
+/// StoredStaticの作成時に読み込む設定ファイル。存在しない場合はbuildでエラーにする
+const REQUIRED_CONFIG_FILES: [&str; 7] = [
+    "channel_abbreviations.txt",
+    "provider_abbreviations.txt",
+    "generic_abbreviations.txt",
+    "default_details.txt",
+    "eventkey_alias.txt",
+    "channel_eid_info.txt",
+    "target_event_IDs.txt",
+];
+
+/// ライブラリとしてhayabusaのルールでイベントを検知するためのスキャナー。
+/// ルールと設定はインスタンスごとに保持するため、1つのプロセスで複数のスキャナーを独立して使用できる
+pub struct Scanner {
+    rules: Vec<RuleNode>,
+    stored_static: StoredStatic,
+    keys: Nested<String>,
+    invalid_rule_cnt: usize,
+}
+
+/// Scannerの設定。rules以外は省略した場合csv-timeline/json-timelineのデフォルトと同じ設定になる
+#[derive(Debug, Clone)]
+pub struct ScannerBuilder {
+    rules_path: PathBuf,
+    config_path: Option<PathBuf>,
+    eventkey_alias: Option<EventKeyAliasConfig>,
+    profile: Option<Vec<(String, String)>>,
+    min_level: String,
+    exact_level: Option<String>,
+    include_tags: Vec<String>,
+    exclude_tags: Vec<String>,
+    include_status: Vec<String>,
+    exclude_status: Vec<String>,
+    enable_noisy_rules: bool,
+    enable_deprecated_rules: bool,
+    enable_unsupported_rules: bool,
+    critical_systems: HashSet<String>,
+    level_overrides: Option<LevelOverrides>,
+    enrichment: Option<Enrichment>,
+    risk_scoring: Option<RiskScoring>,
+}
+
+impl Scanner {
+    /// 指定したルールのファイルまたはディレクトリを読み込むScannerの設定を作成する
+    pub fn builder<P: AsRef<Path>>(rules_path: P) -> ScannerBuilder {
+        ScannerBuilder {
+            rules_path: rules_path.as_ref().to_path_buf(),
+            config_path: None,
+            eventkey_alias: None,
+            profile: None,
+            min_level: "informational".to_string(),
+            exact_level: None,
+            include_tags: vec![],
+            exclude_tags: vec![],
+            include_status: vec![],
+            exclude_status: vec![],
+            enable_noisy_rules: false,
+            enable_deprecated_rules: false,
+            enable_unsupported_rules: false,
+            critical_systems: HashSet::new(),
+            level_overrides: None,
+            enrichment: None,
+            risk_scoring: None,
+        }
+    }
+
+    /// 読み込んだルールの数
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// パースに失敗して読み込まなかったルールの数
+    pub fn invalid_rule_count(&self) -> usize {
+        self.invalid_rule_cnt
+    }
+
+    /// evtxをJSONに変換した形式(Event.System、Event.EventData)のレコードをスキャンして検知結果を返す。
+    /// 集計条件を持つルールと相関ルールの検知結果はfinishで返す
+    pub fn scan(&mut self, records: Vec<Value>) -> Vec<DetectInfo> {
+        let records = self.create_record_infos(records, "");
+        self.scan_record_infos(&records)
+    }
+
+    /// レコードをルールの判定に使用する形式に変換する。pathは%EvtxFile%として出力される
+    pub(crate) fn create_record_infos(
+        &self,
+        records: Vec<Value>,
+        path: &str,
+    ) -> Vec<EvtxRecordInfo> {
+        records
+            .into_iter()
+            .map(|record| {
+                create_rec_info_with_alias(
+                    record,
+                    path.to_string(),
+                    &self.keys,
+                    &false,
+                    &self.stored_static.no_pwsh_field_extraction,
+                    &self.stored_static.eventkey_alias,
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn scan_record_infos(&mut self, records: &[EvtxRecordInfo]) -> Vec<DetectInfo> {
+        let mut ret = vec![];
+        for rule in self.rules.iter_mut() {
+            let (detect_infos, _) = Detection::select_records(rule, records, &self.stored_static);
+            ret.extend(detect_infos);
+        }
+        ret
+    }
+
+    pub(crate) fn stored_static(&self) -> &StoredStatic {
+        &self.stored_static
+    }
+
+    /// これまでにスキャンしたレコードで集計条件を持つルールと相関ルールを判定して検知結果を返す。
+    /// risk_scoringを指定した場合はリスクスコアのしきい値を超えたアラートも返す。
+    /// 判定に使用したレコードの情報は削除されるため、続けてscanを呼び出すと新しいスキャンとして扱われる
+    pub fn finish(&mut self) -> Vec<DetectInfo> {
+        let mut ret = Detection::aggcondition_msges(&self.rules, &self.stored_static);
+        ret.extend(Detection::risk_alerts(&self.stored_static));
+        self.rules
+            .iter_mut()
+            .for_each(|rule| rule.clear_countdata());
+        if let Some(risk_scoring) = self.stored_static.risk_scoring.as_ref() {
+            risk_scoring.clear();
+        }
+        ret
+    }
+}
+
+impl ScannerBuilder {
+    /// ルールの設定ファイルのディレクトリ(デフォルト: <rules>/config)
+    pub fn config_path<P: AsRef<Path>>(mut self, config_path: P) -> Self {
+        self.config_path = Some(config_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// フィールド名のエイリアスの設定(デフォルト: configディレクトリのeventkey_alias.txt)
+    pub fn eventkey_alias(mut self, eventkey_alias: EventKeyAliasConfig) -> Self {
+        self.eventkey_alias = Some(eventkey_alias);
+        self
+    }
+
+    /// 検知結果に出力するフィールド名とエイリアス(ex: ("Level", "%Level%"))の組(デフォルト: default_profile.yaml)
+    pub fn profile<K: Into<String>, V: Into<String>>(
+        mut self,
+        profile: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.profile = Some(
+            profile
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        );
+        self
+    }
+
+    pub fn min_level(mut self, level: &str) -> Self {
+        self.min_level = level.to_string();
+        self
+    }
+
+    pub fn exact_level(mut self, level: &str) -> Self {
+        self.exact_level = Some(level.to_string());
+        self
+    }
+
+    pub fn include_tags<S: Into<String>>(mut self, tags: impl IntoIterator<Item = S>) -> Self {
+        self.include_tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn exclude_tags<S: Into<String>>(mut self, tags: impl IntoIterator<Item = S>) -> Self {
+        self.exclude_tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 読み込むルールのステータス(デフォルト: すべてのステータス)
+    pub fn include_status<S: Into<String>>(mut self, status: impl IntoIterator<Item = S>) -> Self {
+        self.include_status = status.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn exclude_status<S: Into<String>>(mut self, status: impl IntoIterator<Item = S>) -> Self {
+        self.exclude_status = status.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn enable_noisy_rules(mut self, enable: bool) -> Self {
+        self.enable_noisy_rules = enable;
+        self
+    }
+
+    pub fn enable_deprecated_rules(mut self, enable: bool) -> Self {
+        self.enable_deprecated_rules = enable;
+        self
+    }
+
+    pub fn enable_unsupported_rules(mut self, enable: bool) -> Self {
+        self.enable_unsupported_rules = enable;
+        self
+    }
+
+    /// アラートのレベルを1段階上げるコンピュータ名(critical_systems.txtと同じ)
+    pub fn critical_systems<S: Into<String>>(
+        mut self,
+        computers: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.critical_systems = computers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 条件付きでアラートのレベルを変更する設定(--level-overridesと同じ)
+    pub fn level_overrides(mut self, level_overrides: LevelOverrides) -> Self {
+        self.level_overrides = Some(level_overrides);
+        self
+    }
+
+    /// 検知結果にルックアップテーブルや外部コマンドの値を追加する設定(--enrichmentと同じ)
+    pub fn enrichment(mut self, enrichment: Enrichment) -> Self {
+        self.enrichment = Some(enrichment);
+        self
+    }
+
+    /// コンピュータ名とユーザー名ごとにリスクスコアを集計する設定(--risk-scoringと同じ)
+    pub fn risk_scoring(mut self, risk_scoring: RiskScoring) -> Self {
+        self.risk_scoring = Some(risk_scoring);
+        self
+    }
+
+    /// ルールを読み込んでScannerを作成する
+    pub fn build(self) -> Result<Scanner, String> {
+        let config_path = self
+            .config_path
+            .clone()
+            .unwrap_or_else(|| self.rules_path.join("config"));
+        if !self.rules_path.exists() {
+            return Err(format!(
+                "The rules path {} does not exist.",
+                self.rules_path.display()
+            ));
+        }
+        if !config_path.is_dir() {
+            return Err(format!(
+                "The rules config directory {} does not exist.",
+                config_path.display()
+            ));
+        }
+        // 設定ファイルが無い場合はStoredStatic::create_static_dataが標準エラー出力に表示するため、先に確認してErrで返す
+        let missing_files: Vec<_> = REQUIRED_CONFIG_FILES
+            .iter()
+            .filter(|f| !config_path.join(f).is_file())
+            .collect();
+        if !missing_files.is_empty() {
+            return Err(format!(
+                "The following config files do not exist in {}: {}",
+                config_path.display(),
+                missing_files.into_iter().join(", ")
+            ));
+        }
+        for level in [Some(&self.min_level), self.exact_level.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if LEVEL::from(level) == LEVEL::UNDEFINED {
+                return Err(format!(
+                    "Invalid level: {level}. Please specify informational, low, medium, high or critical."
+                ));
+            }
+        }
+        let mut profile: Vec<_> = match &self.profile {
+            Some(profile) => profile
+                .iter()
+                .map(|(k, v)| (CompactString::from(k), Profile::from(v.as_str())))
+                .collect(),
+            None => embedded_default_profile(),
+        };
+        let non_empty = |v: &Vec<String>| (!v.is_empty()).then(|| v.clone());
+        let output_options = OutputOption {
+            rules: self.rules_path.clone(),
+            min_level: self.min_level.clone(),
+            exact_level: self.exact_level.clone(),
+            include_tag: non_empty(&self.include_tags),
+            exclude_tag: non_empty(&self.exclude_tags),
+            include_status: non_empty(&self.include_status),
+            exclude_status: non_empty(&self.exclude_status),
+            enable_noisy_rules: self.enable_noisy_rules,
+            enable_deprecated_rules: self.enable_deprecated_rules,
+            enable_unsupported_rules: self.enable_unsupported_rules,
+            // data_mappingは任意の設定なので、無い場合は警告を表示せずにフィールドの値の変換を行わない
+            no_field: !config_path.join("data_mapping").is_dir(),
+            detect_common_options: DetectCommonOption {
+                config: config_path,
+                quiet_errors: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut stored_static = StoredStatic::create_static_data(Some(Config {
+            action: Some(Action::JsonTimeline(JSONOutputOption {
+                output_options,
+                ..Default::default()
+            })),
+            debug: false,
+        }));
+        if stored_static.include_status.is_empty() {
+            stored_static.include_status.insert("*".into());
+        }
+        if let Some(eventkey_alias) = self.eventkey_alias {
+            stored_static.eventkey_alias = eventkey_alias;
+        }
+        // 未定義のエイリアスは固定文字列として出力する。ライブラリでは警告を表示しない
+        resolve_alias_fields(&mut profile, &stored_static.eventkey_alias);
+        stored_static.profiles = Some(profile);
+        stored_static.critical_systems = self.critical_systems;
+        stored_static.level_overrides = self.level_overrides;
+        stored_static.enrichment = self.enrichment;
+        stored_static.risk_scoring = self.risk_scoring;
+
+        let mut rulefile_loader = ParseYaml::new(&stored_static);
+        rulefile_loader
+            .read_dir(
+                &self.rules_path,
+                &self.min_level,
+                self.exact_level.as_deref().unwrap_or_default(),
+                &filter::exclude_ids(&stored_static),
+                &stored_static,
+            )
+            .map_err(|e| format!("Failed to read the rules. {e}"))?;
+        let mut invalid_rule_cnt = rulefile_loader.errorrule_count;
+        let mut rules = vec![];
+        for (rulepath, yaml) in rulefile_loader.files {
+            let mut rule = rule::create_rule(rulepath, yaml);
+            match rule.init(&stored_static) {
+                Ok(_) => rules.push(rule),
+                Err(_) => invalid_rule_cnt += 1,
+            }
+        }
+        let rules = parse_correlation_rules(rules, &stored_static, &mut invalid_rule_cnt);
+        if rules.is_empty() {
+            return Err(format!(
+                "No rules were loaded from {}.",
+                self.rules_path.display()
+            ));
+        }
+        let mut keys = HashSet::new();
+        for rule in &rules {
+            keys.extend(get_detection_keys(rule).iter().map(|k| k.to_string()));
+        }
+        Ok(Scanner {
+            rules,
+            stored_static,
+            keys: keys.into_iter().collect(),
+            invalid_rule_cnt: invalid_rule_cnt as usize,
+        })
+    }
+}
@@ -88,7 +88,7 @@ pub fn countup_event_by_computer(
     }
 }
 
-fn calc_elapsed_seconds(uptime: &str, last_timestamp: &str) -> String {
+pub fn calc_elapsed_seconds(uptime: &str, last_timestamp: &str) -> String {
     if uptime.is_empty() || last_timestamp.is_empty() {
         return "".to_string();
     }
@@ -236,6 +236,7 @@ mod tests {
                     filepath: Some(Path::new("./dummy.evtx").to_path_buf()),
                     live_analysis: false,
                     recover_records: false,
+                    remove_duplicate_records: false,
                     time_offset: None,
                 },
                 common_options: CommonOptions {
@@ -0,0 +1,427 @@
+use crate::detections::rule::RuleNode;
+use crate::detections::utils::{get_writable_color, write_color_buffer};
+use crate::options::htmlreport;
+use compact_str::CompactString;
+use csv::Writer;
+use hashbrown::HashMap;
+use itertools::Itertools;
+use nested::Nested;
+use num_format::{Locale, ToFormattedString};
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use wildmatch::WildMatch;
+use yaml_rust2::Yaml;
+
+const HTML_SECTION: &str = "Rule Coverage {#rule_coverage}";
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Clone, Copy)]
+pub enum CoverageStatus {
+    Covered,
+    NoMatchingEvents,
+    Unknown,
+}
+
+impl CoverageStatus {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            CoverageStatus::Covered => "Covered",
+            CoverageStatus::NoMatchingEvents => "No Matching Events",
+            CoverageStatus::Unknown => "Unknown",
+        }
+    }
+}
+
+/// ルールのdetectionに記載されているChannelとEventIDの一覧
+#[derive(Debug, Clone, Default)]
+pub struct RuleTarget {
+    pub title: String,
+    pub id: String,
+    pub level: String,
+    pub rulepath: String,
+    pub channels: BTreeSet<String>,
+    pub event_ids: BTreeSet<String>,
+}
+
+impl RuleTarget {
+    pub fn new(rule: &RuleNode) -> Self {
+        let mut target = RuleTarget {
+            title: rule.yaml["title"].as_str().unwrap_or_default().to_string(),
+            id: rule.yaml["id"].as_str().unwrap_or_default().to_string(),
+            level: rule.yaml["level"].as_str().unwrap_or_default().to_string(),
+            rulepath: rule.rulepath.clone(),
+            ..Default::default()
+        };
+        target.visit_channels("", &rule.yaml);
+        target.visit_event_ids("", &rule.yaml["detection"]);
+        target
+    }
+
+    /// ルールに記載されているChannelを集める。Channel|contains等の修飾子はワイルドカードに変換する
+    fn visit_channels(&mut self, key: &str, value: &Yaml) {
+        match value {
+            Yaml::String(s) => {
+                let mut key_parts = key.split('|');
+                if key_parts.next() == Some("Channel") {
+                    let channel = match key_parts.next() {
+                        Some("contains") => format!("*{s}*"),
+                        Some("startswith") => format!("{s}*"),
+                        Some("endswith") => format!("*{s}"),
+                        _ => s.clone(),
+                    };
+                    self.channels.insert(channel);
+                }
+            }
+            Yaml::Hash(map) => {
+                for (k, v) in map {
+                    self.visit_channels(k.as_str().unwrap_or_default(), v);
+                }
+            }
+            Yaml::Array(seq) => {
+                for v in seq {
+                    self.visit_channels(key, v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_event_ids(&mut self, key: &str, value: &Yaml) {
+        match value {
+            Yaml::String(s) if key == "EventID" => {
+                self.event_ids.insert(s.to_string());
+            }
+            Yaml::Integer(i) if key == "EventID" => {
+                self.event_ids.insert(i.to_string());
+            }
+            Yaml::Hash(map) => {
+                for (k, v) in map {
+                    let k = k.as_str().unwrap_or_default();
+                    // filterは検知対象から除外する条件なので、対象のイベントには含めない
+                    if !k.starts_with("filter") {
+                        self.visit_event_ids(k, v);
+                    }
+                }
+            }
+            Yaml::Array(seq) => {
+                for v in seq {
+                    self.visit_event_ids(key, v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn match_channel(&self, channel: &str) -> bool {
+        self.channels.is_empty()
+            || self.channels.iter().any(|ch| {
+                if ch.contains('*') {
+                    WildMatch::new(&ch.to_lowercase()).matches(&channel.to_lowercase())
+                } else {
+                    ch.eq_ignore_ascii_case(channel)
+                }
+            })
+    }
+
+    /// ChannelとEventIDの組み合わせがこのルールの検知対象になり得るかを判定する
+    pub fn is_target(&self, channel: &str, event_id: &str) -> bool {
+        if self.channels.is_empty() && self.event_ids.is_empty() {
+            return false;
+        }
+        self.match_channel(channel)
+            && (self.event_ids.is_empty() || self.event_ids.contains(event_id))
+    }
+}
+
+/// 読み込んだルールと入力されたイベントの種類(Channel, EventID)を突き合わせる
+#[derive(Debug, Clone, Default)]
+pub struct RuleCoverage {
+    pub rules: Vec<RuleTarget>,
+    pub event_types: HashMap<(String, String), usize>,
+}
+
+impl RuleCoverage {
+    pub fn new(rule_nodes: &[RuleNode]) -> Self {
+        RuleCoverage {
+            rules: rule_nodes.iter().map(RuleTarget::new).collect(),
+            event_types: HashMap::new(),
+        }
+    }
+
+    /// EventMetricsで集計したイベントの種類ごとのレコード数を取り込む
+    pub fn set_event_types(&mut self, stats_list: &HashMap<(CompactString, CompactString), usize>) {
+        self.event_types = stats_list
+            .iter()
+            .map(|((eid, ch), cnt)| ((ch.to_string(), eid.to_string()), *cnt))
+            .collect();
+    }
+
+    /// ルールのカバレッジの状態と、検知対象になり得るレコード数を返す
+    pub fn rule_status(&self, rule: &RuleTarget) -> (CoverageStatus, usize) {
+        if rule.channels.is_empty() && rule.event_ids.is_empty() {
+            return (CoverageStatus::Unknown, 0);
+        }
+        let records: usize = self
+            .event_types
+            .iter()
+            .filter(|((ch, eid), _)| rule.is_target(ch, eid))
+            .map(|(_, cnt)| cnt)
+            .sum();
+        if records > 0 {
+            (CoverageStatus::Covered, records)
+        } else {
+            (CoverageStatus::NoMatchingEvents, 0)
+        }
+    }
+
+    /// イベントの種類ごとに、レコード数と検知対象にしているルールの数を返す。レコード数の多い順
+    pub fn event_type_rule_counts(&self) -> Vec<(&str, &str, usize, usize)> {
+        self.event_types
+            .iter()
+            .map(|((ch, eid), cnt)| {
+                let rule_cnt = self.rules.iter().filter(|r| r.is_target(ch, eid)).count();
+                (ch.as_str(), eid.as_str(), *cnt, rule_cnt)
+            })
+            .sorted_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(b.1)))
+            .collect()
+    }
+
+    /// 検知対象のイベントが無かったルールをChannelごとに集計する。ログの取得対象を増やす際の優先度付けに使う
+    pub fn missing_channels(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for rule in &self.rules {
+            if self.rule_status(rule).0 != CoverageStatus::NoMatchingEvents {
+                continue;
+            }
+            for ch in &rule.channels {
+                *counts.entry(ch.as_str()).or_insert(0) += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .map(|(ch, cnt)| (ch.to_string(), cnt))
+            .sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rules": self.rules.iter().map(|r| {
+                let (status, records) = self.rule_status(r);
+                json!({
+                    "status": status.to_str(),
+                    "title": r.title,
+                    "id": r.id,
+                    "level": r.level,
+                    "channels": r.channels,
+                    "event_ids": r.event_ids,
+                    "matching_records": records,
+                    "file": r.rulepath,
+                })
+            }).collect::<Vec<_>>(),
+            "event_types": self.event_type_rule_counts().into_iter().map(|(ch, eid, cnt, rules)| json!({
+                "channel": ch,
+                "event_id": eid,
+                "records": cnt,
+                "rules": rules,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    fn write_csv(&self, out_path: &Path) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+        let stem = out_path.with_extension("").display().to_string();
+        let rules_path = PathBuf::from(format!("{stem}-rules.csv"));
+        let events_path = PathBuf::from(format!("{stem}-event-types.csv"));
+        let mut wtr = Writer::from_path(&rules_path)?;
+        wtr.write_record([
+            "Status",
+            "Rule Title",
+            "Rule ID",
+            "Level",
+            "Channels",
+            "EventIDs",
+            "Matching Records",
+            "Rule Path",
+        ])?;
+        for r in &self.rules {
+            let (status, records) = self.rule_status(r);
+            wtr.write_record([
+                status.to_str(),
+                &r.title,
+                &r.id,
+                &r.level,
+                &r.channels.iter().join(" ¦ "),
+                &r.event_ids.iter().join(" ¦ "),
+                &records.to_string(),
+                &r.rulepath,
+            ])?;
+        }
+        wtr.flush()?;
+        let mut wtr = Writer::from_path(&events_path)?;
+        wtr.write_record(["Channel", "EventID", "Records", "Rules"])?;
+        for (ch, eid, cnt, rules) in self.event_type_rule_counts() {
+            wtr.write_record([ch, eid, &cnt.to_string(), &rules.to_string()])?;
+        }
+        wtr.flush()?;
+        Ok((rules_path, events_path))
+    }
+
+    /// 拡張子が.jsonの場合はJSON、それ以外は<FILE>-rules.csvと<FILE>-event-types.csvの2つのCSVで保存する
+    pub fn save(&self, out_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        if out_path.extension().unwrap_or_default() == "json" {
+            fs::write(out_path, serde_json::to_string_pretty(&self.to_json())?)?;
+            Ok(vec![out_path.to_path_buf()])
+        } else {
+            let (rules_path, events_path) = self.write_csv(out_path)?;
+            Ok(vec![rules_path, events_path])
+        }
+    }
+
+    fn status_counts(&self) -> std::collections::HashMap<CoverageStatus, usize> {
+        self.rules.iter().map(|r| self.rule_status(r).0).counts()
+    }
+
+    pub fn print_summary(&self, no_color: bool) {
+        let counts = self.status_counts();
+        let uncovered = self
+            .event_type_rule_counts()
+            .iter()
+            .filter(|e| e.3 == 0)
+            .count();
+        write_color_buffer(
+            &BufferWriter::stdout(ColorChoice::Always),
+            get_writable_color(Some(Color::Rgb(0, 255, 0)), no_color),
+            "Rule coverage: ",
+            false,
+        )
+        .ok();
+        write_color_buffer(
+            &BufferWriter::stdout(ColorChoice::Always),
+            None,
+            &format!(
+                "Covered: {} ¦ No matching events: {} ¦ Unknown: {} ¦ Event types without rules: {} / {}",
+                counts
+                    .get(&CoverageStatus::Covered)
+                    .unwrap_or(&0)
+                    .to_formatted_string(&Locale::en),
+                counts
+                    .get(&CoverageStatus::NoMatchingEvents)
+                    .unwrap_or(&0)
+                    .to_formatted_string(&Locale::en),
+                counts
+                    .get(&CoverageStatus::Unknown)
+                    .unwrap_or(&0)
+                    .to_formatted_string(&Locale::en),
+                uncovered.to_formatted_string(&Locale::en),
+                self.event_types.len().to_formatted_string(&Locale::en),
+            ),
+            true,
+        )
+        .ok();
+    }
+
+    pub fn add_html_report(&self) {
+        let counts = self.status_counts();
+        let mut data = Nested::<String>::new();
+        data.push(format!(
+            "- Rules that could match the input: {}",
+            counts.get(&CoverageStatus::Covered).unwrap_or(&0)
+        ));
+        data.push(format!(
+            "- Rules that could never match because their Channel/EventID is not in the input: {}",
+            counts.get(&CoverageStatus::NoMatchingEvents).unwrap_or(&0)
+        ));
+        data.push(format!(
+            "- Rules without a Channel or EventID: {}\n",
+            counts.get(&CoverageStatus::Unknown).unwrap_or(&0)
+        ));
+        data.push("### Event types without rules\n");
+        data.push("|Channel|EventID|Records|\n|---|---|---:|");
+        for (ch, eid, cnt, _) in self
+            .event_type_rule_counts()
+            .into_iter()
+            .filter(|e| e.3 == 0)
+        {
+            data.push(format!(
+                "|{ch}|{eid}|{}|",
+                cnt.to_formatted_string(&Locale::en)
+            ));
+        }
+        data.push("\n### Channels not in the input\n");
+        data.push("|Channel|Rules|\n|---|---:|");
+        for (ch, cnt) in self.missing_channels() {
+            data.push(format!("|{ch}|{cnt}|"));
+        }
+        htmlreport::add_md_section(HTML_SECTION, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::rule::create_rule;
+    use crate::timeline::coverage::{CoverageStatus, RuleCoverage};
+    use compact_str::CompactString;
+    use hashbrown::HashMap;
+    use yaml_rust2::YamlLoader;
+
+    #[test]
+    fn test_rule_coverage() {
+        let rules = [
+            "title: Logon\ndetection:\n    selection:\n        Channel: Security\n        EventID: 4624\n    filter:\n        EventID: 4625\n    condition: selection and not filter\n",
+            "title: Sysmon\ndetection:\n    selection:\n        Channel: Microsoft-Windows-Sysmon/Operational\n        EventID: 1\n    condition: selection\n",
+            "title: Wildcard\ndetection:\n    selection:\n        Channel: '*PowerShell*'\n    condition: selection\n",
+            "title: Keyword\ndetection:\n    keywords:\n        - mimikatz\n    condition: keywords\n",
+            "title: Modifier\ndetection:\n    selection:\n        Channel|endswith: '/Operational'\n        EventID: 1\n    condition: selection\n",
+            "title: Prefix\ndetection:\n    selection:\n        Channel|startswith: 'PowerShell'\n    condition: selection\n",
+        ];
+        let rule_nodes: Vec<_> = rules
+            .iter()
+            .map(|r| {
+                let yaml = YamlLoader::load_from_str(r).unwrap().remove(0);
+                create_rule("test.yml".to_string(), yaml)
+            })
+            .collect();
+        let mut coverage = RuleCoverage::new(&rule_nodes);
+        assert!(!coverage.rules[0].event_ids.contains("4625"));
+        // EventMetrics::stats_listと同じく(EventID, Channel)を小文字にしたキー
+        let stats_list: HashMap<(CompactString, CompactString), usize> = [
+            (("4624", "security"), 2),
+            (("4688", "security"), 1),
+            (("400", "windows powershell"), 1),
+        ]
+        .into_iter()
+        .map(|((eid, ch), cnt)| ((eid.into(), ch.into()), cnt))
+        .collect();
+        coverage.set_event_types(&stats_list);
+
+        let status: Vec<_> = coverage
+            .rules
+            .iter()
+            .map(|r| coverage.rule_status(r))
+            .collect();
+        assert_eq!(status[0], (CoverageStatus::Covered, 2));
+        assert_eq!(status[1], (CoverageStatus::NoMatchingEvents, 0));
+        assert_eq!(status[2], (CoverageStatus::Covered, 1));
+        assert_eq!(status[3], (CoverageStatus::Unknown, 0));
+        assert_eq!(status[4], (CoverageStatus::NoMatchingEvents, 0));
+        // startswithは前方一致で判定する
+        assert_eq!(status[5], (CoverageStatus::NoMatchingEvents, 0));
+        let uncovered: Vec<_> = coverage
+            .event_type_rule_counts()
+            .into_iter()
+            .filter(|e| e.3 == 0)
+            .collect();
+        assert_eq!(uncovered, vec![("security", "4688", 1, 0)]);
+        assert_eq!(
+            coverage.missing_channels(),
+            vec![
+                ("*/Operational".to_string(), 1),
+                ("Microsoft-Windows-Sysmon/Operational".to_string(), 1),
+                ("PowerShell*".to_string(), 1)
+            ]
+        );
+    }
+}
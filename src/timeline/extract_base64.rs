@@ -1,6 +1,6 @@
-use crate::detections::configs::TimeFormatOptions;
+use crate::detections::configs::{ExtractBase64Option, TimeFormatOptions};
 use crate::detections::detection::EvtxRecordInfo;
-use crate::detections::message::get_event_time;
+use crate::detections::message::{AlertMessage, ERROR_LOG_STACK, get_event_time};
 use crate::detections::utils::{format_time, get_writable_color, write_color_buffer};
 use base64::Engine;
 use base64::prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD};
@@ -10,10 +10,14 @@ use comfy_table::presets::UTF8_FULL;
 use comfy_table::{Cell, CellAlignment, ContentArrangement, Table};
 use csv::Writer;
 use encoding_rs::{UTF_8, UTF_16BE, UTF_16LE};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use infer::Type;
 use regex::Regex;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::string::FromUtf16Error;
 use std::sync::LazyLock;
@@ -22,6 +26,12 @@ use termcolor::{BufferWriter, Color, ColorChoice};
 
 static TOKEN_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[\w+/]+").unwrap());
 static BASE64_PAD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<Base64String>(=*)").unwrap());
+static CHAR_CODE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\[char\]\s*(0x[0-9a-f]+|\d+)").unwrap());
+static CHAR_CODE_LIST_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:\b\d{2,3}\s*,\s*){3,}\d{2,3}\b").unwrap());
+static HEX_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(?:0x)?((?:[0-9a-fA-F]{2}){8,})\b").unwrap());
 
 struct EvtxInfo {
     ts: String,
@@ -222,6 +232,189 @@ fn is_utf16_be(bytes: &[u8]) -> bool {
     UTF_16BE.decode_without_bom_handling(bytes).0.is_ascii()
 }
 
+/// 復号の1段階分の結果
+struct DecodedLayer {
+    method: &'static str,
+    data: Vec<u8>,
+}
+
+/// 文字列として読めるバイト列であれば、文字列に変換して返す
+fn decode_text(bytes: &[u8]) -> Option<String> {
+    if is_utf16_le(bytes) {
+        utf16_le_to_string(bytes).ok()
+    } else if is_utf16_be(bytes) {
+        utf16_be_to_string(bytes).ok()
+    } else if is_utf8(bytes) {
+        str::from_utf8(bytes).ok().map(|s| s.to_string())
+    } else {
+        None
+    }
+}
+
+/// 展開後のデータの上限サイズ。多重に圧縮されたデータでメモリを使い果たさないようにする
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// 展開したデータを返す。上限サイズを超える場合は復号できないものとして扱う
+fn decompress<R: Read>(decoder: R) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    match decoder
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut out)
+    {
+        Ok(_) if !out.is_empty() && out.len() as u64 <= MAX_DECOMPRESSED_SIZE => Some(out),
+        _ => None,
+    }
+}
+
+fn is_zlib(bytes: &[u8]) -> bool {
+    bytes.len() > 2
+        && bytes[0] == 0x78
+        && (u16::from(bytes[0]) * 256 + u16::from(bytes[1])) % 31 == 0
+}
+
+/// 文字列内の[char]の連結やカンマ区切りの文字コードの配列を展開する
+fn unroll_char_codes(text: &str) -> Option<String> {
+    let to_char = |code: &str| {
+        let num = match code.strip_prefix("0x").or_else(|| code.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => code.parse::<u32>().ok(),
+        };
+        num.and_then(char::from_u32)
+    };
+    let chars: Vec<_> = CHAR_CODE_REGEX
+        .captures_iter(text)
+        .map(|c| to_char(&c[1]))
+        .collect();
+    if chars.len() >= 3 && chars.iter().all(|c| c.is_some()) {
+        return Some(chars.into_iter().flatten().collect());
+    }
+    let list = CHAR_CODE_LIST_REGEX
+        .find_iter(text)
+        .max_by_key(|m| m.len())?;
+    let decoded: Option<String> = list
+        .as_str()
+        .split(',')
+        .map(|c| to_char(c.trim()))
+        .collect();
+    decoded.filter(|s| {
+        s.chars()
+            .all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace())
+    })
+}
+
+/// PowerShellのIO.Compression.DeflateStreamで圧縮されたヘッダーのないdeflate形式のデータを展開する
+fn inflate_raw(bytes: &[u8]) -> Option<DecodedLayer> {
+    if infer::get(bytes).is_some() {
+        return None;
+    }
+    let data = decompress(DeflateDecoder::new(bytes))?;
+    Some(DecodedLayer {
+        method: "Deflate",
+        data,
+    })
+}
+
+/// 1段階分の復号を試みる。圧縮、文字コードの配列、16進数、base64の順に確認する。
+/// ヘッダーのないdeflate形式は誤検知を避けるためbase64をデコードした直後のデータのみ確認し、
+/// base64のデコード結果がdeflate形式の場合は展開した結果も合わせて返す
+fn decode_next(bytes: &[u8], after_base64: bool) -> Vec<DecodedLayer> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        return decompress(GzDecoder::new(bytes))
+            .map(|data| DecodedLayer {
+                method: "Gzip",
+                data,
+            })
+            .into_iter()
+            .collect();
+    }
+    if is_zlib(bytes) {
+        if let Some(data) = decompress(ZlibDecoder::new(bytes)) {
+            return vec![DecodedLayer {
+                method: "Zlib",
+                data,
+            }];
+        }
+    }
+    let Some(text) = decode_text(bytes) else {
+        if !after_base64 {
+            return vec![];
+        }
+        return inflate_raw(bytes).into_iter().collect();
+    };
+    if let Some(unrolled) = unroll_char_codes(&text) {
+        return vec![DecodedLayer {
+            method: "CharCode",
+            data: unrolled.into_bytes(),
+        }];
+    }
+    if let Some(hex_str) = HEX_REGEX
+        .captures_iter(&text)
+        .map(|c| c.get(1).unwrap().as_str())
+        .max_by_key(|s| s.len())
+    {
+        if let Ok(data) = hex::decode(hex_str) {
+            if decode_text(&data).is_some() || is_compressed(&data) {
+                return vec![DecodedLayer {
+                    method: "Hex",
+                    data,
+                }];
+            }
+        }
+    }
+    let Some(token) = tokenize(&text)
+        .into_iter()
+        .filter(|t| t.len() >= 16 && !t.chars().all(|c| c.is_alphabetic()) && is_base64(t))
+        .max_by_key(|t| t.len())
+    else {
+        return vec![];
+    };
+    let Ok(data) = BASE64_STANDARD_NO_PAD
+        .decode(token)
+        .or_else(|_| BASE64_STANDARD.decode(token))
+    else {
+        return vec![];
+    };
+    if decode_text(&data).is_some() || is_compressed(&data) || infer::get(&data).is_some() {
+        return vec![DecodedLayer {
+            method: "Base64",
+            data,
+        }];
+    }
+    // deflate形式か確認するために展開した結果は、そのまま次の段階として使う
+    match inflate_raw(&data) {
+        Some(inflated) => vec![
+            DecodedLayer {
+                method: "Base64",
+                data,
+            },
+            inflated,
+        ],
+        None => vec![],
+    }
+}
+
+fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0x1f, 0x8b]) || is_zlib(bytes)
+}
+
+/// base64をデコードしたデータに対して、復号できなくなるか最大の深さに達するまで復号を繰り返す
+fn deobfuscate(payload: &[u8], max_depth: usize) -> Vec<DecodedLayer> {
+    let mut layers = vec![DecodedLayer {
+        method: "Base64",
+        data: payload.to_vec(),
+    }];
+    while layers.len() < max_depth {
+        let current = &layers[layers.len() - 1];
+        let next = decode_next(&current.data, current.method == "Base64");
+        if next.first().is_none_or(|n| n.data == current.data) {
+            break;
+        }
+        layers.extend(next);
+    }
+    layers.truncate(max_depth);
+    layers
+}
+
 fn extract_payload(data: &Value) -> Vec<(Value, Event)> {
     let ch = data["Event"]["System"]["Channel"].as_str();
     let id = data["Event"]["System"]["EventID"].as_i64();
@@ -287,14 +480,35 @@ fn utf16_be_to_string(bytes: &[u8]) -> Result<String, FromUtf16Error> {
     String::from_utf16(&utf16_data)
 }
 
+/// 最終的に復号したデータを<SHA256>.binというファイル名で保存する
+fn save_payload(dir: &Path, sha256: &str, payload: &[u8]) {
+    let path = dir.join(format!("{sha256}.bin"));
+    if let Err(err) = fs::create_dir_all(dir).and_then(|_| fs::write(&path, payload)) {
+        let errmsg = format!(
+            "Failed to save the decoded payload {}. {err}",
+            path.display()
+        );
+        AlertMessage::alert(&errmsg).ok();
+        ERROR_LOG_STACK
+            .lock()
+            .unwrap()
+            .push(format!("[ERROR] {errmsg}"));
+    }
+}
+
 fn create_base64_extracted_record(
     file: &Path,
     possible_base64: &str,
     data: &Value,
     event: Event,
-    ts_fmt_opt: &TimeFormatOptions,
+    opt: &ExtractBase64Option,
 ) -> Vec<Vec<String>> {
-    let evtx = EvtxInfo::new(data, file.to_string_lossy().to_string(), event, ts_fmt_opt);
+    let evtx = EvtxInfo::new(
+        data,
+        file.to_string_lossy().to_string(),
+        event,
+        &opt.time_format_options,
+    );
     let mut records = Vec::new();
     for token in tokenize(possible_base64) {
         if is_base64(token) {
@@ -317,6 +531,16 @@ fn create_base64_extracted_record(
             if no_pad_original.contains("-<Base64String>") {
                 continue;
             }
+            let layers = deobfuscate(&payload, opt.max_depth);
+            let final_layer = layers.last().unwrap();
+            let sha256 = hex::encode(Sha256::digest(&final_layer.data));
+            if let Some(dir) = &opt.save_payloads {
+                save_payload(dir, &sha256, &final_layer.data);
+            }
+            let final_payload = match decode_text(&final_layer.data) {
+                Some(s) => s.chars().filter(|&c| !c.is_control()).collect(),
+                None => "(Binary Data)".to_string(),
+            };
             let row = vec![
                 evtx.ts.to_string(),
                 evtx.computer.clone(),
@@ -331,6 +555,13 @@ fn create_base64_extracted_record(
                 evtx.event.clone(),
                 evtx.rec_id.clone(),
                 evtx.file_name.clone(),
+                layers
+                    .iter()
+                    .map(|l| l.method)
+                    .collect::<Vec<_>>()
+                    .join(" > "),
+                final_payload,
+                sha256,
             ];
             records.push(row);
         }
@@ -338,7 +569,7 @@ fn create_base64_extracted_record(
     records
 }
 
-fn process_record(data: &Value, file: &Path, opt: &TimeFormatOptions) -> Vec<Vec<String>> {
+fn process_record(data: &Value, file: &Path, opt: &ExtractBase64Option) -> Vec<Vec<String>> {
     let mut records = Vec::new();
     let payloads = extract_payload(data);
     for (payload, event) in payloads {
@@ -351,7 +582,7 @@ fn process_record(data: &Value, file: &Path, opt: &TimeFormatOptions) -> Vec<Vec
 
 pub fn process_evtx_record_infos(
     records: &[EvtxRecordInfo],
-    opt: &TimeFormatOptions,
+    opt: &ExtractBase64Option,
 ) -> Vec<Vec<String>> {
     let mut all_records = Vec::new();
     for record in records {
@@ -394,6 +625,9 @@ pub fn output_all(
             "Event",
             "Record ID",
             "File Name",
+            "Decoding Layers",
+            "Final Payload",
+            "Final SHA256",
         ];
         wtr.write_record(csv_header)?;
         for row in all_records.clone().iter_mut() {
@@ -405,7 +639,15 @@ pub fn output_all(
         }
         wtr.flush()?;
     } else {
-        let term_header = ["Timestamp", "Computer", "Base64 String", "Decoded String"];
+        let term_header = [
+            "Timestamp",
+            "Computer",
+            "Base64 String",
+            "Decoded String",
+            "Decoding Layers",
+            "Final Payload",
+            "Final SHA256",
+        ];
         let term_header_cells: Vec<Cell> = term_header
             .iter()
             .map(|s| Cell::new(s).set_alignment(CellAlignment::Center))
@@ -421,7 +663,7 @@ pub fn output_all(
             if binary == "Y" {
                 row[3] = "(Binary Data)".to_string();
             }
-            table.add_row(&row[0..4]);
+            table.add_row(row[0..4].iter().chain(&row[13..16]));
         }
         println!("{table}");
     }
@@ -505,16 +747,109 @@ mod tests {
             "Sec 4688".to_string(),
             "12345".to_string(),
             "test.evtx".to_string(),
+            "Base64".to_string(),
+            "test command".to_string(),
+            "d9f2fdfac5557a80f854dbc584a167cff297db473243a537a6288fbc585021f0".to_string(),
         ]];
 
         let result = process_record(
             &data,
             Path::new("test.evtx"),
-            &TimeFormatOptions {
-                iso_8601: true,
+            &ExtractBase64Option {
+                time_format_options: TimeFormatOptions {
+                    iso_8601: true,
+                    ..Default::default()
+                },
+                max_depth: 5,
                 ..Default::default()
             },
         );
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_deobfuscate_nested_layers() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        // [char]の連結 -> hex -> gzip -> base64 -> UTF-16LEのbase64
+        let chars = "IEX"
+            .chars()
+            .map(|c| format!("[char]{}", c as u32))
+            .collect::<Vec<_>>()
+            .join("+");
+        let hex_str = hex::encode(chars.as_bytes());
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(hex_str.as_bytes()).unwrap();
+        let inner = BASE64_STANDARD.encode(gz.finish().unwrap());
+        let outer_text = format!("$s = '{inner}'");
+        let outer: Vec<u8> = outer_text
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+
+        let layers = deobfuscate(&outer, 10);
+        let methods: Vec<_> = layers.iter().map(|l| l.method).collect();
+        assert_eq!(methods, vec!["Base64", "Base64", "Gzip", "Hex", "CharCode"]);
+        assert_eq!(layers.last().unwrap().data, b"IEX");
+
+        // 最大の深さで打ち切られる
+        assert_eq!(deobfuscate(&outer, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_deobfuscate_raw_deflate() {
+        use flate2::Compression;
+        use flate2::write::DeflateEncoder;
+        use std::io::Write;
+
+        let mut deflate = DeflateEncoder::new(vec![], Compression::default());
+        deflate.write_all(b"Invoke-Expression").unwrap();
+        let compressed = deflate.finish().unwrap();
+        let outer = format!("$s = '{}'", BASE64_STANDARD.encode(&compressed));
+        let layers = deobfuscate(outer.as_bytes(), 10);
+        let methods: Vec<_> = layers.iter().map(|l| l.method).collect();
+        assert_eq!(methods, vec!["Base64", "Base64", "Deflate"]);
+        assert_eq!(layers.last().unwrap().data, b"Invoke-Expression");
+        assert_eq!(deobfuscate(outer.as_bytes(), 2).len(), 2);
+
+        // base64以外の段階ではヘッダーのないdeflate形式として扱わない
+        let hex_str = hex::encode(&compressed);
+        assert!(decode_next(&compressed, false).is_empty());
+        assert_eq!(
+            deobfuscate(hex_str.as_bytes(), 10)
+                .iter()
+                .map(|l| l.method)
+                .collect::<Vec<_>>(),
+            vec!["Base64"]
+        );
+    }
+
+    #[test]
+    fn test_decompress_size_limit() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&vec![0; MAX_DECOMPRESSED_SIZE as usize + 1])
+            .unwrap();
+        let bomb = gz.finish().unwrap();
+        assert!(decompress(GzDecoder::new(bomb.as_slice())).is_none());
+        assert_eq!(deobfuscate(&bomb, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_unroll_char_codes() {
+        assert_eq!(
+            unroll_char_codes("[Char]73+[char]0x45+[CHAR]88"),
+            Some("IEX".to_string())
+        );
+        assert_eq!(
+            unroll_char_codes("(105,101,120 ,32,104,105) -join ''"),
+            Some("iex hi".to_string())
+        );
+        assert_eq!(unroll_char_codes("Get-Process 1, 2"), None);
+    }
 }
@@ -0,0 +1,345 @@
+use crate::detections::configs::TimeFormatOptions;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::message::get_event_time;
+use crate::detections::utils::{
+    format_time, get_writable_color, value_to_string, write_color_buffer,
+};
+use chrono::{DateTime, Duration, Utc};
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use csv::Writer;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use termcolor::{BufferWriter, Color, ColorChoice};
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+pub enum AnomalyType {
+    LogCleared,
+    LoggingStopped,
+    RecordIdGap,
+    TimeReversal,
+    SilentPeriod,
+    RecoveredRecords,
+}
+
+impl AnomalyType {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            AnomalyType::LogCleared => "Log Cleared",
+            AnomalyType::LoggingStopped => "Event Logging Stopped",
+            AnomalyType::RecordIdGap => "Record ID Gap",
+            AnomalyType::TimeReversal => "Timestamp Went Backwards",
+            AnomalyType::SilentPeriod => "Silent Period",
+            AnomalyType::RecoveredRecords => "Recovered Records",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegrityAnomaly {
+    pub anomaly_type: AnomalyType,
+    pub file: String,
+    pub channel: String,
+    pub record_range: (u64, u64),
+    pub time_range: (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    pub details: String,
+}
+
+#[derive(Debug, Clone)]
+struct RecordMeta {
+    record_id: u64,
+    time: Option<DateTime<Utc>>,
+    recovered: bool,
+}
+
+/// ファイルとチャンネル毎にレコードIDとタイムスタンプを集めて、ログの改ざんや欠損を探す
+#[derive(Debug, Clone, Default)]
+pub struct LogIntegrity {
+    records: HashMap<(String, String), Vec<RecordMeta>>,
+    cleared_events: Vec<IntegrityAnomaly>,
+}
+
+impl LogIntegrity {
+    pub fn process(&mut self, records: &[EvtxRecordInfo], json_input_flag: bool) {
+        for record in records {
+            let system = &record.record["Event"]["System"];
+            let Some(record_id) =
+                value_to_string(&system["EventRecordID"]).and_then(|id| id.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let channel = value_to_string(&system["Channel"]).unwrap_or_default();
+            let event_id = value_to_string(&system["EventID"]).unwrap_or_default();
+            let time = get_event_time(&record.record, json_input_flag);
+            let cleared = match (channel.as_str(), event_id.as_str()) {
+                ("Security", "1102") => Some((AnomalyType::LogCleared, "Security 1102")),
+                ("System", "104") => Some((AnomalyType::LogCleared, "System 104")),
+                ("Security", "1100") => Some((AnomalyType::LoggingStopped, "Security 1100")),
+                _ => None,
+            };
+            if let Some((anomaly_type, event)) = cleared {
+                let user = value_to_string(
+                    &record.record["Event"]["UserData"]["LogFileCleared"]["SubjectUserName"],
+                )
+                .unwrap_or_default();
+                let details = if user.is_empty() {
+                    event.to_string()
+                } else {
+                    format!("{event} (User: {user})")
+                };
+                self.cleared_events.push(IntegrityAnomaly {
+                    anomaly_type,
+                    file: record.evtx_filepath.clone(),
+                    channel: channel.clone(),
+                    record_range: (record_id, record_id),
+                    time_range: (time, time),
+                    details,
+                });
+            }
+            self.records
+                .entry((record.evtx_filepath.clone(), channel))
+                .or_default()
+                .push(RecordMeta {
+                    record_id,
+                    time,
+                    recovered: record.recovered_record,
+                });
+        }
+    }
+
+    /// 集めたレコードを解析して、見つかった異常をファイル、レコードIDの順に返す
+    pub fn analyze(&self, silent_period: Duration) -> Vec<IntegrityAnomaly> {
+        let mut ret = self.cleared_events.clone();
+        for ((file, channel), metas) in &self.records {
+            let new_anomaly = |anomaly_type, record_range, time_range, details| IntegrityAnomaly {
+                anomaly_type,
+                file: file.clone(),
+                channel: channel.clone(),
+                record_range,
+                time_range,
+                details,
+            };
+
+            // 復元したレコードはレコードIDが重複することがあるため、連番のチェックとは分けて扱う
+            let (mut recovered, mut metas): (Vec<&RecordMeta>, Vec<&RecordMeta>) =
+                metas.iter().partition(|m| m.recovered);
+            metas.sort_by_key(|m| m.record_id);
+            metas.dedup_by_key(|m| m.record_id);
+            for pair in metas.windows(2) {
+                let (prev, next) = (pair[0], pair[1]);
+                if next.record_id > prev.record_id + 1 {
+                    let missing = next.record_id - prev.record_id - 1;
+                    ret.push(new_anomaly(
+                        AnomalyType::RecordIdGap,
+                        (prev.record_id + 1, next.record_id - 1),
+                        (prev.time, next.time),
+                        format!("{missing} record(s) missing"),
+                    ));
+                }
+                if let (Some(prev_time), Some(next_time)) = (prev.time, next.time) {
+                    if next_time < prev_time {
+                        ret.push(new_anomaly(
+                            AnomalyType::TimeReversal,
+                            (prev.record_id, next.record_id),
+                            (prev.time, next.time),
+                            format!(
+                                "Went back {} second(s)",
+                                (prev_time - next_time).num_seconds()
+                            ),
+                        ));
+                    } else if next_time - prev_time > silent_period {
+                        ret.push(new_anomaly(
+                            AnomalyType::SilentPeriod,
+                            (prev.record_id, next.record_id),
+                            (prev.time, next.time),
+                            format!(
+                                "No events for {} hour(s)",
+                                (next_time - prev_time).num_hours()
+                            ),
+                        ));
+                    }
+                }
+            }
+
+            recovered.sort_by_key(|m| m.record_id);
+            let mut chunks: Vec<Vec<&RecordMeta>> = vec![];
+            for meta in recovered {
+                match chunks.last_mut() {
+                    Some(chunk) if meta.record_id <= chunk.last().unwrap().record_id + 1 => {
+                        chunk.push(meta)
+                    }
+                    _ => chunks.push(vec![meta]),
+                }
+            }
+            for chunk in chunks {
+                let start = chunk.iter().filter_map(|m| m.time).min();
+                let end = chunk.iter().filter_map(|m| m.time).max();
+                ret.push(new_anomaly(
+                    AnomalyType::RecoveredRecords,
+                    (chunk[0].record_id, chunk[chunk.len() - 1].record_id),
+                    (start, end),
+                    format!("{} record(s) carved from slack space", chunk.len()),
+                ));
+            }
+        }
+        ret.sort_by(|x, y| {
+            (&x.file, &x.channel, x.record_range.0, x.anomaly_type).cmp(&(
+                &y.file,
+                &y.channel,
+                y.record_range.0,
+                y.anomaly_type,
+            ))
+        });
+        ret
+    }
+}
+
+pub fn output_integrity(
+    anomalies: &[IntegrityAnomaly],
+    out_path: Option<&PathBuf>,
+    no_color: bool,
+    time_format_options: &TimeFormatOptions,
+) -> Result<(), Box<dyn Error>> {
+    if anomalies.is_empty() {
+        write_color_buffer(
+            &BufferWriter::stdout(ColorChoice::Always),
+            get_writable_color(Some(Color::Rgb(0, 255, 0)), no_color),
+            "No event log tampering or gaps were found.",
+            true,
+        )
+        .ok();
+        println!();
+        return Ok(());
+    }
+    let header = [
+        "Anomaly",
+        "File",
+        "Channel",
+        "Record IDs",
+        "Start Time",
+        "End Time",
+        "Details",
+    ];
+    let fmt = |t: &Option<DateTime<Utc>>| {
+        t.map(|t| format_time(&t, false, time_format_options).to_string())
+            .unwrap_or_else(|| "n/a".to_string())
+    };
+    let rows = anomalies.iter().map(|a| {
+        let record_ids = if a.record_range.0 == a.record_range.1 {
+            a.record_range.0.to_string()
+        } else {
+            format!("{} - {}", a.record_range.0, a.record_range.1)
+        };
+        [
+            a.anomaly_type.to_str().to_string(),
+            a.file.clone(),
+            a.channel.clone(),
+            record_ids,
+            fmt(&a.time_range.0),
+            fmt(&a.time_range.1),
+            a.details.clone(),
+        ]
+    });
+    if let Some(out_path) = out_path {
+        let mut wtr = Writer::from_path(out_path)?;
+        wtr.write_record(header)?;
+        for row in rows {
+            wtr.write_record(row)?;
+        }
+        wtr.flush()?;
+    } else {
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::DynamicFullWidth)
+            .set_header(header);
+        for row in rows {
+            table.add_row(row);
+        }
+        println!("{table}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::timeline::integrity::{AnomalyType, LogIntegrity};
+    use chrono::Duration;
+    use hashbrown::HashMap;
+    use serde_json::json;
+
+    fn to_rec(
+        channel: &str,
+        event_id: u64,
+        record_id: u64,
+        time: &str,
+        recovered: bool,
+    ) -> EvtxRecordInfo {
+        let record = json!({
+            "Event": {
+                "System": {
+                    "Channel": channel,
+                    "EventID": event_id,
+                    "EventRecordID": record_id,
+                    "TimeCreated_attributes": {"SystemTime": time}
+                }
+            }
+        });
+        EvtxRecordInfo {
+            evtx_filepath: "Security.evtx".to_string(),
+            data_string: record.to_string(),
+            record,
+            key_2_value: HashMap::new(),
+            recovered_record: recovered,
+        }
+    }
+
+    #[test]
+    fn test_record_gap_and_time_anomalies() {
+        let records = vec![
+            to_rec("Security", 4624, 1, "2024-01-01T00:00:00Z", false),
+            to_rec("Security", 4624, 2, "2024-01-01T01:00:00Z", false),
+            to_rec("Security", 4624, 6, "2024-01-01T02:00:00Z", false),
+            to_rec("Security", 4624, 7, "2024-01-01T01:30:00Z", false),
+            to_rec("Security", 4624, 8, "2024-01-03T01:30:00Z", false),
+        ];
+        let mut integrity = LogIntegrity::default();
+        integrity.process(&records, false);
+        let anomalies = integrity.analyze(Duration::hours(24));
+
+        assert_eq!(anomalies.len(), 3);
+        assert_eq!(anomalies[0].anomaly_type, AnomalyType::RecordIdGap);
+        assert_eq!(anomalies[0].record_range, (3, 5));
+        assert_eq!(anomalies[0].details, "3 record(s) missing");
+        assert_eq!(anomalies[1].anomaly_type, AnomalyType::TimeReversal);
+        assert_eq!(anomalies[1].record_range, (6, 7));
+        assert_eq!(anomalies[2].anomaly_type, AnomalyType::SilentPeriod);
+        assert_eq!(anomalies[2].record_range, (7, 8));
+    }
+
+    #[test]
+    fn test_log_cleared_and_recovered_records() {
+        let records = vec![
+            to_rec("Security", 1102, 10, "2024-01-01T00:00:00Z", false),
+            to_rec("Security", 4624, 11, "2024-01-01T00:01:00Z", false),
+            to_rec("Security", 4624, 3, "2023-12-01T00:00:00Z", true),
+            to_rec("Security", 4624, 4, "2023-12-01T00:01:00Z", true),
+            to_rec("Security", 4624, 8, "2023-12-02T00:00:00Z", true),
+        ];
+        let mut integrity = LogIntegrity::default();
+        integrity.process(&records, false);
+        let anomalies = integrity.analyze(Duration::hours(24));
+
+        assert_eq!(anomalies.len(), 3);
+        assert_eq!(anomalies[0].anomaly_type, AnomalyType::RecoveredRecords);
+        assert_eq!(anomalies[0].record_range, (3, 4));
+        assert_eq!(anomalies[1].anomaly_type, AnomalyType::RecoveredRecords);
+        assert_eq!(anomalies[1].record_range, (8, 8));
+        assert_eq!(anomalies[2].anomaly_type, AnomalyType::LogCleared);
+        assert_eq!(anomalies[2].record_range, (10, 10));
+    }
+}
@@ -0,0 +1,524 @@
+use crate::detections::configs::TimeFormatOptions;
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::message::{AlertMessage, get_event_time};
+use crate::detections::utils::{
+    format_time, get_writable_color, value_to_string, write_color_buffer,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use itertools::Itertools;
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use termcolor::{BufferWriter, Color, ColorChoice};
+
+const RDS_RCM_CHANNEL: &str =
+    "Microsoft-Windows-TerminalServices-RemoteConnectionManager/Operational";
+const RDS_LSM_CHANNEL: &str = "Microsoft-Windows-TerminalServices-LocalSessionManager/Operational";
+
+/// 送信元と送信先の組み合わせ毎に集計したログオンの情報
+#[derive(Debug, Clone, Default)]
+pub struct LateralMovementEdge {
+    pub count: usize,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub users: BTreeSet<String>,
+    pub events: BTreeSet<String>,
+}
+
+impl LateralMovementEdge {
+    fn update(&mut self, time: Option<DateTime<Utc>>, user: String, event: &str) {
+        self.count += 1;
+        if let Some(t) = time {
+            if self.first_seen.is_none_or(|f| t < f) {
+                self.first_seen = Some(t);
+            }
+            if self.last_seen.is_none_or(|l| t > l) {
+                self.last_seen = Some(t);
+            }
+        }
+        if !user.is_empty() {
+            self.users.insert(user);
+        }
+        self.events.insert(event.to_string());
+    }
+}
+
+/// ネットワークログオンから作成する有向グラフ。キーは(送信元, 送信先)
+#[derive(Debug, Clone, Default)]
+pub struct LateralMovementGraph {
+    pub edges: HashMap<(String, String), LateralMovementEdge>,
+}
+
+impl LateralMovementGraph {
+    pub fn process(&mut self, records: &[EvtxRecordInfo], json_input_flag: bool) {
+        for record in records {
+            if let Some((src, dst, user, event)) = extract_edge(&record.record) {
+                let time = get_event_time(&record.record, json_input_flag);
+                self.edges
+                    .entry((src, dst))
+                    .or_default()
+                    .update(time, user, event);
+            }
+        }
+    }
+
+    /// 件数の多い順(同数の場合は送信元、送信先の順)に並べたエッジを返す
+    pub fn sorted_edges(&self) -> Vec<(&(String, String), &LateralMovementEdge)> {
+        self.edges
+            .iter()
+            .sorted_by(|x, y| y.1.count.cmp(&x.1.count).then_with(|| x.0.cmp(y.0)))
+            .collect()
+    }
+
+    fn nodes(&self) -> BTreeSet<&str> {
+        self.edges
+            .keys()
+            .flat_map(|(src, dst)| [src.as_str(), dst.as_str()])
+            .collect()
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut ret = String::from("digraph lateral_movement {\n");
+        ret.push_str("    rankdir=LR;\n    node [shape=box];\n");
+        for node in self.nodes() {
+            ret.push_str(&format!("    \"{}\";\n", escape_dot(node)));
+        }
+        for ((src, dst), edge) in self.sorted_edges() {
+            ret.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\", weight={}, first_seen=\"{}\", last_seen=\"{}\", users=\"{}\", events=\"{}\"];\n",
+                escape_dot(src),
+                escape_dot(dst),
+                edge.count,
+                edge.count,
+                rfc3339(&edge.first_seen),
+                rfc3339(&edge.last_seen),
+                escape_dot(&edge.users.iter().join(";")),
+                escape_dot(&edge.events.iter().join(";")),
+            ));
+        }
+        ret.push_str("}\n");
+        ret
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut ret = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="weight" for="edge" attr.name="weight" attr.type="int"/>
+  <key id="first_seen" for="edge" attr.name="first_seen" attr.type="string"/>
+  <key id="last_seen" for="edge" attr.name="last_seen" attr.type="string"/>
+  <key id="users" for="edge" attr.name="users" attr.type="string"/>
+  <key id="events" for="edge" attr.name="events" attr.type="string"/>
+  <graph id="lateral_movement" edgedefault="directed">
+"#,
+        );
+        for node in self.nodes() {
+            let node = escape_xml(node);
+            ret.push_str(&format!(
+                "    <node id=\"{node}\"><data key=\"label\">{node}</data></node>\n"
+            ));
+        }
+        for (i, ((src, dst), edge)) in self.sorted_edges().into_iter().enumerate() {
+            ret.push_str(&format!(
+                "    <edge id=\"e{i}\" source=\"{}\" target=\"{}\">\n",
+                escape_xml(src),
+                escape_xml(dst)
+            ));
+            ret.push_str(&format!(
+                "      <data key=\"weight\">{}</data>\n",
+                edge.count
+            ));
+            ret.push_str(&format!(
+                "      <data key=\"first_seen\">{}</data>\n",
+                rfc3339(&edge.first_seen)
+            ));
+            ret.push_str(&format!(
+                "      <data key=\"last_seen\">{}</data>\n",
+                rfc3339(&edge.last_seen)
+            ));
+            ret.push_str(&format!(
+                "      <data key=\"users\">{}</data>\n",
+                escape_xml(&edge.users.iter().join(";"))
+            ));
+            ret.push_str(&format!(
+                "      <data key=\"events\">{}</data>\n",
+                escape_xml(&edge.events.iter().join(";"))
+            ));
+            ret.push_str("    </edge>\n");
+        }
+        ret.push_str("  </graph>\n</graphml>\n");
+        ret
+    }
+
+    /// networkxのnode-link形式のJSONを作成する
+    pub fn to_json(&self) -> Value {
+        let nodes: Vec<Value> = self.nodes().iter().map(|n| json!({"id": n})).collect();
+        let links: Vec<Value> = self
+            .sorted_edges()
+            .iter()
+            .map(|((src, dst), edge)| {
+                json!({
+                    "source": src,
+                    "target": dst,
+                    "weight": edge.count,
+                    "first_seen": rfc3339(&edge.first_seen),
+                    "last_seen": rfc3339(&edge.last_seen),
+                    "users": edge.users,
+                    "events": edge.events,
+                })
+            })
+            .collect();
+        json!({
+            "directed": true,
+            "multigraph": false,
+            "graph": {},
+            "nodes": nodes,
+            "links": links,
+        })
+    }
+
+    pub fn output(
+        &self,
+        output: &Option<PathBuf>,
+        no_color: bool,
+        time_format_options: &TimeFormatOptions,
+    ) {
+        if let Some(prefix) = output {
+            let prefix = prefix.as_path().display().to_string();
+            let json_str = serde_json::to_string_pretty(&self.to_json()).unwrap_or_default();
+            for (suffix, contents) in [
+                (".dot", self.to_dot()),
+                (".graphml", self.to_graphml()),
+                (".json", json_str),
+            ] {
+                let file_name = format!("{prefix}{suffix}");
+                match File::create(&file_name) {
+                    Ok(file) => {
+                        let mut wtr = BufWriter::new(file);
+                        if let Err(err) = wtr.write_all(contents.as_bytes()) {
+                            AlertMessage::alert(&format!("Failed to write {file_name}. {err}"))
+                                .ok();
+                        }
+                    }
+                    Err(err) => {
+                        AlertMessage::alert(&format!("Failed to open file. {err}")).ok();
+                    }
+                }
+            }
+            return;
+        }
+
+        write_color_buffer(
+            &BufferWriter::stdout(ColorChoice::Always),
+            get_writable_color(Some(Color::Rgb(0, 255, 0)), no_color),
+            "Lateral Movement:",
+            true,
+        )
+        .ok();
+        if self.edges.is_empty() {
+            println!(" No lateral movement events were detected.");
+            return;
+        }
+        let mut tb = Table::new();
+        tb.load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header([
+                "Count",
+                "Source",
+                "Destination",
+                "First Seen",
+                "Last Seen",
+                "Users",
+                "Events",
+            ]);
+        let fmt = |t: &Option<DateTime<Utc>>| {
+            t.map(|t| format_time(&t, false, time_format_options).to_string())
+                .unwrap_or_default()
+        };
+        for ((src, dst), edge) in self.sorted_edges() {
+            tb.add_row([
+                edge.count.to_string(),
+                src.to_string(),
+                dst.to_string(),
+                fmt(&edge.first_seen),
+                fmt(&edge.last_seen),
+                edge.users.iter().join("\n"),
+                edge.events.iter().join("\n"),
+            ]);
+        }
+        println!("{tb}");
+    }
+}
+
+/// レコードから(送信元, 送信先, ユーザ, イベント名)を取り出す。対象外のレコードはNoneを返す
+fn extract_edge(data: &Value) -> Option<(String, String, String, &'static str)> {
+    let system = &data["Event"]["System"];
+    let channel = system["Channel"].as_str()?;
+    let event_id = value_to_string(&system["EventID"])?;
+    let computer = get_str(&system["Computer"]);
+    let event_data = &data["Event"]["EventData"];
+    let user_data = &data["Event"]["UserData"]["EventXML"];
+
+    let (src, dst, user, event) = match (channel, event_id.as_str()) {
+        ("Security", "4624") => {
+            let event = match get_str(&event_data["LogonType"]).as_str() {
+                "3" => "Sec 4624 (Type 3)",
+                "10" => "Sec 4624 (Type 10)",
+                _ => return None,
+            };
+            let ip = normalize_ip(&get_str(&event_data["IpAddress"]));
+            let src = if is_valid_host(&ip) {
+                ip
+            } else {
+                get_str(&event_data["WorkstationName"])
+            };
+            let user = join_user(
+                &get_str(&event_data["TargetDomainName"]),
+                &get_str(&event_data["TargetUserName"]),
+            );
+            (src, computer, user, event)
+        }
+        ("Security", "4648") => {
+            let user = join_user(
+                &get_str(&event_data["TargetDomainName"]),
+                &get_str(&event_data["TargetUserName"]),
+            );
+            let dst = get_str(&event_data["TargetServerName"]);
+            (computer, dst, user, "Sec 4648")
+        }
+        ("Security", "5140") | ("Security", "5145") => {
+            let event = if event_id == "5140" {
+                "Sec 5140"
+            } else {
+                "Sec 5145"
+            };
+            let user = join_user(
+                &get_str(&event_data["SubjectDomainName"]),
+                &get_str(&event_data["SubjectUserName"]),
+            );
+            let src = normalize_ip(&get_str(&event_data["IpAddress"]));
+            (src, computer, user, event)
+        }
+        (RDS_RCM_CHANNEL, "1149") => {
+            let user = join_user(
+                &get_str(&user_data["Param2"]),
+                &get_str(&user_data["Param1"]),
+            );
+            let src = normalize_ip(&get_str(&user_data["Param3"]));
+            (src, computer, user, "RDS-RCM 1149")
+        }
+        (RDS_LSM_CHANNEL, "21") | (RDS_LSM_CHANNEL, "22") | (RDS_LSM_CHANNEL, "25") => {
+            let event = match event_id.as_str() {
+                "21" => "RDS-LSM 21",
+                "22" => "RDS-LSM 22",
+                _ => "RDS-LSM 25",
+            };
+            let src = normalize_ip(&get_str(&user_data["Address"]));
+            (src, computer, get_str(&user_data["User"]), event)
+        }
+        _ => return None,
+    };
+    if !is_valid_host(&src) || !is_valid_host(&dst) || is_same_host(&src, &dst) {
+        return None;
+    }
+    Some((src, dst, user, event))
+}
+
+fn get_str(value: &Value) -> String {
+    value_to_string(value).unwrap_or_default()
+}
+
+fn join_user(domain: &str, user: &str) -> String {
+    if user.is_empty() || user == "-" {
+        String::default()
+    } else if domain.is_empty() || domain == "-" {
+        user.to_string()
+    } else {
+        format!("{domain}\\{user}")
+    }
+}
+
+fn normalize_ip(ip: &str) -> String {
+    ip.trim_start_matches("::ffff:").to_string()
+}
+
+/// 送信元・送信先として扱えない値(空、ローカル、ループバック)を除外する
+fn is_valid_host(host: &str) -> bool {
+    !matches!(
+        host.to_lowercase().as_str(),
+        "" | "-" | "local" | "localhost" | "127.0.0.1" | "::1"
+    )
+}
+
+/// FQDNとホスト名の違いは無視して同一ホストか判定する
+fn is_same_host(src: &str, dst: &str) -> bool {
+    let short = |h: &str| h.split('.').next().unwrap_or_default().to_lowercase();
+    src.eq_ignore_ascii_case(dst)
+        || (src.parse::<std::net::IpAddr>().is_err()
+            && dst.parse::<std::net::IpAddr>().is_err()
+            && short(src) == short(dst))
+}
+
+fn rfc3339(time: &Option<DateTime<Utc>>) -> String {
+    time.map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default()
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::timeline::lateral_movement::LateralMovementGraph;
+    use hashbrown::HashMap;
+    use serde_json::{Value, json};
+
+    fn to_rec(record: Value) -> EvtxRecordInfo {
+        EvtxRecordInfo {
+            evtx_filepath: "test.evtx".to_string(),
+            data_string: record.to_string(),
+            record,
+            key_2_value: HashMap::new(),
+            recovered_record: false,
+        }
+    }
+
+    fn logon(time: &str, logon_type: &str, ip: &str, user: &str) -> EvtxRecordInfo {
+        to_rec(json!({
+            "Event": {
+                "System": {
+                    "Channel": "Security",
+                    "EventID": 4624,
+                    "Computer": "DC01.corp.local",
+                    "TimeCreated_attributes": {"SystemTime": time}
+                },
+                "EventData": {
+                    "LogonType": logon_type,
+                    "IpAddress": ip,
+                    "WorkstationName": "WS01",
+                    "TargetUserName": user,
+                    "TargetDomainName": "CORP"
+                }
+            }
+        }))
+    }
+
+    #[test]
+    fn test_process_network_logons() {
+        let records = vec![
+            logon("2024-01-01T10:00:00Z", "3", "10.0.0.5", "alice"),
+            logon("2024-01-01T09:00:00Z", "10", "::ffff:10.0.0.5", "bob"),
+            logon("2024-01-01T11:00:00Z", "2", "10.0.0.5", "carol"),
+            logon("2024-01-01T12:00:00Z", "3", "127.0.0.1", "dave"),
+            logon("2024-01-01T13:00:00Z", "3", "-", "erin"),
+        ];
+        let mut graph = LateralMovementGraph::default();
+        graph.process(&records, false);
+
+        assert_eq!(graph.edges.len(), 2);
+        let edge = &graph.edges[&("10.0.0.5".to_string(), "DC01.corp.local".to_string())];
+        assert_eq!(edge.count, 2);
+        assert_eq!(
+            edge.users.iter().collect::<Vec<_>>(),
+            vec!["CORP\\alice", "CORP\\bob"]
+        );
+        assert_eq!(
+            edge.first_seen.unwrap().to_rfc3339(),
+            "2024-01-01T09:00:00+00:00"
+        );
+        assert_eq!(
+            edge.last_seen.unwrap().to_rfc3339(),
+            "2024-01-01T10:00:00+00:00"
+        );
+        // IPアドレスがないかループバックの場合はWorkstationNameを送信元にする
+        assert_eq!(
+            graph.edges[&("WS01".to_string(), "DC01.corp.local".to_string())].count,
+            2
+        );
+    }
+
+    #[test]
+    fn test_process_explicit_and_rdp_logons() {
+        let records = vec![
+            to_rec(json!({
+                "Event": {
+                    "System": {"Channel": "Security", "EventID": 4648, "Computer": "WS01.corp.local"},
+                    "EventData": {"TargetServerName": "FS01", "TargetUserName": "admin", "TargetDomainName": "CORP"}
+                }
+            })),
+            to_rec(json!({
+                "Event": {
+                    "System": {"Channel": "Security", "EventID": 4648, "Computer": "WS01.corp.local"},
+                    "EventData": {"TargetServerName": "ws01", "TargetUserName": "admin", "TargetDomainName": "CORP"}
+                }
+            })),
+            to_rec(json!({
+                "Event": {
+                    "System": {"Channel": "Microsoft-Windows-TerminalServices-RemoteConnectionManager/Operational", "EventID": 1149, "Computer": "SRV01"},
+                    "UserData": {"EventXML": {"Param1": "admin", "Param2": "CORP", "Param3": "10.0.0.9"}}
+                }
+            })),
+            to_rec(json!({
+                "Event": {
+                    "System": {"Channel": "Microsoft-Windows-TerminalServices-LocalSessionManager/Operational", "EventID": 21, "Computer": "SRV01"},
+                    "UserData": {"EventXML": {"User": "CORP\\admin", "Address": "LOCAL"}}
+                }
+            })),
+        ];
+        let mut graph = LateralMovementGraph::default();
+        graph.process(&records, false);
+
+        assert_eq!(graph.edges.len(), 2);
+        assert!(
+            graph.edges[&("WS01.corp.local".to_string(), "FS01".to_string())]
+                .events
+                .contains("Sec 4648")
+        );
+        assert!(
+            graph.edges[&("10.0.0.9".to_string(), "SRV01".to_string())]
+                .users
+                .contains("CORP\\admin")
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let records = vec![logon("2024-01-01T10:00:00Z", "3", "10.0.0.5", "a&b")];
+        let mut graph = LateralMovementGraph::default();
+        graph.process(&records, false);
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph lateral_movement {"));
+        assert!(dot.contains(r#""10.0.0.5" -> "DC01.corp.local" [label="1", weight=1"#));
+        assert!(dot.contains(r#"users="CORP\\a&b""#));
+
+        let graphml = graph.to_graphml();
+        assert!(graphml.contains(r#"<edge id="e0" source="10.0.0.5" target="DC01.corp.local">"#));
+        assert!(graphml.contains(r#"<data key="users">CORP\a&amp;b</data>"#));
+        assert!(graphml.contains(r#"<data key="first_seen">2024-01-01T10:00:00.000Z</data>"#));
+
+        let json = graph.to_json();
+        assert_eq!(json["directed"], true);
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["links"][0]["source"], "10.0.0.5");
+        assert_eq!(json["links"][0]["weight"], 1);
+        assert_eq!(json["links"][0]["users"][0], "CORP\\a&b");
+    }
+}
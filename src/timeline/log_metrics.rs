@@ -11,6 +11,7 @@ pub struct LogMetrics {
     pub file_size: String,
     pub computers: HashSet<String>,
     pub event_count: usize,
+    pub duplicate_count: usize,
     pub first_timestamp: Option<DateTime<Utc>>,
     pub last_timestamp: Option<DateTime<Utc>>,
     pub channels: HashSet<String>,
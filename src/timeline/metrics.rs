@@ -90,11 +90,8 @@ impl EventMetrics {
 
         self.stats_time_cnt(records, stored_static);
         let path = Path::new(self.filepath.as_str());
-        let file_name = path
-            .file_name()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default();
+        let file_name = logfile_name(path, stored_static);
+        let file_name = file_name.as_str();
         let file_size = get_file_size(
             path,
             stored_static.verbose_flag,
@@ -121,6 +118,41 @@ impl EventMetrics {
         }
     }
 
+    /// --remove-duplicate-recordsで取り除いたレコードの件数をログファイルごとに数える
+    pub fn logfile_duplicates_cnt(
+        &mut self,
+        filepath: &str,
+        computers: &[String],
+        stored_static: &StoredStatic,
+    ) {
+        if !stored_static.log_metrics_flag || computers.is_empty() {
+            return;
+        }
+        let path = Path::new(filepath);
+        let file_name = logfile_name(path, stored_static);
+        let file_name = file_name.as_str();
+        for computer in computers {
+            if let Some(existing_lm) = self
+                .stats_logfile
+                .iter_mut()
+                .find(|lm| lm.filename == file_name && lm.computers.contains(computer))
+            {
+                existing_lm.duplicate_count += 1;
+            } else {
+                // すべてのレコードが重複しているファイルも一覧に表示する
+                let file_size = get_file_size(
+                    path,
+                    stored_static.verbose_flag,
+                    stored_static.quiet_errors_flag,
+                );
+                let mut lm = LogMetrics::new(file_name, ByteSize::b(file_size).to_string());
+                lm.computers.insert(computer.to_string());
+                lm.duplicate_count = 1;
+                self.stats_logfile.push(lm);
+            }
+        }
+    }
+
     pub fn stats_time_cnt(&mut self, records: &[EvtxRecordInfo], stored_static: &StoredStatic) {
         if records.is_empty() {
             return;
@@ -204,7 +236,7 @@ impl EventMetrics {
     }
 
     /// EventIDで集計
-    fn stats_eventid(
+    pub fn stats_eventid(
         &mut self,
         records: &[EvtxRecordInfo],
         stored_static: &StoredStatic,
@@ -491,6 +523,19 @@ fn is_target_event(idnum: i64, channel: &str) -> Option<Channel> {
     None
 }
 
+/// log-metricsの行を区別するファイル名を返す。--remove-duplicate-recordsが指定されている場合は、
+/// VSSのスナップショットなどの同じ名前のコピーでどのファイルに重複があったかを区別できるようにフルパスを使う
+fn logfile_name(path: &Path, stored_static: &StoredStatic) -> String {
+    if stored_static.remove_duplicate_records {
+        path.display().to_string()
+    } else {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
@@ -528,6 +573,7 @@ mod tests {
                     filepath: None,
                     live_analysis: false,
                     recover_records: false,
+                    remove_duplicate_records: false,
                     time_offset: None,
                 },
                 common_options: CommonOptions {
@@ -1,7 +1,12 @@
 pub mod computer_metrics;
 mod config_critical_systems;
+pub mod coverage;
 mod extract_base64;
+mod integrity;
+mod lateral_movement;
 mod log_metrics;
 pub mod metrics;
+mod record_dedup;
+mod scriptblock;
 pub mod search;
 pub mod timelines;
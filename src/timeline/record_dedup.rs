@@ -0,0 +1,86 @@
+use hashbrown::HashSet;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// 複数の入力ファイルに含まれる同じレコード(VSSのスナップショットやバックアップのコピーなど)を判定する
+#[derive(Debug, Clone, Default)]
+pub struct RecordDedup {
+    seen: HashSet<u128>,
+    pub duplicate_cnt: usize,
+}
+
+impl RecordDedup {
+    /// 既に読み込んだレコードと同じ場合はtrueを返す。
+    /// Computer、Channel、EventRecordID、SystemTimeで判定し、EventRecordIDかSystemTimeがないレコードはレコード全体のハッシュで判定する
+    pub fn is_duplicate(&mut self, record: &Value) -> bool {
+        let mut hasher = Sha256::new();
+        let record_id = get_system_value(record, "EventRecordID");
+        let time = [
+            &record["Event"]["System"]["TimeCreated_attributes"]["SystemTime"],
+            &record["Event"]["EventData"]["@timestamp"],
+            &record["Event"]["System"]["@timestamp"],
+        ]
+        .into_iter()
+        .find(|v| !v.is_null());
+        if let (Some(record_id), Some(time)) = (record_id, time) {
+            for value in [
+                get_system_value(record, "Computer").unwrap_or(&Value::Null),
+                get_system_value(record, "Channel").unwrap_or(&Value::Null),
+                record_id,
+                time,
+            ] {
+                hasher.update(value.to_string());
+                hasher.update([0]);
+            }
+        } else {
+            hasher.update(record.to_string());
+        }
+        let digest = hasher.finalize();
+        let key = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        let is_duplicate = !self.seen.insert(key);
+        if is_duplicate {
+            self.duplicate_cnt += 1;
+        }
+        is_duplicate
+    }
+}
+
+/// JSON入力ではSystemのフィールドがEventDataに入っているため両方を確認する
+fn get_system_value<'a>(record: &'a Value, key: &str) -> Option<&'a Value> {
+    [
+        &record["Event"]["System"][key],
+        &record["Event"]["EventData"][key],
+    ]
+    .into_iter()
+    .find(|v| !v.is_null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_duplicate() {
+        let mut dedup = RecordDedup::default();
+        let record = |id: u64, data: &str| {
+            json!({"Event": {"System": {
+                "Computer": "PC1",
+                "Channel": "Security",
+                "EventRecordID": id,
+                "TimeCreated_attributes": {"SystemTime": "2024-01-01T00:00:00.000Z"}
+            }, "EventData": {"Data": data}}})
+        };
+        assert!(!dedup.is_duplicate(&record(1, "a")));
+        assert!(!dedup.is_duplicate(&record(2, "a")));
+        // 同じレコードIDと時刻であれば別のファイルから読み込んだレコードでも重複とする
+        assert!(dedup.is_duplicate(&record(1, "b")));
+
+        let json_record =
+            |data: &str| json!({"Event": {"EventData": {"Channel": "Security", "Data": data}}});
+        assert!(!dedup.is_duplicate(&json_record("a")));
+        assert!(!dedup.is_duplicate(&json_record("b")));
+        assert!(dedup.is_duplicate(&json_record("a")));
+        assert_eq!(dedup.duplicate_cnt, 2);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::detections::detection::EvtxRecordInfo;
+use crate::detections::message::{AlertMessage, ERROR_LOG_STACK};
+use crate::detections::utils::value_to_string;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+static PWSH_CHANNEL: &str = "Microsoft-Windows-PowerShell/Operational";
+
+/// 1つのScriptBlockIdについて受信済みの断片
+#[derive(Debug, Clone, Default)]
+struct ScriptBlockFragments {
+    total: usize,
+    parts: BTreeMap<usize, String>,
+    first_record: Option<Value>,
+    evtx_filepath: String,
+}
+
+/// 復元が完了したスクリプトブロック
+#[derive(Debug, Clone)]
+pub struct ReassembledScriptBlock {
+    pub computer: String,
+    pub script_block_id: String,
+    pub text: String,
+    pub evtx_filepath: String,
+    /// ScriptBlockTextを復元した全文に置き換えたレコード。ルールの検知に利用する
+    pub record: Value,
+}
+
+/// 複数の4104イベントに分割されたPowerShellのスクリプトブロックをバッチを跨いで復元する
+#[derive(Debug, Clone, Default)]
+pub struct ScriptBlockAssembler {
+    fragments: HashMap<(String, String), ScriptBlockFragments>,
+    pub exported_cnt: usize,
+}
+
+impl ScriptBlockAssembler {
+    pub fn process(&mut self, records: &[EvtxRecordInfo]) -> Vec<ReassembledScriptBlock> {
+        let mut ret = vec![];
+        for record in records {
+            let data = &record.record;
+            let system = &data["Event"]["System"];
+            if system["Channel"].as_str() != Some(PWSH_CHANNEL)
+                || value_to_string(&system["EventID"]).as_deref() != Some("4104")
+            {
+                continue;
+            }
+            let event_data = &data["Event"]["EventData"];
+            let number = get_usize(&event_data["MessageNumber"]);
+            let total = get_usize(&event_data["MessageTotal"]);
+            let sbid = value_to_string(&event_data["ScriptBlockId"]).unwrap_or_default();
+            // 1つのイベントで完結しているものは復元の必要がない
+            if total <= 1 || number == 0 || number > total || sbid.is_empty() {
+                continue;
+            }
+            let computer = value_to_string(&system["Computer"]).unwrap_or_default();
+            let key = (computer, sbid);
+            let fragments = self.fragments.entry(key.clone()).or_default();
+            fragments.total = total;
+            fragments.parts.insert(
+                number,
+                event_data["ScriptBlockText"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            );
+            if number == 1 || fragments.first_record.is_none() {
+                fragments.first_record = Some(data.clone());
+                fragments.evtx_filepath.clone_from(&record.evtx_filepath);
+            }
+            if fragments.parts.len() == fragments.total {
+                let fragments = self.fragments.remove(&key).unwrap();
+                ret.push(fragments.into_reassembled(key));
+            }
+        }
+        ret
+    }
+
+    /// 最後まで揃わなかったスクリプトブロックを返す。欠けている断片は空文字として扱う
+    pub fn take_incomplete(&mut self) -> Vec<ReassembledScriptBlock> {
+        self.fragments
+            .drain()
+            .map(|(key, fragments)| fragments.into_reassembled(key))
+            .collect()
+    }
+
+    /// 復元したスクリプトを<Computer>_<ScriptBlockId>.ps1というファイル名で保存する
+    pub fn export(&mut self, script: &ReassembledScriptBlock, dir: &Path, complete: bool) {
+        let suffix = if complete { "" } else { "_incomplete" };
+        let file_name = format!(
+            "{}_{}{suffix}.ps1",
+            sanitize_file_name(&script.computer),
+            sanitize_file_name(&script.script_block_id)
+        );
+        let result = fs::create_dir_all(dir)
+            .and_then(|_| fs::write(PathBuf::from(dir).join(&file_name), &script.text));
+        match result {
+            Ok(_) => self.exported_cnt += 1,
+            Err(err) => {
+                let errmsg = format!("Failed to save the script block {file_name}. {err}");
+                AlertMessage::alert(&errmsg).ok();
+                ERROR_LOG_STACK
+                    .lock()
+                    .unwrap()
+                    .push(format!("[ERROR] {errmsg}"));
+            }
+        }
+    }
+}
+
+impl ScriptBlockFragments {
+    fn into_reassembled(
+        self,
+        (computer, script_block_id): (String, String),
+    ) -> ReassembledScriptBlock {
+        let text = self.parts.into_values().collect::<String>();
+        let mut record = self.first_record.unwrap_or_default();
+        if let Some(event_data) = record["Event"]["EventData"].as_object_mut() {
+            event_data.insert("ScriptBlockText".to_string(), Value::from(text.as_str()));
+            event_data.insert("MessageNumber".to_string(), Value::from(1));
+            event_data.insert("MessageTotal".to_string(), Value::from(1));
+        }
+        ReassembledScriptBlock {
+            computer,
+            script_block_id,
+            text,
+            evtx_filepath: self.evtx_filepath,
+            record,
+        }
+    }
+}
+
+fn get_usize(value: &Value) -> usize {
+    value_to_string(value)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::timeline::scriptblock::ScriptBlockAssembler;
+    use hashbrown::HashMap;
+    use serde_json::{Value, json};
+    use std::fs;
+    use std::path::Path;
+
+    fn fragment(sbid: &str, number: u64, total: u64, text: &str) -> EvtxRecordInfo {
+        let record = json!({
+            "Event": {
+                "System": {
+                    "Channel": "Microsoft-Windows-PowerShell/Operational",
+                    "EventID": 4104,
+                    "Computer": "WIN-01"
+                },
+                "EventData": {
+                    "MessageNumber": number,
+                    "MessageTotal": total,
+                    "ScriptBlockText": text,
+                    "ScriptBlockId": sbid
+                }
+            }
+        });
+        EvtxRecordInfo {
+            evtx_filepath: "pwsh.evtx".to_string(),
+            data_string: record.to_string(),
+            record,
+            key_2_value: HashMap::new(),
+            recovered_record: false,
+        }
+    }
+
+    #[test]
+    fn test_reassemble_across_batches() {
+        let mut assembler = ScriptBlockAssembler::default();
+        let first = vec![
+            fragment("a1", 2, 3, "Invoke-"),
+            fragment("b2", 1, 1, "Get-Process"),
+        ];
+        assert!(assembler.process(&first).is_empty());
+        let second = vec![
+            fragment("a1", 3, 3, "Mimikatz"),
+            fragment("a1", 1, 3, "IEX "),
+        ];
+        let scripts = assembler.process(&second);
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts[0].computer, "WIN-01");
+        assert_eq!(scripts[0].script_block_id, "a1");
+        assert_eq!(scripts[0].text, "IEX Invoke-Mimikatz");
+        let event_data = &scripts[0].record["Event"]["EventData"];
+        assert_eq!(event_data["ScriptBlockText"], "IEX Invoke-Mimikatz");
+        assert_eq!(event_data["MessageTotal"], 1);
+        assert!(assembler.take_incomplete().is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_and_export() {
+        let mut assembler = ScriptBlockAssembler::default();
+        assert!(
+            assembler
+                .process(&[fragment("{c3/x}", 1, 2, "Write-Host")])
+                .is_empty()
+        );
+        let incomplete = assembler.take_incomplete();
+        assert_eq!(incomplete.len(), 1);
+        assert_eq!(
+            incomplete[0].record["Event"]["EventData"]["MessageNumber"],
+            Value::from(1)
+        );
+
+        let dir = Path::new("./test_scriptblock_export");
+        assembler.export(&incomplete[0], dir, false);
+        assert_eq!(assembler.exported_cnt, 1);
+        let saved = dir.join("WIN-01__c3_x__incomplete.ps1");
+        assert_eq!(fs::read_to_string(&saved).unwrap(), "Write-Host");
+        fs::remove_dir_all(dir).ok();
+    }
+}
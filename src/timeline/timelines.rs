@@ -5,6 +5,7 @@ use crate::detections::utils::{
     self, get_writable_color, make_ascii_titlecase, write_color_buffer,
 };
 use crate::timeline::search::search_result_dsp_msg;
+use chrono::Duration;
 use comfy_table::ColumnConstraint::LowerBoundary;
 use comfy_table::ColumnConstraint::UpperBoundary;
 use comfy_table::Width::Fixed;
@@ -16,6 +17,7 @@ use csv::WriterBuilder;
 use downcast_rs::__std::process;
 use nested::Nested;
 use num_format::{Locale, ToFormattedString};
+use serde_json::Value;
 use std::cmp;
 use std::fs::File;
 use std::io::BufWriter;
@@ -25,11 +27,16 @@ use terminal_size::Width;
 use terminal_size::terminal_size;
 
 use super::computer_metrics;
+use super::coverage::RuleCoverage;
 use super::metrics::EventMetrics;
 use super::search::EventSearch;
 use crate::timeline::config_critical_systems::ConfigCriticalSystems;
 use crate::timeline::extract_base64::{output_all, process_evtx_record_infos};
+use crate::timeline::integrity::{LogIntegrity, output_integrity};
+use crate::timeline::lateral_movement::LateralMovementGraph;
 use crate::timeline::log_metrics::LogMetrics;
+use crate::timeline::record_dedup::RecordDedup;
+use crate::timeline::scriptblock::ScriptBlockAssembler;
 use hashbrown::HashSet;
 use itertools::Itertools;
 
@@ -40,6 +47,11 @@ pub struct Timeline {
     pub event_search: EventSearch,
     pub extracted_base64_records: Vec<Vec<String>>,
     pub config_critical_systems: ConfigCriticalSystems,
+    pub lateral_movement: LateralMovementGraph,
+    pub integrity: LogIntegrity,
+    pub script_blocks: ScriptBlockAssembler,
+    pub coverage: RuleCoverage,
+    pub record_dedup: RecordDedup,
 }
 
 impl Default for Timeline {
@@ -59,9 +71,42 @@ impl Timeline {
             event_search: search,
             extracted_base64_records: vec![],
             config_critical_systems,
+            lateral_movement: LateralMovementGraph::default(),
+            integrity: LogIntegrity::default(),
+            script_blocks: ScriptBlockAssembler::default(),
+            coverage: RuleCoverage::default(),
+            record_dedup: RecordDedup::default(),
         }
     }
 
+    /// --remove-duplicate-recordsが指定されている場合、読み込み済みのレコードと重複するレコードを取り除く
+    pub fn remove_duplicate_records(
+        &mut self,
+        records: &mut Vec<(Value, bool)>,
+        filepath: &str,
+        stored_static: &StoredStatic,
+    ) {
+        if !stored_static.remove_duplicate_records {
+            return;
+        }
+        let mut duplicate_computers = vec![];
+        records.retain(|(record, _)| {
+            if !self.record_dedup.is_duplicate(record) {
+                return true;
+            }
+            if stored_static.log_metrics_flag {
+                duplicate_computers.push(
+                    utils::get_event_value("Computer", record, &stored_static.eventkey_alias)
+                        .map(|computer| computer.to_string().trim_matches('"').to_string())
+                        .unwrap_or_default(),
+                );
+            }
+            false
+        });
+        self.stats
+            .logfile_duplicates_cnt(filepath, &duplicate_computers, stored_static);
+    }
+
     pub fn start(&mut self, records: &[EvtxRecordInfo], stored_static: &StoredStatic) {
         if stored_static.metrics_flag {
             self.stats.evt_stats_start(
@@ -74,13 +119,20 @@ impl Timeline {
             );
         } else if stored_static.logon_summary_flag {
             self.stats.logon_stats_start(records, stored_static);
+        } else if stored_static.lateral_movement_flag {
+            self.stats.stats_time_cnt(records, stored_static);
+            self.lateral_movement
+                .process(records, stored_static.json_input_flag);
+        } else if stored_static.integrity_flag {
+            self.integrity
+                .process(records, stored_static.json_input_flag);
         } else if stored_static.log_metrics_flag {
             self.stats.logfile_stats_start(records, stored_static);
         } else if stored_static.search_flag {
             self.event_search.search_start(records, stored_static);
         } else if stored_static.extract_base64_flag {
             if let Action::ExtractBase64(opt) = &stored_static.config.action.as_ref().unwrap() {
-                let records = process_evtx_record_infos(records, &opt.time_format_options);
+                let records = process_evtx_record_infos(records, opt);
                 self.extracted_base64_records.extend(records);
             }
         } else if let Action::ConfigCriticalSystems(_) =
@@ -92,6 +144,20 @@ impl Timeline {
             Action::CsvTimeline(_) | Action::JsonTimeline(_)
         ) {
             self.stats.stats_time_cnt(records, stored_static);
+            if stored_static
+                .output_option
+                .as_ref()
+                .is_some_and(|o| o.coverage_report.is_some())
+            {
+                self.stats.stats_eventid(
+                    records,
+                    stored_static,
+                    (
+                        &stored_static.include_computer,
+                        &stored_static.exclude_computer,
+                    ),
+                );
+            }
         }
     }
 
@@ -99,6 +165,43 @@ impl Timeline {
         self.config_critical_systems.output_computers(no_color);
     }
 
+    /// lateral-movementコマンドのグラフ出力関数
+    pub fn lateral_movement_dsp_msg(&mut self, stored_static: &StoredStatic) {
+        if let Action::LateralMovement(option) = &stored_static.config.action.as_ref().unwrap() {
+            self.lateral_movement.output(
+                &option.output,
+                stored_static.common_options.no_color,
+                &option.time_format_options,
+            );
+        }
+    }
+
+    /// integrityコマンドの結果出力関数
+    pub fn integrity_dsp_msg(&mut self, stored_static: &StoredStatic) {
+        if let Action::Integrity(option) = &stored_static.config.action.as_ref().unwrap() {
+            let anomalies = self
+                .integrity
+                .analyze(Duration::hours(option.silent_hours.into()));
+            if let Err(err) = output_integrity(
+                &anomalies,
+                stored_static.output_path.as_ref(),
+                stored_static.common_options.no_color,
+                &option.time_format_options,
+            ) {
+                let errmsg = format!("Failed to output integrity results. {err}");
+                if stored_static.verbose_flag {
+                    AlertMessage::alert(&errmsg).ok();
+                }
+                if !stored_static.quiet_errors_flag {
+                    ERROR_LOG_STACK
+                        .lock()
+                        .unwrap()
+                        .push(format!("[ERROR] {errmsg}"));
+                }
+            }
+        }
+    }
+
     /// メトリクスコマンドの統計情報のメッセージ出力関数
     pub fn tm_stats_dsp_msg(
         &mut self,
@@ -548,16 +651,17 @@ impl Timeline {
         if let Action::LogMetrics(opt) = &stored_static.config.action.as_ref().unwrap() {
             let log_metrics = &mut self.stats.stats_logfile;
             log_metrics.sort_by(|a, b| a.event_count.cmp(&b.event_count).reverse());
-            let header = vec![
-                "Filename",
-                "Computers",
-                "Events",
+            let mut header = vec!["Filename", "Computers", "Events"];
+            if stored_static.remove_duplicate_records {
+                header.push("Duplicates");
+            }
+            header.extend([
                 "First Timestamp",
                 "Last Timestamp",
                 "Channels",
                 "Providers",
                 "Size",
-            ];
+            ]);
             if let Some(path) = &opt.output {
                 let file = File::create(path).expect("Failed to create output file");
                 let mut wrt = WriterBuilder::new().from_writer(file);
@@ -575,16 +679,7 @@ impl Timeline {
                     .set_header(&header);
                 for rec in &mut *log_metrics {
                     if let Some(r) = Self::create_record_array(rec, stored_static, "\n") {
-                        tb.add_row(vec![
-                            Cell::new(r[0].to_string()),
-                            Cell::new(r[1].to_string()),
-                            Cell::new(r[2].to_string()),
-                            Cell::new(r[3].to_string()),
-                            Cell::new(r[4].to_string()),
-                            Cell::new(r[5].to_string()),
-                            Cell::new(r[6].to_string()),
-                            Cell::new(r[7].to_string()),
-                        ]);
+                        tb.add_row(r.iter().map(Cell::new).collect::<Vec<_>>());
                     }
                 }
                 if log_metrics.is_empty() {
@@ -622,7 +717,7 @@ impl Timeline {
         rec: &LogMetrics,
         stored_static: &StoredStatic,
         sep: &str,
-    ) -> Option<[String; 8]> {
+    ) -> Option<Vec<String>> {
         let include_computer = &stored_static.include_computer;
         let exclude_computer = &stored_static.exclude_computer;
         if !include_computer.is_empty()
@@ -658,10 +753,15 @@ impl Timeline {
             .iter()
             .map(|ch| replace_provider_abbr(stored_static, &CompactString::from(ch)))
             .collect();
-        Some([
+        let mut ret = vec![
             rec.filename.to_string(),
             rec.computers.iter().sorted().join(sep),
             rec.event_count.to_formatted_string(&Locale::en),
+        ];
+        if stored_static.remove_duplicate_records {
+            ret.push(rec.duplicate_count.to_formatted_string(&Locale::en));
+        }
+        ret.extend([
             utils::format_time(
                 &rec.first_timestamp.unwrap_or_default(),
                 false,
@@ -685,11 +785,12 @@ impl Timeline {
             ab_ch.iter().sorted().join(sep),
             ab_provider.iter().sorted().join(sep),
             rec.file_size.to_string(),
-        ])
+        ]);
+        Some(ret)
     }
 }
 
-fn replace_channel_abbr(stored_static: &StoredStatic, fmted_channel: &CompactString) -> String {
+pub fn replace_channel_abbr(stored_static: &StoredStatic, fmted_channel: &CompactString) -> String {
     stored_static.disp_abbr_generic.replace_all(
         stored_static
             .ch_config
@@ -728,9 +829,9 @@ mod tests {
         detections::{
             configs::{
                 Action, CommonOptions, Config, DetectCommonOption, EidMetricsOption, InputOption,
-                LogonSummaryOption, STORED_EKEY_ALIAS, StoredStatic,
+                IntegrityOption, LogonSummaryOption, STORED_EKEY_ALIAS, StoredStatic,
             },
-            utils::create_rec_info,
+            utils::{create_rec_info, create_rec_info_with_alias},
         },
         timeline::timelines::Timeline,
     };
@@ -752,6 +853,7 @@ mod tests {
                     filepath: None,
                     live_analysis: false,
                     recover_records: false,
+                    remove_duplicate_records: false,
                     time_offset: None,
                 },
                 common_options: CommonOptions {
@@ -941,6 +1043,7 @@ mod tests {
                     filepath: None,
                     live_analysis: false,
                     recover_records: false,
+                    remove_duplicate_records: false,
                     time_offset: None,
                 },
                 common_options: CommonOptions {
@@ -1035,6 +1138,7 @@ mod tests {
                     filepath: Some(Path::new("./dummy.evtx").to_path_buf()),
                     live_analysis: false,
                     recover_records: false,
+                    remove_duplicate_records: false,
                     time_offset: None,
                 },
                 common_options: CommonOptions {
@@ -1209,4 +1313,62 @@ mod tests {
         assert!(remove_file("./test_tm_logon_stats-successful.csv").is_ok());
         assert!(remove_file("./test_tm_logon_stats-failed.csv").is_ok());
     }
+
+    /// VSSのコピーなどで重複したファイルを読み込んでも、integrityでは全てのレコードを確認するテスト
+    #[test]
+    pub fn test_integrity_with_overlapping_files() {
+        let stored_static = create_dummy_stored_static(Action::Integrity(IntegrityOption {
+            input_args: InputOption {
+                remove_duplicate_records: true,
+                ..Default::default()
+            },
+            detect_common_options: DetectCommonOption {
+                config: Path::new("./rules/config").to_path_buf(),
+                ..Default::default()
+            },
+            silent_hours: 24,
+            ..Default::default()
+        }));
+        assert!(!stored_static.remove_duplicate_records);
+
+        let mut timeline = Timeline::default();
+        // 2つ目のファイルは1つ目のファイルのレコードを全て含む
+        for (path, record_ids) in [("vss.evtx", 2..=3), ("Security.evtx", 1..=5)] {
+            let mut records = record_ids
+                .map(|id| {
+                    let record = serde_json::json!({
+                        "Event": {
+                            "System": {
+                                "Channel": "Security",
+                                "Computer": "HAYABUSA-DESKTOP",
+                                "EventID": 4624,
+                                "EventRecordID": id,
+                                "TimeCreated_attributes": {
+                                    "SystemTime": format!("2021-12-23T00:0{id}:00.000Z")
+                                }
+                            }
+                        }
+                    });
+                    (record, false)
+                })
+                .collect();
+            timeline.remove_duplicate_records(&mut records, path, &stored_static);
+            let records: Vec<_> = records
+                .into_iter()
+                .map(|(record, recovered)| {
+                    create_rec_info_with_alias(
+                        record,
+                        path.to_string(),
+                        &Nested::<String>::new(),
+                        &recovered,
+                        &false,
+                        &stored_static.eventkey_alias,
+                    )
+                })
+                .collect();
+            timeline.start(&records, &stored_static);
+        }
+        let anomalies = timeline.integrity.analyze(chrono::Duration::hours(24));
+        assert!(anomalies.is_empty());
+    }
 }
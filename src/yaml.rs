@@ -35,6 +35,12 @@ pub struct ParseYaml {
     pub errorrule_count: u128,
     pub exclude_status: HashSet<String>,
     pub loaded_rule_ids: HashSet<CompactString>,
+    /// 読み込んだ全てのルールのID(フィルタリングで除外されたものも含む)
+    pub defined_rule_ids: HashSet<CompactString>,
+    /// 後から指定されたルールソースで定義されているため読み込まないルールのID
+    pub overridden_rule_ids: HashSet<CompactString>,
+    /// ルールソースごとの(パス, 読み込んだルール数, 上書きされたルール数)
+    pub rule_source_cnt: Vec<(String, u128, u128)>,
 }
 
 impl ParseYaml {
@@ -59,6 +65,9 @@ impl ParseYaml {
             errorrule_count: 0,
             exclude_status: configs::convert_option_vecs_to_hs(exclude_status_vec.as_ref()),
             loaded_rule_ids: HashSet::new(),
+            defined_rule_ids: HashSet::new(),
+            overridden_rule_ids: HashSet::new(),
+            rule_source_cnt: vec![],
         }
     }
 
@@ -112,6 +121,38 @@ impl ParseYaml {
         }
     }
 
+    /// 複数のルールソースを読み込む。後に指定されたソースほど優先され、同じIDのルールを上書きする。
+    /// 各ソースの除外ルールはそのソースとそれより前に指定されたソースのルールに適用される
+    pub fn read_rule_sources(
+        &mut self,
+        sources: &[(PathBuf, RuleExclude)],
+        min_level: &str,
+        target_level: &str,
+        stored_static: &StoredStatic,
+    ) -> io::Result<String> {
+        let mut exclude_ids = RuleExclude::default();
+        for (path, source_exclude_ids) in sources.iter().rev() {
+            for (id, filename) in &source_exclude_ids.no_use_rule {
+                exclude_ids
+                    .no_use_rule
+                    .entry(id.to_owned())
+                    .or_insert_with(|| filename.to_owned());
+            }
+            self.overridden_rule_ids = self.defined_rule_ids.clone();
+            let prev_loaded_cnt = self.files.len() as u128;
+            let prev_overridden_cnt = *self.rule_load_cnt.get("overridden").unwrap_or(&0);
+            self.read_dir(path, min_level, target_level, &exclude_ids, stored_static)?;
+            self.rule_source_cnt.push((
+                path.display().to_string(),
+                self.files.len() as u128 - prev_loaded_cnt,
+                self.rule_load_cnt.get("overridden").unwrap_or(&0) - prev_overridden_cnt,
+            ));
+        }
+        self.overridden_rule_ids.clear();
+        self.rule_source_cnt.reverse();
+        io::Result::Ok(String::default())
+    }
+
     pub fn read_dir<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -266,6 +307,7 @@ impl ParseYaml {
                 // ignore if tool test yml file in hayabusa-rules.
                 if utils::contains_str(path_str, "rules/tools/sigmac/test_files")
                     || utils::contains_str(path_str, "rules\\tools\\sigmac\\test_files")
+                    || utils::is_rule_test_file(&path)
                 {
                     return io::Result::Ok(ret);
                 }
@@ -325,6 +367,15 @@ impl ParseYaml {
         }
         let exist_output_opt = stored_static.output_option.is_some();
         let files = yaml_docs.into_iter().filter_map(|(filepath, yaml_doc)| {
+            // 後から指定されたルールソースに同じIDのルールがある場合は上書きされたものとして読み込まない
+            if let Some(id) = yaml_doc["id"].as_str() {
+                if self.overridden_rule_ids.contains(id) {
+                    let entry = self.rule_load_cnt.entry("overridden".into()).or_insert(0);
+                    *entry += 1;
+                    return Option::None;
+                }
+                self.defined_rule_ids.insert(id.into());
+            }
             let mut expand_found = false;
             let mut expand_enabled_found = false;
             let place_holder_map = expand_map.as_ref().unwrap();
@@ -675,6 +726,7 @@ pub fn count_rules<P: AsRef<Path>>(
                 // ignore if tool test yml file in hayabusa-rules.
                 if utils::contains_str(path_str, "rules/tools/sigmac/test_files")
                     || utils::contains_str(path_str, "rules\\tools\\sigmac\\test_files")
+                    || utils::is_rule_test_file(&path)
                 {
                     return io::Result::Ok(ret);
                 }
@@ -1400,4 +1452,46 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_read_rule_sources() {
+        let dir = Path::new("./test_read_rule_sources");
+        let rule = |title: &str, id: &str| {
+            format!(
+                "title: {title}\nid: {id}\nauthor: test\nlevel: high\nstatus: test\ndate: 2024/01/01\nlogsource:\n    product: windows\ndetection:\n    selection:\n        EventID: 1\n    condition: selection\n"
+            )
+        };
+        let id1 = "00000000-0000-0000-0000-000000000351";
+        let id2 = "00000000-0000-0000-0000-000000000352";
+        std::fs::create_dir_all(dir.join("base")).unwrap();
+        std::fs::create_dir_all(dir.join("internal")).unwrap();
+        std::fs::create_dir_all(dir.join("case/config")).unwrap();
+        std::fs::write(dir.join("base/1.yml"), rule("Base 1", id1)).unwrap();
+        std::fs::write(dir.join("base/2.yml"), rule("Base 2", id2)).unwrap();
+        std::fs::write(dir.join("internal/1.yml"), rule("Internal 1", id1)).unwrap();
+        std::fs::write(dir.join("case/config/exclude_rules.txt"), id2).unwrap();
+
+        let dummy_stored_static = create_dummy_stored_static();
+        let sources = ["base", "internal", "case"]
+            .iter()
+            .map(|s| (dir.join(s), filter::source_exclude_ids(&dir.join(s))))
+            .collect::<Vec<_>>();
+        let mut yaml = ParseYaml::new(&dummy_stored_static);
+        yaml.read_rule_sources(&sources, "informational", "", &dummy_stored_static)
+            .unwrap();
+        std::fs::remove_dir_all(dir).ok();
+
+        // 後のソースのルールが同じIDのルールを上書きし、後のソースの除外設定が前のソースのルールに適用される
+        assert_eq!(yaml.files.len(), 1);
+        assert_eq!(yaml.files[0].1["title"].as_str(), Some("Internal 1"));
+        assert_eq!(yaml.rule_load_cnt.get("overridden"), Some(&1));
+        assert_eq!(yaml.rule_load_cnt.get("excluded"), Some(&1));
+        assert_eq!(
+            yaml.rule_source_cnt
+                .iter()
+                .map(|(_, loaded, overridden)| (*loaded, *overridden))
+                .collect::<Vec<_>>(),
+            vec![(0, 1), (1, 0), (0, 0)]
+        );
+    }
 }
@@ -0,0 +1,246 @@
+use hayabusa::level::LEVEL;
+use hayabusa::options::level_override::LevelOverrides;
+use hayabusa::scanner::Scanner;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+
+const HEADER: &str =
+    "author: test\nstatus: test\ndate: 2024/01/01\nlogsource:\n    product: windows\n";
+
+/// テストごとに独立したルールと設定のディレクトリを作成する
+fn create_rules_dir(name: &str, rules: &[(&str, String)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hayabusa_scanner_{name}_{}", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(dir.join("config")).unwrap();
+    for (file, header) in [
+        ("channel_abbreviations.txt", "Channel,Abbreviation"),
+        ("provider_abbreviations.txt", "Provider,Abbreviation"),
+        ("generic_abbreviations.txt", "Original,Abbreviation"),
+        ("default_details.txt", "Provider,EID,Details"),
+        ("channel_eid_info.txt", "Channel,EventID,Event"),
+        ("target_event_IDs.txt", ""),
+    ] {
+        fs::write(dir.join("config").join(file), format!("{header}\n")).unwrap();
+    }
+    fs::write(
+        dir.join("config/eventkey_alias.txt"),
+        "alias,event_key\nEventID,Event.System.EventID\nChannel,Event.System.Channel\nComputer,Event.System.Computer\nCommandLine,Event.EventData.CommandLine\nTargetUserName,Event.EventData.TargetUserName\n",
+    )
+    .unwrap();
+    for (file, rule) in rules {
+        fs::write(dir.join(file), rule).unwrap();
+    }
+    dir
+}
+
+fn rule(id: &str, title: &str, level: &str, detection: &str) -> String {
+    format!("title: {title}\nid: {id}\nlevel: {level}\n{HEADER}detection:\n{detection}")
+}
+
+fn event(computer: &str, event_id: u64, time: &str, data: Value) -> Value {
+    json!({"Event": {"System": {
+        "Computer": computer,
+        "Channel": "Security",
+        "EventID": event_id,
+        "EventRecordID": 1,
+        "TimeCreated_attributes": {"SystemTime": time}
+    }, "EventData": data}})
+}
+
+fn process_rule() -> (&'static str, String) {
+    (
+        "process.yml",
+        rule(
+            "00000000-0000-0000-0000-000000000101",
+            "Suspicious Process",
+            "medium",
+            "    selection:\n        EventID: 4688\n        CommandLine|contains: mimikatz\n    condition: selection\n",
+        ),
+    )
+}
+
+#[test]
+fn test_scan_records() {
+    let dir = create_rules_dir("scan", &[process_rule()]);
+    let mut scanner = Scanner::builder(&dir)
+        .profile([
+            ("Computer", "%Computer%"),
+            ("Level", "%Level%"),
+            ("RuleTitle", "%RuleTitle%"),
+        ])
+        .critical_systems(["DC01"])
+        .build()
+        .unwrap();
+    assert_eq!(scanner.rule_count(), 1);
+
+    let detect_infos = scanner.scan(vec![
+        event(
+            "PC1",
+            4688,
+            "2024-01-01T00:00:00Z",
+            json!({"CommandLine": "mimikatz.exe"}),
+        ),
+        event(
+            "PC1",
+            4688,
+            "2024-01-01T00:00:01Z",
+            json!({"CommandLine": "notepad.exe"}),
+        ),
+        event(
+            "DC01",
+            4688,
+            "2024-01-01T00:00:02Z",
+            json!({"CommandLine": "mimikatz.exe"}),
+        ),
+    ]);
+    fs::remove_dir_all(&dir).ok();
+    assert_eq!(detect_infos.len(), 2);
+    assert_eq!(detect_infos[0].ruletitle, "Suspicious Process");
+    assert_eq!(detect_infos[0].computername, "PC1");
+    assert_eq!(detect_infos[0].level, LEVEL::MEDIUM);
+    // critical_systemsに指定したコンピュータはレベルが1段階上がる
+    assert_eq!(detect_infos[1].computername, "DC01");
+    assert_eq!(detect_infos[1].level, LEVEL::HIGH);
+    assert_eq!(
+        detect_infos[1]
+            .ext_field
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>(),
+        vec!["Computer", "Level", "RuleTitle"]
+    );
+}
+
+#[test]
+fn test_independent_scanners() {
+    let correlation = format!(
+        "title: Many Logon Failures\nid: 00000000-0000-0000-0000-000000000103\nlevel: high\n{HEADER}correlation:\n    type: event_count\n    rules:\n        - 00000000-0000-0000-0000-000000000102\n    group-by:\n        - Computer\n    timespan: 5m\n    generate: true\n    condition:\n        gte: 3\n"
+    );
+    let logon_rules = [
+        (
+            "logon.yml",
+            rule(
+                "00000000-0000-0000-0000-000000000102",
+                "Logon Failure",
+                "low",
+                "    selection:\n        EventID: 4625\n    condition: selection\n",
+            ),
+        ),
+        ("correlation.yml", correlation),
+    ];
+    let process_dir = create_rules_dir("independent_process", &[process_rule()]);
+    let logon_dir = create_rules_dir("independent_logon", &logon_rules);
+    let overrides = LevelOverrides::parse("- user: svc_*\n  level: suppress\n").unwrap();
+    let mut process_scanner = Scanner::builder(&process_dir).build().unwrap();
+    let mut logon_scanner = Scanner::builder(&logon_dir)
+        .level_overrides(overrides)
+        .build()
+        .unwrap();
+    fs::remove_dir_all(&process_dir).ok();
+    fs::remove_dir_all(&logon_dir).ok();
+
+    let records: Vec<Value> = (0..4)
+        .map(|i| {
+            let user = if i == 0 { "svc_backup" } else { "admin" };
+            event(
+                "PC1",
+                4625,
+                &format!("2024-01-01T00:00:0{i}Z"),
+                json!({"TargetUserName": user}),
+            )
+        })
+        .chain([event(
+            "PC2",
+            4688,
+            "2024-01-01T00:00:05Z",
+            json!({"CommandLine": "mimikatz.exe"}),
+        )])
+        .collect();
+    // それぞれのスキャナーを別のスレッドで同時に実行しても結果が混ざらない
+    let process_records = records.clone();
+    let process_handle = thread::spawn(move || {
+        let mut detect_infos = process_scanner.scan(process_records);
+        detect_infos.extend(process_scanner.finish());
+        detect_infos
+    });
+    let logon_handle = thread::spawn(move || {
+        let detect_infos = logon_scanner.scan(records);
+        let first = logon_scanner.finish();
+        // finishの後は集計の状態がリセットされる
+        let second = logon_scanner.finish();
+        (detect_infos, first, second)
+    });
+    let process_detect_infos = process_handle.join().unwrap();
+    let (logon_detect_infos, correlation_detect_infos, reset_detect_infos) =
+        logon_handle.join().unwrap();
+
+    assert_eq!(process_detect_infos.len(), 1);
+    assert_eq!(process_detect_infos[0].ruletitle, "Suspicious Process");
+    // level_overridesで抑制したレコードは検知結果に含まれない
+    assert_eq!(logon_detect_infos.len(), 3);
+    assert!(
+        logon_detect_infos
+            .iter()
+            .all(|d| d.ruletitle == "Logon Failure")
+    );
+    assert_eq!(correlation_detect_infos.len(), 1);
+    assert_eq!(correlation_detect_infos[0].ruletitle, "Many Logon Failures");
+    assert_eq!(correlation_detect_infos[0].level, LEVEL::HIGH);
+    assert!(reset_detect_infos.is_empty());
+}
+
+#[test]
+fn test_level_override_min_level() {
+    let dir = create_rules_dir("override_min_level", &[process_rule()]);
+    let overrides = LevelOverrides::parse("- computer: LAB*\n  level: low\n").unwrap();
+    let mut scanner = Scanner::builder(&dir)
+        .min_level("medium")
+        .level_overrides(overrides)
+        .build()
+        .unwrap();
+    fs::remove_dir_all(&dir).ok();
+    let detect_infos = scanner.scan(
+        ["PC1", "LAB01"]
+            .into_iter()
+            .map(|computer| {
+                event(
+                    computer,
+                    4688,
+                    "2024-01-01T00:00:00Z",
+                    json!({"CommandLine": "mimikatz.exe"}),
+                )
+            })
+            .collect(),
+    );
+    // level_overridesで--min-levelより低いレベルに変更した検知は出力しない
+    assert_eq!(detect_infos.len(), 1);
+    assert_eq!(detect_infos[0].computername, "PC1");
+}
+
+#[test]
+fn test_build_error() {
+    let dir = create_rules_dir("error", &[]);
+    let no_rules = Scanner::builder(&dir).build();
+    let invalid_level = Scanner::builder(&dir).min_level("severe").build();
+    let no_config = Scanner::builder(&dir)
+        .config_path(dir.join("nothing"))
+        .build();
+    fs::remove_file(dir.join("config/default_details.txt")).unwrap();
+    let missing_config = Scanner::builder(&dir).build();
+    fs::write(dir.join("config/default_details.txt"), "").unwrap();
+    fs::write(dir.join("process.yml"), process_rule().1).unwrap();
+    let filtered = Scanner::builder(&dir).min_level("high").build();
+    fs::remove_dir_all(&dir).ok();
+    assert!(no_rules.is_err());
+    assert!(filtered.is_err());
+    assert!(invalid_level.is_err());
+    assert!(no_config.is_err());
+    assert!(
+        missing_config
+            .err()
+            .is_some_and(|e| e.contains("default_details.txt"))
+    );
+    assert!(Scanner::builder("./not_exist_rules").build().is_err());
+}